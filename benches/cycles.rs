@@ -0,0 +1,89 @@
+//! # Cycle Benchmarks
+//! ## Description
+//! Times how fast a busy program runs with every op code decoded on each cycle, with the decoded instruction cache,
+//! with the cache and run_until() together, and with the recompiler running whole blocks.
+//!
+//! `cargo bench` prints the nanoseconds per instruction of each way, the instructions run per second
+//! and how much faster than decoding every time it is.
+use chip_aight::components::machine::Machine;
+use chip_aight::components::predecode::DecodeCache;
+use chip_aight::components::recompiler::Recompiler;
+use std::time::Instant;
+
+/// Programs timed, all of them loop forever.
+const PROGRAMS: [(&str, &[u8]); 2] = [
+    // 6000 7001 8104 8213 A300 F233 D032 1202: count, mix registers, store their digits and draw
+    (
+        "draw",
+        &[
+            0x60, 0x00, 0x70, 0x01, 0x81, 0x04, 0x82, 0x13, 0xA3, 0x00, 0xF2, 0x33, 0xD0, 0x32,
+            0x12, 0x02,
+        ],
+    ),
+    // 7001 8104 8213 8321 8432 8543 7601 8760 1200: arithmetic only, in long blocks
+    (
+        "arithmetic",
+        &[
+            0x70, 0x01, 0x81, 0x04, 0x82, 0x13, 0x83, 0x21, 0x84, 0x32, 0x85, 0x43, 0x76, 0x01,
+            0x87, 0x60, 0x12, 0x00,
+        ],
+    ),
+];
+/// Instructions run by every way.
+const CYCLES: u64 = 5_000_000;
+
+/// Run the program and return the nanoseconds an instruction took.
+fn time(program: &[u8], setup: fn(&mut Machine), run: fn(&mut Machine)) -> f64 {
+    let mut machine = Machine::new(program).expect("Couldn't create the machine");
+    setup(&mut machine);
+    let start = Instant::now();
+    run(&mut machine);
+    let elapsed = start.elapsed();
+    assert_eq!(machine.cycles, CYCLES, "Program stopped early");
+    return elapsed.as_nanos() as f64 / CYCLES as f64;
+}
+
+fn uncached(_: &mut Machine) {}
+
+fn cached(machine: &mut Machine) {
+    machine.cpu.cache = Some(DecodeCache::default());
+}
+
+fn recompiled(machine: &mut Machine) {
+    machine.cpu.recompiler = Some(Recompiler::default());
+}
+
+fn steps(machine: &mut Machine) {
+    for _ in 0..CYCLES {
+        machine.step().expect("Program shouldn't fail");
+    }
+}
+
+fn run_until(machine: &mut Machine) {
+    machine.run_until(CYCLES).expect("Program shouldn't fail");
+}
+
+fn main() {
+    for (name, program) in PROGRAMS.iter() {
+        let decoded = time(program, uncached, steps);
+        let ways = [
+            ("decode every cycle", decoded),
+            ("cache", time(program, cached, steps)),
+            ("cache and run_until", time(program, cached, run_until)),
+            ("recompiler", time(program, recompiled, run_until)),
+        ];
+        println!(
+            "{:<24}{:>10}{:>14}{:>10}",
+            name, "ns/op", "Minstr/s", "speedup"
+        );
+        for (way, ns) in ways.iter() {
+            println!(
+                "{:<24}{:>10.2}{:>14.1}{:>9.2}x",
+                way,
+                ns,
+                1000.0 / ns,
+                decoded / ns
+            );
+        }
+    }
+}
@@ -0,0 +1,125 @@
+#ifndef CHIP_AIGHT_H
+#define CHIP_AIGHT_H
+
+/* Generated by cbindgen from src/ffi.rs, don't edit by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef struct Chip8Machine Chip8Machine;
+
+// Width of the display in pixels.
+#define CHIP8_WIDTH 64
+
+// Height of the display in pixels.
+#define CHIP8_HEIGHT 32
+
+// Height of the hi-res display in pixels.
+#define CHIP8_MAX_HEIGHT 64
+
+// Bytes chip8_framebuffer writes.
+#define CHIP8_FRAMEBUFFER_SIZE (CHIP8_WIDTH * CHIP8_HEIGHT)
+
+// Room for the display at any height.
+#define CHIP8_MAX_FRAMEBUFFER_SIZE (CHIP8_WIDTH * CHIP8_MAX_HEIGHT)
+
+// A null pointer or a buffer that is too small.
+#define CHIP8_ERROR_ARGUMENT -1
+
+// The rom doesn't fit in memory.
+#define CHIP8_ERROR_ROM -2
+
+// The save state is not valid.
+#define CHIP8_ERROR_STATE -3
+
+// The interpreter crashed, the machine is halted.
+#define CHIP8_ERROR_PANIC -4
+
+// Create a machine with a rom loaded, null if the rom doesn't fit.
+//
+// # Safety
+// `rom` must point to `len` readable bytes.
+Chip8Machine *chip8_new(const uint8_t *rom, size_t len);
+
+// Free a machine made by chip8_new, null is ignored.
+//
+// # Safety
+// `machine` must come from chip8_new and not be used after this.
+void chip8_free(Chip8Machine *machine);
+
+// Swap the rom and start over.
+//
+// # Safety
+// `machine` must come from chip8_new and `rom` must point to `len` readable bytes.
+int chip8_load_rom(Chip8Machine *machine, const uint8_t *rom, size_t len);
+
+// Run frames of cycles_per_frame cycles, returns 1 if the buzzer rang and 0 if not.
+//
+// A rom that crashes the interpreter halts the machine and returns CHIP8_ERROR_PANIC.
+//
+// # Safety
+// `machine` must come from chip8_new.
+int chip8_run_frames(Chip8Machine *machine, uint32_t frames, uint32_t cycles_per_frame);
+
+// Set every key at once, bit n of the mask is key n.
+//
+// # Safety
+// `machine` must come from chip8_new.
+int chip8_set_keys(Chip8Machine *machine, uint16_t mask);
+
+// Copy the display into a buffer of at least CHIP8_WIDTH times chip8_height bytes.
+//
+// # Safety
+// `machine` must come from chip8_new and `out` must point to `len` writable bytes.
+int chip8_framebuffer(const Chip8Machine *machine, uint8_t *out, size_t len);
+
+// Returns the height of the display in pixels.
+//
+// # Safety
+// `machine` must come from chip8_new.
+int chip8_height(const Chip8Machine *machine);
+
+// Returns 1 while the sound timer is running and 0 if not.
+//
+// # Safety
+// `machine` must come from chip8_new.
+int chip8_sound_active(const Chip8Machine *machine);
+
+// Returns the last value loaded into the sound timer since the previous call, or 0 if none.
+//
+// # Safety
+// `machine` must come from chip8_new.
+int chip8_take_beep(Chip8Machine *machine);
+
+// Returns 1 once an instruction failed and the machine stopped, 0 if not.
+//
+// # Safety
+// `machine` must come from chip8_new.
+int chip8_halted(const Chip8Machine *machine);
+
+// Soft reset if hard is 0, hard reset otherwise.
+//
+// # Safety
+// `machine` must come from chip8_new.
+int chip8_reset(Chip8Machine *machine, int hard);
+
+// Write a save state into a buffer, returns its size.
+//
+// Call it with a null buffer first to get the size needed, nothing is written if the buffer is too small.
+//
+// # Safety
+// `machine` must come from chip8_new and `out` must point to `len` writable bytes, or be null.
+ptrdiff_t chip8_save_state(const Chip8Machine *machine,
+                           uint8_t *out,
+                           size_t len);
+
+// Load a save state, the machine is untouched if it isn't valid.
+//
+// # Safety
+// `machine` must come from chip8_new and `state` must point to `len` readable bytes.
+int chip8_load_state(Chip8Machine *machine, const uint8_t *state, size_t len);
+
+#endif /* CHIP_AIGHT_H */
@@ -1,3 +1,29 @@
+pub mod analyzer;
+pub mod breakpoints;
+pub mod capture;
+pub mod cheats;
+pub mod chip8x;
+pub mod control;
 pub mod cpu;
+pub mod decoder;
+pub mod display;
+pub mod environment;
+pub mod filter;
+pub mod gdbstub;
+pub mod keymap;
+pub mod keypad;
+pub mod loader;
+pub mod machine;
+pub mod megachip;
 pub mod memory;
+pub mod platform;
+pub mod predecode;
+pub mod profiler;
+pub mod recompiler;
+pub mod romdb;
+pub mod rpc;
+pub mod savestate;
+pub mod screen;
+pub mod script;
 pub mod sound;
+pub mod watch;
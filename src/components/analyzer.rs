@@ -0,0 +1,500 @@
+//! # Analyzer Module
+//! ## Description
+//! Reads a rom without running it and works out its structure.
+//! ## Walking the program
+//! Starting at the load address every path the program can take is followed, using the decoder of the platform
+//! the interpreter would use, so CHIP-8X Bxyn colours the display and MegaChip 01NN NNNN takes 4 bytes:
+//! * Jumps continue at their target
+//! * Calls continue both at the subroutine and after the call
+//! * Skips (3xnn, 4xnn, 5xy0, 9xy0, Ex9E and ExA1) are two way branches
+//! * Bnnn depends on V0, so the walk stops there and the jump is reported as unresolved
+//! * Returns, invalid op codes, the end of the rom and the end of the 64k the program counter reaches stop the walk
+//!
+//! The instructions reached are split into basic blocks, which together make the control flow graph.
+//! ## Data
+//! Inside each block, the value of I is followed while it's known:
+//! * Sprites drawn from a known I mark those bytes as sprite data
+//! * Fx33 and Fx55 writing to bytes that were reached as code are self-modifying code suspects
+//!
+//! Bytes that are neither code nor sprites are unreachable, they are often other data or dead code.
+use super::decoder::Instruction;
+use super::predecode::Decoder;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// How a basic block ends.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exit {
+    /// Runs into the block at the address
+    Next(u16),
+    /// 1nnn
+    Jump(u16),
+    /// A skip, the first address when it doesn't skip and the second one when it does
+    Branch(u16, u16),
+    /// 2nnn or 0nnn, the subroutine and where it returns to
+    Call(u16, u16),
+    /// 00EE
+    Return,
+    /// Bnnn, the target is nnn + V0
+    Computed(u16),
+    /// The walk couldn't go on: an invalid op code or the end of the rom
+    Stop,
+}
+
+/// Instructions that always run one after the other.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    pub start: u16,
+    /// Address and instruction, in order
+    pub instructions: Vec<(u16, Instruction)>,
+    pub exit: Exit,
+}
+
+/// Everything found out about a rom.
+#[derive(Debug, Default)]
+pub struct Analysis {
+    /// Size of the rom in bytes
+    pub size: usize,
+    /// Basic blocks by their first address
+    pub blocks: BTreeMap<u16, Block>,
+    /// Addresses called with 2nnn or 0nnn
+    pub subroutines: BTreeSet<u16>,
+    /// Addresses of the Bnnn instructions
+    pub computed_jumps: Vec<u16>,
+    /// Addresses where the walk stopped without a return
+    pub invalid: Vec<u16>,
+    /// Byte ranges drawn as sprites, the end is not included
+    pub sprites: Vec<(u16, u16)>,
+    /// Address of the instruction and the byte range it writes to
+    pub self_modifying: Vec<(u16, (u16, u16))>,
+    /// Byte ranges that are neither code nor sprites
+    pub unreachable: Vec<(u16, u16)>,
+}
+
+/// Analyze a rom loaded at an address, 0x200 for most programs, with the decoder of its platform.
+pub fn analyze(program: &[u8], start: u16, decoder: Decoder) -> Analysis {
+    let end = start as usize + program.len();
+    let fetch = |addr: u16| -> Option<Result<Instruction, &'static str>> {
+        if addr < start || addr as usize + 1 >= end {
+            return None;
+        }
+        let idx = (addr - start) as usize;
+        return Some(decoder(
+            (program[idx] as u16) << 8 | program[idx + 1] as u16,
+        ));
+    };
+    let mut analysis = Analysis {
+        size: program.len(),
+        ..Default::default()
+    };
+
+    // Find every reachable instruction and where blocks begin
+    let mut leaders: BTreeSet<u16> = BTreeSet::new();
+    let mut reached: BTreeSet<u16> = BTreeSet::new();
+    let mut pending: Vec<u16> = vec![start];
+    leaders.insert(start);
+    while let Some(addr) = pending.pop() {
+        if !reached.insert(addr) {
+            continue;
+        }
+        let instruction = match fetch(addr) {
+            Some(Ok(instruction)) => instruction,
+            _ => continue,
+        };
+        let next = addr.checked_add(width(instruction));
+        match instruction {
+            Instruction::Jump(nnn) => {
+                leaders.insert(nnn);
+                pending.push(nnn);
+            }
+            Instruction::Call(nnn) | Instruction::MachineCall(nnn) if nnn != 0 => {
+                analysis.subroutines.insert(nnn);
+                leaders.insert(nnn);
+                leaders.extend(next);
+                pending.push(nnn);
+                pending.extend(next);
+            }
+            Instruction::MachineCall(_) | Instruction::Ret | Instruction::JumpV0(_) => (),
+            skip if skip.is_skip() => {
+                let skipped = next.and_then(|next| next.checked_add(2));
+                leaders.extend(next.iter().chain(skipped.iter()));
+                pending.extend(next.iter().chain(skipped.iter()));
+            }
+            _ => pending.extend(next),
+        }
+    }
+
+    // Split them into blocks
+    let mut code: BTreeSet<u16> = BTreeSet::new();
+    let mut writes: Vec<(u16, (u16, u16))> = Vec::new();
+    for leader in leaders.iter() {
+        let mut block = Block {
+            start: *leader,
+            instructions: Vec::new(),
+            exit: Exit::Stop,
+        };
+        let mut addr = *leader;
+        let mut i: Option<u16> = None;
+        loop {
+            let instruction = match fetch(addr) {
+                Some(Ok(instruction)) => instruction,
+                _ => {
+                    analysis.invalid.push(addr);
+                    break;
+                }
+            };
+            block.instructions.push((addr, instruction));
+            code.extend((0..width(instruction)).filter_map(|byte| addr.checked_add(byte)));
+            let next = addr.checked_add(width(instruction));
+            match instruction {
+                Instruction::LoadI(nnn) => i = Some(nnn),
+                Instruction::AddI(_)
+                | Instruction::Font(_)
+                | Instruction::Load(_)
+                | Instruction::LongI(_) => i = None,
+                Instruction::Draw(_, _, n) if n > 0 => {
+                    if let Some(i) = i {
+                        analysis.sprites.push((i, i.saturating_add(n as u16)));
+                    }
+                }
+                Instruction::Bcd(_) => {
+                    if let Some(i) = i {
+                        writes.push((addr, (i, i.saturating_add(3))));
+                    }
+                }
+                Instruction::Store(x) => {
+                    if let Some(start) = i {
+                        writes.push((addr, (start, start.saturating_add(x as u16 + 1))));
+                    }
+                    i = None;
+                }
+                _ => (),
+            }
+            let exit = match (instruction, next) {
+                (Instruction::Jump(nnn), _) => Some(Exit::Jump(nnn)),
+                (Instruction::Call(nnn), Some(next))
+                | (Instruction::MachineCall(nnn), Some(next))
+                    if nnn != 0 =>
+                {
+                    Some(Exit::Call(nnn, next))
+                }
+                (Instruction::MachineCall(_), _) => {
+                    analysis.invalid.push(addr);
+                    Some(Exit::Stop)
+                }
+                (Instruction::Ret, _) => Some(Exit::Return),
+                (Instruction::JumpV0(nnn), _) => {
+                    analysis.computed_jumps.push(addr);
+                    Some(Exit::Computed(nnn))
+                }
+                (skip, Some(next)) if skip.is_skip() && next.checked_add(2).is_some() => {
+                    Some(Exit::Branch(next, next + 2))
+                }
+                (_, Some(next)) if leaders.contains(&next) => Some(Exit::Next(next)),
+                (_, Some(_)) => None,
+                // The program counter can't go past the end of the 64k
+                (_, None) => {
+                    analysis.invalid.push(addr);
+                    Some(Exit::Stop)
+                }
+            };
+            if let Some(exit) = exit {
+                block.exit = exit;
+                break;
+            }
+            addr = next.unwrap_or(addr);
+        }
+        analysis.blocks.insert(*leader, block);
+    }
+
+    for (addr, (start, end)) in writes {
+        if (start..end).any(|byte| code.contains(&byte)) {
+            analysis.self_modifying.push((addr, (start, end)));
+        }
+    }
+    analysis.sprites = merge(analysis.sprites.clone());
+    let unused: Vec<(u16, u16)> = (start as usize..end.min(0x10000))
+        .map(|byte| byte as u16)
+        .filter(|byte| {
+            !code.contains(byte)
+                && !analysis
+                    .sprites
+                    .iter()
+                    .any(|(start, end)| (*start..*end).contains(byte))
+        })
+        .map(|byte| (byte, byte.saturating_add(1)))
+        .collect();
+    analysis.unreachable = merge(unused);
+    return analysis;
+}
+
+/// Bytes taken by an instruction, the MegaChip 01NN NNNN takes the next word too.
+fn width(instruction: Instruction) -> u16 {
+    return match instruction {
+        Instruction::LongI(_) => 4,
+        _ => 2,
+    };
+}
+
+/// Sort ranges and join the ones that touch or overlap, empty ones are dropped.
+fn merge(mut ranges: Vec<(u16, u16)>) -> Vec<(u16, u16)> {
+    ranges.sort();
+    let mut merged: Vec<(u16, u16)> = Vec::new();
+    for (start, end) in ranges.into_iter().filter(|(start, end)| start < end) {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    return merged;
+}
+
+fn addresses_to_string<'a>(addresses: impl Iterator<Item = &'a u16>) -> String {
+    let addresses: Vec<String> = addresses.map(|addr| format!("{:#05x}", addr)).collect();
+    if addresses.is_empty() {
+        return "none".to_owned();
+    }
+    return addresses.join(", ");
+}
+
+fn ranges_to_string(ranges: &[(u16, u16)]) -> String {
+    if ranges.is_empty() {
+        return "none".to_owned();
+    }
+    let ranges: Vec<String> = ranges
+        .iter()
+        .map(|(start, end)| format!("{:#05x}-{:#05x} ({} bytes)", start, end - 1, end - start))
+        .collect();
+    return ranges.join(", ");
+}
+
+impl Analysis {
+    /// Summary of the analysis, meant to be read by people.
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let instructions: usize = self.blocks.values().map(|b| b.instructions.len()).sum();
+        writeln!(report, "Rom size: {} bytes", self.size).unwrap();
+        writeln!(
+            report,
+            "Code: {} instructions in {} blocks",
+            instructions,
+            self.blocks.len()
+        )
+        .unwrap();
+        writeln!(
+            report,
+            "Subroutines: {}",
+            addresses_to_string(self.subroutines.iter())
+        )
+        .unwrap();
+        writeln!(
+            report,
+            "Unresolved computed jumps (Bnnn): {}",
+            addresses_to_string(self.computed_jumps.iter())
+        )
+        .unwrap();
+        writeln!(
+            report,
+            "Invalid op codes or paths leaving the rom: {}",
+            addresses_to_string(self.invalid.iter())
+        )
+        .unwrap();
+        writeln!(report, "Sprite data: {}", ranges_to_string(&self.sprites)).unwrap();
+        writeln!(
+            report,
+            "Unreachable bytes: {}",
+            ranges_to_string(&self.unreachable)
+        )
+        .unwrap();
+        writeln!(report, "Self-modifying code suspects:").unwrap();
+        for (addr, (start, end)) in self.self_modifying.iter() {
+            writeln!(
+                report,
+                "  {:#05x} writes to {:#05x}-{:#05x}",
+                addr,
+                start,
+                end - 1
+            )
+            .unwrap();
+        }
+        return report;
+    }
+    /// Control flow graph in the Graphviz DOT language.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        dot.push_str("digraph rom {\n");
+        dot.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            for (addr, instruction) in block.instructions.iter() {
+                write!(label, "{:#05x}: {}\\l", addr, instruction).unwrap();
+            }
+            if block.instructions.is_empty() {
+                label.push_str("outside the rom\\l");
+            }
+            let colour = if block.exit == Exit::Stop {
+                ", color=red"
+            } else {
+                ""
+            };
+            writeln!(
+                dot,
+                "    b{:03x} [label=\"{}\"{}];",
+                block.start, label, colour
+            )
+            .unwrap();
+            let edges: Vec<(String, &str)> = match block.exit {
+                Exit::Next(next) => vec![(format!("b{:03x}", next), "")],
+                Exit::Jump(target) => vec![(format!("b{:03x}", target), "jump")],
+                Exit::Branch(next, skip) => vec![
+                    (format!("b{:03x}", next), "next"),
+                    (format!("b{:03x}", skip), "skip"),
+                ],
+                Exit::Call(target, back) => vec![
+                    (format!("b{:03x}", target), "call"),
+                    (format!("b{:03x}", back), "return"),
+                ],
+                Exit::Computed(base) => {
+                    writeln!(
+                        dot,
+                        "    c{:03x} [shape=diamond, label=\"V0 + {:#05x}\"];",
+                        block.start, base
+                    )
+                    .unwrap();
+                    vec![(format!("c{:03x}", block.start), "computed")]
+                }
+                Exit::Return | Exit::Stop => vec![],
+            };
+            for (target, label) in edges {
+                let style = match label {
+                    "call" => ", style=dashed",
+                    "computed" => ", style=dotted",
+                    _ => "",
+                };
+                writeln!(
+                    dot,
+                    "    b{:03x} -> {} [label=\"{}\"{}];",
+                    block.start, target, label, style
+                )
+                .unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        return dot;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::decoder::{decode, decode_chip8x, decode_megachip};
+    use super::{analyze, Exit};
+    /// Branch, call, sprite, computed jump and a subroutine writing over the code.
+    const PROGRAM: [u8; 30] = [
+        0x60, 0x00, // 0x200: LD V0, 0
+        0x30, 0x01, // 0x202: SE V0, 1
+        0x22, 0x10, // 0x204: CALL 0x210
+        0xA2, 0x18, // 0x206: LD I, 0x218
+        0xD0, 0x15, // 0x208: DRW V0, V1, 5
+        0xB2, 0x0E, // 0x20A: JP V0, 0x20E
+        0x00, 0x00, 0x00, 0x00, // 0x20C: unreachable
+        0xA2, 0x00, // 0x210: LD I, 0x200
+        0xF0, 0x55, // 0x212: LD [I], V0
+        0x00, 0xEE, // 0x214: RET
+        0x00, 0x00, // 0x216: unreachable
+        0xF0, 0x90, 0x90, 0x90, 0xF0, // 0x218: sprite
+        0x00, // 0x21D: unreachable
+    ];
+    #[test]
+    fn blocks() {
+        let analysis = analyze(&PROGRAM, 0x200, decode);
+        let exits: Vec<(u16, Exit)> = analysis
+            .blocks
+            .values()
+            .map(|block| (block.start, block.exit))
+            .collect();
+        assert_eq!(
+            exits,
+            vec![
+                (0x200, Exit::Branch(0x204, 0x206)),
+                (0x204, Exit::Call(0x210, 0x206)),
+                (0x206, Exit::Computed(0x20E)),
+                (0x210, Exit::Return),
+            ]
+        );
+        assert_eq!(analysis.blocks[&0x206].instructions.len(), 3);
+        assert_eq!(
+            analysis.subroutines.iter().collect::<Vec<_>>(),
+            vec![&0x210]
+        );
+        assert_eq!(analysis.computed_jumps, vec![0x20A]);
+        assert!(analysis.invalid.is_empty(), "No invalid op codes");
+    }
+    #[test]
+    fn data() {
+        let analysis = analyze(&PROGRAM, 0x200, decode);
+        assert_eq!(analysis.sprites, vec![(0x218, 0x21D)], "Wrong sprites");
+        assert_eq!(
+            analysis.unreachable,
+            vec![(0x20C, 0x210), (0x216, 0x218), (0x21D, 0x21E)],
+            "Wrong unreachable bytes"
+        );
+        assert_eq!(analysis.self_modifying, vec![(0x212, (0x200, 0x201))]);
+    }
+    #[test]
+    fn invalid() {
+        // 1204 then an invalid 8xyF
+        let analysis = analyze(&[0x12, 0x04, 0x00, 0x00, 0x81, 0x2F], 0x200, decode);
+        assert_eq!(analysis.invalid, vec![0x204]);
+        assert_eq!(analysis.blocks[&0x204].exit, Exit::Stop);
+        // Running off the end of the rom
+        let analysis = analyze(&[0x60, 0x00], 0x200, decode);
+        assert_eq!(analysis.invalid, vec![0x202]);
+    }
+    #[test]
+    fn platforms() {
+        // B123 colours CHIP-8X rows instead of jumping, then 1302 loops
+        let analysis = analyze(&[0xB1, 0x23, 0x13, 0x02], 0x300, decode_chip8x);
+        assert!(analysis.computed_jumps.is_empty(), "Bxyn isn't a jump");
+        assert_eq!(analysis.blocks[&0x300].exit, Exit::Next(0x302));
+        // 0101 0000 takes 4 bytes on MegaChip, then 1204 loops
+        let analysis = analyze(
+            &[0x01, 0x01, 0x00, 0x00, 0x12, 0x04],
+            0x200,
+            decode_megachip,
+        );
+        assert_eq!(analysis.blocks[&0x200].instructions.len(), 1);
+        assert_eq!(analysis.blocks[&0x200].exit, Exit::Next(0x204));
+        assert!(analysis.invalid.is_empty(), "Long I split in two");
+        assert!(analysis.unreachable.is_empty());
+    }
+    #[test]
+    fn end_of_memory() {
+        // 6000 all the way to a skip and a call at the very end of the 64k
+        let mut program = [0x60, 0x00].repeat((0x10000 - 0x200) / 2);
+        let len = program.len();
+        program[len - 4..].copy_from_slice(&[0x30, 0x00, 0x22, 0x00]);
+        let analysis = analyze(&program, 0x200, decode);
+        assert_eq!(analysis.invalid, vec![0xFFFE]);
+        assert_eq!(analysis.blocks[&0xFFFE].exit, Exit::Stop);
+    }
+    #[test]
+    fn load_address() {
+        // 6000 1602 loaded at 0x600
+        let analysis = analyze(&[0x60, 0x00, 0x16, 0x02], 0x600, decode);
+        assert_eq!(
+            analysis.blocks.keys().collect::<Vec<_>>(),
+            vec![&0x600, &0x602]
+        );
+        assert!(analysis.invalid.is_empty(), "Jump inside the rom");
+        assert!(analysis.unreachable.is_empty());
+    }
+    #[test]
+    fn dot() {
+        let dot = analyze(&PROGRAM, 0x200, decode).to_dot();
+        assert!(dot.starts_with("digraph rom {"));
+        assert!(dot.contains("b200 -> b206 [label=\"skip\"];"));
+        assert!(dot.contains("b204 -> b210 [label=\"call\", style=dashed];"));
+        assert!(dot.contains("c206 [shape=diamond"));
+        assert!(dot.contains("0x212: LD [I], V0\\l"));
+    }
+}
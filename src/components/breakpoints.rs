@@ -0,0 +1,122 @@
+//! # Breakpoints Module
+//! ## Description
+//! Stops the machine right before the instruction at an address is run.
+//! ## Format
+//! Breakpoint files have hex addresses separated by commas, spaces or lines.
+//! Everything after a `#` is a comment:
+//!
+//! ```text
+//! # Main loop
+//! 0x200 0x21A
+//! 2f0 # Collision check
+//! ```
+//! ## Resuming
+//! Once stopped at an address, the same breakpoint is ignored until the instruction there ran,
+//! so resuming runs the instruction instead of stopping again.
+//! A loop on a single instruction, or Fx0A waiting for a key, stops again on the next cycle.
+use std::collections::BTreeSet;
+use std::fs;
+
+/// A set of addresses to stop at.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Breakpoints {
+    pub addresses: BTreeSet<u16>,
+    /// Address the machine last stopped at
+    stopped_at: Option<u16>,
+}
+
+impl Breakpoints {
+    /// Parse a list of hex addresses.
+    pub fn parse(text: &str) -> Result<Breakpoints, &'static str> {
+        let mut breakpoints = Breakpoints {
+            ..Default::default()
+        };
+        for line in text.lines() {
+            let line = line.splitn(2, '#').next().unwrap_or("");
+            for word in line.split(|c: char| c == ',' || c.is_whitespace()) {
+                if word.is_empty() {
+                    continue;
+                }
+                let digits = word.trim_start_matches("0x").trim_start_matches("0X");
+                let address = u16::from_str_radix(digits, 16)
+                    .map_err(|_| "Breakpoints must be hex addresses")?;
+                if address > 0xFFF {
+                    return Err("Breakpoints must be between 0x000 and 0xFFF");
+                }
+                breakpoints.addresses.insert(address);
+            }
+        }
+        return Ok(breakpoints);
+    }
+    /// Read a breakpoint file.
+    pub fn load(path: &str) -> Result<Breakpoints, &'static str> {
+        let text = fs::read_to_string(path).map_err(|_| "Couldn't read the breakpoint file")?;
+        return Breakpoints::parse(&text);
+    }
+    pub fn insert(&mut self, address: u16) {
+        self.addresses.insert(address);
+    }
+    pub fn remove(&mut self, address: u16) {
+        self.addresses.remove(&address);
+    }
+    /// Check if the machine has to stop before running the instruction at the program counter.
+    pub fn check(&mut self, program_counter: u16) -> bool {
+        if self.stopped_at == Some(program_counter) {
+            return false;
+        }
+        self.stopped_at = None;
+        if self.addresses.contains(&program_counter) {
+            self.stopped_at = Some(program_counter);
+            return true;
+        }
+        return false;
+    }
+    /// Tell that the instruction the machine stopped at ran, its breakpoint can stop it again.
+    pub fn ran(&mut self) {
+        self.stopped_at = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Breakpoints;
+    mod parse {
+        use super::Breakpoints;
+        #[test]
+        fn correct_case() {
+            let breakpoints =
+                Breakpoints::parse("# Main loop\n0x200 0x21A\n2f0, 300 # Collision\n")
+                    .expect("Breakpoints should parse");
+            let addresses: Vec<u16> = breakpoints.addresses.iter().cloned().collect();
+            assert_eq!(addresses, vec![0x200, 0x21A, 0x2F0, 0x300]);
+        }
+        #[test]
+        fn wrong_case() {
+            assert!(Breakpoints::parse("0x2G0").is_err(), "Bad digits accepted");
+            assert!(
+                Breakpoints::parse("0x1000").is_err(),
+                "Big address accepted"
+            );
+        }
+    }
+    #[test]
+    fn check() {
+        let mut breakpoints = Breakpoints::parse("0x202").unwrap();
+        assert!(!breakpoints.check(0x200), "Stopped at the wrong address");
+        assert!(breakpoints.check(0x202), "Didn't stop at the breakpoint");
+        assert!(
+            !breakpoints.check(0x202),
+            "Resuming should run the instruction"
+        );
+        assert!(!breakpoints.check(0x204));
+        assert!(
+            breakpoints.check(0x202),
+            "Breakpoint should stop again later"
+        );
+        breakpoints.ran();
+        assert!(
+            breakpoints.check(0x202),
+            "Breakpoint should stop again once the instruction ran"
+        );
+    }
+}
@@ -0,0 +1,338 @@
+//! # Capture Module
+//! ## Description
+//! Saves what the emulator shows, either as a single screenshot or as a recording.
+//! ## Formats
+//! * Screenshots are PNG files
+//! * Recordings are animated GIFs, raw Y4M video or a folder of numbered PNGs, picked by the file name
+//! * The buzzer is always recorded next to the video, with a WAV audio sink
+//!
+//! Every image is scaled by an integer factor so the pixels stay sharp.
+//! The input is the intensity of every pixel after the display filter, so the capture matches the window.
+use super::screen::Screen;
+use super::sound::{AudioSink, Sample, Tone, WavSink};
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Colours used to draw the display.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette {
+    /// Colour of lit pixels
+    pub foreground: [u8; 3],
+    /// Colour of unlit pixels
+    pub background: [u8; 3],
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette {
+            foreground: [0xFF, 0xFF, 0xFF],
+            background: [0x00, 0x00, 0x00],
+        }
+    }
+}
+
+impl Palette {
+    /// Parse a palette written as `RRGGBB,RRGGBB`, foreground first.
+    pub fn parse(text: &str) -> Result<Palette, &'static str> {
+        let colours: Vec<&str> = text.split(',').collect();
+        if colours.len() != 2 {
+            return Err("Palette needs a foreground and a background colour");
+        }
+        return Ok(Palette {
+            foreground: Palette::parse_colour(colours[0])?,
+            background: Palette::parse_colour(colours[1])?,
+        });
+    }
+    fn parse_colour(text: &str) -> Result<[u8; 3], &'static str> {
+        let text = text.trim().trim_start_matches('#');
+        let value = match u32::from_str_radix(text, 16) {
+            Ok(value) if text.len() == 6 => value,
+            _ => return Err("Colours must be written as RRGGBB"),
+        };
+        return Ok([(value >> 16) as u8, (value >> 8) as u8, value as u8]);
+    }
+    /// Mix both colours, 0x00 is the background and 0xFF the foreground.
+    pub fn shade(&self, level: u8) -> [u8; 3] {
+        let mut colour = [0; 3];
+        for (idx, channel) in colour.iter_mut().enumerate() {
+            let fg = self.foreground[idx] as u32 * level as u32;
+            let bg = self.background[idx] as u32 * (0xFF - level as u32);
+            *channel = ((fg + bg) / 0xFF) as u8;
+        }
+        return colour;
+    }
+}
+
+/// Turn the pixel intensities into scaled RGBA, row by row.
+pub fn render_rgba(shades: &Screen<u8>, scale: usize, palette: &Palette) -> Vec<u8> {
+    let (width, height) = (shades.width() * scale, shades.height() * scale);
+    let mut image = Vec::with_capacity(width * height * 4);
+    for row in 0..height {
+        for col in 0..width {
+            image.extend_from_slice(&palette.shade(shades[col / scale][row / scale]));
+            image.push(0xFF);
+        }
+    }
+    return image;
+}
+
+/// Turn the pixel intensities into scaled luma values, row by row.
+fn render_luma(shades: &Screen<u8>, scale: usize, palette: &Palette) -> Vec<u8> {
+    return render_rgba(shades, scale, palette)
+        .chunks_exact(4)
+        .map(|px| ((px[0] as u32 * 299 + px[1] as u32 * 587 + px[2] as u32 * 114) / 1000) as u8)
+        .collect();
+}
+
+/// Write a PNG of the display.
+pub fn save_png(
+    path: &str,
+    shades: &Screen<u8>,
+    scale: usize,
+    palette: &Palette,
+) -> Result<(), &'static str> {
+    let file = File::create(path).map_err(|_| "Couldn't create the PNG file")?;
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        (shades.width() * scale) as u32,
+        (shades.height() * scale) as u32,
+    );
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder
+        .write_header()
+        .map_err(|_| "Couldn't write the PNG header")?;
+    writer
+        .write_image_data(&render_rgba(shades, scale, palette))
+        .map_err(|_| "Couldn't write the PNG image")?;
+    return Ok(());
+}
+
+/// How the video of a recording is stored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordFormat {
+    /// Animated GIF
+    Gif,
+    /// Uncompressed greyscale video, most video tools can read it
+    Y4m,
+    /// A folder with a PNG for every frame
+    PngSequence,
+}
+
+impl RecordFormat {
+    /// Pick the format from the file extension, anything that isn't a GIF or a Y4M is a folder.
+    pub fn from_path(path: &str) -> RecordFormat {
+        let lower = path.to_lowercase();
+        if lower.ends_with(".gif") {
+            return RecordFormat::Gif;
+        } else if lower.ends_with(".y4m") {
+            return RecordFormat::Y4m;
+        }
+        return RecordFormat::PngSequence;
+    }
+}
+
+/// Records every frame and the buzzer until finish() is called.
+pub struct Recorder {
+    pub format: RecordFormat,
+    path: String,
+    scale: usize,
+    /// Size of the display being recorded
+    size: (usize, usize),
+    palette: Palette,
+    frames: u32,
+    gif: Option<gif::Encoder<BufWriter<File>>>,
+    y4m: Option<BufWriter<File>>,
+    audio: WavSink,
+}
+
+impl Recorder {
+    /// Start recording a display of `size` pixels, scaled up.
+    pub fn new(
+        path: &str,
+        size: (usize, usize),
+        scale: usize,
+        palette: Palette,
+        tone: Tone,
+    ) -> Result<Recorder, &'static str> {
+        let format = RecordFormat::from_path(path);
+        let width = size.0 * scale;
+        let height = size.1 * scale;
+        let mut gif = None;
+        let mut y4m = None;
+        match format {
+            RecordFormat::Gif => {
+                // The palette index is the intensity of the pixel
+                let mut colours: Vec<u8> = Vec::with_capacity(256 * 3);
+                for level in 0..=0xFF {
+                    colours.extend_from_slice(&palette.shade(level));
+                }
+                let file = File::create(path).map_err(|_| "Couldn't create the GIF file")?;
+                let mut encoder =
+                    gif::Encoder::new(BufWriter::new(file), width as u16, height as u16, &colours)
+                        .map_err(|_| "Couldn't write the GIF header")?;
+                encoder
+                    .set_repeat(gif::Repeat::Infinite)
+                    .map_err(|_| "Couldn't write the GIF header")?;
+                gif = Some(encoder);
+            }
+            RecordFormat::Y4m => {
+                let file = File::create(path).map_err(|_| "Couldn't create the Y4M file")?;
+                let mut out = BufWriter::new(file);
+                write!(
+                    out,
+                    "YUV4MPEG2 W{} H{} F60:1 Ip A1:1 Cmono\n",
+                    width, height
+                )
+                .map_err(|_| "Couldn't write the Y4M header")?;
+                y4m = Some(out);
+            }
+            RecordFormat::PngSequence => {
+                fs::create_dir_all(path).map_err(|_| "Couldn't create the PNG folder")?;
+            }
+        }
+        let audio = WavSink::create(&Recorder::wav_path(path), tone)?;
+        return Ok(Recorder {
+            format,
+            path: path.to_owned(),
+            scale,
+            size,
+            palette,
+            frames: 0,
+            gif,
+            y4m,
+            audio,
+        });
+    }
+    /// The audio goes next to the video, with the same name.
+    fn wav_path(path: &str) -> String {
+        let path = Path::new(path.trim_end_matches('/'));
+        return path.with_extension("wav").to_string_lossy().into_owned();
+    }
+    /// Ring the recorded beeper, called whenever Fx18 loads the sound timer.
+    pub fn beep_for(&mut self, ticks: u8) {
+        self.audio.beep_for(ticks);
+    }
+    /// Play a digitized sound on the recorded audio, None stops it.
+    pub fn play_sample(&mut self, sample: Option<Sample>) {
+        self.audio.play_sample(sample);
+    }
+    /// Add one 60 hertz frame to the recording.
+    pub fn record_frame(&mut self, shades: &Screen<u8>) -> Result<(), &'static str> {
+        let scale = self.scale;
+        if (shades.width(), shades.height()) != self.size {
+            return Err("The display changed size during the recording");
+        }
+        if let Some(encoder) = self.gif.as_mut() {
+            // GIF delays are in hundredths of a second, spread the rounding over the frames
+            let delay = (self.frames + 1) * 100 / 60 - self.frames * 100 / 60;
+            let (width, height) = (self.size.0 * scale, self.size.1 * scale);
+            let mut buffer: Vec<u8> = Vec::with_capacity(width * height);
+            for row in 0..height {
+                for col in 0..width {
+                    buffer.push(shades[col / scale][row / scale]);
+                }
+            }
+            let frame = gif::Frame {
+                width: width as u16,
+                height: height as u16,
+                delay: delay as u16,
+                buffer: Cow::Owned(buffer),
+                ..Default::default()
+            };
+            encoder
+                .write_frame(&frame)
+                .map_err(|_| "Couldn't write the GIF frame")?;
+        }
+        if let Some(out) = self.y4m.as_mut() {
+            let luma = render_luma(shades, scale, &self.palette);
+            out.write_all(b"FRAME\n")
+                .and_then(|_| out.write_all(&luma))
+                .map_err(|_| "Couldn't write the Y4M frame")?;
+        }
+        if self.format == RecordFormat::PngSequence {
+            let file = format!("{}/{:06}.png", self.path.trim_end_matches('/'), self.frames);
+            save_png(&file, shades, scale, &self.palette)?;
+        }
+        self.audio.end_frame()?;
+        self.frames = self.frames + 1;
+        return Ok(());
+    }
+    /// Write everything still pending and close the files.
+    pub fn finish(mut self) -> Result<(), &'static str> {
+        // The GIF trailer is written when the encoder is dropped
+        drop(self.gif);
+        if let Some(mut out) = self.y4m {
+            out.flush().map_err(|_| "Couldn't write the Y4M file")?;
+        }
+        self.audio.finish()?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::screen::Screen;
+    use super::{render_rgba, Palette, RecordFormat};
+    mod palette {
+        use super::Palette;
+        #[test]
+        fn correct_case() {
+            let palette = Palette::parse("FF8000,#000010").expect("Palette should parse");
+            assert_eq!(palette.foreground, [0xFF, 0x80, 0x00]);
+            assert_eq!(palette.background, [0x00, 0x00, 0x10]);
+        }
+        #[test]
+        fn wrong_case() {
+            assert!(Palette::parse("FFFFFF").is_err(), "Missing colour accepted");
+            assert!(
+                Palette::parse("FFFFF,000000").is_err(),
+                "Short colour accepted"
+            );
+            assert!(
+                Palette::parse("GGGGGG,000000").is_err(),
+                "Bad digits accepted"
+            );
+        }
+        #[test]
+        fn shade() {
+            let palette = Palette {
+                ..Default::default()
+            };
+            assert_eq!(palette.shade(0x00), [0x00, 0x00, 0x00]);
+            assert_eq!(palette.shade(0xFF), [0xFF, 0xFF, 0xFF]);
+            assert_eq!(palette.shade(0x80), [0x80, 0x80, 0x80]);
+        }
+    }
+    #[test]
+    fn render_scaled() {
+        let mut shades: Screen<u8> = Screen::default();
+        shades[1][0] = 0xFF;
+        let image = render_rgba(&shades, 2, &Palette::default());
+        assert_eq!(image.len(), 128 * 64 * 4, "Wrong image size");
+        assert_eq!(&image[8..12], &[0xFF, 0xFF, 0xFF, 0xFF], "Pixel not scaled");
+        assert_eq!(
+            &image[128 * 4 + 12..128 * 4 + 16],
+            &[0xFF, 0xFF, 0xFF, 0xFF]
+        );
+        assert_eq!(
+            &image[0..4],
+            &[0x00, 0x00, 0x00, 0xFF],
+            "Background is wrong"
+        );
+    }
+    #[test]
+    fn render_tall() {
+        let shades: Screen<u8> = Screen::new(64, 64);
+        let image = render_rgba(&shades, 1, &Palette::default());
+        assert_eq!(image.len(), 64 * 64 * 4, "Wrong image size");
+    }
+    #[test]
+    fn record_format() {
+        assert_eq!(RecordFormat::from_path("out.GIF"), RecordFormat::Gif);
+        assert_eq!(RecordFormat::from_path("out.y4m"), RecordFormat::Y4m);
+        assert_eq!(RecordFormat::from_path("frames"), RecordFormat::PngSequence);
+    }
+}
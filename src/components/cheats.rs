@@ -0,0 +1,324 @@
+//! # Cheats Module
+//! ## Description
+//! Finds and changes the bytes games keep their lives, timers and scores in.
+//! ## Searching
+//! A search starts with every address of a range of memory and a snapshot of that range.
+//! The range is the whole memory by default, up to 64k, MegaChip memories are searched a part at a time.
+//! Each filter compares the memory against the previous snapshot and keeps the addresses that match:
+//! * `equal` to a value, `changed`, `unchanged`, `increased` or `decreased`
+//!
+//! Lose a life, filter by `decreased`, play a bit, filter by `unchanged`, and so on until a few addresses are left.
+//! ## Codes
+//! Cheat files have a code per line, everything after a `#` is a comment:
+//!
+//! ```text
+//! # Infinite lives
+//! 3F0:05
+//! # Never run out of time, but only while the level is running
+//! 3F2:FF ? 3F8=01
+//! 3F4:00 ? 3F8!=00
+//! ```
+//!
+//! `ADDR:VALUE` writes the value every frame, after `?` comes a condition on another byte, `=` or `!=`.
+//! Addresses and values are hex.
+//! ## Freezing
+//! Frozen addresses keep the value they had when they were frozen, they are written every frame like codes.
+//! They can be anywhere in memory, codes only go up to 0xFFF.
+//!
+//! Searching and freezing are only reachable through the RPC server, the command line only loads cheat files.
+use super::memory::Memory;
+use std::collections::BTreeMap;
+use std::fs;
+
+/// How an address has to compare against the last snapshot to stay in a search.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Equal(u8),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+impl Comparison {
+    /// Parse `equal`, `changed`, `unchanged`, `increased` or `decreased`, equal takes a value.
+    pub fn parse(text: &str, value: Option<u8>) -> Result<Comparison, &'static str> {
+        return match (text, value) {
+            ("equal", Some(value)) => Ok(Comparison::Equal(value)),
+            ("equal", None) => Err("equal needs a value"),
+            ("changed", _) => Ok(Comparison::Changed),
+            ("unchanged", _) => Ok(Comparison::Unchanged),
+            ("increased", _) => Ok(Comparison::Increased),
+            ("decreased", _) => Ok(Comparison::Decreased),
+            _ => Err("Comparisons are equal, changed, unchanged, increased or decreased"),
+        };
+    }
+    fn matches(&self, before: u8, now: u8) -> bool {
+        return match self {
+            Comparison::Equal(value) => now == *value,
+            Comparison::Changed => now != before,
+            Comparison::Unchanged => now == before,
+            Comparison::Increased => now > before,
+            Comparison::Decreased => now < before,
+        };
+    }
+}
+
+/// Narrows down the addresses a value could be in.
+pub struct Search {
+    candidates: Vec<usize>,
+    /// First address of the snapshot
+    start: usize,
+    snapshot: Vec<u8>,
+}
+
+impl Search {
+    /// Biggest range searched when none is asked for.
+    pub const DEFAULT_LENGTH: usize = 0x10000;
+    /// Start with every address from start to end, remembering that part of the memory as it is now.
+    pub fn new(mem: &Memory, start: usize, end: usize) -> Result<Search, &'static str> {
+        if start > end || end > mem.space.len() {
+            return Err("The search range is out of memory");
+        }
+        return Ok(Search {
+            candidates: (start..end).collect(),
+            start: start,
+            snapshot: mem.space[start..end].to_vec(),
+        });
+    }
+    /// Start with the whole memory, or its first 64k when it's bigger.
+    pub fn whole(mem: &Memory) -> Search {
+        let end = mem.space.len().min(Search::DEFAULT_LENGTH);
+        return Search {
+            candidates: (0..end).collect(),
+            start: 0,
+            snapshot: mem.space[..end].to_vec(),
+        };
+    }
+    /// Keep the addresses that compare as asked against the last snapshot, then take a new one.
+    pub fn filter(&mut self, mem: &Memory, comparison: Comparison) {
+        let end = self.start + self.snapshot.len();
+        let space = &mem.space[self.start..end];
+        let snapshot = &self.snapshot;
+        let start = self.start;
+        self.candidates.retain(|address| {
+            let offset = *address - start;
+            comparison.matches(snapshot[offset], space[offset])
+        });
+        self.snapshot.copy_from_slice(space);
+    }
+    /// Addresses still in the search, in order.
+    pub fn candidates(&self) -> &[usize] {
+        return &self.candidates;
+    }
+}
+
+/// A byte written every frame, maybe only when another byte has some value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Code {
+    pub address: u16,
+    pub value: u8,
+    pub condition: Option<Condition>,
+}
+
+/// Compares a byte of memory against a value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Condition {
+    pub address: u16,
+    pub value: u8,
+    /// True for `=`, false for `!=`
+    pub equal: bool,
+}
+
+/// Every code and frozen address of a rom.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Cheats {
+    pub codes: Vec<Code>,
+    /// Values frozen addresses are kept at
+    pub frozen: BTreeMap<usize, u8>,
+}
+
+impl Cheats {
+    /// Biggest address codes can change.
+    pub const MAX_ADDRESS: u16 = 0xFFF;
+    /// Parse a cheat file.
+    pub fn parse(text: &str) -> Result<Cheats, &'static str> {
+        let mut cheats = Cheats {
+            ..Default::default()
+        };
+        for line in text.lines() {
+            let line = line.splitn(2, '#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.splitn(2, '?');
+            let (address, value) = parse_pair(parts.next().unwrap_or(""), ":")?;
+            let condition = match parts.next() {
+                Some(condition) => {
+                    let equal = !condition.contains("!=");
+                    let (address, value) = parse_pair(condition, if equal { "=" } else { "!=" })?;
+                    Some(Condition {
+                        address: address,
+                        value: value,
+                        equal: equal,
+                    })
+                }
+                None => None,
+            };
+            cheats.codes.push(Code {
+                address: address,
+                value: value,
+                condition: condition,
+            });
+        }
+        return Ok(cheats);
+    }
+    /// Read a cheat file.
+    pub fn load(path: &str) -> Result<Cheats, &'static str> {
+        let text = fs::read_to_string(path).map_err(|_| "Couldn't read the cheat file")?;
+        return Cheats::parse(&text);
+    }
+    /// Keep an address at the value it has now.
+    pub fn freeze(&mut self, mem: &Memory, address: usize) -> Result<(), &'static str> {
+        let value = *mem
+            .space
+            .get(address)
+            .ok_or("Frozen addresses must be in memory")?;
+        self.frozen.insert(address, value);
+        return Ok(());
+    }
+    /// Stop keeping an address at a value.
+    pub fn unfreeze(&mut self, address: usize) {
+        self.frozen.remove(&address);
+    }
+    /// Write every code whose condition holds, and every frozen address.
+    pub fn apply(&self, mem: &mut Memory) {
+        let space = &mut mem.space;
+        for code in self.codes.iter() {
+            let active = match code.condition {
+                Some(condition) => {
+                    (space[condition.address as usize] == condition.value) == condition.equal
+                }
+                None => true,
+            };
+            if active {
+                space[code.address as usize] = code.value;
+            }
+        }
+        for (address, value) in self.frozen.iter() {
+            if let Some(byte) = space.get_mut(*address) {
+                *byte = *value;
+            }
+        }
+    }
+}
+
+/// Parse `ADDR<separator>VALUE` in hex.
+fn parse_pair(text: &str, separator: &str) -> Result<(u16, u8), &'static str> {
+    let mut parts = text.splitn(2, separator);
+    let address = parts.next().unwrap_or("").trim();
+    let value = parts.next().ok_or("Cheats look like ADDR:VALUE")?.trim();
+    let address = u16::from_str_radix(address.trim_start_matches("0x"), 16)
+        .map_err(|_| "Cheat addresses must be hex")?;
+    let value = u8::from_str_radix(value.trim_start_matches("0x"), 16)
+        .map_err(|_| "Cheat values must be a hex byte")?;
+    if address > Cheats::MAX_ADDRESS {
+        return Err("Cheats must be between 0x000 and 0xFFF");
+    }
+    return Ok((address, value));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::machine::Machine;
+    use super::super::memory::Memory;
+    use super::{Cheats, Comparison, Condition, Search};
+    mod parse {
+        use super::{Cheats, Condition};
+        #[test]
+        fn correct_case() {
+            let cheats =
+                Cheats::parse("# Lives\n3F0:05\n3F2:FF ? 3F8=01\n0x3F4:0 ? 3F8!=00 # Time\n")
+                    .expect("Cheats should parse");
+            assert_eq!(cheats.codes.len(), 3);
+            assert_eq!(cheats.codes[0].address, 0x3F0);
+            assert_eq!(cheats.codes[0].value, 5);
+            assert_eq!(cheats.codes[0].condition, None);
+            assert_eq!(
+                cheats.codes[2].condition,
+                Some(Condition {
+                    address: 0x3F8,
+                    value: 0,
+                    equal: false
+                })
+            );
+        }
+        #[test]
+        fn wrong_case() {
+            assert!(Cheats::parse("3F0").is_err(), "Missing value accepted");
+            assert!(Cheats::parse("3F0:100").is_err(), "Big value accepted");
+            assert!(Cheats::parse("1000:01").is_err(), "Big address accepted");
+            assert!(
+                Cheats::parse("3F0:01 ? 3F8").is_err(),
+                "Bad condition accepted"
+            );
+        }
+    }
+    #[test]
+    fn apply() {
+        let mut machine = Machine::new(&[0x12, 0x00]).unwrap();
+        let mut cheats = Cheats::parse("300:AA\n301:BB ? 310=01\n302:CC ? 310!=01").unwrap();
+        machine.mem.space[0x320] = 7;
+        cheats.freeze(&machine.mem, 0x320).unwrap();
+        machine.mem.space[0x320] = 3;
+        cheats.apply(&mut machine.mem);
+        assert_eq!(machine.mem.space[0x300..0x303], [0xAA, 0x00, 0xCC]);
+        assert_eq!(machine.mem.space[0x320], 7, "Frozen value wasn't kept");
+        machine.mem.space[0x310] = 1;
+        cheats.apply(&mut machine.mem);
+        assert_eq!(machine.mem.space[0x301], 0xBB, "Condition should hold now");
+        cheats.unfreeze(0x320);
+        machine.mem.space[0x320] = 3;
+        cheats.apply(&mut machine.mem);
+        assert_eq!(machine.mem.space[0x320], 3);
+    }
+    #[test]
+    fn search() {
+        let mut machine = Machine::new(&[0x12, 0x00]).unwrap();
+        machine.mem.space[0x300] = 3;
+        machine.mem.space[0x301] = 3;
+        let mut search = Search::whole(&machine.mem);
+        assert_eq!(search.candidates().len(), machine.mem.space.len());
+        search.filter(&machine.mem, Comparison::Equal(3));
+        assert_eq!(search.candidates(), &[0x300, 0x301]);
+        machine.mem.space[0x300] = 2;
+        search.filter(&machine.mem, Comparison::Decreased);
+        assert_eq!(search.candidates(), &[0x300]);
+        search.filter(&machine.mem, Comparison::Unchanged);
+        assert_eq!(search.candidates(), &[0x300]);
+        search.filter(&machine.mem, Comparison::Changed);
+        assert!(search.candidates().is_empty());
+    }
+    #[test]
+    fn search_range() {
+        let mut machine = Machine::new(&[0x12, 0x00]).unwrap();
+        machine.mem = Memory::with_size(0x20000);
+        assert_eq!(
+            Search::whole(&machine.mem).candidates().len(),
+            Search::DEFAULT_LENGTH
+        );
+        assert!(Search::new(&machine.mem, 0x1FFFF, 0x20001).is_err());
+        machine.mem.space[0x18000] = 9;
+        let mut search = Search::new(&machine.mem, 0x18000, 0x18010).unwrap();
+        search.filter(&machine.mem, Comparison::Equal(9));
+        assert_eq!(search.candidates(), &[0x18000]);
+        let mut cheats = Cheats {
+            ..Default::default()
+        };
+        cheats.freeze(&machine.mem, 0x18000).unwrap();
+        assert!(cheats.freeze(&machine.mem, 0x20000).is_err());
+        machine.mem.space[0x18000] = 1;
+        cheats.apply(&mut machine.mem);
+        assert_eq!(machine.mem.space[0x18000], 9);
+    }
+}
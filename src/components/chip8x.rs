@@ -0,0 +1,156 @@
+//! # CHIP-8X Module
+//! ## Description
+//! CHIP-8X ran on the COSMAC VIP with its colour board and a second keypad.
+//! It keeps every CHIP-8 instruction but Bnnn, and adds a few of its own:
+//! * 02A0: the background goes to its next colour, blue, black, green and red in a loop
+//! * Bxy0: colour an area made of zones 8 pixels wide and 4 rows tall with the colour in Vy
+//! * Bxyn: colour n rows, starting at the row in Vx+1, with the colour in Vy
+//! * ExF2 and ExF5: skip if the key in Vx of the second keypad is pressed, or not
+//! * FxF8 and FxFB: send Vx to the I/O port, or read the port into Vx
+//! ## Areas
+//! For both Bxy0 and Bxyn, the low nibble of Vx is the first zone from the left and the high nibble how many more zones follow.
+//! Bxy0 reads the vertical zones the same way from Vx+1.
+//! ## Colours
+//! The foreground starts red everywhere and the background blue. Colours are numbered like the colour board:
+//! black, red, blue, violet, green, yellow, aqua and white.
+use super::capture::Palette;
+use super::screen::Screen;
+
+/// Colours of the colour board, by number.
+pub const COLOURS: [[u8; 3]; 8] = [
+    [0x00, 0x00, 0x00],
+    [0xFF, 0x00, 0x00],
+    [0x00, 0x00, 0xFF],
+    [0xFF, 0x00, 0xFF],
+    [0x00, 0xFF, 0x00],
+    [0xFF, 0xFF, 0x00],
+    [0x00, 0xFF, 0xFF],
+    [0xFF, 0xFF, 0xFF],
+];
+/// Colours the background cycles through.
+const BACKGROUNDS: [u8; 4] = [2, 0, 4, 1];
+/// Width of a zone in pixels.
+const ZONE_WIDTH: usize = 8;
+/// Height of a Bxy0 zone in rows.
+const ZONE_HEIGHT: usize = 4;
+
+/// The colour board, the second keypad and the I/O port.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chip8x {
+    /// Position of the background in its loop of colours
+    pub background: u8,
+    /// Foreground colour of every row of every zone
+    pub zones: Screen<u8>,
+    /// Current state of the second hex keypad
+    pub keys: [bool; 16],
+    /// Last value sent to the I/O port by FxF8, not yet seen by the frontend
+    pub port_out: Option<u8>,
+    /// Value FxFB reads from the I/O port
+    pub port_in: u8,
+}
+
+impl Default for Chip8x {
+    fn default() -> Chip8x {
+        let mut zones = Screen::new(Screen::<u8>::WIDTH / ZONE_WIDTH, Screen::<u8>::HEIGHT);
+        zones.fill(1);
+        Chip8x {
+            background: 0,
+            zones: zones,
+            keys: [false; 16],
+            port_out: None,
+            port_in: 0,
+        }
+    }
+}
+
+impl Chip8x {
+    /// 02A0 - Go to the next background colour.
+    pub fn cycle_background(&mut self) {
+        self.background = (self.background + 1) % BACKGROUNDS.len() as u8;
+    }
+    /// Bxy0 - Colour the zones, the horizontal ones are in `across` and the vertical ones in `down`.
+    pub fn colour_zones(&mut self, across: u8, down: u8, colour: u8) {
+        for row in span(down, self.zones.height() / ZONE_HEIGHT) {
+            for r in row * ZONE_HEIGHT..(row + 1) * ZONE_HEIGHT {
+                self.colour_row(across, r, colour);
+            }
+        }
+    }
+    /// Bxyn - Colour `rows` rows of the zones in `across`, starting at `top`.
+    pub fn colour_rows(&mut self, across: u8, top: u8, rows: u8, colour: u8) {
+        let height = self.zones.height();
+        for r in top as usize..top as usize + rows as usize {
+            self.colour_row(across, r % height, colour);
+        }
+    }
+    fn colour_row(&mut self, across: u8, row: usize, colour: u8) {
+        for col in span(across, self.zones.width()) {
+            self.zones[col][row] = colour & 0x7;
+        }
+    }
+    /// Colour of the background.
+    pub fn background(&self) -> [u8; 3] {
+        return COLOURS[BACKGROUNDS[self.background as usize % BACKGROUNDS.len()] as usize];
+    }
+    /// Colours a pixel of the display is drawn with.
+    pub fn palette_at(&self, col: usize, row: usize) -> Palette {
+        let zone = (col / ZONE_WIDTH) % self.zones.width();
+        return Palette {
+            foreground: COLOURS[self.zones[zone][row % self.zones.height()] as usize],
+            background: self.background(),
+        };
+    }
+    /// Get the value sent to the I/O port since the previous call, if any.
+    pub fn take_port(&mut self) -> Option<u8> {
+        return self.port_out.take();
+    }
+}
+
+/// Zones covered by a nibble pair: the low nibble is the first one, the high nibble how many more follow.
+fn span(nibbles: u8, zones: usize) -> impl Iterator<Item = usize> {
+    let first = (nibbles & 0xF) as usize;
+    let count = (nibbles >> 4) as usize + 1;
+    return (first..first + count).map(move |zone| zone % zones);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Chip8x, COLOURS};
+    #[test]
+    fn background() {
+        let mut chip8x = Chip8x::default();
+        assert_eq!(
+            chip8x.background(),
+            COLOURS[2],
+            "Background should start blue"
+        );
+        for _ in 0..4 {
+            chip8x.cycle_background();
+        }
+        assert_eq!(chip8x.background(), COLOURS[2], "Background should loop");
+        chip8x.cycle_background();
+        assert_eq!(chip8x.background(), COLOURS[0]);
+    }
+    #[test]
+    fn zones() {
+        let mut chip8x = Chip8x::default();
+        // Zones 1 and 2 across, zone 0 down, in green
+        chip8x.colour_zones(0x11, 0x00, 4);
+        assert_eq!(chip8x.palette_at(8, 0).foreground, COLOURS[4]);
+        assert_eq!(chip8x.palette_at(23, 3).foreground, COLOURS[4]);
+        assert_eq!(
+            chip8x.palette_at(8, 4).foreground,
+            COLOURS[1],
+            "Zone below changed"
+        );
+        assert_eq!(
+            chip8x.palette_at(24, 0).foreground,
+            COLOURS[1],
+            "Zone right changed"
+        );
+        // Two rows of zone 7, in white
+        chip8x.colour_rows(0x07, 30, 2, 7);
+        assert_eq!(chip8x.palette_at(63, 31).foreground, COLOURS[7]);
+        assert_eq!(chip8x.palette_at(63, 29).foreground, COLOURS[1]);
+    }
+}
@@ -0,0 +1,134 @@
+//! # Control Module
+//! ## Description
+//! Keeps track of how the frontend should be running the machine:
+//! paused, in turbo, faster or slower than real time, or one frame at a time.
+//! ## Hotkeys
+//! * F1 pauses and resumes
+//! * F2 does a soft reset, F3 a hard reset
+//! * F4 pauses and runs a single frame
+//! * Tab runs as fast as possible while held
+//! * Minus and Equals slow down and speed up the emulation
+//!
+//! The speed scales both the cpu and the timers, so games play the same, only slower or faster.
+
+/// Speed multipliers available through the hotkeys.
+pub const SPEEDS: [f64; 7] = [0.125, 0.25, 0.5, 1.0, 2.0, 4.0, 8.0];
+
+/// State of the emulation controls.
+pub struct Controls {
+    pub paused: bool,
+    /// Run without waiting for real time
+    pub turbo: bool,
+    /// Index in SPEEDS of the current speed
+    speed: usize,
+    /// Frames to run even though the machine is paused
+    pending_frames: u32,
+}
+
+impl Default for Controls {
+    fn default() -> Controls {
+        Controls {
+            paused: false,
+            turbo: false,
+            speed: 3,
+            pending_frames: 0,
+        }
+    }
+}
+
+impl Controls {
+    /// Pause if running, resume if paused.
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        self.pending_frames = 0;
+    }
+    /// Pause and queue a single frame to be run.
+    pub fn advance_frame(&mut self) {
+        self.paused = true;
+        self.pending_frames = self.pending_frames + 1;
+    }
+    /// Check if a queued frame should be run now, it is only returned once.
+    pub fn take_frame(&mut self) -> bool {
+        if self.pending_frames > 0 {
+            self.pending_frames = self.pending_frames - 1;
+            return true;
+        }
+        return false;
+    }
+    /// Go to the next speed up, stays at the fastest one.
+    pub fn faster(&mut self) {
+        if self.speed + 1 < SPEEDS.len() {
+            self.speed = self.speed + 1;
+        }
+    }
+    /// Go to the next speed down, stays at the slowest one.
+    pub fn slower(&mut self) {
+        if self.speed > 0 {
+            self.speed = self.speed - 1;
+        }
+    }
+    /// Current speed multiplier, 1.0 is real time.
+    pub fn speed(&self) -> f64 {
+        return SPEEDS[self.speed];
+    }
+    /// Turn a real time duration into the one used at the current speed.
+    pub fn scale_time(&self, micros: u128) -> u128 {
+        return (micros as f64 / self.speed()) as u128;
+    }
+    /// Text shown in the window title, the rom name followed by anything out of the ordinary.
+    pub fn title(&self, rom: &str) -> String {
+        let mut title = format!("chip-aight - {}", rom);
+        if self.paused {
+            title.push_str(" [paused]");
+        } else if self.turbo {
+            title.push_str(" [turbo]");
+        }
+        if self.speed() != 1.0 {
+            title.push_str(&format!(" [{}x]", self.speed()));
+        }
+        return title;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Controls;
+    #[test]
+    fn frame_advance() {
+        let mut controls = Controls {
+            ..Default::default()
+        };
+        assert!(!controls.take_frame(), "No frame should be queued");
+        controls.advance_frame();
+        assert!(controls.paused, "Frame advance should pause");
+        assert!(controls.take_frame(), "Frame should be queued");
+        assert!(!controls.take_frame(), "Frame should be run once");
+        controls.advance_frame();
+        controls.toggle_pause();
+        assert!(!controls.paused, "Machine should resume");
+        assert!(!controls.take_frame(), "Resuming should drop queued frames");
+    }
+    #[test]
+    fn speed() {
+        let mut controls = Controls {
+            ..Default::default()
+        };
+        assert_eq!(controls.speed(), 1.0);
+        controls.faster();
+        assert_eq!(controls.scale_time(16000), 8000, "Time should halve at 2x");
+        for _ in 0..10 {
+            controls.slower();
+        }
+        assert_eq!(controls.speed(), 0.125, "Speed should stop at the slowest");
+    }
+    #[test]
+    fn title() {
+        let mut controls = Controls {
+            ..Default::default()
+        };
+        assert_eq!(controls.title("pong"), "chip-aight - pong");
+        controls.toggle_pause();
+        controls.slower();
+        assert_eq!(controls.title("pong"), "chip-aight - pong [paused] [0.5x]");
+    }
+}
@@ -11,6 +11,9 @@
 //! ## Organization
 //! It is possible to order the instructions by looking at the most and least significant nibbles.
 //! This makes using a switch easier.
+//! The decoding itself lives in the decoder module, so tools that only read programs agree with the interpreter.
+//! With a decode cache, decoded instructions are kept by address and the decoder only runs again when memory changes.
+//! With a recompiler, whole blocks of instructions are compiled and run at once by run_block.
 //! ## Timer woes
 //! So, the CHIP-8 is not really an emulator, instead it is more of an interpreted language.
 //! What this means is that the clock speed is not a constant (Like a modern console) or set by hardware (Like the 8008, which was set by quartz crystal).
@@ -30,16 +33,27 @@
 //!
 //! The number 114 (0b1110010 in binary) gets split the following way:
 //!
-//! ```
+//! ```text
 //! mem[I] = 1
 //! mem[I + 1] = 1
 //! mem[I + 2] = 4
 //! ```
 //!
 //! This is used by the font utility to be able to display big numbers fast.
+//! ## Waiting for keys
+//! Fx0A reads the presses and releases of the keypad in order instead of comparing snapshots,
+//! so a key tapped between two frames still ends the wait.
+//! Whether it ends on the press or on the release, like on the COSMAC VIP, is set by `key_wait`.
 
+use super::chip8x::Chip8x;
+use super::decoder::{decode, decode_chip8x, decode_megachip, Instruction};
+use super::display::Display;
+use super::keypad::{KeyWait, Keypad};
+use super::megachip::{Blend, MegaChip};
 use super::memory;
-use rand::Rng;
+use super::predecode::{DecodeCache, Decoder};
+use super::recompiler::{Op, Recompiler};
+use rand::{Rng, SeedableRng};
 
 /// Represents the processor, running instructions and sending orders to other modules
 pub struct Cpu {
@@ -61,8 +75,8 @@ pub struct Cpu {
     /// Program counter
     ///
     /// It tells us what the current instruction to be executed is.
-    /// Always set to 0x200 when execution begins
-    /// (Only valid for regular CHIP-8 implementations, others may vary).
+    /// Set to 0x200 when execution begins on regular CHIP-8 implementations,
+    /// the machine moves it to the entry point of other platforms.
     pub program_counter: u16,
     /// Address register
     ///
@@ -83,14 +97,32 @@ pub struct Cpu {
     /// Set by instruction Fx18.
     /// Will do nothing if set to 0x01
     pub st: u8,
+    /// Set when Fx18 loads the sound timer, until the machine takes it for the beeper
+    pub sound_loaded: bool,
     /// Used to generate random numbers for Cxnn
-    pub rng: rand::rngs::ThreadRng,
-    /// Used by the Fx0A instruction to be able to compare changes in state
+    ///
+    /// Seeded from the system by default, seed it to make runs repeatable.
+    pub rng: rand::rngs::StdRng,
+    /// Keys pressed since Fx0A started waiting, set while it waits
     pub is_key_pressed_temp: Option<[bool; 16]>,
+    /// If Fx0A ends when a key is pressed or released
+    pub key_wait: KeyWait,
     /// In some implementations, Fx55 and Fx65 don't change the value of I
     pub store_load_quirk: bool,
     /// In some implementations x is shifted, in others, y is
     pub shift_y: bool,
+    /// Colours, second keypad and I/O port of CHIP-8X programs
+    ///
+    /// CHIP-8X instructions are only decoded when it is set.
+    pub chip8x: Option<Chip8x>,
+    /// Colour display, palette and sound of MegaChip programs
+    ///
+    /// MegaChip instructions are only decoded when it is set.
+    pub megachip: Option<MegaChip>,
+    /// Instructions already decoded, off by default so every op code is decoded on every cycle
+    pub cache: Option<DecodeCache>,
+    /// Compiles blocks of instructions for run_block, off by default
+    pub recompiler: Option<Recompiler>,
 }
 
 impl Default for Cpu {
@@ -102,10 +134,16 @@ impl Default for Cpu {
             i: 0,
             dt: 0,
             st: 0,
-            rng: rand::thread_rng(),
+            sound_loaded: false,
+            rng: rand::rngs::StdRng::from_entropy(),
             is_key_pressed_temp: None,
+            key_wait: KeyWait::Release,
             store_load_quirk: false,
             shift_y: false,
+            chip8x: None,
+            megachip: None,
+            cache: None,
+            recompiler: None,
         }
     }
 }
@@ -146,6 +184,26 @@ impl Default for Cpu {
 *   Fx33 = [I, I+1, I+2] = bcd(hex(Vx))
 *   Fx55 = [I, I..., I + x] = [V0, V..., Vx]; I = I + x + 1
 *   Fx65 = [V0, V..., Vx] = [I, I..., I + x]; I = I + x + 1
+*   CHIP-8X only, Bnnn is gone:
+*   02A0 = next_background()
+*   Bxy0 = colour(zones(Vx, Vx+1), Vy)
+*   Bxyn = colour(rows(Vx, Vx+1, n), Vy)
+*   ExF2 = Skip if key2_pressed(hex(Vx))
+*   ExF5 = Skip if !key2_pressed(hex(Vx))
+*   FxF8 = port = Vx
+*   FxFB = Vx = port
+*   MegaChip only, Dxyn draws colour sprites and 00E0 shows the frame while the mode is on:
+*   0010 = mega_off()
+*   0011 = mega_on()
+*   01nn nnnn = I = nnnnnn
+*   02nn = palette[1..=nn] = [I, I..., I + nn * 4]
+*   03nn = sprite_width = nn
+*   04nn = sprite_height = nn
+*   05nn = alpha = nn
+*   060n = play(sound(I), loop: n == 0)
+*   0700 = stop()
+*   080n = blend = n
+*   09nn = collision_colour = nn
 */
 
 impl Cpu {
@@ -173,82 +231,176 @@ impl Cpu {
     pub fn run_cycle(
         &mut self,
         mem: &mut memory::Memory,
-        state: &mut [[bool; 32]; 64],
-        keys_pressed: &[bool; 16],
+        state: &mut dyn Display,
+        keypad: &mut dyn Keypad,
     ) -> Result<&'static str, &'static str> {
         let op_code = mem
             .read(self.program_counter)
             .expect("run_cycle: Failed to read op_code");
-        let first_nibble = (op_code >> 12) as u8;
-        let nnn = op_code & 0xFFF;
-        let nn = (op_code & 0xFF) as u8;
-        let x = ((op_code & 0xF00) >> 8) as u8;
-        let y = ((op_code & 0xF0) >> 4) as u8;
-        let n = (op_code & 0xF) as u8;
         #[cfg(feature = "debug")]
         println!(
             "<< {:04x}: {:04x} >>",
             (self.program_counter) - 0x200,
             op_code
         );
-        let result = match first_nibble {
-            0x0 => match op_code {
-                0x00E0 => Ok(self.cls(state)),
-                0x00EE => Ok(self.ret_sub()),
-                _ => self.ml_sub(nnn),
-            },
-            0x1 => Ok(self.jump(nnn)),
-            0x2 => Ok(self.call_sub(nnn)),
-            0x3 => Ok(self.if_reg_equals_nn(x, nn)),
-            0x4 => Ok(self.if_not_reg_equals_nn(x, nn)),
-            0x5 => match n {
-                0 => Ok(self.if_reg_equals_reg(x, y)),
-                _ => Err("5xy0: Tail nibble was not 0x0"),
-            },
-            0x6 => Ok(self.reg_store_nn(x, nn)),
-            0x7 => Ok(self.reg_add_nn(x, nn)),
-            0x8 => match n {
-                0x0 => Ok(self.assign_reg_to_reg(x, y)),
-                0x1 => Ok(self.reg_or_reg(x, y)),
-                0x2 => Ok(self.reg_and_reg(x, y)),
-                0x3 => Ok(self.reg_xor_reg(x, y)),
-                0x4 => Ok(self.reg_plus_reg(x, y)),
-                0x5 => Ok(self.reg_minus_reg(x, y)),
-                0x6 => Ok(self.reg_shift_right(x, y)),
-                0x7 => Ok(self.reverse_reg_minus_reg(x, y)),
-                0xE => Ok(self.reg_shift_left(x, y)),
-                _ => Err("n was not in the expected values for 0x8... ops"),
-            },
-            0x9 => match n {
-                0 => Ok(self.if_not_reg_equals_reg(x, y)),
-                _ => Err("n was not in the expected values for 0x9... ops"),
-            },
-            0xA => Ok(self.store_addr(nnn)),
-            0xB => Ok(self.reg_plus_nnn_jump(nnn)),
-            0xC => Ok(self.random(x, nn)),
-            0xD => Ok(self.draw_sprite(x, y, n, state, mem)),
-            0xE => match nn {
-                0x9E => Ok(self.if_key_pressed(keys_pressed, x)),
-                0xA1 => Ok(self.if_not_key_pressed(keys_pressed, x)),
-                _ => Err("nn was not in the expected values for 0xE... ops"),
-            },
-            0xF => match nn {
-                0x07 => Ok(self.store_dt(x)),
-                0x0A => Ok(self.wait_for_keypress(x, keys_pressed)),
-                0x15 => Ok(self.dt_from_reg(x)),
-                0x18 => Ok(self.st_from_reg(x)),
-                0x1E => Ok(self.add_reg_to_i(x)),
-                0x29 => Ok(self.get_sprite_address(x)),
-                0x33 => Ok(self.get_bcd(x, mem)),
-                0x55 => Ok(self.store_regs(x, mem)),
-                0x65 => Ok(self.load_regs(x, mem)),
-                _ => Err("nn was not in the expected values for 0xF... ops"),
-            },
-            _ => Err("first_nibble bigger than 0xF"),
+        let (kind, decoder) = self.decoder();
+        let decoded = match self.cache.as_mut() {
+            Some(cache) => cache.fetch(self.program_counter, op_code, kind, decoder),
+            None => decoder(op_code),
+        };
+        let result = match decoded {
+            Ok(instruction) => self.execute(instruction, mem, state, keypad),
+            Err(err) => Err(err),
         };
         self.program_counter += 2;
         return result;
     }
+    /// Run the compiled block at the program counter, but no more than `limit` instructions of it.
+    ///
+    /// Returns how many instructions ran and the result of the last one.
+    /// Without a recompiler, or when there is nothing to compile, a single instruction is run by run_cycle.
+    pub fn run_block(
+        &mut self,
+        mem: &mut memory::Memory,
+        state: &mut dyn Display,
+        keypad: &mut dyn Keypad,
+        limit: u64,
+    ) -> (u64, Result<&'static str, &'static str>) {
+        let mut recompiler = match self.recompiler.take() {
+            Some(recompiler) => recompiler,
+            None => return (1, self.run_cycle(mem, state, keypad)),
+        };
+        let (kind, decoder) = self.decoder();
+        let result = match recompiler.block(mem, self.program_counter, kind, decoder) {
+            Some(block) => self.run_ops(&block.ops, mem, state, keypad, limit),
+            None => (1, self.run_cycle(mem, state, keypad)),
+        };
+        self.recompiler = Some(recompiler);
+        return result;
+    }
+    /// Run the ops of a block, stopping at the first failure.
+    fn run_ops(
+        &mut self,
+        ops: &[Op],
+        mem: &mut memory::Memory,
+        state: &mut dyn Display,
+        keypad: &mut dyn Keypad,
+        limit: u64,
+    ) -> (u64, Result<&'static str, &'static str>) {
+        let mut ran = 0;
+        let mut result = Ok("");
+        for op in ops.iter().take(limit as usize) {
+            result = match *op {
+                Op::Load(x, nn) => {
+                    self.v[x] = nn;
+                    Ok("6xnn")
+                }
+                Op::Add(x, nn) => {
+                    self.v[x] = self.v[x].wrapping_add(nn);
+                    Ok("7xnn")
+                }
+                Op::Assign(x, y) => {
+                    self.v[x] = self.v[y];
+                    Ok("8xy0")
+                }
+                Op::Or(x, y) => {
+                    self.v[x] = self.v[x] | self.v[y];
+                    Ok("8xy1")
+                }
+                Op::And(x, y) => {
+                    self.v[x] = self.v[x] & self.v[y];
+                    Ok("8xy2")
+                }
+                Op::Xor(x, y) => {
+                    self.v[x] = self.v[x] ^ self.v[y];
+                    Ok("8xy3")
+                }
+                Op::Interpret(instruction) => self.execute(instruction, mem, state, keypad),
+            };
+            self.program_counter += 2;
+            ran += 1;
+            if result.is_err() {
+                break;
+            }
+        }
+        return (ran, result);
+    }
+    /// Decoder of the platform, and a number that tells it apart from the others
+    pub fn decoder(&self) -> (u8, Decoder) {
+        return match (&self.chip8x, &self.megachip) {
+            (Some(_), _) => (1, decode_chip8x),
+            (None, Some(_)) => (2, decode_megachip),
+            (None, None) => (0, decode),
+        };
+    }
+    /// Run an instruction that was already decoded
+    fn execute(
+        &mut self,
+        instruction: Instruction,
+        mem: &mut memory::Memory,
+        state: &mut dyn Display,
+        keypad: &mut dyn Keypad,
+    ) -> Result<&'static str, &'static str> {
+        match instruction {
+            // The two-page hi-res interpreter clears its 64x64 display with a routine at 0x230
+            Instruction::MachineCall(0x230) if state.height() == 64 => Ok(self.cls(state)),
+            Instruction::MachineCall(nnn) => self.ml_sub(nnn),
+            Instruction::Cls if self.mega_enabled() => self.mega_cls(),
+            Instruction::Cls => Ok(self.cls(state)),
+            Instruction::Ret => Ok(self.ret_sub()),
+            Instruction::Jump(nnn) => Ok(self.jump(nnn)),
+            Instruction::Call(nnn) => Ok(self.call_sub(nnn)),
+            Instruction::SkipEqNn(x, nn) => Ok(self.if_reg_equals_nn(x, nn)),
+            Instruction::SkipNeNn(x, nn) => Ok(self.if_not_reg_equals_nn(x, nn)),
+            Instruction::SkipEqReg(x, y) => Ok(self.if_reg_equals_reg(x, y)),
+            Instruction::LoadNn(x, nn) => Ok(self.reg_store_nn(x, nn)),
+            Instruction::AddNn(x, nn) => Ok(self.reg_add_nn(x, nn)),
+            Instruction::Assign(x, y) => Ok(self.assign_reg_to_reg(x, y)),
+            Instruction::Or(x, y) => Ok(self.reg_or_reg(x, y)),
+            Instruction::And(x, y) => Ok(self.reg_and_reg(x, y)),
+            Instruction::Xor(x, y) => Ok(self.reg_xor_reg(x, y)),
+            Instruction::AddReg(x, y) => Ok(self.reg_plus_reg(x, y)),
+            Instruction::SubReg(x, y) => Ok(self.reg_minus_reg(x, y)),
+            Instruction::ShiftRight(x, y) => Ok(self.reg_shift_right(x, y)),
+            Instruction::SubnReg(x, y) => Ok(self.reverse_reg_minus_reg(x, y)),
+            Instruction::ShiftLeft(x, y) => Ok(self.reg_shift_left(x, y)),
+            Instruction::SkipNeReg(x, y) => Ok(self.if_not_reg_equals_reg(x, y)),
+            Instruction::LoadI(nnn) => Ok(self.store_addr(nnn)),
+            Instruction::JumpV0(nnn) => Ok(self.reg_plus_nnn_jump(nnn)),
+            Instruction::Random(x, nn) => Ok(self.random(x, nn)),
+            Instruction::Draw(x, y, _) if self.mega_enabled() => self.mega_draw(x, y, mem),
+            Instruction::Draw(x, y, n) => Ok(self.draw_sprite(x, y, n, state, mem)),
+            Instruction::SkipKey(x) => Ok(self.if_key_pressed(keypad, x)),
+            Instruction::SkipNotKey(x) => Ok(self.if_not_key_pressed(keypad, x)),
+            Instruction::LoadDt(x) => Ok(self.store_dt(x)),
+            Instruction::WaitKey(x) => Ok(self.wait_for_keypress(x, keypad)),
+            Instruction::SetDt(x) => Ok(self.dt_from_reg(x)),
+            Instruction::SetSt(x) => Ok(self.st_from_reg(x)),
+            Instruction::AddI(x) => Ok(self.add_reg_to_i(x)),
+            Instruction::Font(x) => Ok(self.get_sprite_address(x)),
+            Instruction::Bcd(x) => self.get_bcd(x, mem),
+            Instruction::Store(x) => self.store_regs(x, mem),
+            Instruction::Load(x) => self.load_regs(x, mem),
+            Instruction::CycleBackground => self.cycle_background(),
+            Instruction::ColourZones(x, y) => self.colour_zones(x, y),
+            Instruction::ColourRows(x, y, n) => self.colour_rows(x, y, n),
+            Instruction::SkipKey2(x) => self.if_key2_pressed(x, true),
+            Instruction::SkipNotKey2(x) => self.if_key2_pressed(x, false),
+            Instruction::PortOut(x) => self.port_out(x),
+            Instruction::PortIn(x) => self.port_in(x),
+            Instruction::MegaOff => self.set_mega(false),
+            Instruction::MegaOn => self.set_mega(true),
+            Instruction::LongI(nn) => self.long_i(nn, mem),
+            Instruction::LoadPalette(nn) => self.load_palette(nn, mem),
+            Instruction::SpriteWidth(nn) => self.set_sprite_width(nn),
+            Instruction::SpriteHeight(nn) => self.set_sprite_height(nn),
+            Instruction::Alpha(nn) => self.set_alpha(nn),
+            Instruction::PlaySample(n) => self.play_sample(n, mem),
+            Instruction::StopSample => self.stop_sample(),
+            Instruction::Blend(n) => self.set_blend(n),
+            Instruction::CollisionColour(nn) => self.set_collision_colour(nn),
+        }
+    }
     /// Used to load the fonts in the default location so that they can be used by Dxyn/draw_sprite()
     pub fn write_fonts_to_mem(mem: &mut memory::Memory) {
         for (idx, sprite) in Cpu::FONT.iter().flatten().enumerate() {
@@ -272,8 +424,8 @@ impl Cpu {
         }
     }
     /// 00E0 - cls()
-    fn cls(&self, state: &mut [[bool; 32]; 64]) -> &'static str {
-        *state = [[false; 32]; 64];
+    fn cls(&self, state: &mut dyn Display) -> &'static str {
+        state.clear();
         return "0E00";
     }
     /// 00EE - Return from subroutine
@@ -428,6 +580,9 @@ impl Cpu {
     }
     /// Annn - I = nnn
     fn store_addr(&mut self, nnn: u16) -> &'static str {
+        if let Some(megachip) = self.megachip.as_mut() {
+            megachip.bank = 0;
+        }
         self.i = nnn;
         #[cfg(feature = "debug")]
         println!("I = {:x}", (nnn - 0x200) * 2);
@@ -449,10 +604,12 @@ impl Cpu {
         x: u8,
         y: u8,
         n: u8,
-        state: &mut [[bool; 32]; 64],
+        state: &mut dyn Display,
         mem: &mut memory::Memory,
     ) -> &'static str {
         self.v[0xF] = 0;
+        #[cfg(feature = "debug")]
+        let (width, height) = (state.width(), state.height());
         for sprite_row in 0..n {
             let row_pos = (self.v[y as usize] + sprite_row) as usize;
             /*let sprite_value = (mem
@@ -462,14 +619,9 @@ impl Cpu {
             let sprite_value = mem.space[(self.i + sprite_row as u16) as usize];
             #[cfg(feature = "debug")]
             println!("{:08b}", sprite_value);
-            for sprite_col in 0..8 as u8 {
-                let col_pos = (sprite_col + self.v[x as usize]) as usize;
-                let bit = (sprite_value >> (7 - sprite_col)) & 1;
-                let state_bit = state[col_pos % 64][row_pos % 32] as u8;
-                if bit & state_bit > 0 {
-                    self.v[0xF] = 1
-                }
-                state[col_pos % 64][row_pos % 32] = (bit ^ state_bit) > 0;
+            // The display wraps the row around its edges
+            if state.draw_row(self.v[x as usize] as usize, row_pos, sprite_value) {
+                self.v[0xF] = 1
             }
         }
         #[cfg(feature = "debug")]
@@ -477,9 +629,9 @@ impl Cpu {
         #[cfg(feature = "debug")]
         let mut table: Vec<String> = Vec::new();
         #[cfg(feature = "debug")]
-        for y in 0..32 as usize {
-            for x in 0..64 as usize {
-                string = string + &((state[x][y] as u8).to_string())[..]
+        for y in 0..height {
+            for x in 0..width {
+                string = string + &((state.pixel(x, y) as u8).to_string())[..]
             }
             table.push(string.clone());
             string = "".to_owned();
@@ -491,15 +643,15 @@ impl Cpu {
         return "Dxyn";
     }
     /// Ex9E = Skip if key_pressed(hex(Vx)) //keypad is formed by numbers in hex
-    fn if_key_pressed(&mut self, keys_pressed: &[bool; 16], x: u8) -> &'static str {
-        if keys_pressed[self.v[x as usize] as usize] {
+    fn if_key_pressed(&mut self, keypad: &dyn Keypad, x: u8) -> &'static str {
+        if keypad.is_pressed(self.v[x as usize]) {
             self.program_counter += 2
         }
         return "Ex9E";
     }
     /// ExA1 = Skip if !key_pressed(hex(Vx))
-    fn if_not_key_pressed(&mut self, keys_pressed: &[bool; 16], x: u8) -> &'static str {
-        if !keys_pressed[self.v[x as usize] as usize] {
+    fn if_not_key_pressed(&mut self, keypad: &dyn Keypad, x: u8) -> &'static str {
+        if !keypad.is_pressed(self.v[x as usize]) {
             self.program_counter += 2
         }
         return "ExA1";
@@ -510,27 +662,29 @@ impl Cpu {
         return "Fx07";
     }
     /// Fx0A = Vx = block_until_keypress()
-    fn wait_for_keypress(&mut self, x: u8, keys_pressed: &[bool; 16]) -> &'static str {
-        match self.is_key_pressed_temp {
-            Some(is_key_pressed_og) => {
-                let mut modded = false; // We must not continue if not key press/release
-                for (idx, key) in keys_pressed.iter().enumerate() {
-                    if is_key_pressed_og[idx] != *key {
-                        self.v[x as usize] = idx as u8;
-                        modded = true;
-                        self.is_key_pressed_temp = None; //Once the modification's been done correctly, we clean up for the next Fx0A
-                        break;
-                    }
-                }
-                if !modded {
-                    self.program_counter -= 2
-                }
+    fn wait_for_keypress(&mut self, x: u8, keypad: &mut dyn Keypad) -> &'static str {
+        if self.is_key_pressed_temp.is_none() {
+            // Presses and releases from before the wait started can't end it
+            while keypad.next_event().is_some() {}
+        }
+        let mut pressed = self.is_key_pressed_temp.unwrap_or([false; 16]);
+        while let Some(event) = keypad.next_event() {
+            let key = event.key & 0xF;
+            let done = match (self.key_wait, event.pressed) {
+                (KeyWait::Press, true) => true,
+                // Keys held before the wait started don't count when released
+                (KeyWait::Release, false) => pressed[key as usize],
+                _ => false,
+            };
+            if done {
+                self.v[x as usize] = key;
+                self.is_key_pressed_temp = None; //Once the modification's been done correctly, we clean up for the next Fx0A
+                return "Fx0A";
             }
-            None => {
-                self.is_key_pressed_temp = Some(keys_pressed.clone());
-                self.program_counter -= 2
-            } //Store state before wait
+            pressed[key as usize] = pressed[key as usize] || event.pressed;
         }
+        self.is_key_pressed_temp = Some(pressed);
+        self.program_counter -= 2;
         return "Fx0A";
     }
     /// Fx15 = dt = Vx - OK
@@ -541,12 +695,43 @@ impl Cpu {
     /// Fx18 = st = Vx
     fn st_from_reg(&mut self, x: u8) -> &'static str {
         self.st = self.v[x as usize];
+        self.sound_loaded = true;
         return "Fx18";
     }
+    /// Address pointed at by I, with the MegaChip bank on top
+    pub fn address(&self) -> usize {
+        return match &self.megachip {
+            Some(megachip) => megachip.address(self.i),
+            None => self.i as usize,
+        };
+    }
+    /// Point I at an address, the part over 16 bits goes in the MegaChip bank
+    ///
+    /// Addresses wrap around at 16 bits, or 24 bits on MegaChip.
+    fn set_address(&mut self, address: usize) {
+        self.i = address as u16;
+        if let Some(megachip) = self.megachip.as_mut() {
+            megachip.bank = (address >> 16) as u8;
+        }
+    }
+    /// The `len` bytes of memory at I, failing if they go past the end of it
+    fn at_i<'a>(
+        &self,
+        mem: &'a mut memory::Memory,
+        len: usize,
+    ) -> Result<&'a mut [u8], &'static str> {
+        let address = self.address();
+        return mem
+            .space
+            .get_mut(address..address + len)
+            .ok_or("I points outside the memory");
+    }
     /// Fx1E = I = I + Vx
+    ///
+    /// On MegaChip, I carries into the bank.
     fn add_reg_to_i(&mut self, x: u8) -> &'static str {
         let old_i = self.i;
-        self.i = self.i + self.v[x as usize] as u16;
+        self.set_address(self.address() + self.v[x as usize] as usize);
         #[cfg(feature = "debug")]
         println!("{} + {} = {}", old_i, self.v[x as usize], self.i);
         return "Fx1E";
@@ -559,7 +744,7 @@ impl Cpu {
         return "Fx29";
     }
     /// Fx33 = [I, I+1, I+2] = bcd(hex(Vx))
-    fn get_bcd(&mut self, x: u8, mem: &mut memory::Memory) -> &'static str {
+    fn get_bcd(&mut self, x: u8, mem: &mut memory::Memory) -> Result<&'static str, &'static str> {
         let mut number = self.v[x as usize];
         let mut stack_of_digits: Vec<u8> = Vec::new();
         while number > 0 {
@@ -570,20 +755,22 @@ impl Cpu {
             stack_of_digits.push(0);
         }
         stack_of_digits.reverse();
-        for (idx, digit) in stack_of_digits.iter().enumerate() {
-            //mem.write(self.i + idx as u16, *digit as u16)
-            //    .expect("Fx33: Failed to write to memory");
-            mem.space[self.i as usize + idx] = *digit
-        }
-        return "Fx33";
+        //mem.write(self.i + idx as u16, *digit as u16)
+        //    .expect("Fx33: Failed to write to memory");
+        self.at_i(mem, 3)?.copy_from_slice(&stack_of_digits);
+        return Ok("Fx33");
     }
     /// Fx55 = [I, I..., I + x] = [V0, V..., Vx]; I = I + x + 1
-    fn store_regs(&mut self, x: u8, mem: &mut memory::Memory) -> &'static str {
+    fn store_regs(
+        &mut self,
+        x: u8,
+        mem: &mut memory::Memory,
+    ) -> Result<&'static str, &'static str> {
+        let space = self.at_i(mem, x as usize + 1)?;
         for reg in 0..=x {
-            let reg_addr = self.i + reg as u16;
             //mem.write(reg_addr, self.v[reg as usize] as u16)
             //    .expect("Fx55: Failed to write to memory");
-            mem.space[reg_addr as usize] = self.v[reg as usize];
+            space[reg as usize] = self.v[reg as usize];
             #[cfg(feature = "debug")]
             println!(
                 "I + {}: {:04x} = {}",
@@ -593,16 +780,16 @@ impl Cpu {
             )
         }
         if !self.store_load_quirk {
-            self.i = self.i + x as u16 + 1;
+            self.set_address(self.address() + x as usize + 1);
         }
-        return "Fx55";
+        return Ok("Fx55");
     }
     /// Fx65 = [V0, V..., Vx] = [I, I..., I + x]; I = I + x + 1
-    fn load_regs(&mut self, x: u8, mem: &mut memory::Memory) -> &'static str {
+    fn load_regs(&mut self, x: u8, mem: &mut memory::Memory) -> Result<&'static str, &'static str> {
+        let space = self.at_i(mem, x as usize + 1)?;
         for reg in 0..=x {
-            let reg_addr = self.i + reg as u16;
             //self.v[reg as usize] = mem.read(reg_addr).expect("Fx65: Failed to read memory") as u8;
-            self.v[reg as usize] = mem.space[reg_addr as usize];
+            self.v[reg as usize] = space[reg as usize];
             #[cfg(feature = "debug")]
             println!(
                 "I + {}: {:04x} = {}",
@@ -612,9 +799,148 @@ impl Cpu {
             )
         }
         if !self.store_load_quirk {
-            self.i = self.i + x as u16 + 1;
+            self.set_address(self.address() + x as usize + 1);
+        }
+        return Ok("Fx65");
+    }
+    /// The CHIP-8X parts, only there for CHIP-8X programs
+    fn chip8x(&mut self) -> Result<&mut Chip8x, &'static str> {
+        return self.chip8x.as_mut().ok_or("Not a CHIP-8X machine");
+    }
+    /// 02A0 = Next background colour
+    fn cycle_background(&mut self) -> Result<&'static str, &'static str> {
+        self.chip8x()?.cycle_background();
+        return Ok("02A0");
+    }
+    /// Bxy0 = colour(zones(Vx, Vx+1), Vy)
+    fn colour_zones(&mut self, x: u8, y: u8) -> Result<&'static str, &'static str> {
+        let (across, down) = (self.v[x as usize], self.v[(x as usize + 1) % 16]);
+        let colour = self.v[y as usize];
+        self.chip8x()?.colour_zones(across, down, colour);
+        return Ok("Bxy0");
+    }
+    /// Bxyn = colour(rows(Vx, Vx+1, n), Vy)
+    fn colour_rows(&mut self, x: u8, y: u8, n: u8) -> Result<&'static str, &'static str> {
+        let (across, top) = (self.v[x as usize], self.v[(x as usize + 1) % 16]);
+        let colour = self.v[y as usize];
+        self.chip8x()?.colour_rows(across, top, n, colour);
+        return Ok("Bxyn");
+    }
+    /// ExF2 = Skip if key2_pressed(hex(Vx)), ExF5 = Skip if !key2_pressed(hex(Vx))
+    fn if_key2_pressed(&mut self, x: u8, pressed: bool) -> Result<&'static str, &'static str> {
+        let key = (self.v[x as usize] & 0xF) as usize;
+        if self.chip8x()?.keys[key] == pressed {
+            self.program_counter += 2
         }
-        return "Fx65";
+        return Ok(if pressed { "ExF2" } else { "ExF5" });
+    }
+    /// FxF8 = port = Vx
+    fn port_out(&mut self, x: u8) -> Result<&'static str, &'static str> {
+        let value = self.v[x as usize];
+        self.chip8x()?.port_out = Some(value);
+        return Ok("FxF8");
+    }
+    /// FxFB = Vx = port
+    fn port_in(&mut self, x: u8) -> Result<&'static str, &'static str> {
+        self.v[x as usize] = self.chip8x()?.port_in;
+        return Ok("FxFB");
+    }
+    /// The MegaChip parts, only there for MegaChip programs
+    fn megachip(&mut self) -> Result<&mut MegaChip, &'static str> {
+        return self.megachip.as_mut().ok_or("Not a MegaChip machine");
+    }
+    fn mega_enabled(&self) -> bool {
+        return self
+            .megachip
+            .as_ref()
+            .map_or(false, |megachip| megachip.enabled);
+    }
+    /// 00E0 = show(frame); clear(frame) while the MegaChip mode is on
+    fn mega_cls(&mut self) -> Result<&'static str, &'static str> {
+        self.megachip()?.clear();
+        return Ok("00E0");
+    }
+    /// Dxyn = draw(x: Vx, y: Vy, sprite: colour_sprite(I)); VF = Collision colour drawn over?
+    fn mega_draw(
+        &mut self,
+        x: u8,
+        y: u8,
+        mem: &mut memory::Memory,
+    ) -> Result<&'static str, &'static str> {
+        let (col, row, i) = (self.v[x as usize], self.v[y as usize], self.i);
+        let megachip = self.megachip()?;
+        let address = megachip.address(i);
+        let collided = megachip.draw(&mem.space, address, col, row)?;
+        self.v[0xF] = collided as u8;
+        return Ok("Dxyn");
+    }
+    /// 0010 = mega_off(), 0011 = mega_on()
+    fn set_mega(&mut self, enabled: bool) -> Result<&'static str, &'static str> {
+        self.megachip()?.enabled = enabled;
+        return Ok(if enabled { "0011" } else { "0010" });
+    }
+    /// 01nn nnnn = I = nnnnnn
+    ///
+    /// The low 16 bits are the next word, which is skipped.
+    fn long_i(&mut self, nn: u8, mem: &mut memory::Memory) -> Result<&'static str, &'static str> {
+        let low = mem.read(self.program_counter + 2)?;
+        self.megachip()?.bank = nn;
+        self.i = low;
+        self.program_counter += 2;
+        return Ok("01nn");
+    }
+    /// 02nn = palette[1..=nn] = [I, I..., I + nn * 4]
+    fn load_palette(
+        &mut self,
+        nn: u8,
+        mem: &mut memory::Memory,
+    ) -> Result<&'static str, &'static str> {
+        let i = self.i;
+        let megachip = self.megachip()?;
+        let address = megachip.address(i);
+        return megachip.load_palette(&mem.space, address, nn);
+    }
+    /// 03nn = sprite_width = nn
+    fn set_sprite_width(&mut self, nn: u8) -> Result<&'static str, &'static str> {
+        self.megachip()?.sprite_width = nn;
+        return Ok("03nn");
+    }
+    /// 04nn = sprite_height = nn
+    fn set_sprite_height(&mut self, nn: u8) -> Result<&'static str, &'static str> {
+        self.megachip()?.sprite_height = nn;
+        return Ok("04nn");
+    }
+    /// 05nn = alpha = nn
+    fn set_alpha(&mut self, nn: u8) -> Result<&'static str, &'static str> {
+        self.megachip()?.alpha = nn;
+        return Ok("05nn");
+    }
+    /// 060n = play(sound(I), loop: n == 0)
+    fn play_sample(
+        &mut self,
+        n: u8,
+        mem: &mut memory::Memory,
+    ) -> Result<&'static str, &'static str> {
+        let i = self.i;
+        let megachip = self.megachip()?;
+        let address = megachip.address(i);
+        return megachip.play_sample(&mem.space, address, n == 0);
+    }
+    /// 0700 = stop()
+    fn stop_sample(&mut self) -> Result<&'static str, &'static str> {
+        self.megachip()?.stop_sample();
+        return Ok("0700");
+    }
+    /// 080n = blend = n
+    fn set_blend(&mut self, n: u8) -> Result<&'static str, &'static str> {
+        let blend = Blend::from_nibble(n)?;
+        self.megachip()?.blend = blend;
+        return Ok("080n");
+    }
+    /// 09nn = collision_colour = nn
+    fn set_collision_colour(&mut self, nn: u8) -> Result<&'static str, &'static str> {
+        self.megachip()?.collision = Some(nn);
+        return Ok("09nn");
     }
 }
 
@@ -637,7 +963,10 @@ mod tests {
         }
     }
     mod ops {
+        use super::super::super::display::BitDisplay;
+        use super::super::super::keypad::{EventKeypad, KeyWait, Keypad};
         use super::super::memory::Memory;
+        use super::super::Display;
         use super::Cpu;
         #[test]
         fn ml_sub() {
@@ -662,10 +991,21 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[true; 32]; 64];
+            let mut test_state = BitDisplay::default();
+            for x in 0..test_state.width() {
+                for y in 0..test_state.height() {
+                    test_state.set_pixel(x, y, true);
+                }
+            }
             cpu.cls(&mut test_state);
-            for item in test_state.iter().flat_map(|sub| sub.iter()) {
-                assert_eq!(*item, false, "Array is not empty in a certain position")
+            for x in 0..test_state.width() {
+                for y in 0..test_state.height() {
+                    assert_eq!(
+                        test_state.pixel(x, y),
+                        false,
+                        "Array is not empty in a certain position"
+                    )
+                }
             }
         }
         #[test]
@@ -1052,7 +1392,7 @@ mod tests {
             cpu.v[x as usize] = 1;
             cpu.v[y as usize] = 3;
             let n: u8 = 5;
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
+            let mut test_state = BitDisplay::default();
             cpu.i = 0x20;
             let mut mem = Memory {
                 ..Default::default()
@@ -1063,7 +1403,7 @@ mod tests {
             let mut table: Vec<String> = Vec::new();
             for y in 0..32 as usize {
                 for x in 0..64 as usize {
-                    string = string + &((test_state[x][y] as u8).to_string())[..]
+                    string = string + &((test_state.pixel(x, y) as u8).to_string())[..]
                 }
                 table.push(string.clone());
                 string = "".to_owned();
@@ -1071,8 +1411,16 @@ mod tests {
             for row in table {
                 println!("{:?}", row);
             }*/
-            assert_eq!(test_state[1][3], true, "Top Left Corner should be true");
-            assert_eq!(test_state[4][7], true, "Bottom Right Corner should be true");
+            assert_eq!(
+                test_state.pixel(1, 3),
+                true,
+                "Top Left Corner should be true"
+            );
+            assert_eq!(
+                test_state.pixel(4, 7),
+                true,
+                "Bottom Right Corner should be true"
+            );
             assert_eq!(cpu.v[0xF], 0, "Overwrite should be 0");
         }
         #[test]
@@ -1085,7 +1433,7 @@ mod tests {
             cpu.v[x as usize] = 62;
             cpu.v[y as usize] = 30;
             let n: u8 = 5;
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
+            let mut test_state = BitDisplay::default();
             cpu.i = 0x20;
             let mut mem = Memory {
                 ..Default::default()
@@ -1096,7 +1444,7 @@ mod tests {
             let mut table: Vec<String> = Vec::new();
             for y in 0..32 as usize {
                 for x in 0..64 as usize {
-                    string = string + &((test_state[x][y] as u8).to_string())[..]
+                    string = string + &((test_state.pixel(x, y) as u8).to_string())[..]
                 }
                 table.push(string.clone());
                 string = "".to_owned();
@@ -1104,8 +1452,16 @@ mod tests {
             for row in table {
                 println!("{:?}", row);
             }*/
-            assert_eq!(test_state[62][30], true, "Top Left Corner should be true");
-            assert_eq!(test_state[1][2], true, "Bottom Right Corner should be true")
+            assert_eq!(
+                test_state.pixel(62, 30),
+                true,
+                "Top Left Corner should be true"
+            );
+            assert_eq!(
+                test_state.pixel(1, 2),
+                true,
+                "Bottom Right Corner should be true"
+            )
         }
         #[test]
         fn draw_sprite_overwrite() {
@@ -1117,7 +1473,7 @@ mod tests {
             cpu.v[x as usize] = 1;
             cpu.v[y as usize] = 3;
             let n: u8 = 5;
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
+            let mut test_state = BitDisplay::default();
             cpu.i = 0x20;
             let mut mem = Memory {
                 ..Default::default()
@@ -1129,7 +1485,7 @@ mod tests {
             let mut table: Vec<String> = Vec::new();
             for y in 0..32 as usize {
                 for x in 0..64 as usize {
-                    string = string + &((test_state[x][y] as u8).to_string())[..]
+                    string = string + &((test_state.pixel(x, y) as u8).to_string())[..]
                 }
                 table.push(string.clone());
                 string = "".to_owned();
@@ -1137,9 +1493,14 @@ mod tests {
             for row in table {
                 println!("{:?}", row);
             }*/
-            assert_eq!(test_state[1][3], false, "Top Left Corner should be false");
             assert_eq!(
-                test_state[4][7], false,
+                test_state.pixel(1, 3),
+                false,
+                "Top Left Corner should be false"
+            );
+            assert_eq!(
+                test_state.pixel(4, 7),
+                false,
                 "Bottom Right Corner should befalse"
             );
             assert_eq!(cpu.v[0xF], 1, "Overwrite should be 1");
@@ -1224,24 +1585,23 @@ mod tests {
         #[test]
         fn wait_for_keypress_press() {
             let mut cpu = Cpu {
+                key_wait: KeyWait::Press,
                 ..Default::default()
             };
             let x: u8 = 0x3;
-            let mut is_key_pressed: [bool; 16] = [false; 16];
+            let mut keypad = EventKeypad::default();
             let expected_pc = cpu.program_counter + 1;
-            cpu.wait_for_keypress(x, &is_key_pressed);
-            cpu.program_counter = cpu.program_counter + 1;
-            cpu.wait_for_keypress(x, &is_key_pressed);
-            cpu.program_counter = cpu.program_counter + 1;
-            cpu.wait_for_keypress(x, &is_key_pressed);
-            cpu.program_counter = cpu.program_counter + 1;
+            for _ in 0..3 {
+                cpu.wait_for_keypress(x, &mut keypad);
+                cpu.program_counter = cpu.program_counter + 1;
+            }
             assert_eq!(
                 cpu.program_counter,
                 expected_pc - 1,
                 "Address shouldn't be incremented yet"
             );
-            is_key_pressed[0xE] = true;
-            cpu.wait_for_keypress(x, &is_key_pressed);
+            keypad.set_key(0xE, true, 0);
+            cpu.wait_for_keypress(x, &mut keypad);
             cpu.program_counter = cpu.program_counter + 1;
             assert_eq!(
                 cpu.program_counter, expected_pc,
@@ -1262,22 +1622,22 @@ mod tests {
                 ..Default::default()
             };
             let x: u8 = 0x3;
-            let mut is_key_pressed: [bool; 16] = [false; 16];
-            is_key_pressed[0xE] = true;
+            let mut keypad = EventKeypad::default();
             let expected_pc = cpu.program_counter + 1;
-            cpu.wait_for_keypress(x, &is_key_pressed);
-            cpu.program_counter = cpu.program_counter + 1;
-            cpu.wait_for_keypress(x, &is_key_pressed);
+            keypad.set_key(0x2, true, 0);
+            cpu.wait_for_keypress(x, &mut keypad);
             cpu.program_counter = cpu.program_counter + 1;
-            cpu.wait_for_keypress(x, &is_key_pressed);
+            keypad.set_key(0xE, true, 1);
+            keypad.set_key(0x2, false, 2);
+            cpu.wait_for_keypress(x, &mut keypad);
             cpu.program_counter = cpu.program_counter + 1;
             assert_eq!(
                 cpu.program_counter,
                 expected_pc - 1,
-                "Address shouldn't be incremented yet"
+                "Address shouldn't be incremented before a release"
             );
-            is_key_pressed[0xE] = false;
-            cpu.wait_for_keypress(x, &is_key_pressed);
+            keypad.set_key(0xE, false, 3);
+            cpu.wait_for_keypress(x, &mut keypad);
             cpu.program_counter = cpu.program_counter + 1;
             assert_eq!(
                 cpu.program_counter, expected_pc,
@@ -1293,6 +1653,48 @@ mod tests {
             );
         }
         #[test]
+        fn wait_for_keypress_tap() {
+            let mut cpu = Cpu {
+                ..Default::default()
+            };
+            let mut keypad = EventKeypad::default();
+            let expected_pc = cpu.program_counter;
+            cpu.wait_for_keypress(0x0, &mut keypad);
+            cpu.program_counter = cpu.program_counter + 2;
+            keypad.set_key(0x7, true, 1);
+            keypad.set_key(0x7, false, 2);
+            keypad.set_key(0x9, true, 3);
+            cpu.wait_for_keypress(0x0, &mut keypad);
+            assert_eq!(cpu.program_counter, expected_pc, "Tap was lost");
+            assert_eq!(cpu.v[0x0], 0x7);
+            assert!(
+                keypad.next_event().map_or(false, |event| event.key == 0x9),
+                "Later events should be left for the next wait"
+            );
+        }
+        #[test]
+        fn wait_for_keypress_stale() {
+            let mut cpu = Cpu {
+                key_wait: KeyWait::Press,
+                ..Default::default()
+            };
+            let mut keypad = EventKeypad::default();
+            keypad.set_key(0x4, true, 0);
+            keypad.set_key(0x4, false, 1);
+            let expected_pc = cpu.program_counter;
+            cpu.wait_for_keypress(0x0, &mut keypad);
+            assert_eq!(
+                cpu.program_counter,
+                expected_pc - 2,
+                "Press from before the wait shouldn't end it"
+            );
+            cpu.program_counter = cpu.program_counter + 2;
+            keypad.set_key(0x4, true, 2);
+            cpu.wait_for_keypress(0x0, &mut keypad);
+            assert_eq!(cpu.program_counter, expected_pc);
+            assert_eq!(cpu.v[0x0], 0x4);
+        }
+        #[test]
         fn dt_from_reg() {
             let mut cpu = Cpu {
                 ..Default::default()
@@ -1311,6 +1713,10 @@ mod tests {
             cpu.v[x as usize] = 10;
             cpu.st_from_reg(x);
             assert_eq!(cpu.st, 10, "Sound timer should be set properly");
+            assert!(
+                cpu.sound_loaded,
+                "Loading the sound timer should be flagged"
+            );
         }
         #[test]
         fn add_reg_to_i() {
@@ -1322,6 +1728,9 @@ mod tests {
             cpu.i = 10;
             cpu.add_reg_to_i(x);
             assert_eq!(cpu.i, 20, "I should be incremented properly");
+            cpu.i = 0xFFFF;
+            cpu.add_reg_to_i(x);
+            assert_eq!(cpu.i, 9, "I should wrap around");
         }
         #[test]
         fn get_sprite_address() {
@@ -1384,7 +1793,7 @@ mod tests {
             let x = 0x3;
             cpu.v[x as usize] = 123;
             cpu.i = 0x400;
-            cpu.get_bcd(x, &mut mem);
+            cpu.get_bcd(x, &mut mem).expect("Fx33 failed");
             assert_eq!(mem.read(cpu.i).unwrap(), 1, "I should be 1");
             assert_eq!(mem.read(cpu.i + 1).unwrap(), 2, "I + 1 should be 2");
             assert_eq!(mem.read(cpu.i + 2).unwrap(), 3, "I + 2 should be 3");
@@ -1405,7 +1814,7 @@ mod tests {
             cpu.v[x as usize] = 4;
             cpu.i = 0x400;
             let original_i = 0x400;
-            cpu.store_regs(x, &mut mem);
+            cpu.store_regs(x, &mut mem).expect("Fx55 failed");
             assert_eq!(mem.read(original_i).unwrap(), 1, "I should be 1");
             assert_eq!(mem.read(original_i + 1).unwrap(), 2, "I + 1 should be 2");
             assert_eq!(mem.read(original_i + 2).unwrap(), 3, "I + 2 should be 3");
@@ -1436,7 +1845,7 @@ mod tests {
                 let reg_addr = original_i + reg as u16;
                 mem.write(reg_addr, 55).unwrap();
             }
-            cpu.load_regs(x, &mut mem);
+            cpu.load_regs(x, &mut mem).expect("Fx65 failed");
             assert_eq!(cpu.v[0], 55, "V0 should be 55");
             assert_eq!(cpu.v[1], 55, "V1 should be 55");
             assert_eq!(cpu.v[2], 55, "V2 should be 55");
@@ -1449,22 +1858,27 @@ mod tests {
         }
     }
     mod cycle {
+        use super::super::super::display::BitDisplay;
         use super::super::memory::Memory;
+        use super::super::Chip8x;
+        use super::super::DecodeCache;
+        use super::super::Display;
+        use super::super::MegaChip;
         use super::Cpu;
         #[test]
         fn ml_sub() {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
             mem.write(0x200, 0x0100)
                 .expect("Example instruction did not write correctly");
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "0nnn");
         }
@@ -1473,15 +1887,15 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
             mem.write(0x200, 0x00E0)
                 .expect("Example instruction did not write correctly");
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "0E00");
         }
@@ -1490,8 +1904,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1499,7 +1913,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "00EE");
         }
@@ -1508,8 +1922,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1517,7 +1931,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "1nnn");
         }
@@ -1526,8 +1940,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1535,7 +1949,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "2nnn");
         }
@@ -1544,8 +1958,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1553,7 +1967,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "3xnn");
         }
@@ -1562,8 +1976,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1571,7 +1985,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "4xnn");
         }
@@ -1580,8 +1994,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1589,7 +2003,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "5xy0");
         }
@@ -1598,8 +2012,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1607,7 +2021,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "6xnn");
         }
@@ -1616,8 +2030,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1625,7 +2039,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "7xnn");
         }
@@ -1634,8 +2048,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1643,7 +2057,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "8xy0");
         }
@@ -1652,8 +2066,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1661,7 +2075,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "8xy1");
         }
@@ -1670,8 +2084,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1679,7 +2093,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "8xy2");
         }
@@ -1688,8 +2102,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1697,7 +2111,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "8xy3");
         }
@@ -1706,8 +2120,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1715,7 +2129,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "8xy4");
         }
@@ -1724,8 +2138,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1733,7 +2147,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "8xy5");
         }
@@ -1742,8 +2156,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1751,7 +2165,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "8xy6");
         }
@@ -1760,8 +2174,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1769,7 +2183,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "8xy7");
         }
@@ -1778,8 +2192,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1787,7 +2201,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "8xyE");
         }
@@ -1796,8 +2210,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1805,7 +2219,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "9xy0");
         }
@@ -1814,8 +2228,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1823,7 +2237,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "Annn");
         }
@@ -1832,8 +2246,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1841,7 +2255,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "Bnnn");
         }
@@ -1850,8 +2264,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1859,7 +2273,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "Cxnn");
         }
@@ -1868,8 +2282,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1877,7 +2291,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "Dxyn");
         }
@@ -1886,8 +2300,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1895,7 +2309,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "Ex9E");
         }
@@ -1904,8 +2318,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1913,7 +2327,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "ExA1");
         }
@@ -1922,8 +2336,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1931,7 +2345,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "Fx07");
         }
@@ -1940,7 +2354,7 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
+            let mut test_state = BitDisplay::default();
             let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
@@ -1949,7 +2363,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "Fx0A");
         }
@@ -1958,8 +2372,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1967,7 +2381,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "Fx15");
         }
@@ -1976,8 +2390,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -1985,7 +2399,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "Fx18");
         }
@@ -1994,8 +2408,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -2003,7 +2417,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "Fx1E");
         }
@@ -2012,8 +2426,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -2021,7 +2435,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "Fx29");
         }
@@ -2030,8 +2444,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -2043,7 +2457,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "Fx33");
         }
@@ -2052,8 +2466,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -2067,7 +2481,7 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "Fx55");
         }
@@ -2076,8 +2490,8 @@ mod tests {
             let mut cpu = Cpu {
                 ..Default::default()
             };
-            let mut test_state: [[bool; 32]; 64] = [[false; 32]; 64];
-            let is_key_pressed: [bool; 16] = [false; 16];
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
             let mut mem = Memory {
                 ..Default::default()
             };
@@ -2085,9 +2499,145 @@ mod tests {
                 .expect("Example instruction did not write correctly");
             cpu.stack.push(0x200);
             let result = cpu
-                .run_cycle(&mut mem, &mut test_state, &is_key_pressed)
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
                 .expect("Cycle did not run correctly");
             assert_eq!(result, "Fx65");
         }
+        #[test]
+        fn chip8x() {
+            let mut cpu = Cpu {
+                chip8x: Some(Chip8x::default()),
+                ..Default::default()
+            };
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
+            let mut mem = Memory {
+                ..Default::default()
+            };
+            cpu.v[3] = 0x5;
+            cpu.chip8x.as_mut().unwrap().keys[5] = true;
+            mem.space[0x200..0x202].copy_from_slice(&[0xE3, 0xF2]);
+            let result = cpu
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
+                .expect("Cycle did not run correctly");
+            assert_eq!(result, "ExF2");
+            assert_eq!(cpu.program_counter, 0x204, "Second keypad key should skip");
+            mem.space[0x204..0x206].copy_from_slice(&[0xF3, 0xF8]);
+            let result = cpu
+                .run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
+                .expect("Cycle did not run correctly");
+            assert_eq!(result, "FxF8");
+            assert_eq!(cpu.chip8x.as_mut().unwrap().take_port(), Some(0x5));
+            cpu.chip8x = None;
+            mem.space[0x206..0x208].copy_from_slice(&[0xE3, 0xF2]);
+            assert!(
+                cpu.run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
+                    .is_err(),
+                "ExF2 ran without CHIP-8X"
+            );
+        }
+        #[test]
+        fn megachip() {
+            let mut cpu = Cpu {
+                megachip: Some(MegaChip::default()),
+                ..Default::default()
+            };
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
+            let mut mem = Memory::with_size(0x20000);
+            // Mega mode on, a green palette entry and a 1x1 sprite, all past 64k
+            mem.space[0x200..0x216].copy_from_slice(&[
+                0x00, 0x11, 0x01, 0x01, 0x00, 0x00, 0x02, 0x01, 0x01, 0x01, 0x00, 0x04, 0x03, 0x01,
+                0x04, 0x01, 0xD0, 0x01, 0x00, 0xE0, 0xA3, 0x00,
+            ]);
+            mem.space[0x10000..0x10005].copy_from_slice(&[0xFF, 0x00, 0xFF, 0x00, 0x01]);
+            let mut results = Vec::new();
+            for _ in 0..8 {
+                results.push(
+                    cpu.run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
+                        .expect("Cycle did not run correctly"),
+                );
+            }
+            assert_eq!(
+                results,
+                vec!["0011", "01nn", "02nn", "01nn", "03nn", "04nn", "Dxyn", "00E0"]
+            );
+            assert_eq!(cpu.v[0xF], 0, "Collided with no colour set");
+            let megachip = cpu.megachip.as_ref().unwrap();
+            assert_eq!(megachip.shown[0][0], [0x00, 0xFF, 0x00], "Sprite not shown");
+            assert!(!test_state.pixel(0, 0), "Drew on the usual display");
+            cpu.run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
+                .expect("Cycle did not run correctly");
+            assert_eq!(cpu.i, 0x300);
+            assert_eq!(cpu.megachip.as_ref().unwrap().bank, 0, "Annn kept the bank");
+        }
+        #[test]
+        fn megachip_addresses() {
+            let mut cpu = Cpu {
+                megachip: Some(MegaChip::default()),
+                ..Default::default()
+            };
+            let mut mem = Memory::with_size(0x20000);
+            cpu.i = 0xFFFE;
+            cpu.v[0] = 0x04;
+            cpu.v[1] = 0x7B;
+            cpu.add_reg_to_i(0);
+            let bank = cpu.megachip.as_ref().unwrap().bank;
+            assert_eq!(
+                (bank, cpu.i),
+                (1, 0x0002),
+                "Fx1E didn't carry into the bank"
+            );
+            cpu.store_regs(1, &mut mem).expect("Fx55 failed");
+            assert_eq!(mem.space[0x10002..0x10004], [0x04, 0x7B]);
+            assert_eq!(cpu.i, 0x0004);
+            cpu.get_bcd(1, &mut mem).expect("Fx33 failed");
+            assert_eq!(mem.space[0x10004..0x10007], [1, 2, 3]);
+            cpu.v = [0; 16];
+            cpu.i = 0x0002;
+            cpu.load_regs(1, &mut mem).expect("Fx65 failed");
+            assert_eq!(cpu.v[..2], [0x04, 0x7B]);
+            cpu.megachip.as_mut().unwrap().bank = 0x02;
+            assert!(
+                cpu.store_regs(0, &mut mem).is_err(),
+                "Wrote past the end of memory"
+            );
+            assert!(cpu.get_bcd(0, &mut mem).is_err());
+            assert!(cpu.load_regs(0, &mut mem).is_err());
+        }
+        #[test]
+        fn cache() {
+            let mut cpu = Cpu {
+                cache: Some(DecodeCache::default()),
+                ..Default::default()
+            };
+            let mut test_state = BitDisplay::default();
+            let mut is_key_pressed: [bool; 16] = [false; 16];
+            let mut mem = Memory {
+                ..Default::default()
+            };
+            // 6062 6109 A208 F155 6203: write 6209 over the last instruction and run it
+            mem.space[0x200..0x20A]
+                .copy_from_slice(&[0x60, 0x62, 0x61, 0x09, 0xA2, 0x08, 0xF1, 0x55, 0x62, 0x03]);
+            cpu.program_counter = 0x208;
+            cpu.run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
+                .expect("Cycle did not run correctly");
+            assert_eq!(cpu.v[2], 0x03);
+            assert_eq!(cpu.cache.as_ref().map(|cache| cache.len()), Some(1));
+            cpu.program_counter = 0x200;
+            for _ in 0..5 {
+                cpu.run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
+                    .expect("Cycle did not run correctly");
+            }
+            assert_eq!(
+                cpu.v[2], 0x09,
+                "Overwritten instruction came from the cache"
+            );
+            cpu.cache = None;
+            cpu.program_counter = 0x200;
+            cpu.run_cycle(&mut mem, &mut test_state, &mut is_key_pressed)
+                .expect("Cycles should run without the cache");
+            assert_eq!(cpu.v[0], 0x62);
+        }
     }
 }
@@ -0,0 +1,381 @@
+//! # Decoder Module
+//! ## Description
+//! Turns the 16 bits of an op code into the instruction it stands for, without running it.
+//!
+//! The interpreter uses it on every cycle, and tools that only read programs, like the analyzer, use it too,
+//! so both always agree on what a program means.
+//! ## Operands
+//! * nnn is an address
+//! * nn is a byte
+//! * n is a nibble
+//! * x and y are registers
+//!
+//! Instructions are written in the usual assembly syntax when printed, `LD V1, 0x05` or `DRW V0, V1, 5`.
+//! ## CHIP-8X
+//! CHIP-8X programs are decoded with decode_chip8x, which knows the colour, second keypad and I/O port instructions.
+//! Bnnn doesn't exist there, Bxyn colours the display instead.
+//! ## MegaChip
+//! MegaChip programs are decoded with decode_megachip, which knows the 00NN instructions of the colour display and sound.
+//! 01NN is followed by the low 16 bits of I, the interpreter reads them as the next word.
+use std::fmt;
+
+/// A single CHIP-8 instruction and its operands.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instruction {
+    /// 0nnn - Execute machine language subroutine at nnn
+    MachineCall(u16),
+    /// 00E0 - Clear the display
+    Cls,
+    /// 00EE - Return from subroutine
+    Ret,
+    /// 1nnn - Jump to nnn
+    Jump(u16),
+    /// 2nnn - Execute subroutine at nnn
+    Call(u16),
+    /// 3xnn - Skip if Vx == nn
+    SkipEqNn(u8, u8),
+    /// 4xnn - Skip if Vx != nn
+    SkipNeNn(u8, u8),
+    /// 5xy0 - Skip if Vx == Vy
+    SkipEqReg(u8, u8),
+    /// 6xnn - Vx = nn
+    LoadNn(u8, u8),
+    /// 7xnn - Vx = Vx + nn
+    AddNn(u8, u8),
+    /// 8xy0 - Vx = Vy
+    Assign(u8, u8),
+    /// 8xy1 - Vx = Vx | Vy
+    Or(u8, u8),
+    /// 8xy2 - Vx = Vx & Vy
+    And(u8, u8),
+    /// 8xy3 - Vx = Vx ^ Vy
+    Xor(u8, u8),
+    /// 8xy4 - Vx = Vx + Vy
+    AddReg(u8, u8),
+    /// 8xy5 - Vx = Vx - Vy
+    SubReg(u8, u8),
+    /// 8xy6 - Vx = Vy >> 1
+    ShiftRight(u8, u8),
+    /// 8xy7 - Vx = Vy - Vx
+    SubnReg(u8, u8),
+    /// 8xyE - Vx = Vy << 1
+    ShiftLeft(u8, u8),
+    /// 9xy0 - Skip if Vx != Vy
+    SkipNeReg(u8, u8),
+    /// Annn - I = nnn
+    LoadI(u16),
+    /// Bnnn - Jump to nnn + V0
+    JumpV0(u16),
+    /// Cxnn - Vx = Rand() & nn
+    Random(u8, u8),
+    /// Dxyn - Draw n rows of the sprite at I on (Vx, Vy)
+    Draw(u8, u8, u8),
+    /// Ex9E - Skip if the key in Vx is pressed
+    SkipKey(u8),
+    /// ExA1 - Skip if the key in Vx is not pressed
+    SkipNotKey(u8),
+    /// Fx07 - Vx = dt
+    LoadDt(u8),
+    /// Fx0A - Vx = block_until_keypress()
+    WaitKey(u8),
+    /// Fx15 - dt = Vx
+    SetDt(u8),
+    /// Fx18 - st = Vx
+    SetSt(u8),
+    /// Fx1E - I = I + Vx
+    AddI(u8),
+    /// Fx29 - I = addr(sprite(Vx))
+    Font(u8),
+    /// Fx33 - [I, I+1, I+2] = bcd(Vx)
+    Bcd(u8),
+    /// Fx55 - [I, I..., I + x] = [V0, V..., Vx]
+    Store(u8),
+    /// Fx65 - [V0, V..., Vx] = [I, I..., I + x]
+    Load(u8),
+    /// 02A0 - Next background colour, CHIP-8X only
+    CycleBackground,
+    /// Bxy0 - Colour the zones in Vx and Vx+1 with Vy, CHIP-8X only
+    ColourZones(u8, u8),
+    /// Bxyn - Colour n rows from Vx+1 of the zones in Vx with Vy, CHIP-8X only
+    ColourRows(u8, u8, u8),
+    /// ExF2 - Skip if the key in Vx of the second keypad is pressed, CHIP-8X only
+    SkipKey2(u8),
+    /// ExF5 - Skip if the key in Vx of the second keypad is not pressed, CHIP-8X only
+    SkipNotKey2(u8),
+    /// FxF8 - Send Vx to the I/O port, CHIP-8X only
+    PortOut(u8),
+    /// FxFB - Vx = the I/O port, CHIP-8X only
+    PortIn(u8),
+    /// 0010 - Turn the MegaChip display off
+    MegaOff,
+    /// 0011 - Turn the MegaChip display on
+    MegaOn,
+    /// 01NN NNNN - I = NNNNNN, MegaChip only
+    LongI(u8),
+    /// 02NN - Load NN colours from I into the palette, MegaChip only
+    LoadPalette(u8),
+    /// 03NN - Width of the sprites, MegaChip only
+    SpriteWidth(u8),
+    /// 04NN - Height of the sprites, MegaChip only
+    SpriteHeight(u8),
+    /// 05NN - Alpha of the sprites, MegaChip only
+    Alpha(u8),
+    /// 060N - Play the sound at I, looping when N is 0, MegaChip only
+    PlaySample(u8),
+    /// 0700 - Stop the sound, MegaChip only
+    StopSample,
+    /// 080N - Blend mode of the sprites, MegaChip only
+    Blend(u8),
+    /// 09NN - Colour that sets VF when drawn over, MegaChip only
+    CollisionColour(u8),
+}
+
+/// Decode an op code, fails if it doesn't match any instruction.
+pub fn decode(op_code: u16) -> Result<Instruction, &'static str> {
+    let first_nibble = (op_code >> 12) as u8;
+    let nnn = op_code & 0xFFF;
+    let nn = (op_code & 0xFF) as u8;
+    let x = ((op_code & 0xF00) >> 8) as u8;
+    let y = ((op_code & 0xF0) >> 4) as u8;
+    let n = (op_code & 0xF) as u8;
+    let instruction = match first_nibble {
+        0x0 => match op_code {
+            0x00E0 => Instruction::Cls,
+            0x00EE => Instruction::Ret,
+            _ => Instruction::MachineCall(nnn),
+        },
+        0x1 => Instruction::Jump(nnn),
+        0x2 => Instruction::Call(nnn),
+        0x3 => Instruction::SkipEqNn(x, nn),
+        0x4 => Instruction::SkipNeNn(x, nn),
+        0x5 => match n {
+            0 => Instruction::SkipEqReg(x, y),
+            _ => return Err("5xy0: Tail nibble was not 0x0"),
+        },
+        0x6 => Instruction::LoadNn(x, nn),
+        0x7 => Instruction::AddNn(x, nn),
+        0x8 => match n {
+            0x0 => Instruction::Assign(x, y),
+            0x1 => Instruction::Or(x, y),
+            0x2 => Instruction::And(x, y),
+            0x3 => Instruction::Xor(x, y),
+            0x4 => Instruction::AddReg(x, y),
+            0x5 => Instruction::SubReg(x, y),
+            0x6 => Instruction::ShiftRight(x, y),
+            0x7 => Instruction::SubnReg(x, y),
+            0xE => Instruction::ShiftLeft(x, y),
+            _ => return Err("n was not in the expected values for 0x8... ops"),
+        },
+        0x9 => match n {
+            0 => Instruction::SkipNeReg(x, y),
+            _ => return Err("n was not in the expected values for 0x9... ops"),
+        },
+        0xA => Instruction::LoadI(nnn),
+        0xB => Instruction::JumpV0(nnn),
+        0xC => Instruction::Random(x, nn),
+        0xD => Instruction::Draw(x, y, n),
+        0xE => match nn {
+            0x9E => Instruction::SkipKey(x),
+            0xA1 => Instruction::SkipNotKey(x),
+            _ => return Err("nn was not in the expected values for 0xE... ops"),
+        },
+        0xF => match nn {
+            0x07 => Instruction::LoadDt(x),
+            0x0A => Instruction::WaitKey(x),
+            0x15 => Instruction::SetDt(x),
+            0x18 => Instruction::SetSt(x),
+            0x1E => Instruction::AddI(x),
+            0x29 => Instruction::Font(x),
+            0x33 => Instruction::Bcd(x),
+            0x55 => Instruction::Store(x),
+            0x65 => Instruction::Load(x),
+            _ => return Err("nn was not in the expected values for 0xF... ops"),
+        },
+        _ => return Err("first_nibble bigger than 0xF"),
+    };
+    return Ok(instruction);
+}
+
+/// Decode an op code of a CHIP-8X program.
+pub fn decode_chip8x(op_code: u16) -> Result<Instruction, &'static str> {
+    let x = ((op_code & 0xF00) >> 8) as u8;
+    let y = ((op_code & 0xF0) >> 4) as u8;
+    let n = (op_code & 0xF) as u8;
+    let instruction = match (op_code >> 12, op_code & 0xFF) {
+        _ if op_code == 0x02A0 => Instruction::CycleBackground,
+        (0xB, _) if n == 0 => Instruction::ColourZones(x, y),
+        (0xB, _) => Instruction::ColourRows(x, y, n),
+        (0xE, 0xF2) => Instruction::SkipKey2(x),
+        (0xE, 0xF5) => Instruction::SkipNotKey2(x),
+        (0xF, 0xF8) => Instruction::PortOut(x),
+        (0xF, 0xFB) => Instruction::PortIn(x),
+        _ => return decode(op_code),
+    };
+    return Ok(instruction);
+}
+
+/// Decode an op code of a MegaChip program.
+pub fn decode_megachip(op_code: u16) -> Result<Instruction, &'static str> {
+    let nn = (op_code & 0xFF) as u8;
+    let instruction = match op_code >> 8 {
+        0x00 if op_code == 0x0010 => Instruction::MegaOff,
+        0x00 if op_code == 0x0011 => Instruction::MegaOn,
+        0x01 => Instruction::LongI(nn),
+        0x02 => Instruction::LoadPalette(nn),
+        0x03 => Instruction::SpriteWidth(nn),
+        0x04 => Instruction::SpriteHeight(nn),
+        0x05 => Instruction::Alpha(nn),
+        0x06 if nn < 0x10 => Instruction::PlaySample(nn),
+        0x07 if nn == 0 => Instruction::StopSample,
+        0x08 if nn < 0x10 => Instruction::Blend(nn),
+        0x09 => Instruction::CollisionColour(nn),
+        _ => return decode(op_code),
+    };
+    return Ok(instruction);
+}
+
+impl Instruction {
+    /// Check if the instruction may skip the next one.
+    pub fn is_skip(&self) -> bool {
+        match self {
+            Instruction::SkipEqNn(..)
+            | Instruction::SkipNeNn(..)
+            | Instruction::SkipEqReg(..)
+            | Instruction::SkipNeReg(..)
+            | Instruction::SkipKey(_)
+            | Instruction::SkipNotKey(_)
+            | Instruction::SkipKey2(_)
+            | Instruction::SkipNotKey2(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::MachineCall(nnn) => write!(f, "SYS {:#05x}", nnn),
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Jump(nnn) => write!(f, "JP {:#05x}", nnn),
+            Instruction::Call(nnn) => write!(f, "CALL {:#05x}", nnn),
+            Instruction::SkipEqNn(x, nn) => write!(f, "SE V{:X}, {:#04x}", x, nn),
+            Instruction::SkipNeNn(x, nn) => write!(f, "SNE V{:X}, {:#04x}", x, nn),
+            Instruction::SkipEqReg(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::LoadNn(x, nn) => write!(f, "LD V{:X}, {:#04x}", x, nn),
+            Instruction::AddNn(x, nn) => write!(f, "ADD V{:X}, {:#04x}", x, nn),
+            Instruction::Assign(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddReg(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::SubReg(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::ShiftRight(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::SubnReg(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::ShiftLeft(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SkipNeReg(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LoadI(nnn) => write!(f, "LD I, {:#05x}", nnn),
+            Instruction::JumpV0(nnn) => write!(f, "JP V0, {:#05x}", nnn),
+            Instruction::Random(x, nn) => write!(f, "RND V{:X}, {:#04x}", x, nn),
+            Instruction::Draw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkipKey(x) => write!(f, "SKP V{:X}", x),
+            Instruction::SkipNotKey(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::LoadDt(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::WaitKey(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::SetDt(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::SetSt(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddI(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::Font(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::Bcd(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::Store(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::Load(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::CycleBackground => write!(f, "BGC"),
+            Instruction::ColourZones(x, y) => write!(f, "COL V{:X}, V{:X}", x, y),
+            Instruction::ColourRows(x, y, n) => write!(f, "COL V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkipKey2(x) => write!(f, "SKP2 V{:X}", x),
+            Instruction::SkipNotKey2(x) => write!(f, "SKNP2 V{:X}", x),
+            Instruction::PortOut(x) => write!(f, "OUT V{:X}", x),
+            Instruction::PortIn(x) => write!(f, "IN V{:X}", x),
+            Instruction::MegaOff => write!(f, "MEGAOFF"),
+            Instruction::MegaOn => write!(f, "MEGAON"),
+            Instruction::LongI(nn) => write!(f, "LDHI {:#04x}", nn),
+            Instruction::LoadPalette(nn) => write!(f, "LDPAL {}", nn),
+            Instruction::SpriteWidth(nn) => write!(f, "SPRW {}", nn),
+            Instruction::SpriteHeight(nn) => write!(f, "SPRH {}", nn),
+            Instruction::Alpha(nn) => write!(f, "ALPHA {:#04x}", nn),
+            Instruction::PlaySample(n) => write!(f, "DIGISND {}", n),
+            Instruction::StopSample => write!(f, "STOPSND"),
+            Instruction::Blend(n) => write!(f, "BMODE {}", n),
+            Instruction::CollisionColour(nn) => write!(f, "CCOL {}", nn),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, decode_chip8x, decode_megachip, Instruction};
+    mod decode {
+        use super::{decode, Instruction};
+        #[test]
+        fn correct_case() {
+            assert_eq!(decode(0x00E0), Ok(Instruction::Cls));
+            assert_eq!(decode(0x00EE), Ok(Instruction::Ret));
+            assert_eq!(decode(0x0123), Ok(Instruction::MachineCall(0x123)));
+            assert_eq!(decode(0x1ABC), Ok(Instruction::Jump(0xABC)));
+            assert_eq!(decode(0x3A42), Ok(Instruction::SkipEqNn(0xA, 0x42)));
+            assert_eq!(decode(0x8AB6), Ok(Instruction::ShiftRight(0xA, 0xB)));
+            assert_eq!(decode(0xD125), Ok(Instruction::Draw(1, 2, 5)));
+            assert_eq!(decode(0xF265), Ok(Instruction::Load(2)));
+        }
+        #[test]
+        fn wrong_case() {
+            assert!(decode(0x5121).is_err(), "5xy1 accepted");
+            assert!(decode(0x8128).is_err(), "8xy8 accepted");
+            assert!(decode(0x9121).is_err(), "9xy1 accepted");
+            assert!(decode(0xE19F).is_err(), "ExnF accepted");
+            assert!(decode(0xF1FF).is_err(), "FxFF accepted");
+        }
+    }
+    #[test]
+    fn chip8x() {
+        assert_eq!(decode_chip8x(0x02A0), Ok(Instruction::CycleBackground));
+        assert_eq!(decode_chip8x(0xB120), Ok(Instruction::ColourZones(1, 2)));
+        assert_eq!(decode_chip8x(0xB123), Ok(Instruction::ColourRows(1, 2, 3)));
+        assert_eq!(decode_chip8x(0xE3F2), Ok(Instruction::SkipKey2(3)));
+        assert_eq!(decode_chip8x(0xE3F5), Ok(Instruction::SkipNotKey2(3)));
+        assert_eq!(decode_chip8x(0xF4F8), Ok(Instruction::PortOut(4)));
+        assert_eq!(decode_chip8x(0xF4FB), Ok(Instruction::PortIn(4)));
+        assert_eq!(decode_chip8x(0xD125), Ok(Instruction::Draw(1, 2, 5)));
+        assert_eq!(decode(0xB123), Ok(Instruction::JumpV0(0x123)));
+        assert!(decode(0xE3F2).is_err(), "ExF2 accepted outside CHIP-8X");
+    }
+    #[test]
+    fn megachip() {
+        assert_eq!(decode_megachip(0x0010), Ok(Instruction::MegaOff));
+        assert_eq!(decode_megachip(0x0011), Ok(Instruction::MegaOn));
+        assert_eq!(decode_megachip(0x0102), Ok(Instruction::LongI(2)));
+        assert_eq!(decode_megachip(0x0210), Ok(Instruction::LoadPalette(0x10)));
+        assert_eq!(decode_megachip(0x0308), Ok(Instruction::SpriteWidth(8)));
+        assert_eq!(decode_megachip(0x0400), Ok(Instruction::SpriteHeight(0)));
+        assert_eq!(decode_megachip(0x05FF), Ok(Instruction::Alpha(0xFF)));
+        assert_eq!(decode_megachip(0x0601), Ok(Instruction::PlaySample(1)));
+        assert_eq!(decode_megachip(0x0700), Ok(Instruction::StopSample));
+        assert_eq!(decode_megachip(0x0804), Ok(Instruction::Blend(4)));
+        assert_eq!(decode_megachip(0x0903), Ok(Instruction::CollisionColour(3)));
+        assert_eq!(decode_megachip(0x00E0), Ok(Instruction::Cls));
+        assert_eq!(decode_megachip(0x0A00), Ok(Instruction::MachineCall(0xA00)));
+        assert_eq!(decode(0x0011), Ok(Instruction::MachineCall(0x011)));
+    }
+    #[test]
+    fn display() {
+        assert_eq!(Instruction::LoadNn(1, 5).to_string(), "LD V1, 0x05");
+        assert_eq!(Instruction::Jump(0x2A0).to_string(), "JP 0x2a0");
+        assert_eq!(Instruction::Draw(0, 1, 5).to_string(), "DRW V0, V1, 5");
+    }
+    #[test]
+    fn is_skip() {
+        assert!(Instruction::SkipKey(0).is_skip());
+        assert!(Instruction::SkipNeReg(0, 1).is_skip());
+        assert!(!Instruction::Jump(0x200).is_skip());
+    }
+}
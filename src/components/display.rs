@@ -0,0 +1,223 @@
+//! # Display Module
+//! ## Description
+//! The monochrome display programs draw on: 64x32 on the usual CHIP-8, 64x64 with the two-page hi-res interpreter.
+//! The interpreter only talks to the Display trait, so other displays can be plugged in.
+//! ## Packing
+//! BitDisplay keeps every row in a u128 with the leftmost pixel in the top bit,
+//! so a whole sprite row is drawn and checked for collisions with a couple of shifts and an XOR.
+//! Displays can be up to 128 pixels wide.
+//! ## Planes
+//! Pixels can be drawn on several planes at once, a pixel is lit when it's lit on any of them.
+//! Drawing and clearing only touch the selected planes, the first one by default.
+//! ## Changes
+//! Anything that changes a pixel sets the dirty flag, frontends take it to know when there is something new to show.
+
+/// A display the interpreter can draw on.
+pub trait Display {
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+    /// Check if a pixel is lit on any plane.
+    fn pixel(&self, x: usize, y: usize) -> bool;
+    /// Light or turn off a pixel of the selected planes.
+    fn set_pixel(&mut self, x: usize, y: usize, lit: bool);
+    /// Turn off every pixel of the selected planes.
+    fn clear(&mut self);
+    /// XOR a row of a sprite at (x, y), wrapping around the edges.
+    ///
+    /// Returns if a lit pixel was turned off.
+    fn draw_row(&mut self, x: usize, y: usize, bits: u8) -> bool;
+    /// Check if any pixel changed since the previous call.
+    fn take_dirty(&mut self) -> bool;
+}
+
+/// A display with every row packed in a u128.
+#[derive(Clone, Debug)]
+pub struct BitDisplay {
+    width: usize,
+    height: usize,
+    /// Rows of every plane, top to bottom
+    planes: Vec<Vec<u128>>,
+    /// A bit per plane, drawing and clearing only touch the ones set
+    pub selected: u8,
+    dirty: bool,
+}
+
+impl Default for BitDisplay {
+    fn default() -> BitDisplay {
+        return BitDisplay::new(BitDisplay::WIDTH, BitDisplay::HEIGHT);
+    }
+}
+
+/// Displays are the same when they have the same size and pixels, even if only one of them was seen.
+impl PartialEq for BitDisplay {
+    fn eq(&self, other: &BitDisplay) -> bool {
+        return (self.width, self.height, self.selected)
+            == (other.width, other.height, other.selected)
+            && self.planes == other.planes;
+    }
+}
+
+impl BitDisplay {
+    /// Width of the usual CHIP-8 display.
+    pub const WIDTH: usize = 64;
+    /// Height of the usual CHIP-8 display.
+    pub const HEIGHT: usize = 32;
+    /// Widest display that fits in the rows.
+    pub const MAX_WIDTH: usize = 128;
+    /// Create a display with a single plane and every pixel off.
+    pub fn new(width: usize, height: usize) -> BitDisplay {
+        return BitDisplay::with_planes(width, height, 1);
+    }
+    /// Create a display with up to 8 planes, only the first one selected.
+    pub fn with_planes(width: usize, height: usize, planes: usize) -> BitDisplay {
+        assert!(
+            width > 0 && width <= BitDisplay::MAX_WIDTH,
+            "Displays are 1 to 128 pixels wide"
+        );
+        return BitDisplay {
+            width: width,
+            height: height,
+            planes: vec![vec![0; height]; planes.clamp(1, 8)],
+            selected: 1,
+            // Nothing was shown yet
+            dirty: true,
+        };
+    }
+    /// A display of the same size and planes, with every pixel off.
+    pub fn cleared(&self) -> BitDisplay {
+        return BitDisplay::with_planes(self.width, self.height, self.planes());
+    }
+    pub fn planes(&self) -> usize {
+        return self.planes.len();
+    }
+    /// A row of every plane together, the leftmost pixel in the top bit.
+    pub fn row(&self, y: usize) -> u128 {
+        return self.planes.iter().fold(0, |row, plane| row | plane[y]);
+    }
+    /// A row of a single plane, the leftmost pixel in the top bit.
+    pub fn plane_row(&self, plane: usize, y: usize) -> u128 {
+        return self.planes[plane][y];
+    }
+    fn selected_planes(&mut self) -> impl Iterator<Item = &mut Vec<u128>> {
+        let selected = self.selected;
+        return self
+            .planes
+            .iter_mut()
+            .enumerate()
+            .filter(move |(idx, _)| selected >> idx & 1 > 0)
+            .map(|(_, plane)| plane);
+    }
+}
+
+/// Bit of a column in a row.
+fn column_bit(x: usize) -> u128 {
+    return 1 << (BitDisplay::MAX_WIDTH - 1 - x);
+}
+
+impl Display for BitDisplay {
+    fn width(&self) -> usize {
+        return self.width;
+    }
+    fn height(&self) -> usize {
+        return self.height;
+    }
+    fn pixel(&self, x: usize, y: usize) -> bool {
+        return self.row(y) & column_bit(x) > 0;
+    }
+    fn set_pixel(&mut self, x: usize, y: usize, lit: bool) {
+        let bit = column_bit(x);
+        let mut changed = false;
+        for plane in self.selected_planes() {
+            let row = if lit { plane[y] | bit } else { plane[y] & !bit };
+            changed = changed || row != plane[y];
+            plane[y] = row;
+        }
+        self.dirty = self.dirty || changed;
+    }
+    fn clear(&mut self) {
+        let mut changed = false;
+        for plane in self.selected_planes() {
+            changed = changed || plane.iter().any(|row| *row > 0);
+            plane.iter_mut().for_each(|row| *row = 0);
+        }
+        self.dirty = self.dirty || changed;
+    }
+    fn draw_row(&mut self, x: usize, y: usize, bits: u8) -> bool {
+        let (x, y) = (x % self.width, y % self.height);
+        // Columns of the sprite past the right edge come back on the left
+        let spill = (x + 8).saturating_sub(self.width);
+        let mut mask = ((bits >> spill) as u128) << (BitDisplay::MAX_WIDTH - 8 + spill - x);
+        if spill > 0 {
+            let wrapped = bits & ((1 << spill) - 1);
+            mask = mask | (wrapped as u128) << (BitDisplay::MAX_WIDTH - spill);
+        }
+        let mut collided = false;
+        for plane in self.selected_planes() {
+            collided = collided || plane[y] & mask > 0;
+            plane[y] = plane[y] ^ mask;
+        }
+        self.dirty = self.dirty || mask > 0;
+        return collided;
+    }
+    fn take_dirty(&mut self) -> bool {
+        let dirty = self.dirty;
+        self.dirty = false;
+        return dirty;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BitDisplay, Display};
+    #[test]
+    fn draw_row() {
+        let mut display = BitDisplay::default();
+        display.take_dirty();
+        assert!(
+            !display.draw_row(2, 1, 0b1000_0001),
+            "Nothing to collide with"
+        );
+        assert!(display.pixel(2, 1) && display.pixel(9, 1));
+        assert!(!display.pixel(3, 1) && !display.pixel(2, 0));
+        assert!(display.take_dirty(), "Drawing should set the dirty flag");
+        assert!(!display.take_dirty(), "Dirty flag should be taken");
+        assert!(display.draw_row(9, 1, 0b1000_0000), "Collision missed");
+        assert!(!display.pixel(9, 1), "Pixel should be turned off");
+    }
+    #[test]
+    fn wrap() {
+        let mut display = BitDisplay::default();
+        display.draw_row(60, 33, 0xFF);
+        for x in (60..64).chain(0..4) {
+            assert!(display.pixel(x, 1), "Pixel {} should be lit", x);
+        }
+        assert!(!display.pixel(4, 1) && !display.pixel(59, 1));
+        let mut wide = BitDisplay::new(128, 64);
+        wide.draw_row(124, 0, 0xFF);
+        assert!(wide.pixel(127, 0) && wide.pixel(3, 0) && !wide.pixel(4, 0));
+    }
+    #[test]
+    fn planes() {
+        let mut display = BitDisplay::with_planes(64, 32, 2);
+        display.draw_row(0, 0, 0x80);
+        display.selected = 2;
+        assert!(!display.draw_row(0, 0, 0x80), "Planes should be apart");
+        display.clear();
+        assert!(display.pixel(0, 0), "Clear should keep the other plane");
+        assert_eq!(display.plane_row(1, 0), 0);
+        display.selected = 3;
+        display.clear();
+        assert!(!display.pixel(0, 0));
+    }
+    #[test]
+    fn dirty() {
+        let mut display = BitDisplay::default();
+        assert!(display.take_dirty(), "New displays should be shown");
+        display.clear();
+        display.set_pixel(0, 0, false);
+        display.draw_row(0, 0, 0);
+        assert!(!display.take_dirty(), "Nothing changed");
+        display.set_pixel(5, 5, true);
+        assert!(display.take_dirty());
+    }
+}
@@ -0,0 +1,198 @@
+//! # Environment Module
+//! ## Description
+//! Wraps the machine in the reset and step loop used to train reinforcement learning agents.
+//!
+//! Agents pick an action, a set of keys held down, and the machine runs a few frames with them.
+//! Afterwards they get what the display looks like and a reward.
+//! ## Rewards
+//! Games keep the score somewhere in memory, the reward of a step is how much it went up.
+//! The score can be a big endian number spread over a few bytes, or a digit per byte, as Fx33 stores them.
+//! ## Episodes
+//! An episode ends when the machine halts, when a byte of memory has a given value,
+//! like a lives counter reaching zero, or after a number of frames.
+use super::machine::Machine;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// Where the score is in memory.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Score {
+    /// Bytes of the score, the most significant first
+    pub addresses: Vec<u16>,
+    /// Every byte is a decimal digit instead of a base 256 one
+    pub bcd: bool,
+}
+
+impl Score {
+    /// Read the score, zero if it has no addresses.
+    pub fn read(&self, machine: &Machine) -> i64 {
+        let base = if self.bcd { 10 } else { 256 };
+        let mut score: i64 = 0;
+        for address in self.addresses.iter() {
+            let byte = machine
+                .mem
+                .space
+                .get(*address as usize)
+                .cloned()
+                .unwrap_or(0);
+            score = score * base + byte as i64;
+        }
+        return score;
+    }
+}
+
+/// What happened during a step.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Step {
+    pub reward: f64,
+    /// The game is over
+    pub terminated: bool,
+    /// The episode ran out of frames
+    pub truncated: bool,
+}
+
+/// A machine ready to be played by an agent.
+pub struct Environment {
+    pub machine: Machine,
+    /// Frames run for every action
+    pub frame_skip: u32,
+    pub cycles_per_frame: u32,
+    /// Keys held down by every action
+    pub actions: Vec<Vec<u8>>,
+    pub score: Score,
+    /// The game is over when the byte at the address has the value
+    pub game_over: Option<(u16, u8)>,
+    /// Frames before the episode is cut short
+    pub max_frames: Option<u32>,
+    frames: u32,
+    last_score: i64,
+}
+
+impl Environment {
+    /// Create an environment with an action for every single key and one for no keys at all.
+    pub fn new(program: &[u8], cycles_per_frame: u32) -> Result<Environment, &'static str> {
+        let mut actions: Vec<Vec<u8>> = vec![Vec::new()];
+        for key in 0..16 {
+            actions.push(vec![key]);
+        }
+        return Ok(Environment {
+            machine: Machine::new(program)?,
+            frame_skip: 4,
+            cycles_per_frame: cycles_per_frame,
+            actions: actions,
+            score: Score::default(),
+            game_over: None,
+            max_frames: None,
+            frames: 0,
+            last_score: 0,
+        });
+    }
+    /// Start a new episode, with repeatable random numbers if seeded.
+    pub fn reset(&mut self, seed: Option<u64>) {
+        self.machine.hard_reset();
+        if let Some(seed) = seed {
+            self.machine.cpu.rng = StdRng::seed_from_u64(seed);
+        }
+        self.frames = 0;
+        self.last_score = self.score.read(&self.machine);
+    }
+    /// Hold the keys of an action down for frame_skip frames.
+    pub fn step(&mut self, action: usize) -> Result<Step, &'static str> {
+        let keys = self.actions.get(action).ok_or("Action is out of range")?;
+        let mut pressed = [false; 16];
+        for key in keys.iter() {
+            *pressed
+                .get_mut(*key as usize)
+                .ok_or("Keys go from 0 to 15")? = true;
+        }
+        self.machine.set_keys(pressed);
+        let mut terminated = self.game_over();
+        for _ in 0..self.frame_skip {
+            if terminated {
+                break;
+            }
+            self.machine.run_frame(self.cycles_per_frame);
+            self.machine.take_breakpoint();
+            self.frames = self.frames + 1;
+            terminated = self.game_over();
+        }
+        let score = self.score.read(&self.machine);
+        let reward = (score - self.last_score) as f64;
+        self.last_score = score;
+        let truncated = !terminated && self.max_frames.map_or(false, |max| self.frames >= max);
+        return Ok(Step {
+            reward: reward,
+            terminated: terminated,
+            truncated: truncated,
+        });
+    }
+    fn game_over(&self) -> bool {
+        if self.machine.halted {
+            return true;
+        }
+        return match self.game_over {
+            Some((address, value)) => self.machine.mem.space.get(address as usize) == Some(&value),
+            None => false,
+        };
+    }
+    /// Frames run since the episode started.
+    pub fn frames(&self) -> u32 {
+        return self.frames;
+    }
+    /// The display row by row, a byte per pixel, 1 when lit.
+    pub fn observation(&self) -> Vec<u8> {
+        return self.machine.framebuffer();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::display::Display;
+    use super::{Environment, Score};
+    /// 7001 F033 1200: count up V0 and store it as the score at I
+    const PROGRAM: [u8; 6] = [0x70, 0x01, 0xF0, 0x33, 0x12, 0x00];
+    fn environment() -> Environment {
+        let mut env = Environment::new(&PROGRAM, 3).expect("Couldn't create the environment");
+        env.machine.cpu.i = 0x300;
+        env.score = Score {
+            addresses: vec![0x300, 0x301, 0x302],
+            bcd: true,
+        };
+        return env;
+    }
+    #[test]
+    fn reward() {
+        let mut env = environment();
+        env.frame_skip = 2;
+        let step = env.step(0).expect("Action 0 exists");
+        assert_eq!(step.reward, 2.0, "Score should go up once per frame");
+        assert_eq!(env.frames(), 2);
+        assert!(!step.terminated && !step.truncated);
+        assert_eq!(env.step(0).unwrap().reward, 2.0);
+        assert!(env.step(17).is_err(), "Only 17 actions by default");
+    }
+    #[test]
+    fn episode_end() {
+        let mut env = environment();
+        env.max_frames = Some(4);
+        env.frame_skip = 3;
+        assert!(!env.step(0).unwrap().truncated);
+        assert!(env.step(0).unwrap().truncated, "Ran out of frames");
+        env.reset(None);
+        env.machine.cpu.i = 0x300;
+        assert_eq!(env.frames(), 0);
+        env.game_over = Some((0x302, 2));
+        let step = env.step(1).unwrap();
+        assert!(step.terminated, "Score reached 2");
+        assert_eq!(env.frames(), 2, "Frames stop once the game is over");
+        assert!(env.machine.keypad.keys()[0], "Action 1 holds key 0");
+    }
+    #[test]
+    fn observation() {
+        let mut env = environment();
+        env.machine.state.set_pixel(2, 1, true);
+        let pixels = env.observation();
+        assert_eq!(pixels.len(), 64 * 32);
+        assert_eq!(pixels[64 + 2], 1, "Pixels should go row by row");
+    }
+}
@@ -0,0 +1,288 @@
+//! # Display Filter Module
+//! ## Description
+//! CHIP-8 programs move sprites by XOR-ing them out and drawing them again,
+//! so a raw copy of the display flickers a lot.
+//! The original hardware hid most of this thanks to the slow phosphor of the CRT.
+//! ## Modes
+//! * Raw: the display is shown as is
+//! * Phosphor: every pixel fades out exponentially instead of turning off at once
+//! * Or: a pixel is lit if it was lit in this frame or the previous one
+//! * Majority: a pixel is lit if it was lit in most of the last N frames
+//!
+//! The filter runs once per drawn frame, between the emulated display and the frame buffer.
+//! Previous frames are forgotten when the size of the display changes.
+//! ## Still frames
+//! Once the filter is still, feeding it the same display gives the same shades,
+//! so frontends only need to run it again when the display changes.
+use super::display::{BitDisplay, Display};
+use super::screen::Screen;
+use std::collections::VecDeque;
+
+/// Post-processing applied to the display before it reaches the screen.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterMode {
+    /// Copy the display unchanged
+    Raw,
+    /// Keep a fraction of the last intensity every frame, 0.0 is the same as Raw
+    Phosphor(f32),
+    /// OR the last two frames
+    Or,
+    /// Light the pixels set in more than half of the last N frames
+    Majority(usize),
+}
+
+impl FilterMode {
+    /// Default fraction of light kept by the phosphor every frame.
+    pub const DEFAULT_DECAY: f32 = 0.6;
+    /// Default amount of frames used by the majority filter.
+    pub const DEFAULT_FRAMES: usize = 3;
+    /// Parse a mode as written in the command line: `raw`, `phosphor[:decay]`, `or` or `majority[:frames]`.
+    pub fn parse(text: &str) -> Result<FilterMode, &'static str> {
+        let mut parts = text.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let arg = parts.next();
+        match (name, arg) {
+            ("raw", None) => Ok(FilterMode::Raw),
+            ("or", None) => Ok(FilterMode::Or),
+            ("phosphor", None) => Ok(FilterMode::Phosphor(FilterMode::DEFAULT_DECAY)),
+            ("phosphor", Some(decay)) => match decay.parse::<f32>() {
+                Ok(decay) if (0.0..1.0).contains(&decay) => Ok(FilterMode::Phosphor(decay)),
+                _ => Err("phosphor decay must be a number between 0 and 1"),
+            },
+            ("majority", None) => Ok(FilterMode::Majority(FilterMode::DEFAULT_FRAMES)),
+            ("majority", Some(frames)) => match frames.parse::<usize>() {
+                Ok(frames) if frames > 0 => Ok(FilterMode::Majority(frames)),
+                _ => Err("majority frames must be a positive number"),
+            },
+            _ => Err("Unknown filter mode"),
+        }
+    }
+}
+
+/// Keeps the history needed by the selected mode.
+pub struct DisplayFilter {
+    pub mode: FilterMode,
+    /// Last intensity of every pixel, used by Phosphor
+    levels: Screen<f32>,
+    /// Last frames, newest first, used by Or and Majority
+    history: VecDeque<BitDisplay>,
+}
+
+impl DisplayFilter {
+    pub fn new(mode: FilterMode) -> DisplayFilter {
+        DisplayFilter {
+            mode,
+            levels: Screen::default(),
+            history: VecDeque::new(),
+        }
+    }
+    /// Feed the current display and get the intensity (0x00 to 0xFF) of every pixel.
+    ///
+    /// Indexed the same way as the display, column first.
+    pub fn apply(&mut self, state: &BitDisplay) -> Screen<u8> {
+        if (self.levels.width(), self.levels.height()) != (state.width(), state.height()) {
+            self.levels = Screen::new(state.width(), state.height());
+            self.history.clear();
+        }
+        let mut shades: Screen<u8> = Screen::new(state.width(), state.height());
+        match self.mode {
+            FilterMode::Raw => {
+                for col in 0..state.width() {
+                    for row in 0..state.height() {
+                        shades[col][row] = if state.pixel(col, row) { 0xFF } else { 0x00 };
+                    }
+                }
+            }
+            FilterMode::Phosphor(decay) => {
+                for col in 0..state.width() {
+                    for row in 0..state.height() {
+                        let level = &mut self.levels[col][row];
+                        *level = if state.pixel(col, row) {
+                            1.0
+                        } else {
+                            *level * decay
+                        };
+                        // Too dim to be seen, so the filter can be still
+                        if *level < 1.0 / 255.0 {
+                            *level = 0.0
+                        }
+                        shades[col][row] = (*level * 255.0) as u8;
+                    }
+                }
+            }
+            FilterMode::Or => {
+                self.remember(state, 2);
+                for frame in self.history.iter() {
+                    for col in 0..frame.width() {
+                        for row in 0..frame.height() {
+                            if frame.pixel(col, row) {
+                                shades[col][row] = 0xFF
+                            }
+                        }
+                    }
+                }
+            }
+            FilterMode::Majority(frames) => {
+                self.remember(state, frames);
+                for col in 0..state.width() {
+                    for row in 0..state.height() {
+                        let votes = self
+                            .history
+                            .iter()
+                            .filter(|frame| frame.pixel(col, row))
+                            .count();
+                        if votes * 2 > self.history.len() {
+                            shades[col][row] = 0xFF
+                        }
+                    }
+                }
+            }
+        }
+        return shades;
+    }
+    /// Check if feeding the filter the same display again would give the same shades.
+    pub fn is_still(&self) -> bool {
+        return match self.mode {
+            FilterMode::Raw => true,
+            FilterMode::Phosphor(_) => self
+                .levels
+                .iter()
+                .flatten()
+                .all(|level| *level == 0.0 || *level == 1.0),
+            // With every remembered frame the same, the shades are that frame
+            FilterMode::Or | FilterMode::Majority(_) => match self.history.front() {
+                Some(newest) => self.history.iter().all(|frame| frame == newest),
+                None => false,
+            },
+        };
+    }
+    /// Forget every previous frame, used when the display is reset.
+    pub fn clear(&mut self) {
+        self.levels.clear();
+        self.history.clear();
+    }
+    fn remember(&mut self, state: &BitDisplay, frames: usize) {
+        self.history.push_front(state.clone());
+        self.history.truncate(frames);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::display::{BitDisplay, Display};
+    use super::{DisplayFilter, FilterMode};
+    mod parse {
+        use super::FilterMode;
+        #[test]
+        fn correct_case() {
+            assert_eq!(FilterMode::parse("raw"), Ok(FilterMode::Raw));
+            assert_eq!(FilterMode::parse("or"), Ok(FilterMode::Or));
+            assert_eq!(
+                FilterMode::parse("phosphor:0.5"),
+                Ok(FilterMode::Phosphor(0.5))
+            );
+            assert_eq!(FilterMode::parse("majority:5"), Ok(FilterMode::Majority(5)));
+        }
+        #[test]
+        fn wrong_case() {
+            assert!(FilterMode::parse("blur").is_err(), "Unknown mode accepted");
+            assert!(
+                FilterMode::parse("phosphor:2").is_err(),
+                "Decay above 1 accepted"
+            );
+            assert!(
+                FilterMode::parse("majority:0").is_err(),
+                "No frames accepted"
+            );
+        }
+    }
+    #[test]
+    fn raw() {
+        let mut filter = DisplayFilter::new(FilterMode::Raw);
+        let mut state = BitDisplay::default();
+        state.set_pixel(3, 4, true);
+        let shades = filter.apply(&state);
+        assert_eq!(shades[3][4], 0xFF, "Lit pixel should be full intensity");
+        assert_eq!(shades[4][3], 0x00, "Unlit pixel should be off");
+    }
+    #[test]
+    fn phosphor() {
+        let mut filter = DisplayFilter::new(FilterMode::Phosphor(0.5));
+        let mut state = BitDisplay::default();
+        state.set_pixel(0, 0, true);
+        filter.apply(&state);
+        state.set_pixel(0, 0, false);
+        let first = filter.apply(&state)[0][0];
+        let second = filter.apply(&state)[0][0];
+        assert_eq!(first, 127, "Pixel should keep half of its light");
+        assert!(second < first, "Pixel should keep fading");
+    }
+    #[test]
+    fn or() {
+        let mut filter = DisplayFilter::new(FilterMode::Or);
+        let mut state = BitDisplay::default();
+        state.set_pixel(0, 0, true);
+        filter.apply(&state);
+        state.set_pixel(0, 0, false);
+        assert_eq!(
+            filter.apply(&state)[0][0],
+            0xFF,
+            "Previous frame should be kept"
+        );
+        assert_eq!(
+            filter.apply(&state)[0][0],
+            0x00,
+            "Older frames should be dropped"
+        );
+    }
+    #[test]
+    fn majority() {
+        let mut filter = DisplayFilter::new(FilterMode::Majority(3));
+        let mut state = BitDisplay::default();
+        state.set_pixel(0, 0, true);
+        assert_eq!(filter.apply(&state)[0][0], 0xFF, "One out of one frames");
+        state.set_pixel(0, 0, false);
+        assert_eq!(filter.apply(&state)[0][0], 0x00, "One out of two frames");
+        state.set_pixel(0, 0, true);
+        assert_eq!(filter.apply(&state)[0][0], 0xFF, "Two out of three frames");
+    }
+    #[test]
+    fn still() {
+        let mut state = BitDisplay::default();
+        state.set_pixel(0, 0, true);
+        let raw = DisplayFilter::new(FilterMode::Raw);
+        assert!(raw.is_still(), "Raw never changes on its own");
+        let mut or = DisplayFilter::new(FilterMode::Or);
+        assert!(!or.is_still(), "Nothing was seen yet");
+        or.apply(&state);
+        assert!(or.is_still());
+        state.set_pixel(0, 0, false);
+        or.apply(&state);
+        assert!(!or.is_still(), "Last frame is still shown");
+        let mut phosphor = DisplayFilter::new(FilterMode::Phosphor(0.5));
+        state.set_pixel(0, 0, true);
+        phosphor.apply(&state);
+        state.set_pixel(0, 0, false);
+        phosphor.apply(&state);
+        let mut frames = 1;
+        while !phosphor.is_still() {
+            phosphor.apply(&state);
+            frames = frames + 1;
+        }
+        assert_eq!(frames, 8, "Pixel should fade out in 8 frames");
+    }
+    #[test]
+    fn resize() {
+        let mut filter = DisplayFilter::new(FilterMode::Or);
+        let mut state = BitDisplay::default();
+        state.set_pixel(0, 0, true);
+        filter.apply(&state);
+        let tall = BitDisplay::new(64, 64);
+        let shades = filter.apply(&tall);
+        assert_eq!(shades.height(), 64, "Shades should follow the display size");
+        assert_eq!(
+            shades[0][0], 0x00,
+            "Frames of another size should be dropped"
+        );
+    }
+}
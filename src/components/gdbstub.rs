@@ -0,0 +1,627 @@
+//! # GDB Stub Module
+//! ## Description
+//! Lets gdb, or any front-end speaking its Remote Serial Protocol, debug a running rom over a local TCP port.
+//!
+//! The socket is polled between frames without blocking, so no extra threads are needed.
+//! While a debugger is attached the machine only runs after a continue or a step.
+//! ## Registers
+//! Registers are big endian, like the CHIP-8 itself, and numbered in this order:
+//! * 0 to 15: V0 to VF, a byte each
+//! * 16: I, two bytes
+//! * 17: The program counter, two bytes
+//! * 18: The delay timer, a byte
+//! * 19: The sound timer, a byte
+//! * 20: The stack pointer, the depth of the stack, a byte
+//!
+//! The stub sends them as a target description, `target.xml`, so gdb knows them without being told an architecture.
+//!
+//! Memory is the whole address space of the machine, fonts included, and breakpoints can go anywhere in it.
+//! ## Supported packets
+//! `?`, `g`, `G`, `p`, `P`, `m`, `M`, `c`, `s`, `Z0`, `z0`, `D`, `k`, the interrupt byte, `qSupported`,
+//! `qXfer:features:read` and a few more queries.
+//! Anything else gets the empty reply, which tells the debugger it isn't supported.
+//!
+//! `?` answers with the reason of the last stop, and waits for the next one while the machine runs.
+use super::machine::Machine;
+use std::fmt::Write as FmtWrite;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+/// Number of registers, see the module description for their order.
+const REGISTERS: usize = 21;
+/// Size of every register in bytes.
+fn register_size(number: usize) -> usize {
+    return match number {
+        16 | 17 => 2,
+        _ => 1,
+    };
+}
+
+/// Target description with the registers, in the order of the module description.
+fn target_xml() -> String {
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n");
+    xml.push_str("<target version=\"1.0\">\n<feature name=\"org.chip8.core\">\n");
+    for number in 0..16 {
+        writeln!(
+            xml,
+            "<reg name=\"v{:x}\" bitsize=\"8\" type=\"uint8\"/>",
+            number
+        )
+        .unwrap();
+    }
+    xml.push_str("<reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>\n");
+    xml.push_str("<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>\n");
+    for name in ["dt", "st", "sp"].iter() {
+        writeln!(xml, "<reg name=\"{}\" bitsize=\"8\" type=\"uint8\"/>", name).unwrap();
+    }
+    xml.push_str("</feature>\n</target>\n");
+    return xml;
+}
+
+/// Listens for a debugger and answers its packets.
+pub struct GdbStub {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    /// Bytes received but not yet a whole packet
+    buffer: Vec<u8>,
+    /// If the machine may run, always true without a debugger
+    running: bool,
+    /// Signal of the last stop, SIGTRAP for breakpoints and steps
+    signal: u8,
+}
+
+impl GdbStub {
+    /// Listen on an address like `127.0.0.1:2159`.
+    pub fn bind(address: &str) -> Result<GdbStub, &'static str> {
+        let listener = TcpListener::bind(address).map_err(|_| "Couldn't listen for gdb")?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|_| "Couldn't listen for gdb")?;
+        return Ok(GdbStub {
+            listener: listener,
+            client: None,
+            buffer: Vec::new(),
+            running: true,
+            signal: SIGTRAP,
+        });
+    }
+    /// Address the stub is listening on.
+    pub fn local_addr(&self) -> Result<SocketAddr, &'static str> {
+        return self
+            .listener
+            .local_addr()
+            .map_err(|_| "Couldn't get the gdb address");
+    }
+    /// Check if a debugger is attached.
+    pub fn attached(&self) -> bool {
+        return self.client.is_some();
+    }
+    /// Check if the machine may run, the debugger stops it on attach, breakpoints and interrupts.
+    pub fn running(&self) -> bool {
+        return self.running;
+    }
+    /// Accept a debugger, answer its packets and report stops, without blocking.
+    ///
+    /// Call it after running the cycles of a frame, so breakpoints are reported before anything else takes them.
+    pub fn poll(&mut self, machine: &mut Machine) {
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if stream.set_nonblocking(true).is_err() {
+                        return;
+                    }
+                    // Debuggers expect to find the target stopped
+                    self.client = Some(stream);
+                    self.buffer.clear();
+                    self.stop(SIGTRAP);
+                    machine.take_breakpoint();
+                }
+                Err(_) => return,
+            }
+        }
+        let mut chunk = [0u8; 1024];
+        loop {
+            let read = match self.client.as_mut() {
+                Some(client) => client.read(&mut chunk),
+                None => return,
+            };
+            match read {
+                Ok(0) => return self.disconnect(),
+                Ok(size) => self.buffer.extend_from_slice(&chunk[..size]),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => return self.disconnect(),
+            }
+        }
+        while let Some(packet) = self.next_packet() {
+            match packet {
+                Packet::Interrupt => {
+                    if self.running {
+                        self.stop(SIGINT);
+                        self.send_stop();
+                    }
+                }
+                Packet::Data(data) => {
+                    self.send_raw(b"+");
+                    if let Some(reply) = self.handle(machine, &data) {
+                        self.send(&reply);
+                    }
+                }
+                Packet::Corrupt => self.send_raw(b"-"),
+            }
+            if self.client.is_none() {
+                return;
+            }
+        }
+        if self.running {
+            if machine.take_breakpoint().is_some() {
+                self.stop(SIGTRAP);
+                self.send_stop();
+            } else if machine.halted {
+                self.stop(SIGILL);
+                self.send_stop();
+            }
+        }
+    }
+    fn stop(&mut self, signal: u8) {
+        self.running = false;
+        self.signal = signal;
+    }
+    /// Tell the debugger why the machine stopped.
+    fn send_stop(&mut self) {
+        let reply = format!("S{:02x}", self.signal);
+        self.send(&reply);
+    }
+    fn disconnect(&mut self) {
+        self.client = None;
+        self.buffer.clear();
+        self.running = true;
+    }
+    /// Take the next whole packet out of the buffer.
+    fn next_packet(&mut self) -> Option<Packet> {
+        loop {
+            let first = *self.buffer.first()?;
+            match first {
+                0x03 => {
+                    self.buffer.remove(0);
+                    return Some(Packet::Interrupt);
+                }
+                b'$' => break,
+                // Acknowledgements and noise
+                _ => {
+                    self.buffer.remove(0);
+                }
+            }
+        }
+        let end = self.buffer.iter().position(|&byte| byte == b'#')?;
+        if self.buffer.len() < end + 3 {
+            return None;
+        }
+        let data: Vec<u8> = self.buffer[1..end].to_vec();
+        let checksum = std::str::from_utf8(&self.buffer[end + 1..end + 3])
+            .ok()
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+        self.buffer.drain(..end + 3);
+        if checksum != Some(checksum_of(&data)) {
+            return Some(Packet::Corrupt);
+        }
+        return Some(Packet::Data(String::from_utf8_lossy(&data).into_owned()));
+    }
+    /// Answer a packet, None when the answer has to wait until the machine stops.
+    fn handle(&mut self, machine: &mut Machine, packet: &str) -> Option<String> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+        let reply = match command {
+            // The stop is reported when it comes
+            "?" if self.running => return None,
+            "?" => format!("S{:02x}", self.signal),
+            "g" => {
+                let mut registers = String::new();
+                for number in 0..REGISTERS {
+                    registers.push_str(&read_register(machine, number));
+                }
+                registers
+            }
+            "G" => {
+                let mut rest = args;
+                for number in 0..REGISTERS {
+                    let size = register_size(number) * 2;
+                    if rest.len() < size {
+                        return Some("E01".to_owned());
+                    }
+                    let (digits, tail) = rest.split_at(size);
+                    if write_register(machine, number, digits).is_err() {
+                        return Some("E01".to_owned());
+                    }
+                    rest = tail;
+                }
+                "OK".to_owned()
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(number) if number < REGISTERS => read_register(machine, number),
+                _ => "E01".to_owned(),
+            },
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let number = parts.next().and_then(|n| usize::from_str_radix(n, 16).ok());
+                match (number, parts.next()) {
+                    (Some(number), Some(digits)) if number < REGISTERS => {
+                        match write_register(machine, number, digits) {
+                            Ok(_) => "OK".to_owned(),
+                            Err(_) => "E01".to_owned(),
+                        }
+                    }
+                    _ => "E01".to_owned(),
+                }
+            }
+            "m" => match parse_range(args, machine.mem.space.len()) {
+                Some((start, end)) => {
+                    let mut bytes = String::new();
+                    for byte in machine.mem.space[start..end].iter() {
+                        write!(bytes, "{:02x}", byte).unwrap();
+                    }
+                    bytes
+                }
+                None => "E01".to_owned(),
+            },
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let range = parts
+                    .next()
+                    .and_then(|range| parse_range(range, machine.mem.space.len()));
+                match (range, parts.next().and_then(|data| parse_hex(data))) {
+                    (Some((start, end)), Some(data)) if data.len() == end - start => {
+                        machine.mem.space[start..end].copy_from_slice(&data);
+                        "OK".to_owned()
+                    }
+                    _ => "E01".to_owned(),
+                }
+            }
+            "c" => {
+                self.running = true;
+                return None;
+            }
+            "s" => {
+                // Stepping off a breakpoint has to run the instruction under it
+                if let Err("Breakpoint") = machine.step() {
+                    let _ = machine.step();
+                }
+                machine.take_breakpoint();
+                self.signal = if machine.halted { SIGILL } else { SIGTRAP };
+                format!("S{:02x}", self.signal)
+            }
+            "Z" | "z" => {
+                let mut parts = args.split(',');
+                let kind = parts.next();
+                let address = parts.next().and_then(|a| u16::from_str_radix(a, 16).ok());
+                match (kind, address) {
+                    (Some("0"), Some(address)) if (address as usize) < machine.mem.space.len() => {
+                        if command == "Z" {
+                            machine.breakpoints.insert(address);
+                        } else {
+                            machine.breakpoints.remove(address);
+                        }
+                        "OK".to_owned()
+                    }
+                    (Some("0"), Some(_)) => "E01".to_owned(),
+                    // Watchpoints and hardware breakpoints aren't supported
+                    _ => String::new(),
+                }
+            }
+            "D" => {
+                self.send("OK");
+                self.disconnect();
+                return None;
+            }
+            "k" => {
+                self.disconnect();
+                return None;
+            }
+            "H" => "OK".to_owned(),
+            "q" if args.starts_with("Supported") => {
+                "PacketSize=4000;qXfer:features:read+".to_owned()
+            }
+            "q" if args.starts_with("Xfer:features:read:") => {
+                read_features(&args["Xfer:features:read:".len()..])
+            }
+            "q" if args == "Attached" => "1".to_owned(),
+            "q" if args == "C" => "QC1".to_owned(),
+            "q" if args == "fThreadInfo" => "m1".to_owned(),
+            "q" if args == "sThreadInfo" => "l".to_owned(),
+            _ => String::new(),
+        };
+        return Some(reply);
+    }
+    /// Send a packet with its checksum.
+    fn send(&mut self, data: &str) {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.send_raw(packet.as_bytes());
+    }
+    fn send_raw(&mut self, bytes: &[u8]) {
+        let failed = match self.client.as_mut() {
+            Some(client) => client.write_all(bytes).is_err(),
+            None => false,
+        };
+        if failed {
+            self.disconnect();
+        }
+    }
+}
+
+/// Pieces of what the debugger sends.
+enum Packet {
+    /// A packet with a valid checksum
+    Data(String),
+    /// A packet with the wrong checksum, the debugger sends it again
+    Corrupt,
+    /// Ctrl-C, stop the machine
+    Interrupt,
+}
+
+/// Signals of the stop replies.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+/// Answer `qXfer:features:read:annex:offset,length` with a piece of the target description.
+fn read_features(args: &str) -> String {
+    let mut parts = args.splitn(2, ':');
+    if parts.next() != Some("target.xml") {
+        return "E00".to_owned();
+    }
+    let xml = target_xml();
+    let (offset, end) = match parts
+        .next()
+        .and_then(|range| parse_range(range, usize::MAX))
+    {
+        Some((offset, end)) => (offset.min(xml.len()), end.min(xml.len())),
+        None => return "E01".to_owned(),
+    };
+    // m when there's more to read, l for the last piece
+    let kind = if end < xml.len() { "m" } else { "l" };
+    return format!("{}{}", kind, &xml[offset..end]);
+}
+
+/// Sum of the bytes of a packet, modulo 256.
+fn checksum_of(data: &[u8]) -> u8 {
+    return data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+}
+
+/// Parse hex bytes like `0a1b`.
+fn parse_hex(digits: &str) -> Option<Vec<u8>> {
+    if digits.len() % 2 != 0 || !digits.is_ascii() {
+        return None;
+    }
+    return (0..digits.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&digits[idx..idx + 2], 16).ok())
+        .collect();
+}
+
+/// Parse `addr,length` into a range of the memory, None if it doesn't fit.
+fn parse_range(args: &str, size: usize) -> Option<(usize, usize)> {
+    let mut parts = args.splitn(2, ',');
+    let start = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let length = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let end = start.checked_add(length)?;
+    if end > size {
+        return None;
+    }
+    return Some((start, end));
+}
+
+/// Value of a register in hex, big endian.
+fn read_register(machine: &Machine, number: usize) -> String {
+    let cpu = &machine.cpu;
+    return match number {
+        0..=15 => format!("{:02x}", cpu.v[number]),
+        16 => format!("{:04x}", cpu.i),
+        17 => format!("{:04x}", cpu.program_counter),
+        18 => format!("{:02x}", cpu.dt),
+        19 => format!("{:02x}", cpu.st),
+        // A byte can't tell deeper stacks apart
+        _ => format!("{:02x}", cpu.stack.len().min(0xFF)),
+    };
+}
+
+/// Set a register from its value in hex, big endian.
+fn write_register(machine: &mut Machine, number: usize, digits: &str) -> Result<(), &'static str> {
+    if digits.len() != register_size(number) * 2 {
+        return Err("Wrong register size");
+    }
+    let value = u16::from_str_radix(digits, 16).map_err(|_| "Register values must be hex")?;
+    let cpu = &mut machine.cpu;
+    match number {
+        0..=15 => cpu.v[number] = value as u8,
+        16 => cpu.i = value,
+        17 => cpu.program_counter = value,
+        18 => cpu.dt = value as u8,
+        19 => cpu.st = value as u8,
+        _ => {
+            if value > 16 {
+                return Err("The stack only has 16 levels");
+            }
+            cpu.stack.resize(value as usize, 0);
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::machine::Machine;
+    use super::GdbStub;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+
+    /// 6005 7001 1202: load 5 into V0 and count up forever
+    const PROGRAM: [u8; 6] = [0x60, 0x05, 0x70, 0x01, 0x12, 0x02];
+
+    fn connect() -> (GdbStub, Machine, TcpStream) {
+        let mut stub = GdbStub::bind("127.0.0.1:0").expect("Couldn't bind the stub");
+        let machine = Machine::new(&PROGRAM).expect("Couldn't create the machine");
+        let client = TcpStream::connect(stub.local_addr().unwrap()).expect("Couldn't connect");
+        client.set_nonblocking(true).unwrap();
+        let mut machine = machine;
+        stub.poll(&mut machine);
+        assert!(stub.attached(), "Debugger wasn't accepted");
+        return (stub, machine, client);
+    }
+    /// Send a packet and wait for the reply, polling the stub in between.
+    fn exchange(
+        stub: &mut GdbStub,
+        machine: &mut Machine,
+        client: &mut TcpStream,
+        packet: &str,
+    ) -> String {
+        let sum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(client, "${}#{:02x}", packet, sum).unwrap();
+        return reply(stub, machine, client);
+    }
+    fn reply(stub: &mut GdbStub, machine: &mut Machine, client: &mut TcpStream) -> String {
+        let mut received = String::new();
+        for _ in 0..200 {
+            stub.poll(machine);
+            let mut chunk = [0u8; 4096];
+            if let Ok(size) = client.read(&mut chunk) {
+                received.push_str(&String::from_utf8_lossy(&chunk[..size]));
+            }
+            let received = received.trim_start_matches('+');
+            if let Some(end) = received.find('#') {
+                if received.len() >= end + 3 {
+                    return received[1..end].to_owned();
+                }
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        panic!("No reply from the stub");
+    }
+    #[test]
+    fn registers() {
+        let (mut stub, mut machine, mut client) = connect();
+        assert!(!stub.running(), "Attaching should stop the machine");
+        machine.cpu.v[1] = 0xAB;
+        let registers = exchange(&mut stub, &mut machine, &mut client, "g");
+        assert_eq!(registers.len(), (16 + 2 + 2 + 1 + 1 + 1) * 2);
+        assert_eq!(&registers[2..4], "ab", "Wrong V1");
+        assert_eq!(&registers[36..40], "0200", "Wrong program counter");
+        assert_eq!(
+            exchange(&mut stub, &mut machine, &mut client, "P10=0345"),
+            "OK"
+        );
+        assert_eq!(machine.cpu.i, 0x345);
+        assert_eq!(
+            exchange(&mut stub, &mut machine, &mut client, "p10"),
+            "0345"
+        );
+        assert_eq!(exchange(&mut stub, &mut machine, &mut client, "p15"), "E01");
+        assert_eq!(
+            exchange(&mut stub, &mut machine, &mut client, "G0102"),
+            "E01",
+            "Short G accepted"
+        );
+    }
+    #[test]
+    fn target_description() {
+        let (mut stub, mut machine, mut client) = connect();
+        let supported = exchange(&mut stub, &mut machine, &mut client, "qSupported");
+        assert!(supported.contains("qXfer:features:read+"));
+        let piece = exchange(
+            &mut stub,
+            &mut machine,
+            &mut client,
+            "qXfer:features:read:target.xml:0,10",
+        );
+        assert_eq!(piece, "m<?xml version=\"1", "Wrong first piece");
+        let xml = exchange(
+            &mut stub,
+            &mut machine,
+            &mut client,
+            "qXfer:features:read:target.xml:0,1000",
+        );
+        assert!(
+            xml.starts_with('l'),
+            "Whole description should be the last piece"
+        );
+        assert_eq!(xml.matches("<reg ").count(), 21);
+        assert!(xml.contains("<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>"));
+        assert_eq!(
+            exchange(
+                &mut stub,
+                &mut machine,
+                &mut client,
+                "qXfer:features:read:other.xml:0,10"
+            ),
+            "E00"
+        );
+    }
+    #[test]
+    fn memory() {
+        let (mut stub, mut machine, mut client) = connect();
+        assert_eq!(
+            exchange(&mut stub, &mut machine, &mut client, "m200,4"),
+            "60057001"
+        );
+        assert_eq!(
+            exchange(&mut stub, &mut machine, &mut client, "M300,2:beef"),
+            "OK"
+        );
+        assert_eq!(machine.mem.space[0x300..0x302], [0xBE, 0xEF]);
+        assert_eq!(
+            exchange(&mut stub, &mut machine, &mut client, "m1fff,2"),
+            "E01"
+        );
+    }
+    #[test]
+    fn step_and_continue() {
+        let (mut stub, mut machine, mut client) = connect();
+        assert_eq!(exchange(&mut stub, &mut machine, &mut client, "s"), "S05");
+        assert_eq!(machine.cpu.program_counter, 0x202, "Step didn't run");
+        assert_eq!(
+            exchange(&mut stub, &mut machine, &mut client, "Z0,204,2"),
+            "OK"
+        );
+        write!(client, "$c#63").unwrap();
+        stub.poll(&mut machine);
+        assert!(stub.running(), "Continue should let the machine run");
+        machine.run_frame(10);
+        assert_eq!(reply(&mut stub, &mut machine, &mut client), "S05");
+        assert!(!stub.running(), "Breakpoint should stop the machine");
+        assert_eq!(machine.cpu.program_counter, 0x204);
+        assert_eq!(exchange(&mut stub, &mut machine, &mut client, "s"), "S05");
+        assert_eq!(
+            machine.cpu.program_counter, 0x202,
+            "Step should leave the breakpoint"
+        );
+        // Breakpoints can go anywhere in memory
+        assert_eq!(
+            exchange(&mut stub, &mut machine, &mut client, "Z0,1ffe,2"),
+            "OK"
+        );
+        assert_eq!(
+            exchange(&mut stub, &mut machine, &mut client, "Z0,2000,2"),
+            "E01"
+        );
+    }
+    #[test]
+    fn stop_reason() {
+        let (mut stub, mut machine, mut client) = connect();
+        assert_eq!(exchange(&mut stub, &mut machine, &mut client, "?"), "S05");
+        write!(client, "$c#63").unwrap();
+        stub.poll(&mut machine);
+        // Asked while running, the answer is the next stop
+        write!(client, "$?#3f").unwrap();
+        stub.poll(&mut machine);
+        client.write_all(&[0x03]).unwrap();
+        assert_eq!(reply(&mut stub, &mut machine, &mut client), "S02");
+        assert_eq!(exchange(&mut stub, &mut machine, &mut client, "?"), "S02");
+    }
+    #[test]
+    fn detach() {
+        let (mut stub, mut machine, mut client) = connect();
+        assert_eq!(exchange(&mut stub, &mut machine, &mut client, "D"), "OK");
+        assert!(!stub.attached());
+        assert!(stub.running(), "Machine should run without a debugger");
+    }
+}
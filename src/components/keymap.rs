@@ -0,0 +1,380 @@
+//! # Keymap Module
+//! ## Description
+//! Maps the keys of the computer to the 16 keys of the CHIP-8 hex keypad.
+//! The original COSMAC VIP keypad looks like this:
+//!
+//! ```text
+//! 1 2 3 C
+//! 4 5 6 D
+//! 7 8 9 E
+//! A 0 B F
+//! ```
+//! ## Layouts
+//! There are three built-in layouts:
+//! * standard: the keypad is laid over 1234/QWER/ASDF/ZXCV keeping its shape
+//! * hex: the same keys, but read in order from 0 to F
+//! * numpad: the digits are on the numpad, A to F are the operators around it
+//!
+//! More can be added in the `[layouts]` table of the config file.
+//! Each CHIP-8 key can have as many physical keys as wanted:
+//!
+//! ```toml
+//! layout = "mine"
+//!
+//! [layouts.mine]
+//! 0 = ["X", "Numpad0"]
+//! 1 = "Key1"
+//! ```
+//!
+//! Keys that aren't listed are not mapped. Roms can pick a layout through the rom database.
+//! ## Second keypad
+//! CHIP-8X games can use a second keypad. The standard and hex layouts put it on the numpad, with the shape of the first one,
+//! and config layouts list it in a `second` table:
+//!
+//! ```toml
+//! [layouts.mine.second]
+//! 0 = "Numpad0"
+//! ```
+use std::collections::HashMap;
+use winit::event::VirtualKeyCode;
+
+/// Keys of the COSMAC VIP keypad, row by row.
+const VIP_KEYPAD: [u8; 16] = [
+    0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF,
+];
+
+/// Maps physical keys to CHIP-8 keys.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    /// Pairs of physical key and CHIP-8 key
+    bindings: Vec<(VirtualKeyCode, u8)>,
+    /// Pairs of physical key and key of the second keypad
+    second: Vec<(VirtualKeyCode, u8)>,
+}
+
+impl Keymap {
+    /// The keypad laid over the left side of the keyboard, keeping its shape.
+    pub fn standard() -> Keymap {
+        use VirtualKeyCode::*;
+        return Keymap {
+            second: Keymap::numpad_grid(),
+            ..Keymap::from_grid([Key1, Key2, Key3, Key4, Q, W, E, R, A, S, D, F, Z, X, C, V])
+        };
+    }
+    /// The same keys as standard, read in order from 0 to F.
+    pub fn hex() -> Keymap {
+        use VirtualKeyCode::*;
+        let keys = [Key1, Key2, Key3, Key4, Q, W, E, R, A, S, D, F, Z, X, C, V];
+        return Keymap {
+            bindings: keys
+                .iter()
+                .enumerate()
+                .map(|(value, key)| (*key, value as u8))
+                .collect(),
+            second: Keymap::numpad_grid(),
+        };
+    }
+    /// Digits on the numpad, A to F on the operators.
+    pub fn numpad() -> Keymap {
+        use VirtualKeyCode::*;
+        let keys = [
+            Numpad0,
+            Numpad1,
+            Numpad2,
+            Numpad3,
+            Numpad4,
+            Numpad5,
+            Numpad6,
+            Numpad7,
+            Numpad8,
+            Numpad9,
+            NumpadDivide,
+            NumpadMultiply,
+            NumpadSubtract,
+            NumpadAdd,
+            NumpadEnter,
+            NumpadDecimal,
+        ];
+        return Keymap {
+            bindings: keys
+                .iter()
+                .enumerate()
+                .map(|(value, key)| (*key, value as u8))
+                .collect(),
+            second: Vec::new(),
+        };
+    }
+    /// Get one of the layouts that don't need a config file.
+    pub fn builtin(name: &str) -> Option<Keymap> {
+        match name {
+            "standard" => Some(Keymap::standard()),
+            "hex" => Some(Keymap::hex()),
+            "numpad" => Some(Keymap::numpad()),
+            _ => None,
+        }
+    }
+    /// Lay the COSMAC VIP keypad over 16 keys, given row by row.
+    fn from_grid(keys: [VirtualKeyCode; 16]) -> Keymap {
+        return Keymap {
+            bindings: Keymap::grid(keys),
+            second: Vec::new(),
+        };
+    }
+    /// The second keypad on the numpad, keeping its shape.
+    fn numpad_grid() -> Vec<(VirtualKeyCode, u8)> {
+        use VirtualKeyCode::*;
+        return Keymap::grid([
+            Numpad7,
+            Numpad8,
+            Numpad9,
+            NumpadDivide,
+            Numpad4,
+            Numpad5,
+            Numpad6,
+            NumpadMultiply,
+            Numpad1,
+            Numpad2,
+            Numpad3,
+            NumpadSubtract,
+            Numpad0,
+            NumpadDecimal,
+            NumpadEnter,
+            NumpadAdd,
+        ]);
+    }
+    fn grid(keys: [VirtualKeyCode; 16]) -> Vec<(VirtualKeyCode, u8)> {
+        return keys
+            .iter()
+            .cloned()
+            .zip(VIP_KEYPAD.iter().cloned())
+            .collect();
+    }
+    /// Read a layout from the config file, each key is a hex digit with one or more key names.
+    pub fn from_toml(layout: &toml::Value) -> Result<Keymap, &'static str> {
+        let table = layout.as_table().ok_or("Layouts must be tables")?;
+        let second = match table.get("second") {
+            Some(second) => Keymap::from_toml(second)?.bindings,
+            None => Vec::new(),
+        };
+        let mut bindings: Vec<(VirtualKeyCode, u8)> = Vec::new();
+        for (chip_key, names) in table.iter().filter(|(name, _)| *name != "second") {
+            let value = u8::from_str_radix(chip_key, 16)
+                .ok()
+                .filter(|value| *value < 16)
+                .ok_or("Layout keys must be hex digits from 0 to F")?;
+            let names: Vec<&toml::Value> = match names {
+                toml::Value::Array(names) => names.iter().collect(),
+                name => vec![name],
+            };
+            for name in names {
+                let name = name.as_str().ok_or("Key names must be strings")?;
+                let key = key_from_name(name).ok_or("Unknown key name in layout")?;
+                bindings.push((key, value));
+            }
+        }
+        return Ok(Keymap { bindings, second });
+    }
+    /// Get the CHIP-8 key a physical key is mapped to.
+    pub fn lookup(&self, key: VirtualKeyCode) -> Option<u8> {
+        return self
+            .bindings
+            .iter()
+            .find(|(physical, _)| *physical == key)
+            .map(|(_, value)| *value);
+    }
+    /// Get the key of the second keypad a physical key is mapped to.
+    pub fn lookup_second(&self, key: VirtualKeyCode) -> Option<u8> {
+        return self
+            .second
+            .iter()
+            .find(|(physical, _)| *physical == key)
+            .map(|(_, value)| *value);
+    }
+}
+
+/// Layouts from the config file, plus the built-in ones.
+pub struct KeymapConfig {
+    /// Layout used when the rom doesn't ask for another one
+    pub default: String,
+    pub layouts: HashMap<String, Keymap>,
+}
+
+impl Default for KeymapConfig {
+    fn default() -> KeymapConfig {
+        let mut layouts = HashMap::new();
+        for name in ["standard", "hex", "numpad"].iter() {
+            layouts.insert(name.to_string(), Keymap::builtin(name).unwrap());
+        }
+        KeymapConfig {
+            default: "standard".to_owned(),
+            layouts,
+        }
+    }
+}
+
+impl KeymapConfig {
+    /// Read the `layout` key and the `[layouts]` table of a parsed config file.
+    pub fn from_toml(config: &toml::Value) -> Result<KeymapConfig, &'static str> {
+        let mut keymaps = KeymapConfig {
+            ..Default::default()
+        };
+        if let Some(layouts) = config.get("layouts") {
+            let layouts = layouts.as_table().ok_or("layouts must be a table")?;
+            for (name, layout) in layouts.iter() {
+                keymaps
+                    .layouts
+                    .insert(name.clone(), Keymap::from_toml(layout)?);
+            }
+        }
+        if let Some(default) = config.get("layout") {
+            keymaps.default = default.as_str().ok_or("layout must be a name")?.to_owned();
+        }
+        if !keymaps.layouts.contains_key(&keymaps.default) {
+            return Err("The default layout doesn't exist");
+        }
+        return Ok(keymaps);
+    }
+    /// Get a layout by name, or the default one if there is no name or it doesn't exist.
+    pub fn get(&self, name: Option<&str>) -> Keymap {
+        let layout = name
+            .and_then(|name| self.layouts.get(name))
+            .unwrap_or_else(|| &self.layouts[&self.default]);
+        return layout.clone();
+    }
+}
+
+/// Parse the name of a key, as written in the winit VirtualKeyCode enum.
+///
+/// Digits can also be written without the `Key` prefix.
+pub fn key_from_name(name: &str) -> Option<VirtualKeyCode> {
+    use VirtualKeyCode::*;
+    let key = match name {
+        "0" | "Key0" => Key0,
+        "1" | "Key1" => Key1,
+        "2" | "Key2" => Key2,
+        "3" | "Key3" => Key3,
+        "4" | "Key4" => Key4,
+        "5" | "Key5" => Key5,
+        "6" | "Key6" => Key6,
+        "7" | "Key7" => Key7,
+        "8" | "Key8" => Key8,
+        "9" | "Key9" => Key9,
+        "A" => A,
+        "B" => B,
+        "C" => C,
+        "D" => D,
+        "E" => E,
+        "F" => F,
+        "G" => G,
+        "H" => H,
+        "I" => I,
+        "J" => J,
+        "K" => K,
+        "L" => L,
+        "M" => M,
+        "N" => N,
+        "O" => O,
+        "P" => P,
+        "Q" => Q,
+        "R" => R,
+        "S" => S,
+        "T" => T,
+        "U" => U,
+        "V" => V,
+        "W" => W,
+        "X" => X,
+        "Y" => Y,
+        "Z" => Z,
+        "Numpad0" => Numpad0,
+        "Numpad1" => Numpad1,
+        "Numpad2" => Numpad2,
+        "Numpad3" => Numpad3,
+        "Numpad4" => Numpad4,
+        "Numpad5" => Numpad5,
+        "Numpad6" => Numpad6,
+        "Numpad7" => Numpad7,
+        "Numpad8" => Numpad8,
+        "Numpad9" => Numpad9,
+        "NumpadAdd" => NumpadAdd,
+        "NumpadDivide" => NumpadDivide,
+        "NumpadDecimal" => NumpadDecimal,
+        "NumpadEnter" => NumpadEnter,
+        "NumpadMultiply" => NumpadMultiply,
+        "NumpadSubtract" => NumpadSubtract,
+        "Up" => Up,
+        "Down" => Down,
+        "Left" => Left,
+        "Right" => Right,
+        "Space" => Space,
+        "Return" => Return,
+        "Back" => Back,
+        "LShift" => LShift,
+        "RShift" => RShift,
+        "LControl" => LControl,
+        "RControl" => RControl,
+        "Comma" => Comma,
+        "Period" => Period,
+        "Slash" => Slash,
+        "Semicolon" => Semicolon,
+        _ => return None,
+    };
+    return Some(key);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Keymap, KeymapConfig};
+    use winit::event::VirtualKeyCode;
+    #[test]
+    fn standard() {
+        let keymap = Keymap::standard();
+        assert_eq!(keymap.lookup(VirtualKeyCode::Key4), Some(0xC));
+        assert_eq!(keymap.lookup(VirtualKeyCode::X), Some(0x0));
+        assert_eq!(keymap.lookup(VirtualKeyCode::V), Some(0xF));
+        assert_eq!(keymap.lookup(VirtualKeyCode::P), None);
+        assert_eq!(keymap.lookup_second(VirtualKeyCode::Numpad0), Some(0xA));
+        assert_eq!(keymap.lookup_second(VirtualKeyCode::X), None);
+    }
+    #[test]
+    fn hex() {
+        let keymap = Keymap::hex();
+        assert_eq!(keymap.lookup(VirtualKeyCode::Key1), Some(0x0));
+        assert_eq!(keymap.lookup(VirtualKeyCode::V), Some(0xF));
+    }
+    #[test]
+    fn from_toml() {
+        let text = "layout = \"mine\"\n[layouts.mine]\n0 = [\"X\", \"Numpad0\"]\nf = \"Space\"\n\
+                    [layouts.mine.second]\n1 = \"P\"\n";
+        let config: toml::Value = toml::from_str(text).unwrap();
+        let keymaps = KeymapConfig::from_toml(&config).expect("Config should parse");
+        let keymap = keymaps.get(None);
+        assert_eq!(keymap.lookup(VirtualKeyCode::X), Some(0x0));
+        assert_eq!(keymap.lookup(VirtualKeyCode::Numpad0), Some(0x0));
+        assert_eq!(keymap.lookup(VirtualKeyCode::Space), Some(0xF));
+        assert_eq!(keymap.lookup_second(VirtualKeyCode::P), Some(0x1));
+        assert_eq!(keymap.lookup(VirtualKeyCode::P), None);
+        assert_eq!(
+            keymaps.get(Some("hex")),
+            Keymap::hex(),
+            "Built-in layouts are kept"
+        );
+    }
+    #[test]
+    fn wrong_toml() {
+        let bad_key: toml::Value = toml::from_str("[layouts.mine]\n10 = \"X\"\n").unwrap();
+        assert!(
+            KeymapConfig::from_toml(&bad_key).is_err(),
+            "Key 0x10 accepted"
+        );
+        let bad_name: toml::Value = toml::from_str("[layouts.mine]\n1 = \"Nope\"\n").unwrap();
+        assert!(
+            KeymapConfig::from_toml(&bad_name).is_err(),
+            "Unknown key accepted"
+        );
+        let bad_default: toml::Value = toml::from_str("layout = \"nope\"\n").unwrap();
+        assert!(
+            KeymapConfig::from_toml(&bad_default).is_err(),
+            "Missing layout accepted"
+        );
+    }
+}
@@ -0,0 +1,149 @@
+//! # Keypad Module
+//! ## Description
+//! The hex keypad of the machine, keys 0 to F.
+//! The interpreter only talks to the Keypad trait, so any input can be plugged in.
+//! ## Events
+//! Besides which keys are held down, EventKeypad keeps every press and release in the order they happened,
+//! stamped with the cycle they happened at. Fx0A reads the ones that came after it started waiting,
+//! so taps shorter than a frame aren't lost.
+//! Events nobody reads are forgotten by the machine after a frame.
+//! ## Waiting for keys
+//! Fx0A can end in two ways:
+//! * `release`: like on the COSMAC VIP, once a key pressed while waiting is released
+//! * `press`: as soon as a key is pressed, like most later interpreters
+use std::collections::VecDeque;
+
+/// A key going down or up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyEvent {
+    pub key: u8,
+    pub pressed: bool,
+    /// Cycle of the machine the event happened at
+    pub time: u64,
+}
+
+/// When Fx0A stops waiting.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyWait {
+    /// When a key pressed while waiting is released
+    Release,
+    /// When a key is pressed
+    Press,
+}
+
+impl Default for KeyWait {
+    fn default() -> KeyWait {
+        KeyWait::Release
+    }
+}
+
+impl KeyWait {
+    /// Parse `release` or `press`.
+    pub fn parse(text: &str) -> Result<KeyWait, &'static str> {
+        return match text {
+            "release" => Ok(KeyWait::Release),
+            "press" => Ok(KeyWait::Press),
+            _ => Err("Key waits are release or press"),
+        };
+    }
+}
+
+/// A keypad the interpreter can read.
+pub trait Keypad {
+    /// Check if a key is held down.
+    fn is_pressed(&self, key: u8) -> bool;
+    /// Take the oldest press or release not taken yet.
+    fn next_event(&mut self) -> Option<KeyEvent>;
+}
+
+/// A snapshot of the keys, without any events.
+impl Keypad for [bool; 16] {
+    fn is_pressed(&self, key: u8) -> bool {
+        return self[key as usize];
+    }
+    fn next_event(&mut self) -> Option<KeyEvent> {
+        return None;
+    }
+}
+
+/// A keypad that remembers every press and release in order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EventKeypad {
+    keys: [bool; 16],
+    events: VecDeque<KeyEvent>,
+}
+
+impl EventKeypad {
+    /// Press or release a key, nothing happens if it already was.
+    pub fn set_key(&mut self, key: u8, pressed: bool, time: u64) {
+        let key = key & 0xF;
+        if self.keys[key as usize] == pressed {
+            return;
+        }
+        self.keys[key as usize] = pressed;
+        self.events.push_back(KeyEvent {
+            key: key,
+            pressed: pressed,
+            time: time,
+        });
+    }
+    /// Every key, held down or not.
+    pub fn keys(&self) -> [bool; 16] {
+        return self.keys;
+    }
+    /// Events not taken yet, oldest first.
+    pub fn events(&self) -> impl Iterator<Item = &KeyEvent> {
+        return self.events.iter();
+    }
+    /// Forget the events that happened before a cycle.
+    pub fn forget_before(&mut self, time: u64) {
+        self.events.retain(|event| event.time >= time);
+    }
+}
+
+impl Keypad for EventKeypad {
+    fn is_pressed(&self, key: u8) -> bool {
+        return self.keys[key as usize];
+    }
+    fn next_event(&mut self) -> Option<KeyEvent> {
+        return self.events.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventKeypad, KeyWait, Keypad};
+    #[test]
+    fn events() {
+        let mut keypad = EventKeypad::default();
+        keypad.set_key(5, true, 10);
+        keypad.set_key(5, true, 11);
+        keypad.set_key(5, false, 12);
+        assert!(!keypad.is_pressed(5), "Tap should be over");
+        assert_eq!(
+            keypad.events().count(),
+            2,
+            "Repeated press should be ignored"
+        );
+        let press = keypad.next_event().unwrap();
+        assert_eq!((press.key, press.pressed, press.time), (5, true, 10));
+        let release = keypad.next_event().unwrap();
+        assert_eq!((release.key, release.pressed, release.time), (5, false, 12));
+        assert_eq!(keypad.next_event(), None);
+    }
+    #[test]
+    fn forget_before() {
+        let mut keypad = EventKeypad::default();
+        keypad.set_key(1, true, 5);
+        keypad.set_key(2, true, 20);
+        keypad.forget_before(10);
+        assert_eq!(keypad.next_event().map(|event| event.key), Some(2));
+        assert!(keypad.keys()[1], "Forgetting shouldn't release keys");
+    }
+    #[test]
+    fn parse() {
+        assert_eq!(KeyWait::parse("press"), Ok(KeyWait::Press));
+        assert_eq!(KeyWait::default(), KeyWait::Release);
+        assert!(KeyWait::parse("tap").is_err());
+    }
+}
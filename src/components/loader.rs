@@ -0,0 +1,330 @@
+//! # Loader Module
+//! ## Description
+//! Reads roms from the formats they are shared in, guessing the format from the contents.
+//! ## Formats
+//! * Raw binaries, always the case for `.ch8`, `.sc8`, `.xo8` and `.c8` files
+//! * Hex dumps in ASCII: `60 05 F0 18`, `0x6005, 0xF018` or `6005F018`, an `0200:` before a line is skipped
+//! * Zip archives, with the rom in the entry asked for or else the first one that looks like a rom
+//! * Octo cartridges, GIF images with the program and its options hidden in the pixels
+//! ## Octo cartridges
+//! The low nibble of every pixel, frame after frame, makes up the payload two nibbles per byte:
+//! a 32 bit big endian length and then JSON with the `program` and its `options`.
+//!
+//! Octo stores the source of the program, only sources made of plain bytes (`0x60 0x05 ...`)
+//! can be loaded, anything else has to be assembled in Octo first.
+//! The tick rate, the shift and load/store quirks and the colours of the options are kept in the rom options.
+//! Octo's memory size tells the platform: the sizes of the VIP and SUPER-CHIP, up to 3584 bytes, are plain CHIP-8 roms.
+//! XO-CHIP roms, with 65024 bytes, have no platform here and are left to the other ways of picking one.
+use super::capture::Palette;
+use super::platform::Platform;
+use serde_json::Value;
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+/// Where a rom was found.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Binary,
+    Hex,
+    Zip,
+    Cartridge,
+}
+
+/// Settings a rom comes with, None when it doesn't say.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RomOptions {
+    pub cycles_per_frame: Option<u32>,
+    pub shift_y: Option<bool>,
+    pub store_load_quirk: Option<bool>,
+    pub palette: Option<Palette>,
+    pub platform: Option<Platform>,
+}
+
+/// A program ready to be loaded into memory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rom {
+    pub program: Vec<u8>,
+    pub format: Format,
+    pub options: RomOptions,
+}
+
+/// Extensions that are always raw binaries.
+const BINARY_EXTENSIONS: [&str; 4] = ["ch8", "sc8", "xo8", "c8"];
+
+/// Read a rom file, entry picks the file inside zip archives.
+pub fn load(path: &str, entry: Option<&str>) -> Result<Rom, &'static str> {
+    let data = fs::read(path).map_err(|_| "Couldn't read the rom")?;
+    return parse(path, &data, entry);
+}
+
+/// Find the rom in the contents of a file, the name is only used for its extension.
+pub fn parse(name: &str, data: &[u8], entry: Option<&str>) -> Result<Rom, &'static str> {
+    let extension = Path::new(name)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let mut rom = Rom {
+        program: data.to_vec(),
+        format: Format::Binary,
+        options: RomOptions::default(),
+    };
+    if BINARY_EXTENSIONS.contains(&extension.as_str()) {
+        return Ok(rom);
+    }
+    if data.starts_with(b"PK\x03\x04") {
+        return unzip(data, entry);
+    }
+    if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        return cartridge(data);
+    }
+    if let Some(program) = parse_hex(data) {
+        rom.program = program;
+        rom.format = Format::Hex;
+    }
+    return Ok(rom);
+}
+
+/// Read an ASCII hex dump, None if it isn't one.
+fn parse_hex(data: &[u8]) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(data).ok()?;
+    let mut program = Vec::new();
+    for line in text.lines() {
+        let line = line.splitn(2, '#').next().unwrap_or("");
+        for word in line.split(|c: char| c == ',' || c.is_whitespace()) {
+            if word.is_empty() || word.ends_with(':') {
+                continue;
+            }
+            let digits = word.trim_start_matches("0x").trim_start_matches("0X");
+            if digits.is_empty() || digits.len() % 2 != 0 {
+                return None;
+            }
+            for pair in digits.as_bytes().chunks(2) {
+                let pair = std::str::from_utf8(pair).ok()?;
+                program.push(u8::from_str_radix(pair, 16).ok()?);
+            }
+        }
+    }
+    if program.is_empty() {
+        return None;
+    }
+    return Some(program);
+}
+
+/// Take the rom out of a zip archive, the entry asked for or the first that looks like a rom.
+fn unzip(data: &[u8], entry: Option<&str>) -> Result<Rom, &'static str> {
+    let mut archive =
+        zip::ZipArchive::new(Cursor::new(data)).map_err(|_| "Couldn't read the zip archive")?;
+    let name = match entry {
+        Some(entry) => entry.to_owned(),
+        None => {
+            let names: Vec<String> = archive
+                .file_names()
+                .filter(|name| !name.ends_with('/'))
+                .map(String::from)
+                .collect();
+            let is_rom = |name: &&String| {
+                let extension = Path::new(name.as_str())
+                    .extension()
+                    .map(|extension| extension.to_string_lossy().to_lowercase());
+                extension.map_or(false, |extension| {
+                    BINARY_EXTENSIONS.contains(&extension.as_str())
+                })
+            };
+            // Archives don't keep their entries in order
+            let mut roms: Vec<&String> = names.iter().filter(is_rom).collect();
+            if roms.is_empty() {
+                roms = names.iter().collect();
+            }
+            roms.sort();
+            roms.first().ok_or("Zip archive is empty")?.to_string()
+        }
+    };
+    let mut file = archive
+        .by_name(&name)
+        .map_err(|_| "Entry isn't in the zip archive")?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)
+        .map_err(|_| "Couldn't unpack the zip entry")?;
+    if contents.starts_with(b"PK\x03\x04") {
+        return Err("Zip archives inside zip archives aren't supported");
+    }
+    let mut rom = parse(&name, &contents, None)?;
+    if rom.format != Format::Cartridge {
+        rom.format = Format::Zip;
+    }
+    return Ok(rom);
+}
+
+/// Read the program and the options of an Octo cartridge.
+fn cartridge(data: &[u8]) -> Result<Rom, &'static str> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options
+        .read_info(data)
+        .map_err(|_| "Couldn't read the cartridge GIF")?;
+    let mut nibbles = Vec::new();
+    while let Some(frame) = decoder
+        .read_next_frame()
+        .map_err(|_| "Couldn't read the cartridge GIF")?
+    {
+        nibbles.extend(frame.buffer.iter().map(|index| index & 0xF));
+    }
+    let bytes: Vec<u8> = nibbles
+        .chunks_exact(2)
+        .map(|pair| pair[0] << 4 | pair[1])
+        .collect();
+    if bytes.len() < 4 {
+        return Err("GIF is too small to be a cartridge");
+    }
+    let size = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    let payload = bytes
+        .get(4..4 + size)
+        .ok_or("GIF isn't an Octo cartridge")?;
+    let payload: Value =
+        serde_json::from_slice(payload).map_err(|_| "GIF isn't an Octo cartridge")?;
+    let source = payload["program"]
+        .as_str()
+        .ok_or("Cartridge has no program")?;
+    return Ok(Rom {
+        program: octo_bytes(source)?,
+        format: Format::Cartridge,
+        options: octo_options(&payload["options"]),
+    });
+}
+
+/// Read an Octo source made of plain numbers, labels are ignored.
+fn octo_bytes(source: &str) -> Result<Vec<u8>, &'static str> {
+    let mut program = Vec::new();
+    let mut label = false;
+    for line in source.lines() {
+        let line = line.splitn(2, '#').next().unwrap_or("");
+        for word in line.split_whitespace() {
+            if label {
+                label = false;
+                continue;
+            }
+            if word == ":" {
+                label = true;
+                continue;
+            }
+            let value = if let Some(digits) = word.strip_prefix("0x") {
+                u8::from_str_radix(digits, 16)
+            } else if let Some(digits) = word.strip_prefix("0b") {
+                u8::from_str_radix(digits, 2)
+            } else {
+                word.parse::<u8>()
+            };
+            program.push(
+                value.map_err(|_| "Cartridge program isn't plain bytes, assemble it in Octo")?,
+            );
+        }
+    }
+    return Ok(program);
+}
+
+/// Keep the Octo options the emulator understands.
+fn octo_options(options: &Value) -> RomOptions {
+    let colour = |key: &str| options[key].as_str().map(String::from);
+    let palette = match (colour("fillColor"), colour("backgroundColor")) {
+        (Some(fill), Some(background)) => Palette::parse(&format!("{},{}", fill, background)).ok(),
+        _ => None,
+    };
+    // Octo writes the size as a number or as a string, depending on the version
+    let max_size = match &options["maxSize"] {
+        Value::String(size) => size.parse::<u64>().ok(),
+        size => size.as_u64(),
+    };
+    let platform = match max_size {
+        Some(size) if size <= 3584 => Some(Platform::Chip8),
+        _ => None,
+    };
+    return RomOptions {
+        cycles_per_frame: options["tickrate"].as_u64().map(|rate| rate as u32),
+        // Octo's shift quirk shifts vx in place, without vy
+        shift_y: options["shiftQuirks"].as_bool().map(|quirk| !quirk),
+        store_load_quirk: options["loadStoreQuirks"].as_bool(),
+        palette: palette,
+        platform: platform,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::platform::Platform;
+    use super::{parse, Format};
+    use std::io::{Cursor, Write};
+    mod parse {
+        use super::{parse, Format};
+        #[test]
+        fn correct_case() {
+            let rom = parse("pong.hex", b"0200: 60 05 0xF018\n# loop\n12 04", None).unwrap();
+            assert_eq!(rom.format, Format::Hex);
+            assert_eq!(rom.program, vec![0x60, 0x05, 0xF0, 0x18, 0x12, 0x04]);
+            let rom = parse("pong.ch8", b"6005", None).unwrap();
+            assert_eq!(rom.format, Format::Binary, "Extension should win");
+            assert_eq!(rom.program, b"6005".to_vec());
+            let rom = parse("pong", &[0x60, 0x05, 0x12, 0x02], None).unwrap();
+            assert_eq!(rom.format, Format::Binary);
+        }
+        #[test]
+        fn wrong_case() {
+            assert!(parse("pong.zip", b"PK\x03\x04 cut short", None).is_err());
+            assert!(parse("pong.gif", b"GIF89a cut short", None).is_err());
+        }
+    }
+    #[test]
+    fn zip() {
+        let mut data = Vec::new();
+        {
+            let mut writer = zip::ZipWriter::new(Cursor::new(&mut data));
+            let options = zip::write::SimpleFileOptions::default()
+                .compression_method(zip::CompressionMethod::Deflated);
+            writer.start_file("readme.txt", options).unwrap();
+            writer.write_all(b"Not a rom").unwrap();
+            writer.start_file("pong.ch8", options).unwrap();
+            writer.write_all(&[0x12, 0x00]).unwrap();
+            writer.start_file("brix.hex", options).unwrap();
+            writer.write_all(b"13 00").unwrap();
+            writer.finish().unwrap();
+        }
+        let rom = parse("roms.zip", &data, None).unwrap();
+        assert_eq!(rom.format, Format::Zip);
+        assert_eq!(rom.program, vec![0x12, 0x00], "Wrong entry picked");
+        let rom = parse("roms.zip", &data, Some("brix.hex")).unwrap();
+        assert_eq!(rom.program, vec![0x13, 0x00]);
+        assert!(parse("roms.zip", &data, Some("tetris.ch8")).is_err());
+    }
+    #[test]
+    fn cartridge() {
+        let payload = br##"{"program": ": main\n0x12 0x00 # loop\n", "options": {
+            "tickrate": 20, "shiftQuirks": true, "loadStoreQuirks": false,
+            "fillColor": "#FFCC00", "backgroundColor": "#996600", "maxSize": "3215"}}"##;
+        let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(payload);
+        // The label is drawn with the high nibble, colour 3 here
+        let mut pixels: Vec<u8> = bytes
+            .iter()
+            .flat_map(|byte| vec![0x30 | byte >> 4, 0x30 | byte & 0xF])
+            .collect();
+        pixels.resize(32 * 16, 0x30);
+        let mut data = Vec::new();
+        {
+            let colours = [0u8; 256 * 3];
+            let mut encoder = gif::Encoder::new(&mut data, 32, 8, &colours).unwrap();
+            for half in pixels.chunks(32 * 8) {
+                let frame = gif::Frame::from_indexed_pixels(32, 8, half.to_vec(), None);
+                encoder.write_frame(&frame).unwrap();
+            }
+        }
+        let rom = parse("pong.gif", &data, None).expect("Cartridge should load");
+        assert_eq!(rom.format, Format::Cartridge);
+        assert_eq!(rom.program, vec![0x12, 0x00]);
+        assert_eq!(rom.options.cycles_per_frame, Some(20));
+        assert_eq!(rom.options.shift_y, Some(false));
+        assert_eq!(rom.options.store_load_quirk, Some(false));
+        let palette = rom.options.palette.expect("Colours should be kept");
+        assert_eq!(palette.foreground, [0xFF, 0xCC, 0x00]);
+        assert_eq!(rom.options.platform, Some(Platform::Chip8));
+    }
+}
@@ -0,0 +1,629 @@
+//! # CHIP-8 Machine Module
+//! ## Description
+//! Puts together every piece of the virtual computer: the processor, the memory, the display and the keypad.
+//!
+//! Frontends only have to decide when cycles and timer ticks happen, the machine takes care of the rest.
+//! ## Timing
+//! The timers count down at 60 hertz, which is also the rate the display is usually drawn at.
+//! A frame is made of the cycles run between two timer ticks.
+//! ## Resets
+//! * A soft reset puts the processor back to its starting state and loads the rom and fonts again, the rest of the memory is kept
+//! * A hard reset starts over with a fresh machine, as if it was just turned on
+//!
+//! The quirks selected for the processor are kept in both, and so are the breakpoints, the profiler and the cheats.
+//! CHIP-8X machines stay CHIP-8X ones, with their colours and port back to the start, and MegaChip ones stay MegaChip ones.
+//! ## Addresses
+//! Programs are loaded at 0x200 and start there, unless the machine is made for another platform.
+//! Both addresses are kept across resets and when the rom is swapped.
+//! ## Display
+//! The display is 64x32, or 64x64 with the two-page hi-res interpreter. Its size and planes are kept across resets.
+//! Frontends can take its dirty flag to only redraw when something changed.
+//! MegaChip programs draw on a display of their own while its mode is on.
+//! ## Memory
+//! The memory is 8k, MegaChip machines are made with a bigger one. Its size is kept across resets.
+//! ## Running fast
+//! run_until() runs many cycles in a row. Without breakpoints or a profiler it skips checking them on every cycle,
+//! which is the fastest way to run a program one instruction at a time.
+//! When the processor has a recompiler, it runs whole compiled blocks there instead of single instructions.
+//! ## Keypad
+//! Frontends press and release keys with set_key() or set_keys(), every change is stamped with the cycles run so far.
+//! Presses and releases no Fx0A read are forgotten a frame after they happened.
+//! ## Breakpoints
+//! When the program counter reaches a breakpoint the instruction isn't run and step() fails,
+//! without halting the machine. The next step() runs the instruction as usual.
+//! ## Cheats
+//! Cheat codes and frozen addresses are written once per frame, right after the timers tick.
+use super::breakpoints::Breakpoints;
+use super::cheats::Cheats;
+use super::chip8x::Chip8x;
+use super::cpu::Cpu;
+use super::display::{BitDisplay, Display};
+use super::keypad::EventKeypad;
+use super::megachip::MegaChip;
+use super::memory::Memory;
+use super::platform::Platform;
+use super::predecode::DecodeCache;
+use super::profiler::Profiler;
+use super::recompiler::Recompiler;
+use super::sound::Sample;
+
+/// Represents the whole virtual computer.
+pub struct Machine {
+    pub cpu: Cpu,
+    pub mem: Memory,
+    /// Display the programs draw on
+    pub state: BitDisplay,
+    /// Keys held down and their presses and releases
+    pub keypad: EventKeypad,
+    /// Instructions run so far, key events are stamped with it
+    pub cycles: u64,
+    /// Cycles run when the timers last ticked
+    last_tick: u64,
+    /// Set once an instruction fails, no more cycles are run after that
+    pub halted: bool,
+    /// Value loaded into the sound timer by the last Fx18 not yet sent to the beeper
+    pending_beep: Option<u8>,
+    /// Addresses to stop at
+    pub breakpoints: Breakpoints,
+    /// Breakpoint the machine stopped at, not yet seen by the frontend
+    pending_break: Option<u16>,
+    /// Counts every instruction run, when profiling
+    pub profiler: Option<Profiler>,
+    /// Codes and frozen addresses written every frame
+    pub cheats: Cheats,
+    /// Address the program counter starts at
+    pub entry_point: u16,
+    /// Rom loaded again on resets
+    program: Vec<u8>,
+}
+
+impl Default for Machine {
+    fn default() -> Machine {
+        Machine {
+            cpu: Cpu {
+                ..Default::default()
+            },
+            mem: Memory {
+                ..Default::default()
+            },
+            state: BitDisplay::default(),
+            keypad: EventKeypad::default(),
+            cycles: 0,
+            last_tick: 0,
+            halted: false,
+            pending_beep: None,
+            breakpoints: Breakpoints::default(),
+            pending_break: None,
+            profiler: None,
+            cheats: Cheats::default(),
+            entry_point: Memory::START,
+            program: Vec::new(),
+        }
+    }
+}
+
+impl Machine {
+    /// Timers and display refresh rate.
+    pub const FRAMES_PER_SECOND: u32 = 60;
+    /// Create a machine with the fonts and the program already in memory.
+    pub fn new(program: &[u8]) -> Result<Machine, &'static str> {
+        return Machine::with_addresses(program, Memory::START, Memory::START);
+    }
+    /// Create a machine that loads the program somewhere else, and starts running it at the entry point.
+    pub fn with_addresses(
+        program: &[u8],
+        load_address: u16,
+        entry_point: u16,
+    ) -> Result<Machine, &'static str> {
+        return Machine::with_memory(program, load_address, entry_point, Memory::BYTE_MAX);
+    }
+    /// Create a machine with a memory of another size, for programs that reach past the usual 8k.
+    pub fn with_memory(
+        program: &[u8],
+        load_address: u16,
+        entry_point: u16,
+        memory_size: usize,
+    ) -> Result<Machine, &'static str> {
+        let mut machine = Machine {
+            entry_point: entry_point,
+            mem: Memory::with_size(memory_size),
+            ..Default::default()
+        };
+        machine.mem.start = load_address;
+        machine.mem.load(program)?;
+        Cpu::write_fonts_to_mem(&mut machine.mem);
+        machine.cpu.program_counter = entry_point;
+        machine.program = program.to_vec();
+        return Ok(machine);
+    }
+    /// Create a machine for a platform, with its memory, display and extra hardware.
+    ///
+    /// The addresses are given apart, they can be changed for roms that need something else.
+    pub fn with_platform(
+        program: &[u8],
+        platform: Platform,
+        load_address: u16,
+        entry_point: u16,
+    ) -> Result<Machine, &'static str> {
+        let mut machine =
+            Machine::with_memory(program, load_address, entry_point, platform.memory_size())?;
+        let (width, height) = platform.screen_size();
+        machine.state = BitDisplay::new(width, height);
+        if platform == Platform::Chip8x {
+            machine.cpu.chip8x = Some(Chip8x::default());
+        }
+        if platform == Platform::MegaChip {
+            machine.cpu.megachip = Some(MegaChip::default());
+        }
+        return Ok(machine);
+    }
+    /// Restart the program without clearing the memory.
+    pub fn soft_reset(&mut self) {
+        self.cpu = Cpu {
+            store_load_quirk: self.cpu.store_load_quirk,
+            shift_y: self.cpu.shift_y,
+            key_wait: self.cpu.key_wait,
+            cache: self.cpu.cache.as_ref().map(|_| DecodeCache::default()),
+            recompiler: self.cpu.recompiler.as_ref().map(|_| Recompiler::default()),
+            chip8x: self.cpu.chip8x.as_ref().map(|_| Chip8x::default()),
+            megachip: self.cpu.megachip.as_ref().map(|_| MegaChip::default()),
+            program_counter: self.entry_point,
+            ..Default::default()
+        };
+        self.mem
+            .load(&self.program)
+            .expect("The rom fit in memory when it was first loaded");
+        Cpu::write_fonts_to_mem(&mut self.mem);
+        self.state = self.state.cleared();
+        self.halted = false;
+        self.pending_beep = None;
+    }
+    /// Restart with a fresh machine, only the rom, the addresses and the quirks are kept.
+    pub fn hard_reset(&mut self) {
+        let cpu = Cpu {
+            store_load_quirk: self.cpu.store_load_quirk,
+            shift_y: self.cpu.shift_y,
+            key_wait: self.cpu.key_wait,
+            cache: self.cpu.cache.as_ref().map(|_| DecodeCache::default()),
+            recompiler: self.cpu.recompiler.as_ref().map(|_| Recompiler::default()),
+            chip8x: self.cpu.chip8x.as_ref().map(|_| Chip8x::default()),
+            megachip: self.cpu.megachip.as_ref().map(|_| MegaChip::default()),
+            program_counter: self.entry_point,
+            ..Default::default()
+        };
+        let breakpoints = self.breakpoints.clone();
+        let profiler = self.profiler.take();
+        let cheats = self.cheats.clone();
+        let state = self.state.cleared();
+        let (start, size) = (self.mem.start, self.mem.space.len());
+        *self = Machine::with_memory(&self.program, start, self.entry_point, size)
+            .expect("The rom fit in memory when it was first loaded");
+        self.cpu = cpu;
+        self.state = state;
+        self.breakpoints = breakpoints;
+        self.profiler = profiler;
+        self.cheats = cheats;
+    }
+    /// Swap the rom for another one and do a hard reset.
+    pub fn load_program(&mut self, program: &[u8]) -> Result<(), &'static str> {
+        let size = self.mem.space.len();
+        Machine::with_memory(program, self.mem.start, self.entry_point, size)?;
+        self.program = program.to_vec();
+        self.hard_reset();
+        return Ok(());
+    }
+    /// Run one instruction, the machine halts on the first failure.
+    pub fn step(&mut self) -> Result<&'static str, &'static str> {
+        if self.halted {
+            return Err("Machine is halted");
+        }
+        if self.breakpoints.check(self.cpu.program_counter) {
+            self.pending_break = Some(self.cpu.program_counter);
+            return Err("Breakpoint");
+        }
+        let address = self.cpu.program_counter;
+        let result = self
+            .cpu
+            .run_cycle(&mut self.mem, &mut self.state, &mut self.keypad);
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(
+                address,
+                &result,
+                self.cpu.stack.len(),
+                self.cpu.program_counter,
+            );
+        }
+        if result.is_ok() {
+            self.breakpoints.ran();
+        }
+        self.finish_cycles(1, &result);
+        return result;
+    }
+    /// Run instructions until `cycles` were run since the machine was made.
+    ///
+    /// Stops early on the first failure or breakpoint, and fails with it.
+    pub fn run_until(&mut self, cycles: u64) -> Result<(), &'static str> {
+        if self.profiler.is_some() || !self.breakpoints.addresses.is_empty() {
+            while self.cycles < cycles {
+                self.step()?;
+            }
+            return Ok(());
+        }
+        if self.halted && self.cycles < cycles {
+            return Err("Machine is halted");
+        }
+        while self.cycles < cycles {
+            let (ran, result) = self.cpu.run_block(
+                &mut self.mem,
+                &mut self.state,
+                &mut self.keypad,
+                cycles - self.cycles,
+            );
+            self.finish_cycles(ran, &result);
+            result?;
+        }
+        return Ok(());
+    }
+    /// Count the cycles that were run and keep what the frontend has to know about the last one.
+    fn finish_cycles(&mut self, ran: u64, result: &Result<&'static str, &'static str>) {
+        self.cycles += ran;
+        if self.cpu.sound_loaded {
+            self.cpu.sound_loaded = false;
+            self.pending_beep = Some(self.cpu.st);
+        }
+        if result.is_err() {
+            self.halted = true;
+        }
+    }
+    /// Press or release a key of the hex keypad.
+    pub fn set_key(&mut self, key: usize, pressed: bool) {
+        self.keypad.set_key(key as u8, pressed, self.cycles);
+    }
+    /// Press or release every key of the hex keypad at once.
+    pub fn set_keys(&mut self, keys: [bool; 16]) {
+        for (key, pressed) in keys.iter().enumerate() {
+            self.set_key(key, *pressed);
+        }
+    }
+    /// Get the last value loaded into the sound timer since the previous call, if any.
+    ///
+    /// Beepers use it to ring for exactly as long as the timer lasts.
+    pub fn take_beep(&mut self) -> Option<u8> {
+        return self.pending_beep.take();
+    }
+    /// Get the sound a MegaChip program started or stopped since the previous call, if any.
+    pub fn take_sample(&mut self) -> Option<Option<Sample>> {
+        return self
+            .cpu
+            .megachip
+            .as_mut()
+            .and_then(|megachip| megachip.take_sample());
+    }
+    /// Get the address of the breakpoint the machine stopped at since the previous call, if any.
+    pub fn take_breakpoint(&mut self) -> Option<u16> {
+        return self.pending_break.take();
+    }
+    /// Get the address of the breakpoint the machine stopped at, without taking it.
+    pub fn peek_breakpoint(&self) -> Option<u16> {
+        return self.pending_break;
+    }
+    /// The display row by row, a byte per pixel, 1 when lit.
+    pub fn framebuffer(&self) -> Vec<u8> {
+        let (width, height) = (self.state.width(), self.state.height());
+        let mut pixels = Vec::with_capacity(width * height);
+        for row in 0..height {
+            for col in 0..width {
+                pixels.push(self.state.pixel(col, row) as u8);
+            }
+        }
+        return pixels;
+    }
+    /// Count down both timers once and apply the cheats.
+    ///
+    /// Returns if the buzzer should be ringing during this tick.
+    pub fn tick_timers(&mut self) -> bool {
+        self.cheats.apply(&mut self.mem);
+        self.keypad.forget_before(self.last_tick);
+        self.last_tick = self.cycles;
+        if self.cpu.dt > 0 {
+            self.cpu.dt = self.cpu.dt - 1
+        };
+        if self.cpu.st > 0 {
+            self.cpu.st = self.cpu.st - 1;
+            return true;
+        }
+        return false;
+    }
+    /// Run the cycles of a frame and then tick the timers.
+    ///
+    /// Returns if the buzzer should be ringing during this frame.
+    pub fn run_frame(&mut self, cycles: u32) -> bool {
+        // Failures and breakpoints are kept for the frontend to take, the timers tick anyway
+        let _ = self.run_until(self.cycles + cycles as u64);
+        return self.tick_timers();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::chip8x::{Chip8x, COLOURS};
+    use super::super::megachip::MegaChip;
+    use super::super::platform::Platform;
+    use super::Machine;
+    use super::{BitDisplay, Display};
+    #[test]
+    fn new() {
+        let machine = Machine::new(&[0x12, 0x00]).expect("Couldn't create the machine");
+        assert_eq!(machine.mem.space[0x200], 0x12, "Program should be loaded");
+        assert_eq!(
+            machine.cpu.program_counter, 0x200,
+            "Execution should start at 0x200"
+        );
+    }
+    #[test]
+    fn run_frame() {
+        // 6005 F018 1204: load 5 into st and loop forever
+        let mut machine = Machine::new(&[0x60, 0x05, 0xF0, 0x18, 0x12, 0x04])
+            .expect("Couldn't create the machine");
+        assert!(machine.run_frame(10), "Buzzer should ring");
+        assert_eq!(machine.cpu.st, 4, "Sound timer should count down");
+        assert_eq!(machine.take_beep(), Some(5), "Fx18 should be reported");
+        assert_eq!(machine.take_beep(), None, "Fx18 should be reported once");
+        assert!(!machine.halted, "Machine shouldn't halt");
+    }
+    #[test]
+    fn soft_reset() {
+        // 6005 A300 F055 0000: store V0 at 0x300, then halt
+        let mut machine = Machine::new(&[0x60, 0x05, 0xA3, 0x00, 0xF0, 0x55, 0x00, 0x00])
+            .expect("Couldn't create the machine");
+        machine.cpu.shift_y = true;
+        machine.run_frame(10);
+        assert!(machine.halted, "Program should halt");
+        machine.mem.space[0x200] = 0xFF;
+        machine.soft_reset();
+        assert!(!machine.halted, "Reset should resume the machine");
+        assert_eq!(machine.cpu.program_counter, 0x200, "Cpu should restart");
+        assert_eq!(machine.cpu.v[0], 0, "Registers should be cleared");
+        assert!(machine.cpu.shift_y, "Quirks should be kept");
+        assert_eq!(machine.mem.space[0x200], 0x60, "Rom should be loaded again");
+        assert_eq!(machine.mem.space[0x300], 0x05, "Memory should be kept");
+    }
+    #[test]
+    fn hard_reset() {
+        let mut machine = Machine::new(&[0x60, 0x05, 0xA3, 0x00, 0xF0, 0x55, 0x00, 0x00])
+            .expect("Couldn't create the machine");
+        machine.cpu.store_load_quirk = true;
+        machine.run_frame(10);
+        machine.state.set_pixel(0, 0, true);
+        machine.hard_reset();
+        assert!(!machine.halted, "Reset should resume the machine");
+        assert_eq!(machine.mem.space[0x300], 0x00, "Memory should be cleared");
+        assert_eq!(machine.mem.space[0x200], 0x60, "Rom should be loaded again");
+        assert!(!machine.state.pixel(0, 0), "Display should be cleared");
+        assert!(machine.cpu.store_load_quirk, "Quirks should be kept");
+    }
+    #[test]
+    fn breakpoint() {
+        // 6005 6106 1204: the breakpoint is on 6106
+        let mut machine = Machine::new(&[0x60, 0x05, 0x61, 0x06, 0x12, 0x04])
+            .expect("Couldn't create the machine");
+        machine.breakpoints.insert(0x202);
+        machine.run_frame(10);
+        assert_eq!(
+            machine.take_breakpoint(),
+            Some(0x202),
+            "Should stop at 0x202"
+        );
+        assert_eq!(
+            machine.cpu.v[1], 0,
+            "Instruction shouldn't run before stopping"
+        );
+        assert!(!machine.halted, "Breakpoints shouldn't halt");
+        machine.run_frame(10);
+        assert_eq!(machine.cpu.v[1], 6, "Instruction should run after resuming");
+        assert_eq!(machine.take_breakpoint(), None);
+    }
+    #[test]
+    fn breakpoint_loop() {
+        // 1200: the breakpoint is on a jump to itself
+        let mut machine = Machine::new(&[0x12, 0x00]).expect("Couldn't create the machine");
+        machine.breakpoints.insert(0x200);
+        for _ in 0..3 {
+            assert_eq!(machine.step(), Err("Breakpoint"));
+            assert_eq!(machine.take_breakpoint(), Some(0x200));
+            assert_eq!(machine.step(), Ok("1nnn"), "Resuming should run the jump");
+        }
+        assert_eq!(machine.cycles, 3);
+    }
+    #[test]
+    fn load_program() {
+        let mut machine =
+            Machine::new(&[0x60, 0x05, 0x00, 0x00]).expect("Couldn't create the machine");
+        machine.breakpoints.insert(0x202);
+        machine.run_frame(10);
+        machine
+            .load_program(&[0x61, 0x06])
+            .expect("Program should load");
+        assert_eq!(machine.mem.space[0x200], 0x61, "New rom should be loaded");
+        assert_eq!(machine.mem.space[0x202], 0x00, "Old rom should be gone");
+        assert_eq!(machine.cpu.v[0], 0, "Cpu should be reset");
+        assert!(
+            machine.breakpoints.addresses.contains(&0x202),
+            "Breakpoints should be kept"
+        );
+        machine.soft_reset();
+        assert_eq!(
+            machine.mem.space[0x200], 0x61,
+            "Resets should use the new rom"
+        );
+    }
+    #[test]
+    fn platforms() {
+        let program = [0x12, 0x00];
+        let machine = Machine::with_platform(&program, Platform::Hires, 0x200, 0x2C0)
+            .expect("Couldn't create the machine");
+        assert_eq!(machine.state.height(), 64, "Hi-res display is 64x64");
+        assert_eq!(machine.cpu.program_counter, 0x2C0);
+        let machine = Machine::with_platform(&program, Platform::Chip8x, 0x300, 0x300).unwrap();
+        assert!(machine.cpu.chip8x.is_some(), "Colour board missing");
+        assert_eq!(machine.mem.space[0x300], 0x12);
+        let machine = Machine::with_platform(&program, Platform::MegaChip, 0x200, 0x200).unwrap();
+        assert!(machine.cpu.megachip.is_some());
+        assert_eq!(machine.mem.space.len(), Platform::MegaChip.memory_size());
+    }
+    #[test]
+    fn addresses() {
+        // 6005 1602: the jump only lands on itself when loaded at 0x600
+        let mut machine = Machine::with_addresses(&[0x60, 0x05, 0x16, 0x02], 0x600, 0x600)
+            .expect("Couldn't create the machine");
+        assert_eq!(machine.mem.space[0x600], 0x60, "Rom should be at 0x600");
+        assert_eq!(machine.cpu.program_counter, 0x600);
+        machine.run_frame(10);
+        assert!(!machine.halted, "Program should loop at 0x602");
+        assert_eq!(machine.cpu.program_counter, 0x602);
+        machine.soft_reset();
+        assert_eq!(
+            machine.cpu.program_counter, 0x600,
+            "Soft reset lost the entry point"
+        );
+        machine.hard_reset();
+        assert_eq!(
+            machine.cpu.program_counter, 0x600,
+            "Hard reset lost the entry point"
+        );
+        machine.load_program(&[0x12, 0x00]).unwrap();
+        assert_eq!(machine.mem.space[0x600], 0x12, "New rom should be at 0x600");
+        let big = vec![0; 8192 - 0x600 + 1];
+        assert!(Machine::with_addresses(&big, 0x600, 0x600).is_err());
+    }
+    #[test]
+    fn hires() {
+        // 1260 at 0x200, then 6000 613C A2CC D015 0230 12CA and a 5 row sprite at 0x2CC:
+        // the sprite drawn on the last rows wraps to the top, then the screen is cleared
+        let mut program = vec![0xFF; 0xD1];
+        program[..2].copy_from_slice(&[0x12, 0x60]);
+        program[0xC0..0xCC].copy_from_slice(&[
+            0x60, 0x00, 0x61, 0x3C, 0xA2, 0xCC, 0xD0, 0x15, 0x02, 0x30, 0x12, 0xCA,
+        ]);
+        let mut machine =
+            Machine::with_addresses(&program, 0x200, 0x2C0).expect("Couldn't create the machine");
+        machine.state = BitDisplay::new(64, 64);
+        for _ in 0..4 {
+            machine.step().expect("Program shouldn't fail");
+        }
+        assert!(
+            machine.state.pixel(0, 60),
+            "Sprite should be on the bottom page"
+        );
+        assert!(machine.state.pixel(0, 0), "Sprite should wrap at 64 rows");
+        machine.step().expect("0230 should clear the screen");
+        assert!(
+            !machine.state.pixel(0, 60) && !machine.state.pixel(0, 0),
+            "Screen wasn't cleared"
+        );
+        machine.hard_reset();
+        assert_eq!(
+            machine.state.height(),
+            64,
+            "Reset should keep the screen size"
+        );
+        assert_eq!(machine.cpu.program_counter, 0x2C0);
+    }
+    #[test]
+    fn chip8x() {
+        // 6011 6100 6204 B020 02A0 130A: zones 1 and 2 of the top row go green, the background goes black
+        let program = [
+            0x60, 0x11, 0x61, 0x00, 0x62, 0x04, 0xB0, 0x20, 0x02, 0xA0, 0x13, 0x0A,
+        ];
+        let mut machine =
+            Machine::with_addresses(&program, 0x300, 0x300).expect("Couldn't create the machine");
+        machine.cpu.chip8x = Some(Chip8x::default());
+        machine.run_frame(10);
+        assert!(!machine.halted, "CHIP-8X instructions should run");
+        let chip8x = machine.cpu.chip8x.as_ref().unwrap();
+        assert_eq!(chip8x.palette_at(8, 0).foreground, COLOURS[4]);
+        assert_eq!(chip8x.background(), COLOURS[0]);
+        machine.soft_reset();
+        assert_eq!(
+            machine.cpu.chip8x,
+            Some(Chip8x::default()),
+            "Reset should keep CHIP-8X"
+        );
+    }
+    #[test]
+    fn megachip() {
+        // 0011 6000 A300 0601 0303 0401 120C: mega mode on, play the sound at 0x300 once, sprites 3x1
+        let mut program = vec![
+            0x00, 0x11, 0x60, 0x00, 0xA3, 0x00, 0x06, 0x01, 0x03, 0x03, 0x04, 0x01, 0x12, 0x0C,
+        ];
+        program.resize(0x100, 0);
+        program.extend_from_slice(&[0x1F, 0x40, 0x00, 0x00, 0x01, 0x00, 0x80]);
+        let mut machine = Machine::with_memory(&program, 0x200, 0x200, 0x1000000)
+            .expect("Couldn't create the machine");
+        machine.cpu.megachip = Some(MegaChip::default());
+        machine.run_frame(10);
+        assert!(!machine.halted, "MegaChip instructions should run");
+        let sample = machine.take_sample().expect("Sound should have started");
+        assert_eq!(sample.map(|sample| sample.data), Some(vec![0x80]));
+        assert_eq!(machine.cpu.megachip.as_ref().unwrap().sprite_width, 3);
+        machine.hard_reset();
+        assert_eq!(
+            machine.mem.space.len(),
+            0x1000000,
+            "Reset should keep the memory size"
+        );
+        assert_eq!(
+            machine.cpu.megachip,
+            Some(MegaChip::default()),
+            "Reset should keep MegaChip"
+        );
+    }
+    #[test]
+    fn halt() {
+        let mut machine = Machine::new(&[0x00, 0x00]).expect("Couldn't create the machine");
+        machine.run_frame(10);
+        assert!(machine.halted, "0000 should halt the machine");
+        assert!(machine.step().is_err(), "Halted machine shouldn't run");
+    }
+    #[test]
+    fn keypad() {
+        // F30A 1202: wait for a key and loop forever
+        let mut machine =
+            Machine::new(&[0xF3, 0x0A, 0x12, 0x02]).expect("Couldn't create the machine");
+        machine.run_frame(10);
+        machine.set_key(5, true);
+        machine.set_key(5, false);
+        machine.run_frame(10);
+        assert_eq!(machine.cpu.v[3], 5, "Tap between frames was lost");
+        assert_eq!(machine.cpu.program_counter, 0x202);
+        machine.set_keys([true; 16]);
+        assert_eq!(machine.keypad.events().count(), 16);
+        machine.run_frame(10);
+        machine.run_frame(10);
+        assert_eq!(
+            machine.keypad.events().count(),
+            0,
+            "Old events should be forgotten"
+        );
+        assert!(machine.keypad.keys()[0xF], "Keys should stay held");
+    }
+    #[test]
+    fn run_until() {
+        // 6001 7001 1202: count up in V0 forever
+        let mut machine = Machine::new(&[0x60, 0x01, 0x70, 0x01, 0x12, 0x02])
+            .expect("Couldn't create the machine");
+        machine.run_until(21).expect("Program shouldn't fail");
+        assert_eq!((machine.cycles, machine.cpu.v[0]), (21, 11));
+        machine.run_until(10).expect("Nothing left to run");
+        assert_eq!(machine.cycles, 21);
+        machine.breakpoints.insert(0x204);
+        assert_eq!(machine.run_until(100), Err("Breakpoint"));
+        assert_eq!(machine.cpu.program_counter, 0x204);
+        machine.breakpoints.remove(0x204);
+        machine.mem.space[0x204] = 0x00;
+        machine.mem.space[0x205] = 0x00;
+        assert!(machine.run_until(100).is_err(), "0000 should fail");
+        assert!(machine.halted);
+        assert_eq!(machine.run_until(200), Err("Machine is halted"));
+    }
+}
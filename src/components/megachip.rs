@@ -0,0 +1,280 @@
+//! # MegaChip Module
+//! ## Description
+//! MegaChip-8 adds a 256x192 display with 256 colours and digitized sound on top of CHIP-8.
+//! Programs turn it on with 0011 and back off with 0010, the usual display is used while it's off.
+//! * 01NN NNNN: I = NNNNNN, a 24 bit address, the instruction takes 4 bytes
+//! * 02NN: load NN colours from I into the palette, starting at colour 1
+//! * 03NN and 04NN: width and height of the sprites, 0 is 256
+//! * 05NN: alpha of the sprites, 0 is transparent and FF opaque
+//! * 060N: play the digitized sound at I, looping when N is 0
+//! * 0700: stop the sound
+//! * 080N: blend mode, normal, 25%, 50%, 75%, add or multiply
+//! * 09NN: colour that sets VF when a sprite draws over it, sprites never collide until it's set
+//! ## Drawing
+//! While it's on, Dxyn draws a sprite of the set size with a byte per pixel, each a colour of the palette.
+//! Colour 0 is transparent, pixels out of the display are clipped instead of wrapping around.
+//!
+//! Sprites are drawn on a frame that isn't shown until 00E0, which shows it and starts a new one.
+//! ## Colours
+//! Every colour of the palette takes 4 bytes, alpha, red, green and blue, and is mixed with the frame as the blend mode says.
+//! ## Sounds
+//! A sound starts with its sample rate in 2 bytes, its length in 3 and a byte that isn't used, then a byte per sample.
+//! ## Addresses
+//! Annn clears the top 8 bits of I and Fx1E, Fx55 and Fx65 carry into them, I wraps around at 24 bits.
+//! Dxyn, 02NN, 060N, Fx33, Fx55 and Fx65 read and write the whole 24 bit address.
+use super::screen::Screen;
+use super::sound::Sample;
+
+/// Width of the MegaChip display.
+pub const WIDTH: usize = 256;
+/// Height of the MegaChip display.
+pub const HEIGHT: usize = 192;
+
+/// How sprite colours are mixed with the frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Blend {
+    /// Mixed by the alpha of the colour and the sprite
+    Normal,
+    Percent25,
+    Percent50,
+    Percent75,
+    Add,
+    Multiply,
+}
+
+impl Blend {
+    /// Blend mode set by 080N.
+    pub fn from_nibble(n: u8) -> Result<Blend, &'static str> {
+        return match n {
+            0 => Ok(Blend::Normal),
+            1 => Ok(Blend::Percent25),
+            2 => Ok(Blend::Percent50),
+            3 => Ok(Blend::Percent75),
+            4 => Ok(Blend::Add),
+            5 => Ok(Blend::Multiply),
+            _ => Err("Unknown blend mode"),
+        };
+    }
+}
+
+/// The MegaChip display, palette and sound.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MegaChip {
+    /// Set by 0011 and cleared by 0010
+    pub enabled: bool,
+    /// Top 8 bits of I
+    pub bank: u8,
+    /// Colours as alpha, red, green and blue
+    pub palette: [[u8; 4]; 256],
+    pub sprite_width: u8,
+    pub sprite_height: u8,
+    pub alpha: u8,
+    pub blend: Blend,
+    /// Colour that sets VF when drawn over, None until 09NN
+    pub collision: Option<u8>,
+    /// Colour number of every pixel of the frame being drawn
+    pub indexes: Screen<u8>,
+    /// Frame being drawn
+    pub frame: Screen<[u8; 3]>,
+    /// Last frame finished by 00E0
+    pub shown: Screen<[u8; 3]>,
+    /// Sound started or stopped since the frontend last looked, None stops it
+    pub sample: Option<Option<Sample>>,
+}
+
+impl Default for MegaChip {
+    fn default() -> MegaChip {
+        let mut palette = [[0xFF, 0xFF, 0xFF, 0xFF]; 256];
+        palette[0] = [0x00, 0x00, 0x00, 0x00];
+        MegaChip {
+            enabled: false,
+            bank: 0,
+            palette: palette,
+            sprite_width: 0,
+            sprite_height: 0,
+            alpha: 0xFF,
+            blend: Blend::Normal,
+            collision: None,
+            indexes: Screen::new(WIDTH, HEIGHT),
+            frame: Screen::new(WIDTH, HEIGHT),
+            shown: Screen::new(WIDTH, HEIGHT),
+            sample: None,
+        }
+    }
+}
+
+impl MegaChip {
+    /// Full address pointed at by I.
+    pub fn address(&self, i: u16) -> usize {
+        return (self.bank as usize) << 16 | i as usize;
+    }
+    /// 02NN - Load `count` colours from `address`, starting at colour 1.
+    pub fn load_palette(
+        &mut self,
+        mem: &[u8],
+        address: usize,
+        count: u8,
+    ) -> Result<&'static str, &'static str> {
+        let bytes = read(mem, address, count as usize * 4)?;
+        for (idx, colour) in bytes.chunks(4).enumerate() {
+            self.palette[idx + 1].copy_from_slice(colour);
+        }
+        return Ok("02nn");
+    }
+    /// Dxyn - Draw the sprite at `address` on the frame, returns if it drew over the collision colour.
+    pub fn draw(&mut self, mem: &[u8], address: usize, x: u8, y: u8) -> Result<bool, &'static str> {
+        let width = size(self.sprite_width);
+        let height = size(self.sprite_height);
+        let sprite = read(mem, address, width * height)?;
+        let mut collided = false;
+        for row in 0..height {
+            for col in 0..width {
+                let index = sprite[row * width + col];
+                let (px, py) = (x as usize + col, y as usize + row);
+                if index == 0 || px >= WIDTH || py >= HEIGHT {
+                    continue;
+                }
+                if self.collision == Some(self.indexes[px][py]) {
+                    collided = true;
+                }
+                self.indexes[px][py] = index;
+                self.frame[px][py] = self.mix(self.frame[px][py], self.palette[index as usize]);
+            }
+        }
+        return Ok(collided);
+    }
+    /// 00E0 - Show the frame and start a new one.
+    pub fn clear(&mut self) {
+        self.shown = self.frame.clone();
+        self.frame.clear();
+        self.indexes.clear();
+    }
+    /// 060N - Play the sound at `address`.
+    pub fn play_sample(
+        &mut self,
+        mem: &[u8],
+        address: usize,
+        looping: bool,
+    ) -> Result<&'static str, &'static str> {
+        let header = read(mem, address, 6)?;
+        let rate = (header[0] as u16) << 8 | header[1] as u16;
+        let len = (header[2] as usize) << 16 | (header[3] as usize) << 8 | header[4] as usize;
+        self.sample = Some(Some(Sample {
+            rate: rate,
+            data: read(mem, address + 6, len)?.to_vec(),
+            looping: looping,
+        }));
+        return Ok("060n");
+    }
+    /// 0700 - Stop the sound.
+    pub fn stop_sample(&mut self) {
+        self.sample = Some(None);
+    }
+    /// Get the sound started or stopped since the previous call, if any.
+    pub fn take_sample(&mut self) -> Option<Option<Sample>> {
+        return self.sample.take();
+    }
+    /// Mix a colour of the palette over a pixel of the frame.
+    fn mix(&self, under: [u8; 3], colour: [u8; 4]) -> [u8; 3] {
+        let opacity = match self.blend {
+            Blend::Percent25 => 0x40,
+            Blend::Percent50 => 0x80,
+            Blend::Percent75 => 0xC0,
+            _ => colour[0] as u32 * self.alpha as u32 / 0xFF,
+        };
+        let mut mixed = [0; 3];
+        for channel in 0..3 {
+            let (under, over) = (under[channel] as u32, colour[channel + 1] as u32);
+            mixed[channel] = match self.blend {
+                Blend::Add => (under + over * opacity / 0xFF).min(0xFF),
+                Blend::Multiply => under * over / 0xFF,
+                _ => (over * opacity + under * (0xFF - opacity)) / 0xFF,
+            } as u8;
+        }
+        return mixed;
+    }
+}
+
+/// Sprite sizes of 0 are 256 pixels.
+fn size(value: u8) -> usize {
+    return match value {
+        0 => 256,
+        _ => value as usize,
+    };
+}
+
+fn read(mem: &[u8], address: usize, len: usize) -> Result<&[u8], &'static str> {
+    return mem
+        .get(address..address + len)
+        .ok_or("MegaChip data is out of memory");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Blend, MegaChip};
+    #[test]
+    fn palette() {
+        let mut megachip = MegaChip::default();
+        let mem = [0xFF, 0x10, 0x20, 0x30, 0x80, 0xFF, 0x00, 0x00];
+        megachip
+            .load_palette(&mem, 0, 2)
+            .expect("Palette should load");
+        assert_eq!(megachip.palette[1], [0xFF, 0x10, 0x20, 0x30]);
+        assert_eq!(megachip.palette[2], [0x80, 0xFF, 0x00, 0x00]);
+        assert_eq!(megachip.palette[0], [0; 4], "Colour 0 is transparent");
+        assert!(
+            megachip.load_palette(&mem, 4, 2).is_err(),
+            "Palette out of memory"
+        );
+    }
+    #[test]
+    fn draw() {
+        let mut megachip = MegaChip::default();
+        megachip.palette[1] = [0xFF, 0xFF, 0x00, 0x00];
+        megachip.palette[2] = [0x80, 0x00, 0x00, 0xFF];
+        megachip.sprite_width = 2;
+        megachip.sprite_height = 2;
+        let mem = [1, 0, 2, 1];
+        assert_eq!(
+            megachip.draw(&mem, 0, 0, 0),
+            Ok(false),
+            "Collided with no colour set"
+        );
+        megachip.collision = Some(1);
+        assert_eq!(megachip.draw(&mem, 0, 255, 191), Ok(false));
+        assert_eq!(megachip.frame[0][0], [0xFF, 0x00, 0x00]);
+        assert_eq!(megachip.frame[1][0], [0, 0, 0], "Colour 0 drew");
+        assert_eq!(megachip.frame[0][1], [0x00, 0x00, 0x80], "Alpha not used");
+        assert_eq!(megachip.frame[255][191], [0xFF, 0x00, 0x00]);
+        assert_eq!(megachip.indexes[255][191], 1);
+        assert_eq!(megachip.indexes[0][0], 1, "Clipped pixels wrapped");
+        assert_eq!(megachip.draw(&mem, 0, 0, 0), Ok(true), "No collision");
+        megachip.blend = Blend::Multiply;
+        megachip.draw(&mem, 0, 1, 0).unwrap();
+        assert_eq!(
+            megachip.frame[1][1],
+            [0, 0, 0],
+            "Red times blue should be black"
+        );
+        megachip.clear();
+        assert_eq!(megachip.shown[0][0], [0xFF, 0x00, 0x00], "Frame not shown");
+        assert_eq!(megachip.frame[0][0], [0, 0, 0], "Frame not cleared");
+        assert_eq!(megachip.indexes[0][0], 0);
+    }
+    #[test]
+    fn sample() {
+        let mut megachip = MegaChip::default();
+        let mem = [0x1F, 0x40, 0x00, 0x00, 0x02, 0x00, 0x80, 0xFF];
+        megachip
+            .play_sample(&mem, 0, true)
+            .expect("Sample should load");
+        let sample = megachip.take_sample().unwrap().unwrap();
+        assert_eq!(sample.rate, 8000);
+        assert_eq!(sample.data, vec![0x80, 0xFF]);
+        assert!(sample.looping);
+        assert_eq!(megachip.take_sample(), None, "Sample taken twice");
+        megachip.stop_sample();
+        assert_eq!(megachip.take_sample(), Some(None));
+        assert!(megachip.play_sample(&mem[..7], 0, false).is_err());
+    }
+}
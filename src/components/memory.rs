@@ -8,35 +8,44 @@
 //! * Reading a specific address
 //! * Writing to a specific address
 //! Inside the struct, the memory is represented as an array of u8, and the functions join or split the inputs and outputs when necessary.
+//! ## Start address
+//! Programs are loaded at 0x200 unless the platform says otherwise, like 0x600 on the ETI-660.
+//! Everything below the start belongs to the interpreter.
+//! ## Size
+//! The memory is 8k like on the COSMAC VIP, MegaChip machines get 16M so its 24 bit I can reach all of it.
 
 /// Represents the memory of the virtual computer.
 ///
 /// Remember to load the fonts so they can be used by the programs.
 pub struct Memory {
-    pub space: [u8; Memory::BYTE_MAX],
+    pub space: Vec<u8>,
+    /// Address programs are loaded at
+    pub start: u16,
 }
 
 impl Default for Memory {
     fn default() -> Memory {
         Memory {
-            space: [0; Memory::BYTE_MAX],
+            space: vec![0; Memory::BYTE_MAX],
+            start: Memory::START,
         }
     }
 }
 
 impl Memory {
     /// CHIP-8 programs are loaded at address 0x200, 0x000 to 0x1FF is reserved for the interpreter.
-    const START: u16 = 0x200;
+    pub const START: u16 = 0x200;
     /// It is technically impossible to access more than 0xFFF due to how the I register is loaded.
     const MAX: u16 = 0xFFF;
     /// The biggest memory size used with the CHIP-8 is 8k on the COSMAC VIP.
-    const BYTE_MAX: usize = 8192;
-    /// Maximun size a program can be.
+    pub const BYTE_MAX: usize = 8192;
+    /// Maximun size a program loaded at 0x200 can be.
+    #[cfg(test)]
     const USABLE_SPACE: usize = (Memory::MAX as usize - Memory::START as usize + 1) * 2;
     /// Write to a memory address.
     pub fn write(&mut self, pos: u16, data: u16) -> Result<&'static str, &'static str> {
         let pos_u: usize = (pos * 2) as usize;
-        if pos >= Memory::START && pos <= Memory::MAX {
+        if pos >= self.start && pos <= Memory::MAX {
             let data_head: u8 = (data >> 8) as u8;
             let data_tail: u8 = (data & 0xFF) as u8;
             self.space[pos_u] = data_head;
@@ -63,7 +72,9 @@ impl Memory {
     /// Read the value from a memory address.
     pub fn read(&mut self, pos: u16) -> Result<u16, &'static str> {
         let pos_u: usize = (pos) as usize;
-        if pos <= Memory::MAX {
+        // Bigger memories than the VIP's are for programs that can reach past 0xFFF
+        let beyond = self.space.len() > Memory::BYTE_MAX && pos_u + 1 < self.space.len();
+        if pos <= Memory::MAX || beyond {
             let data_head: u16 = ((self.space[pos_u]) as u16) << 8;
             let data_tail: u16 = (self.space[pos_u + 1]) as u16;
             //println!("{:x} {:x}", self.space[pos_u], self.space[pos_u + 1]);
@@ -72,10 +83,21 @@ impl Memory {
             return Err("Out of bounds exception");
         }
     }
-    /// Load a program to memory, it starts at 0x200.
+    /// Maximun size a program can be, from the start address to the end of memory.
+    pub fn usable_space(&self) -> usize {
+        return self.space.len().saturating_sub(self.start as usize);
+    }
+    /// Create a memory of another size, all of it cleared.
+    pub fn with_size(size: usize) -> Memory {
+        return Memory {
+            space: vec![0; size],
+            ..Default::default()
+        };
+    }
+    /// Load a program to memory, at the start address.
     pub fn load(&mut self, program: &[u8]) -> Result<&'static str, &'static str> {
-        let pos: usize = (Memory::START) as usize;
-        if program.len() <= Memory::USABLE_SPACE {
+        let pos: usize = (self.start) as usize;
+        if program.len() <= self.usable_space() {
             let mut idx: usize = 0;
             while idx < program.len() {
                 self.space[pos + idx] = program[idx].clone();
@@ -87,10 +109,10 @@ impl Memory {
         }
     }
     pub fn print_memory(&mut self) {
-        for idx in 0x200..=Memory::MAX {
+        for idx in self.start..=Memory::MAX {
             println!(
                 "{:4x}: {:4x}",
-                idx - 0x200,
+                idx - self.start,
                 self.read(idx).expect("Couldn't print a valid memory addr")
             )
         }
@@ -120,6 +142,25 @@ mod tests {
             assert_eq!(*mem.space.last().unwrap(), 1 as u8);
             assert!(!result.is_err(), "Couldn't load the memory")
         }
+        #[test]
+        fn other_start() {
+            let mut mem = Memory {
+                start: 0x600,
+                ..Default::default()
+            };
+            let values = vec![1; Memory::BYTE_MAX - 0x600];
+            assert!(mem.load(&values).is_ok(), "Couldn't load at 0x600");
+            assert_eq!(mem.space[0x5FF], 0, "Loaded before the start");
+            assert_eq!(mem.space[0x600], 1);
+            assert!(mem.load(&[values, vec![1]].concat()).is_err(), "Too big");
+        }
+        #[test]
+        fn other_size() {
+            let mut mem = Memory::with_size(0x10000);
+            let values = vec![1; 0x8000];
+            assert!(mem.load(&values).is_ok(), "Couldn't load past 8k");
+            assert_eq!(mem.space[0x81FF], 1);
+        }
     }
 
     mod write {
@@ -0,0 +1,128 @@
+//! # Platform Module
+//! ## Description
+//! CHIP-8 ran on several computers, and each put programs somewhere else in memory.
+//! ## Platforms
+//! * `chip8`: the COSMAC VIP interpreter, programs are loaded and start at 0x200
+//! * `eti660`: the ETI-660, programs are loaded and start at 0x600
+//! * `hires`: the two-page hi-res interpreter for the VIP, programs are loaded at 0x200 and start at 0x2C0
+//! * `chip8x`: CHIP-8X for the VIP with its colour board, programs are loaded and start at 0x300
+//! * `megachip`: MegaChip-8, programs are loaded and start at 0x200 with 16M of memory
+//!
+//! The load address and the entry point can also be set on their own, for roms that need something else.
+//! ## Hi-res
+//! The hi-res interpreter shows 64x64 pixels and clears the screen with 0230.
+//! Its roms begin with 1260 and a patch for the VIP interpreter, which is skipped by starting at 0x2C0.
+//! Roms beginning with 1260 are detected as hi-res when no platform is given.
+use super::memory::Memory;
+
+/// A computer CHIP-8 programs were written for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Platform {
+    Chip8,
+    Eti660,
+    Hires,
+    Chip8x,
+    MegaChip,
+}
+
+impl Default for Platform {
+    fn default() -> Platform {
+        Platform::Chip8
+    }
+}
+
+impl Platform {
+    /// Parse `chip8`, `eti660`, `hires`, `chip8x` or `megachip`.
+    pub fn parse(text: &str) -> Result<Platform, &'static str> {
+        return match text {
+            "chip8" => Ok(Platform::Chip8),
+            "eti660" => Ok(Platform::Eti660),
+            "hires" => Ok(Platform::Hires),
+            "chip8x" => Ok(Platform::Chip8x),
+            "megachip" => Ok(Platform::MegaChip),
+            _ => Err("Platforms are chip8, eti660, hires, chip8x or megachip"),
+        };
+    }
+    /// Address programs are loaded at.
+    pub fn load_address(&self) -> u16 {
+        return match self {
+            Platform::Chip8 => 0x200,
+            Platform::Eti660 => 0x600,
+            Platform::Hires => 0x200,
+            Platform::Chip8x => 0x300,
+            Platform::MegaChip => 0x200,
+        };
+    }
+    /// Address the program counter starts at.
+    pub fn entry_point(&self) -> u16 {
+        return match self {
+            Platform::Hires => 0x2C0,
+            _ => self.load_address(),
+        };
+    }
+    /// Width and height of the display.
+    pub fn screen_size(&self) -> (usize, usize) {
+        return match self {
+            Platform::Hires => (64, 64),
+            _ => (64, 32),
+        };
+    }
+    /// Size of the memory in bytes.
+    pub fn memory_size(&self) -> usize {
+        return match self {
+            Platform::MegaChip => 0x1000000,
+            _ => Memory::BYTE_MAX,
+        };
+    }
+    /// Guess the platform from the first bytes of a rom, if they give it away.
+    pub fn detect(program: &[u8]) -> Option<Platform> {
+        return match program {
+            [0x12, 0x60, ..] => Some(Platform::Hires),
+            _ => None,
+        };
+    }
+}
+
+/// Parse an address written in hex, with or without `0x`.
+pub fn parse_address(text: &str) -> Result<u16, &'static str> {
+    let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+    return match u16::from_str_radix(digits, 16) {
+        Ok(address) if address <= 0xFFF => Ok(address),
+        _ => Err("Addresses must be hex, from 0x000 to 0xFFF"),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_address, Platform};
+    mod parse {
+        use super::{parse_address, Platform};
+        #[test]
+        fn correct_case() {
+            assert_eq!(Platform::parse("eti660"), Ok(Platform::Eti660));
+            assert_eq!(Platform::parse("chip8x").unwrap().entry_point(), 0x300);
+            assert_eq!(Platform::parse("hires").unwrap().entry_point(), 0x2C0);
+            assert_eq!(parse_address("0x600"), Ok(0x600));
+            assert_eq!(parse_address("2c0"), Ok(0x2C0));
+        }
+        #[test]
+        fn wrong_case() {
+            assert!(Platform::parse("vip").is_err());
+            assert!(parse_address("0x1000").is_err(), "Address out of memory");
+            assert!(parse_address("start").is_err());
+        }
+    }
+    #[test]
+    fn default() {
+        assert_eq!(Platform::default().load_address(), 0x200);
+        assert_eq!(Platform::default().screen_size(), (64, 32));
+        assert_eq!(Platform::default().memory_size(), 8192);
+        assert_eq!(Platform::MegaChip.memory_size(), 0x1000000);
+    }
+    #[test]
+    fn detect() {
+        assert_eq!(Platform::detect(&[0x12, 0x60, 0x01]), Some(Platform::Hires));
+        assert_eq!(Platform::detect(&[0x12, 0x00]), None);
+        assert_eq!(Platform::detect(&[]), None);
+    }
+}
@@ -0,0 +1,117 @@
+//! # Predecode Module
+//! ## Description
+//! Remembers the instruction decoded at every address, so loops don't go through the decoder on every cycle.
+//! It's optional, the processor only uses it when it has one.
+//! ## Invalidation
+//! Every entry keeps the op code it was decoded from. When the two bytes at its address don't match anymore,
+//! because the program, a cheat, a script or a debugger wrote over them, the entry is decoded again.
+//! That way no writer has to tell the cache, and self-modifying programs keep working,
+//! but the op code is still read on every cycle and only the decoding is saved.
+//! ## Decoders
+//! CHIP-8X and MegaChip programs are decoded differently, the cache starts over when the decoder changes.
+//! ## Size
+//! The program counter is 16 bits, so there is an entry for each of the first 64k addresses, made on first use.
+use super::decoder::Instruction;
+
+/// Turns an op code into an instruction.
+pub type Decoder = fn(u16) -> Result<Instruction, &'static str>;
+
+/// Instructions already decoded, by address.
+#[derive(Clone, Debug, Default)]
+pub struct DecodeCache {
+    /// Op code and instruction at every address that was run
+    entries: Vec<Option<(u16, Instruction)>>,
+    /// Decoder the entries were made with
+    kind: u8,
+    /// Addresses with an instruction decoded
+    decoded: usize,
+}
+
+impl DecodeCache {
+    /// Addresses the program counter can reach.
+    const SIZE: usize = 0x10000;
+    /// Get the instruction of an op code at an address, decoding it only if it changed since last time.
+    ///
+    /// `kind` tells the decoders apart, entries made with another one are thrown away.
+    pub fn fetch(
+        &mut self,
+        address: u16,
+        op_code: u16,
+        kind: u8,
+        decoder: Decoder,
+    ) -> Result<Instruction, &'static str> {
+        if self.entries.is_empty() || kind != self.kind {
+            self.entries = vec![None; DecodeCache::SIZE];
+            self.kind = kind;
+            self.decoded = 0;
+        }
+        let entry = &mut self.entries[address as usize];
+        if let Some((cached, instruction)) = *entry {
+            if cached == op_code {
+                return Ok(instruction);
+            }
+        }
+        let instruction = decoder(op_code)?;
+        if entry.is_none() {
+            self.decoded += 1;
+        }
+        *entry = Some((op_code, instruction));
+        return Ok(instruction);
+    }
+    /// Forget every instruction.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.decoded = 0;
+    }
+    /// Count the addresses with an instruction decoded.
+    pub fn len(&self) -> usize {
+        return self.decoded;
+    }
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::decoder::{decode, decode_chip8x, Instruction};
+    use super::DecodeCache;
+    #[test]
+    fn fetch() {
+        let mut cache = DecodeCache::default();
+        assert!(cache.is_empty());
+        assert_eq!(
+            cache.fetch(0x200, 0x6105, 0, decode),
+            Ok(Instruction::LoadNn(1, 5))
+        );
+        // A decoder that can't be called again shows the entry was used
+        let never: fn(u16) -> Result<Instruction, &'static str> = |_| Err("Decoded again");
+        assert_eq!(
+            cache.fetch(0x200, 0x6105, 0, never),
+            Ok(Instruction::LoadNn(1, 5))
+        );
+        assert_eq!(
+            cache.fetch(0x200, 0x6207, 0, decode),
+            Ok(Instruction::LoadNn(2, 7)),
+            "Overwritten op code should be decoded again"
+        );
+        assert_eq!(cache.len(), 1);
+        assert!(cache.fetch(0x202, 0x5121, 0, decode).is_err());
+        assert_eq!(cache.len(), 1, "Failures shouldn't be kept");
+    }
+    #[test]
+    fn decoders() {
+        let mut cache = DecodeCache::default();
+        assert_eq!(
+            cache.fetch(0x200, 0xB120, 0, decode),
+            Ok(Instruction::JumpV0(0x120))
+        );
+        assert_eq!(
+            cache.fetch(0x200, 0xB120, 1, decode_chip8x),
+            Ok(Instruction::ColourZones(1, 2)),
+            "Another decoder should start over"
+        );
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,232 @@
+//! # Profiler Module
+//! ## Description
+//! Counts what a running rom does, to find out which code runs and where the time goes.
+//! ## What is counted
+//! * Executions of every address
+//! * Executions of every kind of instruction, named like the op codes (`Dxyn`, `Fx18`...)
+//! * Cycles spent in every chain of subroutines, following the depth of the cpu stack
+//!
+//! Time is measured in cycles, so results don't depend on the speed of the computer.
+//! ## Output
+//! * An annotated disassembly of the rom, with the hits of every instruction, decoded for the platform the rom ran on
+//! * A folded stack file, one line per chain of calls and its cycles, ready for flamegraph.pl or inferno
+use super::analyzer;
+use super::decoder::Instruction;
+use super::predecode::Decoder;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Counters of a profiling session.
+#[derive(Default)]
+pub struct Profiler {
+    /// Executions of every address
+    pub hits: HashMap<u16, u64>,
+    /// Executions of every kind of instruction
+    pub classes: HashMap<&'static str, u64>,
+    /// Cycles spent in every chain of subroutines, from the outermost one
+    pub stacks: HashMap<Vec<u16>, u64>,
+    /// Entry address of every subroutine being run right now
+    frames: Vec<u16>,
+}
+
+impl Profiler {
+    /// Count an instruction run at address, with the result of run_cycle and the cpu after it.
+    pub fn record(
+        &mut self,
+        address: u16,
+        result: &Result<&'static str, &'static str>,
+        depth: usize,
+        program_counter: u16,
+    ) {
+        *self.hits.entry(address).or_insert(0) += 1;
+        let class = match result {
+            Ok(class) => class,
+            Err(_) => "error",
+        };
+        *self.classes.entry(class).or_insert(0) += 1;
+        match self.stacks.get_mut(&self.frames) {
+            Some(cycles) => *cycles += 1,
+            None => {
+                self.stacks.insert(self.frames.clone(), 1);
+            }
+        }
+        // After a call the program counter is on the first instruction of the subroutine
+        while self.frames.len() < depth {
+            self.frames.push(program_counter);
+        }
+        self.frames.truncate(depth);
+    }
+    /// Total cycles of every subroutine, including the ones it called.
+    pub fn subroutine_cycles(&self) -> Vec<(u16, u64)> {
+        let mut totals: HashMap<u16, u64> = HashMap::new();
+        for (stack, cycles) in self.stacks.iter() {
+            let mut seen: Vec<u16> = Vec::new();
+            for entry in stack.iter() {
+                // Recursion shouldn't count the same cycles twice
+                if !seen.contains(entry) {
+                    *totals.entry(*entry).or_insert(0) += cycles;
+                    seen.push(*entry);
+                }
+            }
+        }
+        let mut totals: Vec<(u16, u64)> = totals.into_iter().collect();
+        totals.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        return totals;
+    }
+    /// Folded stacks, `main;0x2a0;0x2c4 1234` for every chain of calls.
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|(stack, cycles)| {
+                let mut line = "main".to_owned();
+                for entry in stack.iter() {
+                    write!(line, ";{:#05x}", entry).unwrap();
+                }
+                format!("{} {}", line, cycles)
+            })
+            .collect();
+        lines.sort();
+        return lines.join("\n") + "\n";
+    }
+    /// Disassembly of the rom loaded at an address, with the hits of every instruction and a summary on top.
+    ///
+    /// The decoder is the one of the platform, the processor gives it.
+    pub fn listing(&self, program: &[u8], start: u16, decoder: Decoder) -> String {
+        let mut listing = String::new();
+        let total: u64 = self.hits.values().sum();
+        let code = analyzer::analyze(program, start, decoder);
+        let reachable: Vec<u16> = code
+            .blocks
+            .values()
+            .flat_map(|block| block.instructions.iter().map(|(addr, _)| *addr))
+            .collect();
+        let covered = reachable
+            .iter()
+            .filter(|addr| self.hits.contains_key(addr))
+            .count();
+        writeln!(listing, "; Cycles: {}", total).unwrap();
+        writeln!(
+            listing,
+            "; Coverage: {} of {} reachable instructions ran",
+            covered,
+            reachable.len()
+        )
+        .unwrap();
+        writeln!(listing, ";").unwrap();
+        writeln!(listing, "; Instructions by kind:").unwrap();
+        let mut classes: Vec<(&&str, &u64)> = self.classes.iter().collect();
+        classes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (class, hits) in classes {
+            writeln!(listing, ";   {:<6} {:>10}", class, hits).unwrap();
+        }
+        writeln!(listing, ";").unwrap();
+        writeln!(listing, "; Subroutines by cycles, including their calls:").unwrap();
+        for (entry, cycles) in self.subroutine_cycles() {
+            writeln!(listing, ";   {:#05x} {:>10}", entry, cycles).unwrap();
+        }
+        writeln!(listing, ";").unwrap();
+
+        // Past 64k nothing can run, the rest of the rom is left out
+        let end = (start as usize + program.len()).min(0x10000);
+        let mut addr = start as usize;
+        let mut operand = false;
+        while addr < end {
+            let idx = addr - start as usize;
+            // Instructions can start on odd addresses, don't let the listing hide them
+            let hides_next = !self.hits.contains_key(&(addr as u16))
+                && self.hits.contains_key(&(addr as u16).wrapping_add(1));
+            if addr + 1 < end && !hides_next {
+                let op_code = (program[idx] as u16) << 8 | program[idx + 1] as u16;
+                let decoded = decoder(op_code);
+                let text = match decoded {
+                    // The second word of 01NN NNNN is the low part of the address
+                    _ if operand => format!("DW {:#06x}", op_code),
+                    Ok(instruction) => instruction.to_string(),
+                    Err(_) => format!("DW {:#06x}", op_code),
+                };
+                operand = match decoded {
+                    Ok(Instruction::LongI(_)) => !operand,
+                    _ => false,
+                };
+                let hits = match self.hits.get(&(addr as u16)) {
+                    Some(hits) => hits.to_string(),
+                    None => "-".to_owned(),
+                };
+                writeln!(
+                    listing,
+                    "{:#05x}  {:04x}  {:<20} ; {}",
+                    addr, op_code, text, hits
+                )
+                .unwrap();
+                addr = addr + 2;
+            } else {
+                operand = false;
+                let text = format!("DB {:#04x}", program[idx]);
+                writeln!(
+                    listing,
+                    "{:#05x}  {:02x}    {:<20} ; -",
+                    addr, program[idx], text
+                )
+                .unwrap();
+                addr = addr + 1;
+            }
+        }
+        return listing;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::decoder::{decode, decode_chip8x, decode_megachip};
+    use super::super::machine::Machine;
+    use super::Profiler;
+    /// 2206 1200 0000 6001 00EE: call a subroutine forever
+    const PROGRAM: [u8; 10] = [0x22, 0x06, 0x12, 0x00, 0x00, 0x00, 0x60, 0x01, 0x00, 0xEE];
+    fn profile(cycles: u32) -> Profiler {
+        let mut machine = Machine::new(&PROGRAM).expect("Couldn't create the machine");
+        machine.profiler = Some(Profiler::default());
+        machine.run_frame(cycles);
+        return machine.profiler.take().unwrap();
+    }
+    #[test]
+    fn counts() {
+        let profiler = profile(8);
+        assert_eq!(profiler.hits[&0x200], 2, "Wrong hits for the call");
+        assert_eq!(profiler.hits[&0x206], 2, "Wrong hits for the subroutine");
+        assert_eq!(profiler.hits.get(&0x204), None, "Dead code ran");
+        assert_eq!(profiler.classes["2nnn"], 2);
+        assert_eq!(profiler.classes["00EE"], 2);
+    }
+    #[test]
+    fn stacks() {
+        let profiler = profile(8);
+        assert_eq!(profiler.stacks[&vec![]], 4, "Cycles in main");
+        assert_eq!(profiler.stacks[&vec![0x206]], 4, "Cycles in the subroutine");
+        assert_eq!(profiler.subroutine_cycles(), vec![(0x206, 4)]);
+        assert_eq!(profiler.folded(), "main 4\nmain;0x206 4\n");
+    }
+    #[test]
+    fn listing() {
+        let listing = profile(8).listing(&PROGRAM, 0x200, decode);
+        assert!(listing.contains("; Coverage: 4 of 4 reachable instructions ran"));
+        assert!(listing.contains("0x200  2206  CALL 0x206           ; 2"));
+        assert!(listing.contains("0x204  0000  SYS 0x000            ; -"));
+        let listing = Profiler::default().listing(&[0x60, 0x01], 0x600, decode);
+        assert!(listing.contains("; Coverage: 0 of 1 reachable instructions ran"));
+        assert!(listing.contains("0x600  6001"), "Wrong load address");
+        // B123 and the long I 0101 2234
+        let listing = Profiler::default().listing(&[0xB1, 0x23], 0x300, decode_chip8x);
+        assert!(
+            listing.contains("0x300  b123  COL V1, V2, 3"),
+            "Not a CHIP-8X listing"
+        );
+        let listing =
+            Profiler::default().listing(&[0x01, 0x01, 0x22, 0x34], 0x200, decode_megachip);
+        assert!(listing.contains("0x200  0101  LDHI 0x01"));
+        assert!(
+            listing.contains("0x202  2234  DW 0x2234"),
+            "Low word listed as a call"
+        );
+    }
+}
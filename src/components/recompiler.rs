@@ -0,0 +1,345 @@
+//! # Recompiler Module
+//! ## Description
+//! Translates the basic blocks of a program into a compact bytecode the processor runs without fetching or decoding,
+//! one block at a time instead of one instruction at a time. It's optional, the machine only uses it when the processor has one.
+//! ## Blocks
+//! A block starts wherever the program counter is and goes on until an instruction that:
+//! * Jumps, calls, returns or may skip the next instruction, or is a machine language call
+//! * Writes to memory (Fx33 and Fx55), which may be the block itself
+//! * Waits for a key (Fx0A) or loads the sound timer (Fx18), which the machine has to see
+//! * Reads the word after it, like the 01NN of MegaChip programs
+//!
+//! Blocks are also cut before an op code that doesn't decode, the interpreter reports it, and after 64 instructions.
+//! ## Bytecode
+//! Loads and arithmetic that only touch V0 to VF are turned into ops with their registers resolved,
+//! everything else is handed back to the interpreter already decoded.
+//! ## Invalidation
+//! Every block keeps the bytes it was compiled from. They are compared with the memory before the block runs,
+//! and a block that was written over, by the program itself or anything else, is compiled again.
+//! CHIP-8X and MegaChip programs are decoded differently, every block is thrown away when the decoder changes.
+use super::decoder::Instruction;
+use super::memory::Memory;
+use super::predecode::Decoder;
+
+/// An instruction of a compiled block.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    /// 6xnn
+    Load(usize, u8),
+    /// 7xnn
+    Add(usize, u8),
+    /// 8xy0
+    Assign(usize, usize),
+    /// 8xy1
+    Or(usize, usize),
+    /// 8xy2
+    And(usize, usize),
+    /// 8xy3
+    Xor(usize, usize),
+    /// Run by the interpreter
+    Interpret(Instruction),
+}
+
+/// Instructions that always run one after the other, compiled.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    pub start: u16,
+    /// Memory the block was compiled from
+    bytes: Vec<u8>,
+    pub ops: Vec<Op>,
+}
+
+impl Block {
+    /// Check if the memory still holds what the block was compiled from.
+    fn is_valid(&self, mem: &Memory) -> bool {
+        let start = self.start as usize;
+        return mem.space.get(start..start + self.bytes.len()) == Some(&self.bytes[..]);
+    }
+}
+
+/// Compiled blocks, by their first address.
+#[derive(Clone, Debug, Default)]
+pub struct Recompiler {
+    blocks: Vec<Option<Box<Block>>>,
+    /// Decoder the blocks were compiled with
+    kind: u8,
+    /// Blocks compiled so far, including the ones compiled again
+    pub compiled: u64,
+}
+
+impl Recompiler {
+    /// Addresses the program counter can reach.
+    const SIZE: usize = 0x10000;
+    /// Most instructions in a block.
+    const MAX_LEN: usize = 64;
+    /// Get the block starting at an address, compiling it if it's new or was written over.
+    ///
+    /// None when the instruction at the address can't be read or decoded, the interpreter has to run it.
+    pub fn block(
+        &mut self,
+        mem: &mut Memory,
+        address: u16,
+        kind: u8,
+        decoder: Decoder,
+    ) -> Option<&Block> {
+        if self.blocks.is_empty() || kind != self.kind {
+            self.blocks = vec![None; Recompiler::SIZE];
+            self.kind = kind;
+        }
+        let valid = self.blocks[address as usize]
+            .as_ref()
+            .map_or(false, |block| block.is_valid(mem));
+        if !valid {
+            self.blocks[address as usize] = compile(mem, address, decoder).map(Box::new);
+            self.compiled += 1;
+        }
+        return self.blocks[address as usize].as_deref();
+    }
+    /// Forget every block.
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+    }
+    /// Count the blocks compiled and kept.
+    pub fn len(&self) -> usize {
+        return self.blocks.iter().filter(|block| block.is_some()).count();
+    }
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+}
+
+/// Compile the block starting at an address.
+fn compile(mem: &mut Memory, start: u16, decoder: Decoder) -> Option<Block> {
+    let mut ops = Vec::new();
+    let mut address = start;
+    while ops.len() < Recompiler::MAX_LEN {
+        // Read like the interpreter does, so blocks stop where it would fail
+        let instruction = match mem.read(address).ok().map(decoder) {
+            Some(Ok(instruction)) => instruction,
+            _ => break,
+        };
+        ops.push(translate(instruction));
+        address = match address.checked_add(2) {
+            Some(next) if !ends_block(instruction) => next,
+            _ => break,
+        };
+    }
+    if ops.is_empty() {
+        return None;
+    }
+    let end = start as usize + ops.len() * 2;
+    return Some(Block {
+        start: start,
+        bytes: mem.space[start as usize..end].to_vec(),
+        ops: ops,
+    });
+}
+
+fn translate(instruction: Instruction) -> Op {
+    return match instruction {
+        Instruction::LoadNn(x, nn) => Op::Load(x as usize, nn),
+        Instruction::AddNn(x, nn) => Op::Add(x as usize, nn),
+        Instruction::Assign(x, y) => Op::Assign(x as usize, y as usize),
+        Instruction::Or(x, y) => Op::Or(x as usize, y as usize),
+        Instruction::And(x, y) => Op::And(x as usize, y as usize),
+        Instruction::Xor(x, y) => Op::Xor(x as usize, y as usize),
+        _ => Op::Interpret(instruction),
+    };
+}
+
+/// Check if nothing can run after the instruction in the same block.
+fn ends_block(instruction: Instruction) -> bool {
+    return match instruction {
+        Instruction::MachineCall(_)
+        | Instruction::Ret
+        | Instruction::Jump(_)
+        | Instruction::Call(_)
+        | Instruction::JumpV0(_)
+        | Instruction::WaitKey(_)
+        | Instruction::SetSt(_)
+        | Instruction::Bcd(_)
+        | Instruction::Store(_)
+        | Instruction::LongI(_) => true,
+        skip => skip.is_skip(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::decoder::{decode, decode_chip8x, Instruction};
+    use super::super::machine::Machine;
+    use super::super::memory::Memory;
+    use super::super::platform::Platform;
+    use super::{Op, Recompiler};
+    use rand::SeedableRng;
+    #[test]
+    fn compile() {
+        let mut mem = Memory::default();
+        // 6105 8120 F155 7101: the block ends at the store
+        mem.space[0x200..0x208].copy_from_slice(&[0x61, 0x05, 0x81, 0x20, 0xF1, 0x55, 0x71, 0x01]);
+        let mut recompiler = Recompiler::default();
+        let block = recompiler.block(&mut mem, 0x200, 0, decode).unwrap();
+        assert_eq!(
+            block.ops,
+            vec![
+                Op::Load(1, 5),
+                Op::Assign(1, 2),
+                Op::Interpret(Instruction::Store(1))
+            ]
+        );
+        recompiler.block(&mut mem, 0x200, 0, decode).unwrap();
+        assert_eq!(recompiler.compiled, 1, "Block should be kept");
+        mem.space[0x203] = 0x30;
+        let block = recompiler.block(&mut mem, 0x200, 0, decode).unwrap();
+        assert_eq!(
+            block.ops[1],
+            Op::Assign(1, 3),
+            "Written block wasn't compiled again"
+        );
+        assert_eq!(recompiler.compiled, 2);
+        mem.space[0x206] = 0xB1;
+        let block = recompiler.block(&mut mem, 0x206, 1, decode_chip8x).unwrap();
+        assert_eq!(
+            block.ops,
+            vec![
+                Op::Interpret(Instruction::ColourRows(1, 0, 1)),
+                Op::Interpret(Instruction::MachineCall(0))
+            ]
+        );
+        assert_eq!(recompiler.len(), 1, "Another decoder should start over");
+    }
+    #[test]
+    fn invalid() {
+        let mut mem = Memory::default();
+        // 6001 5121: the block stops before the invalid op code
+        mem.space[0x200..0x204].copy_from_slice(&[0x60, 0x01, 0x51, 0x21]);
+        let mut recompiler = Recompiler::default();
+        assert_eq!(
+            recompiler
+                .block(&mut mem, 0x200, 0, decode)
+                .map(|block| block.ops.len()),
+            Some(1)
+        );
+        assert!(recompiler.block(&mut mem, 0x202, 0, decode).is_none());
+        assert!(recompiler.block(&mut mem, 0xFFF, 0, decode).is_some());
+        assert!(
+            recompiler.block(&mut mem, 0x1000, 0, decode).is_none(),
+            "Past the memory the interpreter reads"
+        );
+    }
+    /// Programs both ways of running have to agree on, with the platform they run on.
+    fn corpus() -> Vec<(Platform, Vec<u8>)> {
+        let chip8: [&[u8]; 8] = [
+            // Count, mix registers, store their digits and draw them
+            &[
+                0x60, 0x00, 0x70, 0x01, 0x81, 0x04, 0x82, 0x13, 0xA3, 0x00, 0xF2, 0x33, 0xD0, 0x32,
+                0x12, 0x02,
+            ],
+            // Calls, returns and skips
+            &[
+                0x61, 0x05, 0x22, 0x10, 0x41, 0x00, 0x12, 0x06, 0x12, 0x02, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x71, 0xFF, 0x31, 0x03, 0x6A, 0x01, 0x8A, 0x14, 0x00, 0xEE,
+            ],
+            // Adds one to the 6300 at 0x20C on every loop
+            &[
+                0xA2, 0x0D, 0xF0, 0x65, 0x70, 0x01, 0xA2, 0x0D, 0xF0, 0x55, 0x84, 0x30, 0x63, 0x00,
+                0x12, 0x00,
+            ],
+            // Random numbers, timers and the buzzer
+            &[
+                0xC0, 0xFF, 0xA3, 0x00, 0xF0, 0x33, 0xF2, 0x65, 0xF0, 0x15, 0xF1, 0x07, 0xF1, 0x18,
+                0xD1, 0x23, 0x12, 0x00,
+            ],
+            // Keys
+            &[0xE0, 0xA1, 0x12, 0x00, 0xF1, 0x0A, 0x72, 0x01, 0x12, 0x00],
+            // An invalid op code in the middle of a block
+            &[0x60, 0x01, 0x70, 0x01, 0x51, 0x21],
+            // Writes V1 + 1 over the 7101 at the start of its own block, already compiled
+            &[
+                0x71, 0x01, 0x80, 0x10, 0x70, 0x01, 0xA2, 0x01, 0xF0, 0x55, 0x12, 0x00,
+            ],
+            // Waits for two keys in a row, counting them
+            &[0xF0, 0x0A, 0x71, 0x01, 0xF2, 0x0A, 0x71, 0x01, 0x12, 0x00],
+        ];
+        let mut corpus: Vec<(Platform, Vec<u8>)> = chip8
+            .iter()
+            .map(|program| (Platform::Chip8, program.to_vec()))
+            .collect();
+        // Colours zones and rows, and changes the background
+        corpus.push((
+            Platform::Chip8x,
+            vec![
+                0x60, 0x05, 0x61, 0x02, 0xB1, 0x00, 0xB0, 0x13, 0x02, 0xA0, 0x70, 0x01, 0x13, 0x04,
+            ],
+        ));
+        // Starts at 0x2C0 and draws below the 32 rows of the usual display
+        let mut hires = vec![0; 0xC0];
+        hires.extend_from_slice(&[
+            0x02, 0x30, 0x61, 0x30, 0xA2, 0xD0, 0xD0, 0x14, 0x70, 0x03, 0x12, 0xC4, 0x00, 0x00,
+            0x00, 0x00, 0xF0, 0x90, 0x90, 0xF0,
+        ]);
+        corpus.push((Platform::Hires, hires));
+        // Loads a colour and draws a 2x2 sprite through 24 bit addresses, showing every frame
+        let mut megachip = vec![
+            0x00, 0x11, 0x01, 0x00, 0x02, 0x40, 0x02, 0x01, 0x03, 0x02, 0x04, 0x02, 0x01, 0x00,
+            0x02, 0x44, 0xD0, 0x11, 0x00, 0xE0, 0x70, 0x01, 0x12, 0x0C,
+        ];
+        megachip.resize(0x40, 0);
+        megachip.extend_from_slice(&[0xFF, 0x00, 0xFF, 0x00, 0x01, 0x01, 0x01, 0x00]);
+        corpus.push((Platform::MegaChip, megachip));
+        return corpus;
+    }
+    #[test]
+    fn equivalence() {
+        for (idx, (platform, program)) in corpus().iter().enumerate() {
+            let mut machines = Vec::new();
+            for recompiled in [false, true].iter() {
+                let mut machine = Machine::with_platform(
+                    program,
+                    *platform,
+                    platform.load_address(),
+                    platform.entry_point(),
+                )
+                .expect("Couldn't create the machine");
+                machine.cpu.rng = rand::rngs::StdRng::seed_from_u64(8);
+                if *recompiled {
+                    machine.cpu.recompiler = Some(Recompiler::default());
+                }
+                machines.push(machine);
+            }
+            for frame in 0..40 {
+                for machine in machines.iter_mut() {
+                    match frame {
+                        10 | 20 => machine.set_key(5, true),
+                        12 | 21 => machine.set_key(5, false),
+                        _ => (),
+                    }
+                    machine.run_frame(37);
+                }
+                let (interpreted, recompiled) = machines.split_at_mut(1);
+                let (interpreted, recompiled) = (&mut interpreted[0], &mut recompiled[0]);
+                let (a, b) = (&interpreted.cpu, &recompiled.cpu);
+                let message = format!("Program {} differs at frame {}", idx, frame);
+                assert_eq!(
+                    (a.v, a.program_counter, a.i, &a.stack, a.dt, a.st),
+                    (b.v, b.program_counter, b.i, &b.stack, b.dt, b.st),
+                    "{}",
+                    message
+                );
+                assert_eq!(a.is_key_pressed_temp, b.is_key_pressed_temp, "{}", message);
+                assert_eq!(a.chip8x, b.chip8x, "{}", message);
+                assert!(a.megachip == b.megachip, "{}", message);
+                assert_eq!(interpreted.cycles, recompiled.cycles, "{}", message);
+                assert_eq!(interpreted.halted, recompiled.halted, "{}", message);
+                assert!(interpreted.mem.space == recompiled.mem.space, "{}", message);
+                assert_eq!(interpreted.state, recompiled.state, "{}", message);
+                assert_eq!(
+                    interpreted.take_beep(),
+                    recompiled.take_beep(),
+                    "{}",
+                    message
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! # ROM Database Module
+//! ## Description
+//! Some roms need their own settings to be playable, like a different keypad layout.
+//! Roms are identified by the CRC-32 of their contents, so renaming the file doesn't matter.
+//! ## Format
+//! The database lives in the `[roms]` table of the config file, one entry per rom:
+//!
+//! ```toml
+//! [roms.5b4b1c63]
+//! name = "Pong"
+//! layout = "hex"
+//!
+//! [roms.0a1b2c3d]
+//! name = "Hi-res Astro Dodge"
+//! platform = "hires"
+//! ```
+//!
+//! The id of the running rom is printed on start so new entries are easy to add.
+use std::collections::HashMap;
+
+/// Settings for a single rom.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RomEntry {
+    pub name: Option<String>,
+    /// Name of the keypad layout to use
+    pub layout: Option<String>,
+    /// Name of the platform the rom was written for
+    pub platform: Option<String>,
+}
+
+/// Every known rom, by CRC-32.
+#[derive(Default)]
+pub struct RomDatabase {
+    pub entries: HashMap<u32, RomEntry>,
+}
+
+impl RomDatabase {
+    /// Read the `[roms]` table of a parsed config file, it's fine if there is none.
+    pub fn from_toml(config: &toml::Value) -> Result<RomDatabase, &'static str> {
+        let mut database = RomDatabase {
+            ..Default::default()
+        };
+        let roms = match config.get("roms") {
+            Some(roms) => roms.as_table().ok_or("roms must be a table")?,
+            None => return Ok(database),
+        };
+        for (id, settings) in roms.iter() {
+            let crc = u32::from_str_radix(id, 16).map_err(|_| "Rom ids must be a CRC-32 in hex")?;
+            let settings = settings.as_table().ok_or("Every rom must be a table")?;
+            let text = |key: &str| settings.get(key).and_then(|v| v.as_str()).map(String::from);
+            database.entries.insert(
+                crc,
+                RomEntry {
+                    name: text("name"),
+                    layout: text("layout"),
+                    platform: text("platform"),
+                },
+            );
+        }
+        return Ok(database);
+    }
+    /// Find the entry of a rom by its contents.
+    pub fn lookup(&self, program: &[u8]) -> Option<&RomEntry> {
+        return self.entries.get(&rom_id(program));
+    }
+}
+
+/// CRC-32 (the same one used by zip files) of a rom.
+pub fn rom_id(program: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFFFFFF;
+    for byte in program {
+        crc = crc ^ *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+    return !crc;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rom_id, RomDatabase};
+    #[test]
+    fn crc() {
+        assert_eq!(rom_id(b"123456789"), 0xCBF43926, "Wrong CRC-32");
+        assert_eq!(rom_id(&[]), 0, "Wrong CRC-32 of nothing");
+    }
+    #[test]
+    fn from_toml() {
+        let config: toml::Value = toml::from_str(
+            "[roms.cbf43926]\nname = \"Test\"\nlayout = \"hex\"\nplatform = \"hires\"\n",
+        )
+        .unwrap();
+        let database = RomDatabase::from_toml(&config).expect("Database should parse");
+        let entry = database.lookup(b"123456789").expect("Rom should be found");
+        assert_eq!(entry.name, Some("Test".to_owned()));
+        assert_eq!(entry.layout, Some("hex".to_owned()));
+        assert_eq!(entry.platform, Some("hires".to_owned()));
+        assert!(database.lookup(b"12345678").is_none(), "Unknown rom found");
+    }
+    #[test]
+    fn wrong_id() {
+        let config: toml::Value = toml::from_str("[roms.pong]\nname = \"Pong\"\n").unwrap();
+        assert!(RomDatabase::from_toml(&config).is_err(), "Bad id accepted");
+    }
+}
@@ -0,0 +1,813 @@
+//! # RPC Module
+//! ## Description
+//! Lets bots and scripts in any language drive the machine through JSON-RPC 2.0 over a local socket.
+//!
+//! The machine only moves when a client asks, so the same calls always give the same results.
+//! Seed the random numbers with `reset` to make Cxnn repeatable too.
+//! ## Transport
+//! Listens on TCP, like `127.0.0.1:9000`, or on a Unix socket, like `unix:/tmp/chip.sock`.
+//! Every request and every response is a single line of JSON.
+//! The socket is polled between frames without blocking, so no extra threads are needed.
+//! Lines are only as long as a save state in hex can be, a client sending a longer one is dropped.
+//! ## Methods
+//! * `step` `{count}`: run instructions, stops early on a failure
+//! * `run_frames` `{frames}`: run whole frames, timers included
+//! * `set_keys` `{keys}`: the keys pressed right now, like `[1, 4]`
+//! * `read_screen`: the display, as rows of 0 and 1
+//! * `read_registers`: V0 to VF, I, the program counter, the timers and the stack
+//! * `read_memory` `{address, length}` and `write_memory` `{address, data}`
+//! * `save_state` and `load_state` `{state}`: a save state as a hex string
+//! * `reset` `{hard, seed}`: a soft or hard reset, optionally seeding the random numbers
+//! * `search_start` `{address, length}` and `search_filter` `{comparison, value}`: a memory search, see the cheats module
+//! * `freeze` `{address, value}` and `unfreeze` `{address}`: keep a byte at a value, the current one by default
+//! * `set_cheats` `{codes}`: replace the cheat codes with the ones in a text, like a cheat file
+use super::cheats::{Cheats, Comparison, Search};
+use super::display::Display;
+use super::machine::Machine;
+use super::savestate;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde_json::{json, Value};
+use std::fmt::Write as FmtWrite;
+#[cfg(unix)]
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+
+/// Error codes from the JSON-RPC specification.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The machine refused the call.
+const MACHINE_ERROR: i64 = -32000;
+/// Room in a line for everything but the memory and the stack of a save state.
+const LINE_ROOM: usize = 0x100000;
+
+/// A failed call, its code and message.
+type RpcError = (i64, &'static str);
+
+/// Anything a client can be connected through.
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, String),
+}
+
+struct Client {
+    stream: Box<dyn Stream>,
+    /// Bytes received but not yet a whole line
+    buffer: Vec<u8>,
+    /// Responses the socket didn't take yet
+    outgoing: Vec<u8>,
+}
+
+impl Client {
+    /// Send as much of the responses as the socket takes, false once it's gone.
+    fn flush(&mut self) -> bool {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return false,
+                Ok(size) => {
+                    self.outgoing.drain(..size);
+                }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return false,
+            }
+        }
+        return true;
+    }
+}
+
+/// Serves every client connected to a socket.
+pub struct RpcServer {
+    listener: Listener,
+    clients: Vec<Client>,
+    /// Cycles run in every frame of run_frames
+    pub cycles_per_frame: u32,
+    /// Set once a client came and every client left
+    served: bool,
+    /// Memory search in progress
+    search: Option<Search>,
+}
+
+impl RpcServer {
+    /// Listen on a TCP address, or on a Unix socket if the address starts with `unix:`.
+    pub fn bind(address: &str, cycles_per_frame: u32) -> Result<RpcServer, &'static str> {
+        let listener = if address.starts_with("unix:") {
+            RpcServer::bind_unix(&address[5..])?
+        } else {
+            let listener =
+                TcpListener::bind(address).map_err(|_| "Couldn't listen for json-rpc")?;
+            listener
+                .set_nonblocking(true)
+                .map_err(|_| "Couldn't listen for json-rpc")?;
+            Listener::Tcp(listener)
+        };
+        return Ok(RpcServer {
+            listener,
+            clients: Vec::new(),
+            cycles_per_frame,
+            served: false,
+            search: None,
+        });
+    }
+    #[cfg(unix)]
+    fn bind_unix(path: &str) -> Result<Listener, &'static str> {
+        let listener = UnixListener::bind(path).map_err(|_| "Couldn't listen for json-rpc")?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|_| "Couldn't listen for json-rpc")?;
+        return Ok(Listener::Unix(listener, path.to_owned()));
+    }
+    #[cfg(not(unix))]
+    fn bind_unix(_path: &str) -> Result<Listener, &'static str> {
+        return Err("Unix sockets aren't supported here");
+    }
+    /// Port the server is listening on, for TCP.
+    pub fn port(&self) -> Option<u16> {
+        return match &self.listener {
+            Listener::Tcp(listener) => listener.local_addr().ok().map(|addr| addr.port()),
+            #[cfg(unix)]
+            Listener::Unix(..) => None,
+        };
+    }
+    /// Check if any client is connected, the frontend shouldn't run the machine then.
+    pub fn attached(&self) -> bool {
+        return !self.clients.is_empty();
+    }
+    /// Check if a client came and every client already left.
+    pub fn served(&self) -> bool {
+        return self.served;
+    }
+    /// Accept clients and answer their requests, without blocking.
+    pub fn poll(&mut self, machine: &mut Machine) {
+        while let Some(stream) = self.accept() {
+            self.clients.push(Client {
+                stream: stream,
+                buffer: Vec::new(),
+                outgoing: Vec::new(),
+            });
+        }
+        let mut idx = 0;
+        while idx < self.clients.len() {
+            if self.serve_client(idx, machine) {
+                idx = idx + 1;
+            } else {
+                self.clients.remove(idx);
+                if self.clients.is_empty() {
+                    self.served = true;
+                }
+            }
+        }
+    }
+    fn accept(&mut self) -> Option<Box<dyn Stream>> {
+        return match &self.listener {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept().ok()?;
+                stream.set_nonblocking(true).ok()?;
+                // Agents send small requests and wait for each answer
+                stream.set_nodelay(true).ok()?;
+                Some(Box::new(stream) as Box<dyn Stream>)
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().ok()?;
+                stream.set_nonblocking(true).ok()?;
+                Some(Box::new(stream) as Box<dyn Stream>)
+            }
+        };
+    }
+    /// Answer every whole line a client sent and send what's left of the answers, false once it's gone.
+    fn serve_client(&mut self, idx: usize, machine: &mut Machine) -> bool {
+        let mut chunk = [0u8; 4096];
+        let max_line = max_line(machine);
+        loop {
+            let client = &mut self.clients[idx];
+            // The rest waits in the socket until these lines are answered
+            if client.buffer.len() > max_line {
+                break;
+            }
+            match client.stream.read(&mut chunk) {
+                Ok(0) => return false,
+                Ok(size) => client.buffer.extend_from_slice(&chunk[..size]),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return false,
+            }
+        }
+        while let Some(end) = self.clients[idx]
+            .buffer
+            .iter()
+            .position(|&byte| byte == b'\n')
+        {
+            let line: Vec<u8> = self.clients[idx].buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = self.handle(machine, &line) {
+                let outgoing = &mut self.clients[idx].outgoing;
+                outgoing.extend_from_slice(response.as_bytes());
+                outgoing.push(b'\n');
+            }
+        }
+        if self.clients[idx].buffer.len() > max_line {
+            return false;
+        }
+        return self.clients[idx].flush();
+    }
+    /// Answer a line of JSON, None for notifications.
+    pub fn handle(&mut self, machine: &mut Machine, line: &str) -> Option<String> {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(_) => return Some(error_response(Value::Null, (PARSE_ERROR, "Parse error"))),
+        };
+        let id = request.get("id").cloned();
+        let method = match request.get("method").and_then(|method| method.as_str()) {
+            Some(method) if request.is_object() => method,
+            _ => {
+                return Some(error_response(
+                    id.unwrap_or(Value::Null),
+                    (INVALID_REQUEST, "Invalid request"),
+                ))
+            }
+        };
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+        let result = self.call(machine, method, &params);
+        let id = id?;
+        let response = match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}).to_string(),
+            Err(error) => error_response(id, error),
+        };
+        return Some(response);
+    }
+    /// Run a method.
+    fn call(
+        &mut self,
+        machine: &mut Machine,
+        method: &str,
+        params: &Value,
+    ) -> Result<Value, RpcError> {
+        match method {
+            "step" => {
+                let count = param_u64(params, "count")?.unwrap_or(1);
+                let mut cycles = 0;
+                let mut error = Value::Null;
+                while cycles < count {
+                    match machine.step() {
+                        Ok(_) => cycles = cycles + 1,
+                        Err("Breakpoint") => {
+                            machine.take_breakpoint();
+                        }
+                        Err(err) => {
+                            error = json!(err);
+                            break;
+                        }
+                    }
+                }
+                return Ok(json!({
+                    "cycles": cycles,
+                    "program_counter": machine.cpu.program_counter,
+                    "halted": machine.halted,
+                    "error": error,
+                }));
+            }
+            "run_frames" => {
+                let frames = param_u64(params, "frames")?.unwrap_or(1);
+                let mut beep = false;
+                for _ in 0..frames {
+                    beep = machine.run_frame(self.cycles_per_frame) || beep;
+                    machine.take_breakpoint();
+                }
+                return Ok(json!({
+                    "frames": frames,
+                    "program_counter": machine.cpu.program_counter,
+                    "halted": machine.halted,
+                    "beep": beep,
+                }));
+            }
+            "set_keys" => {
+                let keys = param_bytes(params, "keys")?.ok_or((INVALID_PARAMS, "Missing keys"))?;
+                let mut pressed = [false; 16];
+                for key in keys {
+                    if key > 0xF {
+                        return Err((INVALID_PARAMS, "Keys go from 0 to 15"));
+                    }
+                    pressed[key as usize] = true;
+                }
+                machine.set_keys(pressed);
+                return Ok(Value::Null);
+            }
+            "read_screen" => {
+                let (width, height) = (machine.state.width(), machine.state.height());
+                let rows: Vec<Vec<u8>> = (0..height)
+                    .map(|row| {
+                        (0..width)
+                            .map(|col| machine.state.pixel(col, row) as u8)
+                            .collect()
+                    })
+                    .collect();
+                return Ok(json!({"width": width, "height": height, "pixels": rows}));
+            }
+            "read_registers" => {
+                let cpu = &machine.cpu;
+                return Ok(json!({
+                    "v": cpu.v.to_vec(),
+                    "i": cpu.i,
+                    "program_counter": cpu.program_counter,
+                    "dt": cpu.dt,
+                    "st": cpu.st,
+                    "stack": cpu.stack,
+                }));
+            }
+            "read_memory" => {
+                let address =
+                    param_u64(params, "address")?.ok_or((INVALID_PARAMS, "Missing address"))?;
+                let length =
+                    param_u64(params, "length")?.ok_or((INVALID_PARAMS, "Missing length"))?;
+                let (start, end) = memory_range(machine, address, length)?;
+                return Ok(json!({"data": machine.mem.space[start..end].to_vec()}));
+            }
+            "write_memory" => {
+                let address =
+                    param_u64(params, "address")?.ok_or((INVALID_PARAMS, "Missing address"))?;
+                let data = param_bytes(params, "data")?.ok_or((INVALID_PARAMS, "Missing data"))?;
+                let (start, end) = memory_range(machine, address, data.len() as u64)?;
+                machine.mem.space[start..end].copy_from_slice(&data);
+                return Ok(Value::Null);
+            }
+            "save_state" => {
+                let mut state = String::new();
+                for byte in savestate::save(machine) {
+                    write!(state, "{:02x}", byte).unwrap();
+                }
+                return Ok(json!({ "state": state }));
+            }
+            "load_state" => {
+                let state = params
+                    .get("state")
+                    .and_then(|state| state.as_str())
+                    .and_then(parse_hex)
+                    .ok_or((INVALID_PARAMS, "The state must be a hex string"))?;
+                savestate::load(machine, &state).map_err(|err| (MACHINE_ERROR, err))?;
+                return Ok(Value::Null);
+            }
+            "reset" => {
+                let hard = match params.get("hard") {
+                    None | Some(Value::Null) => false,
+                    Some(hard) => hard
+                        .as_bool()
+                        .ok_or((INVALID_PARAMS, "hard must be a boolean"))?,
+                };
+                let seed = param_u64(params, "seed")?;
+                if hard {
+                    machine.hard_reset();
+                } else {
+                    machine.soft_reset();
+                }
+                if let Some(seed) = seed {
+                    machine.cpu.rng = StdRng::seed_from_u64(seed);
+                }
+                return Ok(Value::Null);
+            }
+            "search_start" => {
+                let address = param_u64(params, "address")?;
+                let search = match param_u64(params, "length")? {
+                    Some(length) => {
+                        let (start, end) = memory_range(machine, address.unwrap_or(0), length)?;
+                        Search::new(&machine.mem, start, end)
+                            .map_err(|err| (INVALID_PARAMS, err))?
+                    }
+                    None if address.is_some() => return Err((INVALID_PARAMS, "Missing length")),
+                    None => Search::whole(&machine.mem),
+                };
+                let count = search.candidates().len();
+                self.search = Some(search);
+                return Ok(json!({ "count": count }));
+            }
+            "search_filter" => {
+                let value = match param_u64(params, "value")? {
+                    Some(value) if value > 0xFF => {
+                        return Err((INVALID_PARAMS, "Values must be bytes"))
+                    }
+                    value => value.map(|value| value as u8),
+                };
+                let comparison = params
+                    .get("comparison")
+                    .and_then(|comparison| comparison.as_str())
+                    .ok_or((INVALID_PARAMS, "Missing comparison"))?;
+                let comparison =
+                    Comparison::parse(comparison, value).map_err(|err| (INVALID_PARAMS, err))?;
+                let search = self
+                    .search
+                    .as_mut()
+                    .ok_or((MACHINE_ERROR, "No search was started"))?;
+                search.filter(&machine.mem, comparison);
+                let candidates = search.candidates();
+                // Only the first addresses, a fresh search has thousands
+                let shown = &candidates[..candidates.len().min(256)];
+                return Ok(json!({"count": candidates.len(), "candidates": shown}));
+            }
+            "freeze" => {
+                let address =
+                    param_u64(params, "address")?.ok_or((INVALID_PARAMS, "Missing address"))?;
+                let (address, _) = memory_range(machine, address, 1)?;
+                match param_u64(params, "value")? {
+                    Some(value) if value <= 0xFF => {
+                        machine.cheats.frozen.insert(address, value as u8);
+                    }
+                    Some(_) => return Err((INVALID_PARAMS, "Values must be bytes")),
+                    None => machine
+                        .cheats
+                        .freeze(&machine.mem, address)
+                        .map_err(|err| (INVALID_PARAMS, err))?,
+                }
+                return Ok(Value::Null);
+            }
+            "unfreeze" => {
+                let address =
+                    param_u64(params, "address")?.ok_or((INVALID_PARAMS, "Missing address"))?;
+                machine.cheats.unfreeze(address as usize);
+                return Ok(Value::Null);
+            }
+            "set_cheats" => {
+                let codes = params
+                    .get("codes")
+                    .and_then(|codes| codes.as_str())
+                    .ok_or((INVALID_PARAMS, "Missing codes"))?;
+                machine.cheats.codes = Cheats::parse(codes)
+                    .map_err(|err| (INVALID_PARAMS, err))?
+                    .codes;
+                return Ok(Value::Null);
+            }
+            _ => return Err((METHOD_NOT_FOUND, "Method not found")),
+        }
+    }
+}
+
+impl Drop for RpcServer {
+    fn drop(&mut self) {
+        // Unix sockets stay on disk and would stop the next bind
+        #[cfg(unix)]
+        {
+            if let Listener::Unix(_, path) = &self.listener {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+fn error_response(id: Value, error: RpcError) -> String {
+    return json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": error.0, "message": error.1},
+    })
+    .to_string();
+}
+
+/// Read an optional number parameter.
+fn param_u64(params: &Value, name: &str) -> Result<Option<u64>, RpcError> {
+    return match params.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => match value.as_u64() {
+            Some(value) => Ok(Some(value)),
+            None => Err((INVALID_PARAMS, "Numbers must be positive integers")),
+        },
+    };
+}
+
+/// Read an optional array of bytes parameter.
+fn param_bytes(params: &Value, name: &str) -> Result<Option<Vec<u8>>, RpcError> {
+    let values = match params.get(name) {
+        None | Some(Value::Null) => return Ok(None),
+        Some(Value::Array(values)) => values,
+        Some(_) => return Err((INVALID_PARAMS, "Expected an array of bytes")),
+    };
+    let mut bytes = Vec::new();
+    for value in values {
+        match value.as_u64() {
+            Some(byte) if byte <= 0xFF => bytes.push(byte as u8),
+            _ => return Err((INVALID_PARAMS, "Expected an array of bytes")),
+        }
+    }
+    return Ok(Some(bytes));
+}
+
+/// Longest line a client can send, a save state in hex with some room for the JSON around it.
+fn max_line(machine: &Machine) -> usize {
+    return 2 * (machine.mem.space.len() + 2 * machine.cpu.stack.len()) + LINE_ROOM;
+}
+
+/// Check that a range fits in the memory.
+fn memory_range(machine: &Machine, address: u64, length: u64) -> Result<(usize, usize), RpcError> {
+    let end = address.saturating_add(length);
+    if end > machine.mem.space.len() as u64 {
+        return Err((INVALID_PARAMS, "Range is out of memory"));
+    }
+    return Ok((address as usize, end as usize));
+}
+
+/// Parse hex bytes like `0a1b`.
+fn parse_hex(digits: &str) -> Option<Vec<u8>> {
+    if digits.len() % 2 != 0 || !digits.is_ascii() {
+        return None;
+    }
+    return (0..digits.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&digits[idx..idx + 2], 16).ok())
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::display::Display;
+    use super::super::machine::Machine;
+    use super::{Client, RpcServer};
+    use serde_json::{json, Value};
+    use std::cell::RefCell;
+    use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+    use std::net::TcpStream;
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// C0FF 7101 1200: random number in V0, count up V1 and loop
+    const PROGRAM: [u8; 6] = [0xC0, 0xFF, 0x71, 0x01, 0x12, 0x00];
+
+    fn call(server: &mut RpcServer, machine: &mut Machine, method: &str, params: Value) -> Value {
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+        let response = server
+            .handle(machine, &request.to_string())
+            .expect("Requests with an id need an answer");
+        return serde_json::from_str(&response).unwrap();
+    }
+    fn setup() -> (RpcServer, Machine) {
+        let server = RpcServer::bind("127.0.0.1:0", 10).expect("Couldn't bind the server");
+        let machine = Machine::new(&PROGRAM).expect("Couldn't create the machine");
+        return (server, machine);
+    }
+    #[test]
+    fn step() {
+        let (mut server, mut machine) = setup();
+        let response = call(&mut server, &mut machine, "step", json!({"count": 3}));
+        assert_eq!(response["result"]["cycles"], 3);
+        assert_eq!(response["result"]["program_counter"], 0x200);
+        let response = call(&mut server, &mut machine, "read_registers", Value::Null);
+        assert_eq!(response["result"]["v"][1], 1, "Wrong V1");
+        let response = call(
+            &mut server,
+            &mut machine,
+            "run_frames",
+            json!({"frames": 2}),
+        );
+        assert_eq!(response["result"]["frames"], 2);
+        assert_eq!(machine.cpu.v[1], 1 + 7, "Frames should run 10 cycles each");
+    }
+    #[test]
+    fn memory_and_screen() {
+        let (mut server, mut machine) = setup();
+        let params = json!({"address": 0x300, "data": [0xBE, 0xEF]});
+        call(&mut server, &mut machine, "write_memory", params);
+        let params = json!({"address": 0x300, "length": 2});
+        let response = call(&mut server, &mut machine, "read_memory", params);
+        assert_eq!(response["result"]["data"], json!([0xBE, 0xEF]));
+        let params = json!({"address": 0x1FFF, "length": 2});
+        let response = call(&mut server, &mut machine, "read_memory", params);
+        assert_eq!(response["error"]["code"], -32602, "Read out of memory");
+        machine.state.set_pixel(3, 1, true);
+        let response = call(&mut server, &mut machine, "read_screen", Value::Null);
+        assert_eq!(
+            response["result"]["pixels"][1][3], 1,
+            "Rows should come first"
+        );
+        assert_eq!(response["result"]["pixels"][3][1], 0);
+        call(
+            &mut server,
+            &mut machine,
+            "set_keys",
+            json!({"keys": [1, 0xF]}),
+        );
+        let keys = machine.keypad.keys();
+        assert!(keys[1] && keys[0xF] && !keys[0]);
+    }
+    #[test]
+    fn state_and_reset() {
+        let (mut server, mut machine) = setup();
+        call(&mut server, &mut machine, "reset", json!({"seed": 7}));
+        call(&mut server, &mut machine, "step", Value::Null);
+        let random = machine.cpu.v[0];
+        let response = call(&mut server, &mut machine, "save_state", Value::Null);
+        let state = response["result"]["state"].clone();
+        call(
+            &mut server,
+            &mut machine,
+            "reset",
+            json!({"hard": true, "seed": 7}),
+        );
+        assert_eq!(machine.cpu.program_counter, 0x200);
+        call(&mut server, &mut machine, "step", Value::Null);
+        assert_eq!(machine.cpu.v[0], random, "Same seed, same numbers");
+        call(&mut server, &mut machine, "step", json!({"count": 4}));
+        call(
+            &mut server,
+            &mut machine,
+            "load_state",
+            json!({ "state": state }),
+        );
+        assert_eq!(machine.cpu.program_counter, 0x202, "State wasn't loaded");
+    }
+    #[test]
+    fn cheats() {
+        let (mut server, mut machine) = setup();
+        machine.mem.space[0x300] = 3;
+        call(&mut server, &mut machine, "search_start", Value::Null);
+        let params = json!({"comparison": "equal", "value": 3});
+        let response = call(&mut server, &mut machine, "search_filter", params);
+        assert_eq!(response["result"]["candidates"], json!([0x300]));
+        call(
+            &mut server,
+            &mut machine,
+            "freeze",
+            json!({"address": 0x300}),
+        );
+        let codes = json!({"codes": "301:09"});
+        call(&mut server, &mut machine, "set_cheats", codes);
+        machine.mem.space[0x300] = 0;
+        call(&mut server, &mut machine, "run_frames", Value::Null);
+        assert_eq!(
+            machine.mem.space[0x300..0x302],
+            [3, 9],
+            "Cheats weren't applied"
+        );
+        let params = json!({"comparison": "bigger"});
+        let response = call(&mut server, &mut machine, "search_filter", params);
+        assert_eq!(response["error"]["code"], -32602);
+    }
+    #[test]
+    fn cheats_beyond_0xfff() {
+        let (mut server, mut machine) = setup();
+        machine.mem.space[0x1800] = 4;
+        let params = json!({"address": 0x1800, "length": 0x10});
+        let response = call(&mut server, &mut machine, "search_start", params);
+        assert_eq!(response["result"]["count"], 0x10);
+        let params = json!({"comparison": "equal", "value": 4});
+        let response = call(&mut server, &mut machine, "search_filter", params);
+        assert_eq!(response["result"]["candidates"], json!([0x1800]));
+        let params = json!({"address": 0x1FF8, "length": 0x10});
+        let response = call(&mut server, &mut machine, "search_start", params);
+        assert_eq!(response["error"]["code"], -32602, "Search out of memory");
+        let params = json!({"address": 0x1800});
+        let response = call(&mut server, &mut machine, "freeze", params);
+        assert_eq!(response["result"], Value::Null);
+        let params = json!({"address": 0x2000});
+        let response = call(&mut server, &mut machine, "freeze", params);
+        assert_eq!(response["error"]["code"], -32602, "Froze out of memory");
+        machine.mem.space[0x1800] = 0;
+        call(&mut server, &mut machine, "run_frames", Value::Null);
+        assert_eq!(machine.mem.space[0x1800], 4, "Freeze wasn't applied");
+    }
+    #[test]
+    fn errors() {
+        let (mut server, mut machine) = setup();
+        let response = call(&mut server, &mut machine, "fly", Value::Null);
+        assert_eq!(response["error"]["code"], -32601);
+        let response = call(&mut server, &mut machine, "set_keys", json!({"keys": [16]}));
+        assert_eq!(response["error"]["code"], -32602);
+        let response: Value =
+            serde_json::from_str(&server.handle(&mut machine, "{oops").unwrap()).unwrap();
+        assert_eq!(response["error"]["code"], -32700);
+        let notification = json!({"jsonrpc": "2.0", "method": "step"}).to_string();
+        assert_eq!(server.handle(&mut machine, &notification), None);
+        assert_eq!(
+            machine.cpu.program_counter, 0x202,
+            "Notifications still run"
+        );
+    }
+    #[test]
+    fn socket() {
+        let (mut server, mut machine) = setup();
+        let port = server.port().expect("TCP servers have a port");
+        let mut client = TcpStream::connect(("127.0.0.1", port)).expect("Couldn't connect");
+        writeln!(
+            client,
+            "{}",
+            json!({"jsonrpc": "2.0", "id": 5, "method": "step"})
+        )
+        .unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(5)))
+            .unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut line = String::new();
+        for _ in 0..200 {
+            server.poll(&mut machine);
+            if reader.read_line(&mut line).is_ok() && line.ends_with('\n') {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        let response: Value = serde_json::from_str(&line).expect("No answer from the server");
+        assert_eq!(response["id"], 5);
+        assert_eq!(response["result"]["cycles"], 1);
+        assert!(server.attached());
+        drop(reader);
+        drop(client);
+        for _ in 0..200 {
+            server.poll(&mut machine);
+            if server.served() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(server.served(), "Server should notice the client left");
+    }
+    /// A socket that takes a few bytes, then would block every other write.
+    struct Slow {
+        input: Vec<u8>,
+        output: Rc<RefCell<Vec<u8>>>,
+        blocked: bool,
+    }
+    impl Read for Slow {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.input.is_empty() {
+                return Err(io::Error::from(ErrorKind::WouldBlock));
+            }
+            let size = buf.len().min(self.input.len());
+            buf[..size].copy_from_slice(&self.input[..size]);
+            self.input.drain(..size);
+            return Ok(size);
+        }
+    }
+    impl Write for Slow {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.blocked = !self.blocked;
+            if self.blocked {
+                return Err(io::Error::from(ErrorKind::WouldBlock));
+            }
+            let size = buf.len().min(16);
+            self.output.borrow_mut().extend_from_slice(&buf[..size]);
+            return Ok(size);
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+    #[test]
+    fn slow_client() {
+        let (mut server, mut machine) = setup();
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut input = Vec::new();
+        for id in 0..2 {
+            let request = json!({"jsonrpc": "2.0", "id": id, "method": "read_registers"});
+            writeln!(input, "{}", request).unwrap();
+        }
+        server.clients.push(Client {
+            stream: Box::new(Slow {
+                input: input,
+                output: output.clone(),
+                blocked: false,
+            }),
+            buffer: Vec::new(),
+            outgoing: Vec::new(),
+        });
+        server.poll(&mut machine);
+        assert!(server.attached(), "A full socket isn't a disconnect");
+        for _ in 0..200 {
+            server.poll(&mut machine);
+        }
+        assert!(server.attached());
+        let output = String::from_utf8(output.borrow().clone()).unwrap();
+        let ids: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap()["id"].clone())
+            .collect();
+        assert_eq!(ids, vec![json!(0), json!(1)], "Answers were cut");
+    }
+    #[test]
+    fn long_line() {
+        let (mut server, mut machine) = setup();
+        let output = Rc::new(RefCell::new(Vec::new()));
+        server.clients.push(Client {
+            stream: Box::new(Slow {
+                input: vec![b' '; super::max_line(&machine) + 2],
+                output: output.clone(),
+                blocked: false,
+            }),
+            buffer: Vec::new(),
+            outgoing: Vec::new(),
+        });
+        server.poll(&mut machine);
+        assert!(
+            !server.attached(),
+            "A line past the limit should drop the client"
+        );
+    }
+}
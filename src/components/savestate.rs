@@ -0,0 +1,418 @@
+//! # Save State Module
+//! ## Description
+//! Stores the whole machine in a file so it can be picked up at the exact same point later.
+//! ## Format
+//! Every number bigger than a byte is big endian, like the CHIP-8 itself.
+//! * The magic bytes `C8ST` and a version byte
+//! * V0 to VF, the program counter, I and both timers
+//! * The depth of the stack in 4 bytes followed by every address in it
+//! * A byte of flags: the quirks, if the machine is halted, if Fx0A is waiting, if it runs CHIP-8X, if the port was written, if it runs MegaChip
+//!   and if Fx0A ends on presses
+//! * The keys pressed since Fx0A started waiting, one bit per key
+//! * The width and height of the display, the number of planes and the ones selected,
+//!   then a bit per pixel row by row, for every plane one after the other
+//! * For CHIP-8X: the background, the colour of every zone row by row, the second keypad and both sides of the port
+//! * For MegaChip: if its mode is on, the top byte of I, the palette, the sprite size, alpha, blend mode,
+//!   if a collision colour is set and the colour,
+//!   then the colour number of every pixel and both frames, row by row. A sound that was playing isn't kept
+//! * The size of the memory in 4 bytes followed by all of it
+//!
+//! The rom itself is not stored apart, it's already in the memory.
+use super::chip8x::Chip8x;
+use super::display::{BitDisplay, Display};
+use super::keypad::KeyWait;
+use super::machine::Machine;
+use super::megachip::{Blend, MegaChip};
+use std::fs;
+
+/// Magic bytes at the start of every save state.
+const MAGIC: &[u8; 4] = b"C8ST";
+/// Changes whenever the format does, older states are refused.
+const VERSION: u8 = 7;
+
+/// Turn the machine into the bytes of a save state.
+pub fn save(machine: &Machine) -> Vec<u8> {
+    let cpu = &machine.cpu;
+    let mut data: Vec<u8> = Vec::new();
+    data.extend_from_slice(MAGIC);
+    data.push(VERSION);
+    data.extend_from_slice(&cpu.v);
+    data.extend_from_slice(&cpu.program_counter.to_be_bytes());
+    data.extend_from_slice(&cpu.i.to_be_bytes());
+    data.push(cpu.dt);
+    data.push(cpu.st);
+    data.extend_from_slice(&(cpu.stack.len() as u32).to_be_bytes());
+    for address in cpu.stack.iter() {
+        data.extend_from_slice(&address.to_be_bytes());
+    }
+    let flags = cpu.store_load_quirk as u8
+        | (cpu.shift_y as u8) << 1
+        | (machine.halted as u8) << 2
+        | (cpu.is_key_pressed_temp.is_some() as u8) << 3
+        | (cpu.chip8x.is_some() as u8) << 4
+        | (cpu
+            .chip8x
+            .as_ref()
+            .map_or(false, |chip8x| chip8x.port_out.is_some()) as u8)
+            << 5
+        | (cpu.megachip.is_some() as u8) << 6
+        | ((cpu.key_wait == KeyWait::Press) as u8) << 7;
+    data.push(flags);
+    let waiting_keys = cpu.is_key_pressed_temp.unwrap_or([false; 16]);
+    data.extend_from_slice(&pack_bits(&waiting_keys).to_be_bytes());
+    let (width, height) = (machine.state.width(), machine.state.height());
+    data.push(width as u8);
+    data.push(height as u8);
+    data.push(machine.state.planes() as u8);
+    data.push(machine.state.selected);
+    for plane in 0..machine.state.planes() {
+        for row in 0..height {
+            let bits = machine.state.plane_row(plane, row);
+            for byte in 0..width / 8 {
+                data.push((bits >> (BitDisplay::MAX_WIDTH - 8 - byte * 8)) as u8);
+            }
+        }
+    }
+    if let Some(chip8x) = &cpu.chip8x {
+        data.push(chip8x.background);
+        for row in 0..chip8x.zones.height() {
+            for zone in 0..chip8x.zones.width() {
+                data.push(chip8x.zones[zone][row]);
+            }
+        }
+        data.extend_from_slice(&pack_bits(&chip8x.keys).to_be_bytes());
+        data.push(chip8x.port_out.unwrap_or(0));
+        data.push(chip8x.port_in);
+    }
+    if let Some(megachip) = &cpu.megachip {
+        data.push(megachip.enabled as u8);
+        data.push(megachip.bank);
+        for colour in megachip.palette.iter() {
+            data.extend_from_slice(colour);
+        }
+        data.push(megachip.sprite_width);
+        data.push(megachip.sprite_height);
+        data.push(megachip.alpha);
+        data.push(megachip.blend as u8);
+        data.push(megachip.collision.is_some() as u8);
+        data.push(megachip.collision.unwrap_or(0));
+        for row in 0..megachip.indexes.height() {
+            for col in 0..megachip.indexes.width() {
+                data.push(megachip.indexes[col][row]);
+            }
+        }
+        for frame in [&megachip.frame, &megachip.shown].iter() {
+            for row in 0..frame.height() {
+                for col in 0..frame.width() {
+                    data.extend_from_slice(&frame[col][row]);
+                }
+            }
+        }
+    }
+    data.extend_from_slice(&(machine.mem.space.len() as u32).to_be_bytes());
+    data.extend_from_slice(&machine.mem.space);
+    return data;
+}
+
+/// Put the machine back in the state stored in the bytes, it's left untouched if they aren't valid.
+pub fn load(machine: &mut Machine, data: &[u8]) -> Result<(), &'static str> {
+    let mut reader = Reader { data, pos: 0 };
+    if reader.bytes(4)? != MAGIC {
+        return Err("Not a save state");
+    }
+    if reader.byte()? != VERSION {
+        return Err("Save state was made by another version");
+    }
+    let mut v = [0; 16];
+    v.copy_from_slice(reader.bytes(16)?);
+    let program_counter = reader.word()?;
+    let i = reader.word()?;
+    let dt = reader.byte()?;
+    let st = reader.byte()?;
+    let depth = reader.long()?;
+    let mut stack: Vec<u16> = Vec::new();
+    for _ in 0..depth {
+        stack.push(reader.word()?);
+    }
+    let flags = reader.byte()?;
+    let waiting_keys = unpack_bits(reader.word()?);
+    let width = reader.byte()? as usize;
+    let height = reader.byte()? as usize;
+    if width == 0 || width % 8 != 0 || width > BitDisplay::MAX_WIDTH || height == 0 {
+        return Err("Save state has a display of the wrong size");
+    }
+    let planes = reader.byte()? as usize;
+    let selected = reader.byte()?;
+    if planes == 0 || planes > 8 {
+        return Err("Save state has a display with the wrong planes");
+    }
+    let mut state = BitDisplay::with_planes(width, height, planes);
+    for plane in 0..planes {
+        // Drawing only touches the selected planes
+        state.selected = 1 << plane;
+        for row in 0..height {
+            for byte in 0..width / 8 {
+                state.draw_row(byte * 8, row, reader.byte()?);
+            }
+        }
+    }
+    state.selected = selected;
+    let chip8x = if flags & 16 > 0 {
+        let mut chip8x = Chip8x {
+            background: reader.byte()?,
+            ..Default::default()
+        };
+        for row in 0..chip8x.zones.height() {
+            for zone in 0..chip8x.zones.width() {
+                chip8x.zones[zone][row] = reader.byte()? & 0x7;
+            }
+        }
+        chip8x.keys = unpack_bits(reader.word()?);
+        let port_out = reader.byte()?;
+        chip8x.port_out = if flags & 32 > 0 { Some(port_out) } else { None };
+        chip8x.port_in = reader.byte()?;
+        Some(chip8x)
+    } else {
+        None
+    };
+    let megachip = if flags & 64 > 0 {
+        let mut megachip = MegaChip {
+            enabled: reader.byte()? > 0,
+            bank: reader.byte()?,
+            ..Default::default()
+        };
+        for colour in megachip.palette.iter_mut() {
+            colour.copy_from_slice(reader.bytes(4)?);
+        }
+        megachip.sprite_width = reader.byte()?;
+        megachip.sprite_height = reader.byte()?;
+        megachip.alpha = reader.byte()?;
+        megachip.blend = Blend::from_nibble(reader.byte()?)?;
+        let has_collision = reader.byte()? > 0;
+        let collision = reader.byte()?;
+        megachip.collision = if has_collision { Some(collision) } else { None };
+        for row in 0..megachip.indexes.height() {
+            for col in 0..megachip.indexes.width() {
+                megachip.indexes[col][row] = reader.byte()?;
+            }
+        }
+        for frame in [&mut megachip.frame, &mut megachip.shown].iter_mut() {
+            for row in 0..frame.height() {
+                for col in 0..frame.width() {
+                    frame[col][row].copy_from_slice(reader.bytes(3)?);
+                }
+            }
+        }
+        Some(megachip)
+    } else {
+        None
+    };
+    let size = reader.long()? as usize;
+    if size != machine.mem.space.len() {
+        return Err("Save state has a different memory size");
+    }
+    let memory = reader.bytes(size)?;
+
+    let cpu = &mut machine.cpu;
+    cpu.v = v;
+    cpu.program_counter = program_counter;
+    cpu.i = i;
+    cpu.dt = dt;
+    cpu.st = st;
+    cpu.stack = stack;
+    cpu.store_load_quirk = flags & 1 > 0;
+    cpu.shift_y = flags & 2 > 0;
+    cpu.key_wait = if flags & 128 > 0 {
+        KeyWait::Press
+    } else {
+        KeyWait::Release
+    };
+    cpu.chip8x = chip8x;
+    cpu.megachip = megachip;
+    cpu.is_key_pressed_temp = if flags & 8 > 0 {
+        Some(waiting_keys)
+    } else {
+        None
+    };
+    machine.halted = flags & 4 > 0;
+    machine.state = state;
+    machine.mem.space.copy_from_slice(memory);
+    return Ok(());
+}
+
+/// Write a save state file.
+pub fn save_file(path: &str, machine: &Machine) -> Result<(), &'static str> {
+    return fs::write(path, save(machine)).map_err(|_| "Couldn't write the save state");
+}
+
+/// Read a save state file into the machine.
+pub fn load_file(path: &str, machine: &mut Machine) -> Result<(), &'static str> {
+    let data = fs::read(path).map_err(|_| "Couldn't read the save state")?;
+    return load(machine, &data);
+}
+
+fn pack_bits(keys: &[bool; 16]) -> u16 {
+    let mut bits: u16 = 0;
+    for (idx, key) in keys.iter().enumerate() {
+        bits = bits | (*key as u16) << idx;
+    }
+    return bits;
+}
+
+fn unpack_bits(bits: u16) -> [bool; 16] {
+    let mut keys = [false; 16];
+    for (idx, key) in keys.iter_mut().enumerate() {
+        *key = (bits >> idx) & 1 > 0;
+    }
+    return keys;
+}
+
+/// Reads a save state from the start, failing if it ends too soon.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+        if self.pos + len > self.data.len() {
+            return Err("Save state is truncated");
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos = self.pos + len;
+        return Ok(bytes);
+    }
+    fn byte(&mut self) -> Result<u8, &'static str> {
+        return Ok(self.bytes(1)?[0]);
+    }
+    fn word(&mut self) -> Result<u16, &'static str> {
+        let bytes = self.bytes(2)?;
+        return Ok((bytes[0] as u16) << 8 | bytes[1] as u16);
+    }
+    fn long(&mut self) -> Result<u32, &'static str> {
+        return Ok((self.word()? as u32) << 16 | self.word()? as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::chip8x::Chip8x;
+    use super::super::display::{BitDisplay, Display};
+    use super::super::keypad::KeyWait;
+    use super::super::machine::Machine;
+    use super::super::megachip::{Blend, MegaChip};
+    use super::{load, save};
+    #[test]
+    fn round_trip() {
+        // 6005 A300 F055 2208: store V0 at 0x300 and call a subroutine
+        let mut machine = Machine::new(&[0x60, 0x05, 0xA3, 0x00, 0xF0, 0x55, 0x22, 0x08])
+            .expect("Couldn't create the machine");
+        machine.cpu.shift_y = true;
+        machine.cpu.key_wait = KeyWait::Press;
+        machine.cpu.dt = 30;
+        machine.cpu.is_key_pressed_temp = Some([true; 16]);
+        machine.state.set_pixel(63, 31, true);
+        for _ in 0..4 {
+            machine.step().expect("Program shouldn't fail");
+        }
+        let data = save(&machine);
+        let mut restored = Machine::new(&[]).expect("Couldn't create the machine");
+        load(&mut restored, &data).expect("Save state should load");
+        assert_eq!(restored.cpu.v, machine.cpu.v, "Registers are different");
+        assert_eq!(restored.cpu.program_counter, machine.cpu.program_counter);
+        assert_eq!(restored.cpu.i, machine.cpu.i);
+        assert_eq!(restored.cpu.dt, 30);
+        assert_eq!(restored.cpu.stack, machine.cpu.stack, "Stack is different");
+        assert!(restored.cpu.shift_y, "Quirks are different");
+        assert_eq!(restored.cpu.key_wait, KeyWait::Press);
+        assert_eq!(restored.cpu.is_key_pressed_temp, Some([true; 16]));
+        assert!(restored.state.pixel(63, 31), "Display is different");
+        assert!(!restored.state.pixel(0, 0), "Display is different");
+        assert_eq!(restored.mem.space[0x300], 0x05, "Memory is different");
+        assert_eq!(save(&restored), data, "Saving again should be the same");
+    }
+    #[test]
+    fn planes() {
+        let mut machine = Machine::new(&[0x12, 0x00]).expect("Couldn't create the machine");
+        machine.state = BitDisplay::with_planes(128, 64, 2);
+        machine.state.set_pixel(0, 0, true);
+        machine.state.selected = 2;
+        machine.state.set_pixel(127, 63, true);
+        machine.state.selected = 3;
+        let mut restored = Machine::new(&[]).expect("Couldn't create the machine");
+        load(&mut restored, &save(&machine)).expect("Save state should load");
+        assert_eq!(restored.state, machine.state, "Planes are different");
+        assert_eq!(restored.state.plane_row(1, 0), 0, "Planes were mixed");
+    }
+    #[test]
+    fn deep_stack() {
+        let mut machine = Machine::new(&[0x12, 0x00]).expect("Couldn't create the machine");
+        machine.cpu.stack = vec![0x202; 300];
+        let mut restored = Machine::new(&[]).expect("Couldn't create the machine");
+        load(&mut restored, &save(&machine)).expect("Save state should load");
+        assert_eq!(restored.cpu.stack.len(), 300, "Stack depth wrapped");
+    }
+    #[test]
+    fn chip8x() {
+        let mut machine = Machine::new(&[0x12, 0x00]).expect("Couldn't create the machine");
+        let mut chip8x = Chip8x::default();
+        chip8x.cycle_background();
+        chip8x.colour_rows(0x07, 31, 1, 6);
+        chip8x.keys[0xA] = true;
+        chip8x.port_out = Some(0x42);
+        machine.cpu.chip8x = Some(chip8x.clone());
+        let data = save(&machine);
+        let mut restored = Machine::new(&[]).expect("Couldn't create the machine");
+        load(&mut restored, &data).expect("Save state should load");
+        assert_eq!(restored.cpu.chip8x, Some(chip8x), "CHIP-8X is different");
+        machine.cpu.chip8x = None;
+        load(&mut restored, &save(&machine)).expect("Save state should load");
+        assert_eq!(restored.cpu.chip8x, None, "CHIP-8X should be gone");
+    }
+    #[test]
+    fn megachip() {
+        let mut machine = Machine::with_memory(&[0x12, 0x00], 0x200, 0x200, 0x1000000)
+            .expect("Couldn't create the machine");
+        let mut megachip = MegaChip {
+            enabled: true,
+            bank: 2,
+            blend: Blend::Add,
+            ..Default::default()
+        };
+        megachip.palette[7] = [0x80, 0x01, 0x02, 0x03];
+        megachip.indexes[255][191] = 7;
+        megachip.shown[3][4] = [0x01, 0x02, 0x03];
+        machine.cpu.megachip = Some(megachip.clone());
+        machine.mem.space[0xFFFFFF] = 0x42;
+        let data = save(&machine);
+        let mut restored = Machine::new(&[]).expect("Couldn't create the machine");
+        assert!(
+            load(&mut restored, &data).is_err(),
+            "Loaded into a smaller memory"
+        );
+        let mut restored = Machine::with_memory(&[], 0x200, 0x200, 0x1000000)
+            .expect("Couldn't create the machine");
+        load(&mut restored, &data).expect("Save state should load");
+        assert_eq!(
+            restored.cpu.megachip,
+            Some(megachip),
+            "MegaChip is different"
+        );
+        assert_eq!(restored.mem.space[0xFFFFFF], 0x42, "Memory is different");
+    }
+    #[test]
+    fn wrong_data() {
+        let machine = Machine::new(&[0x12, 0x00]).expect("Couldn't create the machine");
+        let data = save(&machine);
+        let mut other = Machine::new(&[0x13, 0x00]).expect("Couldn't create the machine");
+        assert!(load(&mut other, b"NOPE").is_err(), "Bad magic accepted");
+        assert!(
+            load(&mut other, &data[..data.len() - 1]).is_err(),
+            "Truncated state accepted"
+        );
+        assert_eq!(
+            other.mem.space[0x200], 0x13,
+            "Failed load changed the machine"
+        );
+    }
+}
@@ -0,0 +1,98 @@
+//! # Screen Module
+//! ## Description
+//! The pixels of the display, for any resolution: 64x32 on the usual CHIP-8, 64x64 with the two-page hi-res interpreter.
+//! ## Layout
+//! Pixels are indexed by column first, `screen[x][y]`, x going right and y going down from the top left corner.
+//! The same type holds the lit pixels of the machine and the shades the display filter turns them into.
+use std::ops::{Index, IndexMut};
+use std::slice;
+
+/// A grid of pixels, column by column.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Screen<T = bool> {
+    columns: Vec<Vec<T>>,
+}
+
+impl<T: Clone + Default> Default for Screen<T> {
+    fn default() -> Screen<T> {
+        return Screen::new(Screen::<T>::WIDTH, Screen::<T>::HEIGHT);
+    }
+}
+
+impl<T: Clone + Default> Screen<T> {
+    /// Width of the usual CHIP-8 display.
+    pub const WIDTH: usize = 64;
+    /// Height of the usual CHIP-8 display.
+    pub const HEIGHT: usize = 32;
+    /// Create a screen with every pixel off.
+    pub fn new(width: usize, height: usize) -> Screen<T> {
+        return Screen {
+            columns: vec![vec![T::default(); height]; width],
+        };
+    }
+    /// Turn every pixel off.
+    pub fn clear(&mut self) {
+        self.fill(T::default());
+    }
+    /// Set every pixel to the same value.
+    pub fn fill(&mut self, value: T) {
+        for column in self.columns.iter_mut() {
+            for pixel in column.iter_mut() {
+                *pixel = value.clone();
+            }
+        }
+    }
+}
+
+impl<T> Screen<T> {
+    pub fn width(&self) -> usize {
+        return self.columns.len();
+    }
+    pub fn height(&self) -> usize {
+        return self.columns.first().map_or(0, |column| column.len());
+    }
+    /// Every column, left to right.
+    pub fn iter(&self) -> slice::Iter<'_, Vec<T>> {
+        return self.columns.iter();
+    }
+    /// Turn every pixel into something else, keeping the size.
+    pub fn map<U>(&self, mut f: impl FnMut(&T) -> U) -> Screen<U> {
+        return Screen {
+            columns: self
+                .columns
+                .iter()
+                .map(|column| column.iter().map(&mut f).collect())
+                .collect(),
+        };
+    }
+}
+
+impl<T> Index<usize> for Screen<T> {
+    type Output = [T];
+    fn index(&self, x: usize) -> &[T] {
+        return &self.columns[x];
+    }
+}
+
+impl<T> IndexMut<usize> for Screen<T> {
+    fn index_mut(&mut self, x: usize) -> &mut [T] {
+        return &mut self.columns[x];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Screen;
+    #[test]
+    fn size() {
+        let mut screen: Screen = Screen::default();
+        assert_eq!((screen.width(), screen.height()), (64, 32));
+        screen[63][31] = true;
+        let shades = screen.map(|pixel| *pixel as u8 * 0xFF);
+        assert_eq!(shades[63][31], 0xFF);
+        screen.clear();
+        assert!(!screen[63][31], "Screen should be cleared");
+        let tall: Screen<u8> = Screen::new(64, 64);
+        assert_eq!(tall.height(), 64);
+    }
+}
@@ -0,0 +1,518 @@
+//! # Script Module
+//! ## Description
+//! Runs [Rhai](https://rhai.rs) scripts next to a rom: bots that play, HUDs that show what a game keeps in memory
+//! and regression tests, all without building the emulator again.
+//! ## Hooks
+//! Scripts run once when loaded, and register closures or `Fn("name")` pointers to be called later:
+//! * `on_frame(|| ...)`: at the end of every frame, after the timers ticked
+//! * `on_pc(address, || ...)`: right before the instruction at the address runs
+//! * `on_write(address, |address, value| ...)`: after an instruction writes the byte, even if it didn't change
+//!
+//! Only instructions count as writes, `poke` and cheats don't call hooks.
+//!
+//! Addresses go up to the end of memory, MegaChip banks included, the program counter only reaches 0xFFFF.
+//! ## Functions
+//! * `v(x)`, `set_v(x, value)`, `i()`, `set_i(value)`, `pc()`, `set_pc(address)`
+//! * `dt()`, `set_dt(value)`, `st()`, `set_st(value)`
+//! * `peek(address)` and `poke(address, value)`
+//! * `key(key)`, `press(key)` and `release(key)`
+//! * `pixel(x, y)` and `frame()`, the number of frames since the script was loaded
+//! * `text(x, y, text)`: draw text over the display, it stays until the end of the next frame
+//! * `stop()`: ask the frontend to quit, `throw` fails the script instead
+//!
+//! ```rhai
+//! let lives = 3;
+//! on_write(0x3F0, |address, value| lives = value);
+//! on_frame(|| text(0, 0, `LIVES ${lives}`));
+//! ```
+//!
+//! Numbers out of range fail the script. A failed script stops calling hooks and the rom keeps running.
+use super::decoder::Instruction;
+use super::display::Display;
+use super::machine::Machine;
+use super::screen::Screen;
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, FuncArgs, Scope, AST};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::mem;
+use std::rc::Rc;
+
+/// Text drawn over the display.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Text {
+    pub x: usize,
+    pub y: usize,
+    pub text: String,
+}
+
+/// What the functions registered in the engine work on.
+struct Host {
+    /// The machine of the frontend, swapped in while the script runs
+    machine: Machine,
+    frame_hooks: Vec<FnPtr>,
+    pc_hooks: BTreeMap<u16, Vec<FnPtr>>,
+    write_hooks: BTreeMap<usize, Vec<FnPtr>>,
+    /// Text drawn during the current frame
+    texts: Vec<Text>,
+    frames: i64,
+    stopped: bool,
+}
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// A loaded script and the hooks it registered.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    host: Rc<RefCell<Host>>,
+    /// Text drawn during the last frame
+    overlay: Vec<Text>,
+    /// Address a breakpoint stopped at, its hooks already ran
+    stopped_at: Option<u16>,
+    error: Option<String>,
+    failed: bool,
+}
+
+impl Script {
+    /// Compile a script and run it once, so it can register its hooks.
+    pub fn new(source: &str, machine: &mut Machine) -> Result<Script, String> {
+        let host = Rc::new(RefCell::new(Host {
+            machine: Machine::default(),
+            frame_hooks: Vec::new(),
+            pc_hooks: BTreeMap::new(),
+            write_hooks: BTreeMap::new(),
+            texts: Vec::new(),
+            frames: 0,
+            stopped: false,
+        }));
+        let engine = engine(&host);
+        let ast = engine.compile(source).map_err(|err| err.to_string())?;
+        let script = Script {
+            engine: engine,
+            ast: ast,
+            host: host,
+            overlay: Vec::new(),
+            stopped_at: None,
+            error: None,
+            failed: false,
+        };
+        mem::swap(machine, &mut script.host.borrow_mut().machine);
+        let result = script
+            .engine
+            .run_ast_with_scope(&mut Scope::new(), &script.ast);
+        mem::swap(machine, &mut script.host.borrow_mut().machine);
+        result.map_err(|err| err.to_string())?;
+        return Ok(script);
+    }
+    /// Read a script file.
+    pub fn load(path: &str, machine: &mut Machine) -> Result<Script, String> {
+        let source = fs::read_to_string(path).map_err(|_| "Couldn't read the script".to_owned())?;
+        return Script::new(&source, machine);
+    }
+    /// Call a hook with the machine swapped in, the first error stops the script.
+    fn call(&mut self, machine: &mut Machine, hook: &FnPtr, args: impl FuncArgs) {
+        if self.failed {
+            return;
+        }
+        mem::swap(machine, &mut self.host.borrow_mut().machine);
+        let result = hook.call::<Dynamic>(&self.engine, &self.ast, args);
+        mem::swap(machine, &mut self.host.borrow_mut().machine);
+        if let Err(err) = result {
+            self.error = Some(err.to_string());
+            self.failed = true;
+        }
+    }
+    /// Run one instruction like Machine::step, calling the hooks on its address and on the bytes it writes.
+    pub fn step(&mut self, machine: &mut Machine) -> Result<&'static str, &'static str> {
+        let address = machine.cpu.program_counter;
+        if !machine.halted && self.stopped_at != Some(address) {
+            let hooks = self.host.borrow().pc_hooks.get(&address).cloned();
+            for hook in hooks.unwrap_or_default() {
+                self.call(machine, &hook, ());
+            }
+        }
+        let writes = written(machine);
+        let result = machine.step();
+        self.stopped_at = match result {
+            Err("Breakpoint") => Some(address),
+            _ => None,
+        };
+        if result.is_ok() {
+            for written in writes {
+                let hooks = self.host.borrow().write_hooks.get(&written).cloned();
+                let value = machine.mem.space[written] as i64;
+                for hook in hooks.unwrap_or_default() {
+                    self.call(machine, &hook, (written as i64, value));
+                }
+            }
+        }
+        return result;
+    }
+    /// Call the frame hooks, for frontends that tick the timers themselves.
+    pub fn end_frame(&mut self, machine: &mut Machine) {
+        self.host.borrow_mut().frames += 1;
+        let hooks = self.host.borrow().frame_hooks.clone();
+        for hook in hooks {
+            self.call(machine, &hook, ());
+        }
+        self.overlay = mem::take(&mut self.host.borrow_mut().texts);
+    }
+    /// Run the cycles of a frame, tick the timers and call the frame hooks, like Machine::run_frame.
+    pub fn run_frame(&mut self, machine: &mut Machine, cycles: u32) -> bool {
+        for _ in 0..cycles {
+            if self.step(machine).is_err() {
+                break;
+            }
+        }
+        let beep = machine.tick_timers();
+        self.end_frame(machine);
+        return beep;
+    }
+    /// Get the error that stopped the script, if it failed since the previous call.
+    pub fn take_error(&mut self) -> Option<String> {
+        return self.error.take();
+    }
+    /// If the script asked to quit.
+    pub fn stopped(&self) -> bool {
+        return self.host.borrow().stopped;
+    }
+    /// Text drawn during the last frame.
+    pub fn texts(&self) -> &[Text] {
+        return &self.overlay;
+    }
+    /// Pixels of the text drawn during the last frame, on a screen of the given size.
+    pub fn overlay(&self, width: usize, height: usize) -> Screen {
+        let mut pixels: Screen = Screen::new(width, height);
+        for text in self.overlay.iter() {
+            for (idx, c) in text.text.chars().enumerate() {
+                let rows = glyph(c);
+                for (row, bits) in rows.iter().enumerate() {
+                    for col in 0..3 {
+                        let x = text.x + idx * 4 + col;
+                        let y = text.y + row;
+                        if bits & (0b100 >> col) != 0 && x < width && y < height {
+                            pixels[x][y] = true;
+                        }
+                    }
+                }
+            }
+        }
+        return pixels;
+    }
+}
+
+/// Addresses the instruction at the program counter writes to.
+fn written(machine: &Machine) -> Vec<usize> {
+    let pc = machine.cpu.program_counter as usize;
+    let space = &machine.mem.space;
+    if pc + 1 >= space.len() {
+        return Vec::new();
+    }
+    let op_code = (space[pc] as u16) << 8 | space[pc + 1] as u16;
+    let (_, decoder) = machine.cpu.decoder();
+    let count = match decoder(op_code) {
+        Ok(Instruction::Bcd(_)) => 3,
+        Ok(Instruction::Store(x)) => x as usize + 1,
+        _ => 0,
+    };
+    let address = machine.cpu.address();
+    return (address..address + count).collect();
+}
+
+/// Addresses the program counter can be at, the memory up to 0xFFFF.
+fn program_space(machine: &Machine) -> usize {
+    return machine.mem.space.len().min(0x10000);
+}
+
+/// Check a number fits in a range, the error names what it is.
+fn within(value: i64, max: usize, what: &str) -> ScriptResult<usize> {
+    if value < 0 || value as usize >= max {
+        return Err(format!("{} {} is out of range", what, value).into());
+    }
+    return Ok(value as usize);
+}
+
+/// Build an engine with the machine functions and the hooks registered.
+fn engine(host: &Rc<RefCell<Host>>) -> Engine {
+    let mut engine = Engine::new();
+    let h = host.clone();
+    engine.register_fn("on_frame", move |hook: FnPtr| {
+        h.borrow_mut().frame_hooks.push(hook);
+    });
+    let h = host.clone();
+    engine.register_fn(
+        "on_pc",
+        move |address: i64, hook: FnPtr| -> ScriptResult<()> {
+            let address = within(address, program_space(&h.borrow().machine), "Address")? as u16;
+            h.borrow_mut()
+                .pc_hooks
+                .entry(address)
+                .or_default()
+                .push(hook);
+            return Ok(());
+        },
+    );
+    let h = host.clone();
+    engine.register_fn(
+        "on_write",
+        move |address: i64, hook: FnPtr| -> ScriptResult<()> {
+            let address = within(address, h.borrow().machine.mem.space.len(), "Address")?;
+            h.borrow_mut()
+                .write_hooks
+                .entry(address)
+                .or_default()
+                .push(hook);
+            return Ok(());
+        },
+    );
+    let h = host.clone();
+    engine.register_fn("v", move |x: i64| -> ScriptResult<i64> {
+        let x = within(x, 16, "Register")?;
+        return Ok(h.borrow().machine.cpu.v[x] as i64);
+    });
+    let h = host.clone();
+    engine.register_fn("set_v", move |x: i64, value: i64| -> ScriptResult<()> {
+        let x = within(x, 16, "Register")?;
+        h.borrow_mut().machine.cpu.v[x] = within(value, 0x100, "Value")? as u8;
+        return Ok(());
+    });
+    let h = host.clone();
+    engine.register_fn("i", move || h.borrow().machine.cpu.i as i64);
+    let h = host.clone();
+    engine.register_fn("set_i", move |value: i64| -> ScriptResult<()> {
+        h.borrow_mut().machine.cpu.i = within(value, 0x10000, "Value")? as u16;
+        return Ok(());
+    });
+    let h = host.clone();
+    engine.register_fn("pc", move || h.borrow().machine.cpu.program_counter as i64);
+    let h = host.clone();
+    engine.register_fn("set_pc", move |address: i64| -> ScriptResult<()> {
+        let mut host = h.borrow_mut();
+        let address = within(address, program_space(&host.machine), "Address")?;
+        host.machine.cpu.program_counter = address as u16;
+        return Ok(());
+    });
+    let h = host.clone();
+    engine.register_fn("dt", move || h.borrow().machine.cpu.dt as i64);
+    let h = host.clone();
+    engine.register_fn("set_dt", move |value: i64| -> ScriptResult<()> {
+        h.borrow_mut().machine.cpu.dt = within(value, 0x100, "Value")? as u8;
+        return Ok(());
+    });
+    let h = host.clone();
+    engine.register_fn("st", move || h.borrow().machine.cpu.st as i64);
+    let h = host.clone();
+    engine.register_fn("set_st", move |value: i64| -> ScriptResult<()> {
+        h.borrow_mut().machine.cpu.st = within(value, 0x100, "Value")? as u8;
+        return Ok(());
+    });
+    let h = host.clone();
+    engine.register_fn("peek", move |address: i64| -> ScriptResult<i64> {
+        let host = h.borrow();
+        let address = within(address, host.machine.mem.space.len(), "Address")?;
+        return Ok(host.machine.mem.space[address] as i64);
+    });
+    let h = host.clone();
+    engine.register_fn(
+        "poke",
+        move |address: i64, value: i64| -> ScriptResult<()> {
+            let mut host = h.borrow_mut();
+            let address = within(address, host.machine.mem.space.len(), "Address")?;
+            host.machine.mem.space[address] = within(value, 0x100, "Value")? as u8;
+            return Ok(());
+        },
+    );
+    let h = host.clone();
+    engine.register_fn("key", move |key: i64| -> ScriptResult<bool> {
+        return Ok(h.borrow().machine.keypad.keys()[within(key, 16, "Key")?]);
+    });
+    let h = host.clone();
+    engine.register_fn("press", move |key: i64| -> ScriptResult<()> {
+        h.borrow_mut()
+            .machine
+            .set_key(within(key, 16, "Key")?, true);
+        return Ok(());
+    });
+    let h = host.clone();
+    engine.register_fn("release", move |key: i64| -> ScriptResult<()> {
+        h.borrow_mut()
+            .machine
+            .set_key(within(key, 16, "Key")?, false);
+        return Ok(());
+    });
+    let h = host.clone();
+    engine.register_fn("pixel", move |x: i64, y: i64| -> ScriptResult<bool> {
+        let host = h.borrow();
+        let state = &host.machine.state;
+        let (x, y) = (
+            within(x, state.width(), "Column")?,
+            within(y, state.height(), "Row")?,
+        );
+        return Ok(state.pixel(x, y));
+    });
+    let h = host.clone();
+    engine.register_fn("frame", move || h.borrow().frames);
+    let h = host.clone();
+    engine.register_fn(
+        "text",
+        move |x: i64, y: i64, text: &str| -> ScriptResult<()> {
+            let (width, height) = (
+                h.borrow().machine.state.width(),
+                h.borrow().machine.state.height(),
+            );
+            let (x, y) = (within(x, width, "Column")?, within(y, height, "Row")?);
+            h.borrow_mut().texts.push(Text {
+                x: x,
+                y: y,
+                text: text.to_owned(),
+            });
+            return Ok(());
+        },
+    );
+    let h = host.clone();
+    engine.register_fn("stop", move || h.borrow_mut().stopped = true);
+    return engine;
+}
+
+/// Rows of a 3x5 character, the high bit is the left column. Lowercase is drawn as uppercase.
+fn glyph(c: char) -> [u8; 5] {
+    return match c.to_ascii_uppercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        ' ' => [0, 0, 0, 0, 0],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        _ => [0b110, 0b001, 0b010, 0b000, 0b010],
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::machine::Machine;
+    use super::super::platform::Platform;
+    use super::{Script, Text};
+    /// 6005 A300 F033 6106 1208: store the digits of 5 at 0x300, then loop
+    const PROGRAM: [u8; 10] = [0x60, 0x05, 0xA3, 0x00, 0xF0, 0x33, 0x61, 0x06, 0x12, 0x08];
+    #[test]
+    fn hooks() {
+        let mut machine = Machine::new(&PROGRAM).unwrap();
+        let source = r#"
+            let writes = [];
+            on_pc(0x206, || set_v(0, 9));
+            on_write(0x302, |address, value| writes.push([address, value]));
+            on_frame(|| {
+                poke(0x310, writes.len());
+                text(1, 2, `V0 ${v(0)}`);
+                press(3);
+            });
+        "#;
+        let mut script = Script::new(source, &mut machine).expect("Script should load");
+        script.run_frame(&mut machine, 10);
+        assert_eq!(script.take_error(), None);
+        assert_eq!(machine.cpu.v[0], 9, "Program counter hook didn't run");
+        assert_eq!(machine.mem.space[0x302], 5);
+        assert_eq!(machine.mem.space[0x310], 1, "Write hook didn't run once");
+        assert!(machine.keypad.keys()[3], "Key wasn't pressed");
+        assert_eq!(
+            script.texts(),
+            &[Text {
+                x: 1,
+                y: 2,
+                text: "V0 9".to_owned()
+            }]
+        );
+        let overlay = script.overlay(64, 32);
+        assert!(overlay[1][2] && !overlay[0][2], "Text wasn't drawn");
+    }
+    #[test]
+    fn breakpoint() {
+        let mut machine = Machine::new(&PROGRAM).unwrap();
+        machine.breakpoints.insert(0x202);
+        let source = "on_pc(0x202, || poke(0x320, peek(0x320) + 1));";
+        let mut script = Script::new(source, &mut machine).unwrap();
+        script.run_frame(&mut machine, 10);
+        assert_eq!(machine.take_breakpoint(), Some(0x202));
+        script.run_frame(&mut machine, 10);
+        assert_eq!(machine.mem.space[0x320], 1, "Hook ran twice on resuming");
+    }
+    #[test]
+    fn errors() {
+        let mut machine = Machine::new(&PROGRAM).unwrap();
+        assert!(Script::new("let x = ;", &mut machine).is_err());
+        assert!(Script::new("poke(0x2000, 1);", &mut machine).is_err());
+        let source = "on_frame(|| { stop(); throw \"Lost a life\"; });";
+        let mut script = Script::new(source, &mut machine).unwrap();
+        script.run_frame(&mut machine, 10);
+        assert!(script.stopped());
+        let error = script.take_error().expect("Script should fail");
+        assert!(error.contains("Lost a life"), "Wrong error: {}", error);
+        script.run_frame(&mut machine, 10);
+        assert_eq!(script.take_error(), None, "Failed scripts shouldn't run");
+    }
+    #[test]
+    fn bounds() {
+        let mut machine = Machine::new(&PROGRAM).unwrap();
+        let source = "on_pc(0x1FFE, || 0); on_write(0x1FFF, |a, v| 0); set_pc(0x1FFE);";
+        assert!(Script::new(source, &mut machine).is_ok());
+        assert!(Script::new("on_write(0x2000, |a, v| 0);", &mut machine).is_err());
+        assert!(Script::new("set_pc(0x2000);", &mut machine).is_err());
+    }
+    #[test]
+    fn megachip_writes() {
+        // 0112 0300 6007 F033 1208: I = 0x120300, then store the digits of 7 there
+        let program = [0x01, 0x12, 0x03, 0x00, 0x60, 0x07, 0xF0, 0x33, 0x12, 0x08];
+        let mut machine = Machine::with_platform(
+            &program,
+            Platform::MegaChip,
+            Platform::MegaChip.load_address(),
+            Platform::MegaChip.entry_point(),
+        )
+        .unwrap();
+        let source = "on_write(0x120302, |address, value| poke(0x400, value));";
+        let mut script = Script::new(source, &mut machine).expect("Script should load");
+        script.run_frame(&mut machine, 10);
+        assert_eq!(script.take_error(), None);
+        assert_eq!(machine.mem.space[0x120302], 7);
+        assert_eq!(machine.mem.space[0x400], 7, "Write hook missed the bank");
+    }
+}
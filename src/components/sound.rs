@@ -1,50 +1,555 @@
+//! # Sound Module
+//! ## Description
+//! The CHIP-8 has a single buzzer, ringing while the sound timer is above 0.
+//! The waveform and frequency were never specified, so they can be configured here.
+//! ## Synthesis
+//! The tone is generated in code, sample by sample:
+//! * The oscillator never resets its phase, so the wave stays continuous between beeps
+//! * Beeps fade in and out over a couple of milliseconds to avoid clicks
+//! * A beep lasts exactly as long as the value loaded by Fx18, counted in samples instead of redraws
+//! ## Digitized sound
+//! MegaChip programs can also play 8 bit samples, mixed over the buzzer at the same volume.
+//! ## Sinks
+//! Frontends don't talk to the audio device directly, they go through an AudioSink:
+//! * KiraSink plays the beeper through the speakers
+//! * NullSink drops everything, for machines without a sound device
+//! * WavSink renders the beeper to a WAV file, one emulated frame at a time, so the output is always the same
 use kira::{
-    arrangement::{handle::ArrangementHandle, Arrangement, LoopArrangementSettings},
-    instance::{InstanceSettings, PauseInstanceSettings, ResumeInstanceSettings},
-    sound::{Sound, SoundSettings},
-};
-use kira::{
+    audio_stream::AudioStream,
     manager::{AudioManager, AudioManagerSettings},
-    sound::handle::SoundHandle,
+    mixer::TrackIndex,
+    Frame,
 };
-pub struct SoundManager {
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Sample rate of the audio files.
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// Shape of the buzzer wave.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+}
+
+impl Waveform {
+    pub fn parse(text: &str) -> Result<Waveform, &'static str> {
+        match text {
+            "square" => Ok(Waveform::Square),
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            _ => Err("Unknown waveform"),
+        }
+    }
+    /// Value of the wave at a point of its cycle, from 0.0 to 1.0.
+    fn sample(&self, phase: f32) -> f32 {
+        match self {
+            Waveform::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Sine => (phase * 2.0 * PI).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+        }
+    }
+}
+
+/// Sound of the buzzer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tone {
+    pub waveform: Waveform,
+    /// Pitch in hertz
+    pub frequency: f32,
+    /// From 0.0 to 1.0
+    pub volume: f32,
+}
+
+impl Default for Tone {
+    fn default() -> Tone {
+        Tone {
+            waveform: Waveform::Square,
+            frequency: 440.0,
+            volume: 0.25,
+        }
+    }
+}
+
+impl Tone {
+    /// Parse a pitch in hertz, it has to be above 0.
+    pub fn parse_frequency(text: &str) -> Result<f32, &'static str> {
+        return match text.parse::<f32>() {
+            Ok(frequency) if frequency.is_finite() && frequency > 0.0 => Ok(frequency),
+            Ok(_) => Err("The pitch must be above 0 hertz"),
+            Err(_) => Err("The pitch is not a number"),
+        };
+    }
+    /// Parse a volume, from 0.0 to 1.0.
+    pub fn parse_volume(text: &str) -> Result<f32, &'static str> {
+        return match text.parse::<f32>() {
+            Ok(volume) if (0.0..=1.0).contains(&volume) => Ok(volume),
+            Ok(_) => Err("The volume goes from 0.0 to 1.0"),
+            Err(_) => Err("The volume is not a number"),
+        };
+    }
+}
+
+/// Generates the buzzer, one sample at a time.
+#[derive(Debug)]
+pub struct Beeper {
+    pub tone: Tone,
+    /// Position inside the current cycle of the wave
+    phase: f32,
+    /// Seconds left until the beep stops
+    remaining: f64,
+    /// Current volume of the fade in and fade out
+    gain: f32,
+}
+
+impl Beeper {
+    /// Length of the fades.
+    const RAMP_SECONDS: f32 = 0.002;
+    /// Rate the sound timer counts down at.
+    const TICKS_PER_SECOND: f64 = 60.0;
+    pub fn new(tone: Tone) -> Beeper {
+        Beeper {
+            tone,
+            phase: 0.0,
+            remaining: 0.0,
+            gain: 0.0,
+        }
+    }
+    /// Ring for as long as a sound timer loaded with `ticks` would last, 0 stops it.
+    pub fn beep_for(&mut self, ticks: u8) {
+        self.remaining = ticks as f64 / Beeper::TICKS_PER_SECOND;
+    }
+    /// If the beeper is making any sound, including the fade out.
+    pub fn is_ringing(&self) -> bool {
+        return self.remaining > 0.0 || self.gain > 0.0;
+    }
+    /// Get the next sample, `dt` seconds after the last one.
+    pub fn next_sample(&mut self, dt: f64) -> f32 {
+        let target = if self.remaining > 0.0 { 1.0 } else { 0.0 };
+        let step = dt as f32 / Beeper::RAMP_SECONDS;
+        if self.gain < target {
+            self.gain = (self.gain + step).min(target);
+        } else if self.gain > target {
+            self.gain = (self.gain - step).max(target);
+        }
+        self.remaining = (self.remaining - dt).max(0.0);
+        let value = self.tone.waveform.sample(self.phase) * self.gain * self.tone.volume;
+        self.phase = (self.phase + self.tone.frequency * dt as f32).fract();
+        return value;
+    }
+    /// Fill a buffer with consecutive samples at the given sample rate.
+    pub fn fill(&mut self, out: &mut [f32], sample_rate: u32) {
+        let dt = 1.0 / sample_rate as f64;
+        for sample in out.iter_mut() {
+            *sample = self.next_sample(dt);
+        }
+    }
+}
+
+/// A digitized sound, unsigned 8 bit samples.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    /// Samples per second
+    pub rate: u16,
+    pub data: Vec<u8>,
+    /// Start over at the end instead of stopping
+    pub looping: bool,
+}
+
+/// Plays a digitized sound at any output sample rate.
+#[derive(Debug, Default)]
+pub struct SamplePlayer {
+    pub volume: f32,
+    sample: Option<Sample>,
+    /// Position inside the sample, in its own samples
+    position: f64,
+}
+
+impl SamplePlayer {
+    pub fn new(volume: f32) -> SamplePlayer {
+        return SamplePlayer {
+            volume: volume,
+            ..Default::default()
+        };
+    }
+    /// Start playing a sound from its beginning, None stops the current one.
+    pub fn play(&mut self, sample: Option<Sample>) {
+        self.sample = sample;
+        self.position = 0.0;
+    }
+    pub fn is_playing(&self) -> bool {
+        return self.sample.is_some();
+    }
+    /// Get the next sample, `dt` seconds after the last one.
+    pub fn next_sample(&mut self, dt: f64) -> f32 {
+        let sample = match &self.sample {
+            Some(sample) if !sample.data.is_empty() => sample,
+            _ => return 0.0,
+        };
+        let len = sample.data.len() as f64;
+        if self.position >= len {
+            if !sample.looping {
+                self.sample = None;
+                return 0.0;
+            }
+            self.position = self.position % len;
+        }
+        let value = (sample.data[self.position as usize] as f32 - 128.0) / 128.0;
+        self.position = self.position + sample.rate as f64 * dt;
+        return value * self.volume;
+    }
+}
+
+/// Writes mono 16 bit PCM audio files, the sizes in the header are filled in by finish().
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    samples: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create(path: &str) -> Result<WavWriter<BufWriter<File>>, &'static str> {
+        let file = File::create(path).map_err(|_| "Couldn't create the WAV file")?;
+        return WavWriter::new(BufWriter::new(file));
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W) -> Result<WavWriter<W>, &'static str> {
+        let mut header: Vec<u8> = Vec::with_capacity(44);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        header.extend_from_slice(&1u16.to_le_bytes()); // Mono
+        header.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+        header.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        out.write_all(&header)
+            .map_err(|_| "Couldn't write the WAV header")?;
+        return Ok(WavWriter { out, samples: 0 });
+    }
+    /// Write samples going from -1.0 to 1.0.
+    pub fn write_samples(&mut self, samples: &[f32]) -> Result<(), &'static str> {
+        let mut data: Vec<u8> = Vec::with_capacity(samples.len() * 2);
+        for sample in samples {
+            let value = (sample.max(-1.0).min(1.0) * i16::MAX as f32) as i16;
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        self.samples = self.samples + samples.len() as u32;
+        return self
+            .out
+            .write_all(&data)
+            .map_err(|_| "Couldn't write the WAV samples");
+    }
+    /// Fill in the header sizes and give back the output.
+    pub fn finish(mut self) -> Result<W, &'static str> {
+        let data_size = self.samples * 2;
+        let sizes = [(4, data_size + 36), (40, data_size)];
+        for (pos, size) in sizes.iter() {
+            self.out
+                .seek(SeekFrom::Start(*pos))
+                .and_then(|_| self.out.write_all(&size.to_le_bytes()))
+                .map_err(|_| "Couldn't finish the WAV header")?;
+        }
+        self.out
+            .flush()
+            .map_err(|_| "Couldn't write the WAV file")?;
+        return Ok(self.out);
+    }
+}
+
+/// Anything the buzzer can be played through.
+pub trait AudioSink {
+    /// Ring for `ticks` sound timer ticks, called whenever Fx18 loads the sound timer.
+    fn beep_for(&mut self, ticks: u8);
+    /// Play a digitized sound, None stops it. Sinks that can't play them ignore it.
+    fn play_sample(&mut self, _sample: Option<Sample>) {}
+    /// Called after every emulated 60 hertz frame.
+    fn end_frame(&mut self) -> Result<(), &'static str> {
+        return Ok(());
+    }
+    /// Called once when the emulator stops.
+    fn finish(&mut self) -> Result<(), &'static str> {
+        return Ok(());
+    }
+}
+
+/// Drops every beep.
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn beep_for(&mut self, _ticks: u8) {}
+}
+
+/// Renders the beeper to a WAV file, a frame of audio for every emulated frame.
+pub struct WavSink {
+    beeper: Beeper,
+    player: SamplePlayer,
+    wav: Option<WavWriter<BufWriter<File>>>,
+}
+
+impl WavSink {
+    const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / 60) as usize;
+    pub fn create(path: &str, tone: Tone) -> Result<WavSink, &'static str> {
+        return Ok(WavSink {
+            beeper: Beeper::new(tone),
+            player: SamplePlayer::new(tone.volume),
+            wav: Some(WavWriter::create(path)?),
+        });
+    }
+}
+
+impl AudioSink for WavSink {
+    fn beep_for(&mut self, ticks: u8) {
+        self.beeper.beep_for(ticks);
+    }
+    fn play_sample(&mut self, sample: Option<Sample>) {
+        self.player.play(sample);
+    }
+    fn end_frame(&mut self) -> Result<(), &'static str> {
+        let mut samples = [0.0; WavSink::SAMPLES_PER_FRAME];
+        self.beeper.fill(&mut samples, SAMPLE_RATE);
+        let dt = 1.0 / SAMPLE_RATE as f64;
+        for sample in samples.iter_mut() {
+            *sample = *sample + self.player.next_sample(dt);
+        }
+        return match self.wav.as_mut() {
+            Some(wav) => wav.write_samples(&samples),
+            None => Err("WAV file is already finished"),
+        };
+    }
+    fn finish(&mut self) -> Result<(), &'static str> {
+        if let Some(wav) = self.wav.take() {
+            wav.finish()?;
+        }
+        return Ok(());
+    }
+}
+
+/// Used to tell the audio thread about a new beep, NO_REQUEST when there is nothing new.
+const NO_REQUEST: u16 = 0xFFFF;
+
+/// Plays the beeper from the audio thread.
+#[derive(Debug)]
+struct BeeperStream {
+    beeper: Beeper,
+    request: Arc<AtomicU16>,
+    player: SamplePlayer,
+    /// Digitized sound to play next, if there is a new one
+    sample_request: Arc<Mutex<Option<Option<Sample>>>>,
+}
+
+impl AudioStream for BeeperStream {
+    fn next(&mut self, dt: f64) -> Frame {
+        let request = self.request.swap(NO_REQUEST, Ordering::Relaxed);
+        if request != NO_REQUEST {
+            self.beeper.beep_for(request as u8);
+        }
+        // Never wait for the emulator here, a new sample can start on the next call
+        if let Ok(mut sample_request) = self.sample_request.try_lock() {
+            if let Some(sample) = sample_request.take() {
+                self.player.play(sample);
+            }
+        }
+        return Frame::from_mono(self.beeper.next_sample(dt) + self.player.next_sample(dt));
+    }
+}
+
+/// Plays the beeper through the speakers.
+pub struct KiraSink {
     audio_manager: AudioManager,
-    sound_handle: SoundHandle,
-    arrangement_handle: ArrangementHandle,
-}
-impl SoundManager {
-    pub fn new() -> Result<SoundManager, &'static str> {
-        let mut audio_manager = AudioManager::new(AudioManagerSettings::default()).unwrap();
-        let sound_handle_result =
-            audio_manager.load_sound("data/beep.wav", SoundSettings::default());
-        match sound_handle_result {
-            Ok(sound_handle) => {
-                //sound_handle.play(InstanceSettings::default()).unwrap();
-                let mut arrangement_handle = audio_manager
-                    .add_arrangement(Arrangement::new_loop(
-                        &sound_handle,
-                        LoopArrangementSettings::default(),
-                    ))
-                    .unwrap();
-                arrangement_handle.play(InstanceSettings::default());
-                arrangement_handle.pause(PauseInstanceSettings::default());
-                return Ok(SoundManager {
-                    audio_manager,
-                    sound_handle,
-                    arrangement_handle,
-                });
+    request: Arc<AtomicU16>,
+    sample_request: Arc<Mutex<Option<Option<Sample>>>>,
+}
+impl KiraSink {
+    /// Fails when there is no audio device.
+    pub fn new(tone: Tone) -> Result<KiraSink, &'static str> {
+        let mut audio_manager = AudioManager::new(AudioManagerSettings::default())
+            .map_err(|_| "Failed to open the audio device")?;
+        let request = Arc::new(AtomicU16::new(NO_REQUEST));
+        let sample_request = Arc::new(Mutex::new(None));
+        let stream = BeeperStream {
+            beeper: Beeper::new(tone),
+            request: request.clone(),
+            player: SamplePlayer::new(tone.volume),
+            sample_request: sample_request.clone(),
+        };
+        audio_manager
+            .add_stream(stream, TrackIndex::Main)
+            .map_err(|_| "Failed to start the beeper")?;
+        return Ok(KiraSink {
+            audio_manager,
+            request,
+            sample_request,
+        });
+    }
+}
+
+impl AudioSink for KiraSink {
+    fn beep_for(&mut self, ticks: u8) {
+        self.request.store(ticks as u16, Ordering::Relaxed);
+    }
+    fn play_sample(&mut self, sample: Option<Sample>) {
+        *self.sample_request.lock().unwrap() = Some(sample);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AudioSink, Beeper, NullSink, Sample, SamplePlayer, Tone, WavSink, WavWriter, Waveform,
+    };
+    use std::io::Cursor;
+    #[test]
+    fn waveform_parse() {
+        assert_eq!(Waveform::parse("sine"), Ok(Waveform::Sine));
+        assert!(Waveform::parse("saw").is_err(), "Unknown waveform accepted");
+    }
+    #[test]
+    fn tone_parse() {
+        assert_eq!(Tone::parse_frequency("220.5"), Ok(220.5));
+        assert!(Tone::parse_frequency("0").is_err(), "Silent pitch accepted");
+        assert!(
+            Tone::parse_frequency("-440").is_err(),
+            "Negative pitch accepted"
+        );
+        assert!(
+            Tone::parse_frequency("inf").is_err(),
+            "Endless pitch accepted"
+        );
+        assert_eq!(Tone::parse_volume("1"), Ok(1.0));
+        assert!(
+            Tone::parse_volume("-0.5").is_err(),
+            "Negative volume accepted"
+        );
+        assert!(Tone::parse_volume("1.5").is_err(), "Loud volume accepted");
+        assert!(Tone::parse_volume("NaN").is_err(), "NaN volume accepted");
+    }
+    #[test]
+    fn silent_by_default() {
+        let mut beeper = Beeper::new(Tone::default());
+        let mut buffer = [1.0; 64];
+        beeper.fill(&mut buffer, 44100);
+        assert!(buffer.iter().all(|s| *s == 0.0), "Beeper should be quiet");
+    }
+    #[test]
+    fn beep_length() {
+        let mut beeper = Beeper::new(Tone::default());
+        beeper.beep_for(6);
+        // 6 ticks are a tenth of a second, plus the fade out
+        let mut buffer = [0.0; 4410];
+        beeper.fill(&mut buffer, 44100);
+        assert!(beeper.is_ringing(), "Beeper should still be fading out");
+        let mut tail = [0.0; 100];
+        beeper.fill(&mut tail, 44100);
+        assert!(!beeper.is_ringing(), "Beeper should have stopped");
+        assert_eq!(tail[99], 0.0, "Beeper should be quiet");
+    }
+    #[test]
+    fn no_clicks() {
+        let mut beeper = Beeper::new(Tone {
+            waveform: Waveform::Square,
+            frequency: 440.0,
+            volume: 1.0,
+        });
+        beeper.beep_for(1);
+        let mut buffer = [0.0; 2000];
+        beeper.fill(&mut buffer, 44100);
+        let mut last: f32 = 0.0;
+        // Outside of the square edges the wave should never jump more than the fade step
+        for sample in buffer[..20].iter() {
+            assert!((sample - last).abs() < 0.02, "Fade in is too sharp");
+            last = *sample;
+        }
+    }
+    #[test]
+    fn wav() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new())).expect("Couldn't write header");
+        wav.write_samples(&[1.0, -1.0, 0.0]).unwrap();
+        let data = wav.finish().unwrap().into_inner();
+        assert_eq!(data.len(), 44 + 3 * 2, "Wrong file size");
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(&data[4..8], &(36 + 3 * 2 as u32).to_le_bytes());
+        assert_eq!(&data[40..44], &(3 * 2 as u32).to_le_bytes());
+        assert_eq!(&data[44..46], &i16::MAX.to_le_bytes(), "Wrong first sample");
+        assert_eq!(&data[48..50], &[0, 0], "Wrong last sample");
+    }
+    #[test]
+    fn wav_sink() {
+        let path = std::env::temp_dir().join("chip_aight_wav_sink.wav");
+        let path = path.to_str().unwrap();
+        let mut files: Vec<Vec<u8>> = Vec::new();
+        for _ in 0..2 {
+            let mut sink = WavSink::create(path, Tone::default()).expect("Couldn't create");
+            sink.end_frame().unwrap();
+            sink.beep_for(2);
+            for _ in 0..3 {
+                sink.end_frame().unwrap();
             }
-            Err(err) => return Err("Failed to load data/beep.wav"),
+            sink.finish().unwrap();
+            files.push(std::fs::read(path).unwrap());
         }
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(
+            files[0].len(),
+            44 + 4 * 735 * 2,
+            "Every frame should be 735 samples"
+        );
+        assert_eq!(files[0], files[1], "Output should be deterministic");
+        assert!(
+            files[0][44..44 + 735 * 2].iter().all(|b| *b == 0),
+            "First frame is quiet"
+        );
+        assert!(
+            files[0][44 + 735 * 2..].iter().any(|b| *b != 0),
+            "Beep is missing"
+        );
     }
-    pub fn play(&mut self) {
-        self.arrangement_handle
-            .resume(ResumeInstanceSettings::default());
-        return ();
+    #[test]
+    fn sample_player() {
+        let mut player = SamplePlayer::new(1.0);
+        assert_eq!(player.next_sample(0.1), 0.0, "Nothing should play yet");
+        player.play(Some(Sample {
+            rate: 10,
+            data: vec![0xFF, 0x00],
+            looping: false,
+        }));
+        assert!(player.next_sample(0.1) > 0.9, "Wrong first sample");
+        assert_eq!(player.next_sample(0.1), -1.0, "Wrong second sample");
+        assert_eq!(player.next_sample(0.1), 0.0, "Sample should end");
+        assert!(!player.is_playing());
+        player.play(Some(Sample {
+            rate: 10,
+            data: vec![0x00, 0x80],
+            looping: true,
+        }));
+        for _ in 0..2 {
+            assert_eq!(player.next_sample(0.1), -1.0, "Sample should loop");
+            assert_eq!(player.next_sample(0.1), 0.0);
+        }
+        player.play(None);
+        assert!(!player.is_playing(), "Sample should stop");
     }
-    pub fn pause(&mut self) {
-        self.arrangement_handle
-            .pause(PauseInstanceSettings::default());
-        return ();
+    #[test]
+    fn null_sink() {
+        let mut sink = NullSink;
+        sink.beep_for(10);
+        assert!(sink.end_frame().is_ok(), "Null sink should never fail");
+        assert!(sink.finish().is_ok(), "Null sink should never fail");
     }
 }
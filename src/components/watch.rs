@@ -0,0 +1,91 @@
+//! # Watch Module
+//! ## Description
+//! Notices when the rom changes on disk, so it can be reloaded without restarting the emulator.
+//! ## Polling
+//! The modification time of the file is checked every so often, which works everywhere without extra threads.
+//! When it changes the file is read again, and only reported if the contents are really different.
+//!
+//! Assemblers often write the file in more than one go, so empty or unreadable files are ignored until the next poll.
+use std::fs;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Watches a single file.
+pub struct FileWatcher {
+    path: String,
+    /// Time between checks
+    pub interval: Duration,
+    last_poll: Instant,
+    modified: Option<SystemTime>,
+    contents: Vec<u8>,
+}
+
+impl FileWatcher {
+    /// Start watching a file, the contents it has now are not reported as a change.
+    pub fn new(path: &str, contents: &[u8]) -> FileWatcher {
+        return FileWatcher {
+            path: path.to_owned(),
+            interval: Duration::from_millis(500),
+            last_poll: Instant::now(),
+            modified: FileWatcher::modified(path),
+            contents: contents.to_vec(),
+        };
+    }
+    fn modified(path: &str) -> Option<SystemTime> {
+        return fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    }
+    /// Get the new contents of the file if it changed since the last call.
+    pub fn poll(&mut self) -> Option<Vec<u8>> {
+        if self.last_poll.elapsed() < self.interval {
+            return None;
+        }
+        self.last_poll = Instant::now();
+        let modified = FileWatcher::modified(&self.path);
+        if modified == self.modified {
+            return None;
+        }
+        let contents = match fs::read(&self.path) {
+            Ok(contents) if !contents.is_empty() => contents,
+            _ => return None,
+        };
+        self.modified = modified;
+        if contents == self.contents {
+            return None;
+        }
+        self.contents = contents.clone();
+        return Some(contents);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileWatcher;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::time::{Duration, SystemTime};
+    /// Write the file and give it a modification time of its own, so coarse file system clocks can't hide the change.
+    fn write_at(path: &str, contents: &[u8], seconds: u64) {
+        fs::write(path, contents).unwrap();
+        let file = fs::OpenOptions::new().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+            .unwrap();
+    }
+    #[test]
+    fn poll() {
+        // A folder of its own, tests running at the same time don't share the file
+        let folder = env::temp_dir().join(format!("chip_aight_watch_{}", process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let path = folder.join("rom.ch8");
+        let path = path.to_str().unwrap();
+        write_at(path, &[0x12, 0x00], 1000);
+        let mut watcher = FileWatcher::new(path, &[0x12, 0x00]);
+        watcher.interval = Duration::from_millis(0);
+        assert_eq!(watcher.poll(), None, "Nothing changed yet");
+        write_at(path, &[0x12, 0x02], 2000);
+        assert_eq!(watcher.poll(), Some(vec![0x12, 0x02]), "Change not found");
+        assert_eq!(watcher.poll(), None, "Change reported twice");
+        write_at(path, &[], 3000);
+        assert_eq!(watcher.poll(), None, "Empty file reported");
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
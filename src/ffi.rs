@@ -0,0 +1,404 @@
+//! # FFI Module
+//! ## Description
+//! A C interface to the machine, for programs that embed the emulator instead of running the window.
+//!
+//! Build the `cdylib` with `cargo build --release` and include `include/chip_aight.h`,
+//! which `cbindgen --config cbindgen.toml --output include/chip_aight.h src/ffi.rs` writes again after changes.
+//! ## Conventions
+//! * Machines are opaque pointers, made by `chip8_new` and freed by `chip8_free`
+//! * Functions that can fail return 0 on success and a negative number otherwise
+//! * Null pointers are refused, never dereferenced
+//! * Panics never reach C, the machine is halted and CHIP8_ERROR_PANIC returned instead
+//! * Displays are a byte per pixel, row by row, 1 when lit
+//! * Displays are 64x32, save states of hi-res machines bring a 64x64 one with them
+use crate::components::display::Display;
+use crate::components::machine::Machine;
+use crate::components::savestate;
+use std::os::raw::c_int;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+/// Width of the display in pixels.
+pub const CHIP8_WIDTH: usize = 64;
+/// Height of the display in pixels.
+pub const CHIP8_HEIGHT: usize = 32;
+/// Height of the hi-res display in pixels.
+pub const CHIP8_MAX_HEIGHT: usize = 64;
+/// Bytes chip8_framebuffer writes.
+pub const CHIP8_FRAMEBUFFER_SIZE: usize = CHIP8_WIDTH * CHIP8_HEIGHT;
+/// Room for the display at any height.
+pub const CHIP8_MAX_FRAMEBUFFER_SIZE: usize = CHIP8_WIDTH * CHIP8_MAX_HEIGHT;
+
+/// A null pointer or a buffer that is too small.
+pub const CHIP8_ERROR_ARGUMENT: c_int = -1;
+/// The rom doesn't fit in memory.
+pub const CHIP8_ERROR_ROM: c_int = -2;
+/// The save state is not valid.
+pub const CHIP8_ERROR_STATE: c_int = -3;
+/// The interpreter crashed, the machine is halted.
+pub const CHIP8_ERROR_PANIC: c_int = -4;
+
+/// Turn a pointer and a length into a slice, None for null pointers.
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    if data.is_null() {
+        return None;
+    }
+    return Some(slice::from_raw_parts(data, len));
+}
+
+/// Run the body of an entry point, a panic halts the machine and gives back `error` instead of unwinding into C.
+fn guard<T>(machine: *mut Machine, error: T, body: impl FnOnce() -> T) -> T {
+    return match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(result) => result,
+        Err(_) => {
+            if let Some(machine) = unsafe { machine.as_mut() } {
+                machine.halted = true;
+            }
+            error
+        }
+    };
+}
+
+/// Create a machine with a rom loaded, null if the rom doesn't fit.
+///
+/// # Safety
+/// `rom` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_new(rom: *const u8, len: usize) -> *mut Machine {
+    return guard(ptr::null_mut(), ptr::null_mut(), || {
+        let rom = match bytes(rom, len) {
+            Some(rom) => rom,
+            None => return ptr::null_mut(),
+        };
+        return match Machine::new(rom) {
+            Ok(machine) => Box::into_raw(Box::new(machine)),
+            Err(_) => ptr::null_mut(),
+        };
+    });
+}
+
+/// Free a machine made by chip8_new, null is ignored.
+///
+/// # Safety
+/// `machine` must come from chip8_new and not be used after this.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_free(machine: *mut Machine) {
+    guard(ptr::null_mut(), (), || {
+        if !machine.is_null() {
+            drop(Box::from_raw(machine));
+        }
+    });
+}
+
+/// Swap the rom and start over.
+///
+/// # Safety
+/// `machine` must come from chip8_new and `rom` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_load_rom(
+    machine: *mut Machine,
+    rom: *const u8,
+    len: usize,
+) -> c_int {
+    return guard(machine, CHIP8_ERROR_PANIC, || {
+        let (machine, rom) = match (machine.as_mut(), bytes(rom, len)) {
+            (Some(machine), Some(rom)) => (machine, rom),
+            _ => return CHIP8_ERROR_ARGUMENT,
+        };
+        return match machine.load_program(rom) {
+            Ok(_) => 0,
+            Err(_) => CHIP8_ERROR_ROM,
+        };
+    });
+}
+
+/// Run frames of cycles_per_frame cycles, returns 1 if the buzzer rang and 0 if not.
+///
+/// A rom that crashes the interpreter halts the machine and returns CHIP8_ERROR_PANIC.
+///
+/// # Safety
+/// `machine` must come from chip8_new.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_run_frames(
+    machine: *mut Machine,
+    frames: u32,
+    cycles_per_frame: u32,
+) -> c_int {
+    return guard(machine, CHIP8_ERROR_PANIC, || {
+        let machine = match machine.as_mut() {
+            Some(machine) => machine,
+            None => return CHIP8_ERROR_ARGUMENT,
+        };
+        let mut beep = false;
+        for _ in 0..frames {
+            beep = machine.run_frame(cycles_per_frame) || beep;
+            machine.take_breakpoint();
+        }
+        return beep as c_int;
+    });
+}
+
+/// Set every key at once, bit n of the mask is key n.
+///
+/// # Safety
+/// `machine` must come from chip8_new.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_keys(machine: *mut Machine, mask: u16) -> c_int {
+    return guard(machine, CHIP8_ERROR_PANIC, || {
+        let machine = match machine.as_mut() {
+            Some(machine) => machine,
+            None => return CHIP8_ERROR_ARGUMENT,
+        };
+        for key in 0..16 {
+            machine.set_key(key, mask & (1 << key) != 0);
+        }
+        return 0;
+    });
+}
+
+/// Copy the display into a buffer of at least CHIP8_WIDTH times chip8_height bytes.
+///
+/// # Safety
+/// `machine` must come from chip8_new and `out` must point to `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_framebuffer(
+    machine: *const Machine,
+    out: *mut u8,
+    len: usize,
+) -> c_int {
+    return guard(machine as *mut Machine, CHIP8_ERROR_PANIC, || {
+        let machine = match machine.as_ref() {
+            Some(machine) => machine,
+            None => return CHIP8_ERROR_ARGUMENT,
+        };
+        let pixels = machine.framebuffer();
+        if out.is_null() || len < pixels.len() {
+            return CHIP8_ERROR_ARGUMENT;
+        }
+        ptr::copy_nonoverlapping(pixels.as_ptr(), out, pixels.len());
+        return 0;
+    });
+}
+
+/// Returns the height of the display in pixels.
+///
+/// # Safety
+/// `machine` must come from chip8_new.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_height(machine: *const Machine) -> c_int {
+    return guard(machine as *mut Machine, CHIP8_ERROR_PANIC, || {
+        return match machine.as_ref() {
+            Some(machine) => machine.state.height() as c_int,
+            None => CHIP8_ERROR_ARGUMENT,
+        };
+    });
+}
+
+/// Returns 1 while the sound timer is running and 0 if not.
+///
+/// # Safety
+/// `machine` must come from chip8_new.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_sound_active(machine: *const Machine) -> c_int {
+    return guard(machine as *mut Machine, CHIP8_ERROR_PANIC, || {
+        return match machine.as_ref() {
+            Some(machine) => (machine.cpu.st > 0) as c_int,
+            None => CHIP8_ERROR_ARGUMENT,
+        };
+    });
+}
+
+/// Returns the last value loaded into the sound timer since the previous call, or 0 if none.
+///
+/// # Safety
+/// `machine` must come from chip8_new.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_take_beep(machine: *mut Machine) -> c_int {
+    return guard(machine, CHIP8_ERROR_PANIC, || {
+        return match machine.as_mut() {
+            Some(machine) => machine.take_beep().map_or(0, |st| st as c_int),
+            None => CHIP8_ERROR_ARGUMENT,
+        };
+    });
+}
+
+/// Returns 1 once an instruction failed and the machine stopped, 0 if not.
+///
+/// # Safety
+/// `machine` must come from chip8_new.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_halted(machine: *const Machine) -> c_int {
+    return guard(machine as *mut Machine, CHIP8_ERROR_PANIC, || {
+        return match machine.as_ref() {
+            Some(machine) => machine.halted as c_int,
+            None => CHIP8_ERROR_ARGUMENT,
+        };
+    });
+}
+
+/// Soft reset if hard is 0, hard reset otherwise.
+///
+/// # Safety
+/// `machine` must come from chip8_new.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_reset(machine: *mut Machine, hard: c_int) -> c_int {
+    return guard(machine, CHIP8_ERROR_PANIC, || {
+        let machine = match machine.as_mut() {
+            Some(machine) => machine,
+            None => return CHIP8_ERROR_ARGUMENT,
+        };
+        if hard != 0 {
+            machine.hard_reset();
+        } else {
+            machine.soft_reset();
+        }
+        return 0;
+    });
+}
+
+/// Write a save state into a buffer, returns its size.
+///
+/// Call it with a null buffer first to get the size needed, nothing is written if the buffer is too small.
+///
+/// # Safety
+/// `machine` must come from chip8_new and `out` must point to `len` writable bytes, or be null.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_save_state(
+    machine: *const Machine,
+    out: *mut u8,
+    len: usize,
+) -> isize {
+    return guard(machine as *mut Machine, CHIP8_ERROR_PANIC as isize, || {
+        let machine = match machine.as_ref() {
+            Some(machine) => machine,
+            None => return CHIP8_ERROR_ARGUMENT as isize,
+        };
+        let state = savestate::save(machine);
+        if !out.is_null() && len >= state.len() {
+            ptr::copy_nonoverlapping(state.as_ptr(), out, state.len());
+        }
+        return state.len() as isize;
+    });
+}
+
+/// Load a save state, the machine is untouched if it isn't valid.
+///
+/// # Safety
+/// `machine` must come from chip8_new and `state` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_load_state(
+    machine: *mut Machine,
+    state: *const u8,
+    len: usize,
+) -> c_int {
+    return guard(machine, CHIP8_ERROR_PANIC, || {
+        let (machine, state) = match (machine.as_mut(), bytes(state, len)) {
+            (Some(machine), Some(state)) => (machine, state),
+            _ => return CHIP8_ERROR_ARGUMENT,
+        };
+        return match savestate::load(machine, state) {
+            Ok(_) => 0,
+            Err(_) => CHIP8_ERROR_STATE,
+        };
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+    /// 6005 F018 D005 1206: load 5 into st, draw the font at I and loop
+    const PROGRAM: [u8; 8] = [0x60, 0x05, 0xF0, 0x18, 0xD0, 0x05, 0x12, 0x06];
+    #[test]
+    fn lifecycle() {
+        unsafe {
+            let machine = chip8_new(PROGRAM.as_ptr(), PROGRAM.len());
+            assert!(!machine.is_null(), "Couldn't create the machine");
+            assert_eq!(chip8_run_frames(machine, 1, 10), 1, "Buzzer should ring");
+            assert_eq!(chip8_take_beep(machine), 5);
+            assert_eq!(chip8_take_beep(machine), 0);
+            assert_eq!(chip8_sound_active(machine), 1);
+            assert_eq!(chip8_halted(machine), 0);
+            let mut pixels = [0u8; CHIP8_FRAMEBUFFER_SIZE];
+            assert_eq!(
+                chip8_framebuffer(machine, pixels.as_mut_ptr(), pixels.len()),
+                0
+            );
+            assert_eq!(pixels.to_vec(), (*machine).framebuffer());
+            assert_eq!(chip8_height(machine), CHIP8_HEIGHT as c_int);
+            assert_eq!(chip8_set_keys(machine, 0b1000_0000_0000_0010), 0);
+            let keys = (*machine).keypad.keys();
+            assert!(keys[1] && keys[15] && !keys[0]);
+            assert_eq!(chip8_reset(machine, 1), 0);
+            assert_eq!((*machine).cpu.program_counter, 0x200);
+            chip8_free(machine);
+        }
+    }
+    #[test]
+    fn save_states() {
+        unsafe {
+            let machine = chip8_new(PROGRAM.as_ptr(), PROGRAM.len());
+            chip8_run_frames(machine, 1, 2);
+            let size = chip8_save_state(machine, ptr::null_mut(), 0);
+            assert!(size > 0);
+            let mut state = vec![0u8; size as usize];
+            assert_eq!(
+                chip8_save_state(machine, state.as_mut_ptr(), state.len()),
+                size
+            );
+            chip8_reset(machine, 1);
+            assert_eq!(chip8_load_state(machine, state.as_ptr(), state.len()), 0);
+            assert_eq!((*machine).cpu.program_counter, 0x204, "State wasn't loaded");
+            assert_eq!(
+                chip8_load_state(machine, state.as_ptr(), 3),
+                CHIP8_ERROR_STATE
+            );
+            chip8_free(machine);
+        }
+    }
+    #[test]
+    fn wrong_arguments() {
+        unsafe {
+            assert!(chip8_new(ptr::null(), 0).is_null());
+            let big = vec![0u8; 0x2000];
+            assert!(
+                chip8_new(big.as_ptr(), big.len()).is_null(),
+                "Rom is too big"
+            );
+            assert_eq!(
+                chip8_run_frames(ptr::null_mut(), 1, 1),
+                CHIP8_ERROR_ARGUMENT
+            );
+            assert_eq!(chip8_take_beep(ptr::null_mut()), CHIP8_ERROR_ARGUMENT);
+            let machine = chip8_new(PROGRAM.as_ptr(), PROGRAM.len());
+            let mut small = [0u8; 16];
+            assert_eq!(
+                chip8_framebuffer(machine, small.as_mut_ptr(), small.len()),
+                CHIP8_ERROR_ARGUMENT
+            );
+            assert_eq!(
+                chip8_load_rom(machine, big.as_ptr(), big.len()),
+                CHIP8_ERROR_ROM
+            );
+            chip8_free(machine);
+            chip8_free(ptr::null_mut());
+        }
+    }
+    #[test]
+    fn panics() {
+        unsafe {
+            // 00EE with nothing to return to
+            let rom = [0x00, 0xEE];
+            let machine = chip8_new(rom.as_ptr(), rom.len());
+            assert_eq!(chip8_run_frames(machine, 1, 10), CHIP8_ERROR_PANIC);
+            assert_eq!(chip8_halted(machine), 1, "Machine should be halted");
+            assert_eq!(
+                chip8_run_frames(machine, 1, 10),
+                0,
+                "Halted machines don't run"
+            );
+            chip8_free(machine);
+        }
+    }
+}
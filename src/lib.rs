@@ -0,0 +1,13 @@
+//! # chip-aight
+//! ## Description
+//! The emulator core as a library, so other programs can run CHIP-8 roms without the window.
+//!
+//! The `cdylib` has a C interface to the machine, described in `include/chip_aight.h`.
+//! The `python` feature builds a Python module with the machine and a training environment,
+//! and the `libretro` feature turns the library into a libretro core.
+pub mod components;
+pub mod ffi;
+#[cfg(feature = "libretro")]
+pub mod libretro;
+#[cfg(feature = "python")]
+mod python;
@@ -0,0 +1,779 @@
+//! # Libretro Module
+//! ## Description
+//! Turns the shared library into a libretro core, so RetroArch and other frontends can run CHIP-8 roms.
+//!
+//! Build it with `cargo build --release --features libretro`.
+//! ## Video and audio
+//! * Every frame the display is sent as 64x32 XRGB8888 pixels, or 64x64 for hi-res roms
+//! * CHIP-8X roms are painted with their colour board, MegaChip roms are sent at 256x192 while its mode is on
+//! * The beeper is synthesized by the sound module and sent as 16 bit stereo, a frame of samples at a time
+//! ## Input
+//! The keypad is on the RetroPad, the directions are the usual 2, 4, 6 and 8:
+//!
+//! | Button | Key | Button | Key | Button | Key | Button | Key |
+//! |--------|-----|--------|-----|--------|-----|--------|-----|
+//! | Up     | 2   | A      | 5   | L      | 7   | L3     | C   |
+//! | Down   | 8   | B      | 0   | R      | 9   | R3     | D   |
+//! | Left   | 4   | X      | 1   | L2     | A   | Select | E   |
+//! | Right  | 6   | Y      | 3   | R2     | B   | Start  | F   |
+//!
+//! The keyboard has the whole keypad too, on the 1234, QWER, ASDF and ZXCV keys.
+//! ## Roms
+//! Games are read by the loader, so hex dumps, zip archives and Octo cartridges work as well as binaries.
+//! The platform comes from the options of the rom, then from the extension, `.c8x` for CHIP-8X and `.mc8` for MegaChip,
+//! and else from the first bytes of the rom.
+//! ## Save states
+//! States are the ones of the save state module, padded to a deep stack so their size never changes.
+//! Deeper stacks don't fit and can't be saved.
+//! ## Crashes
+//! A rom that crashes the interpreter halts the machine instead of taking the frontend down.
+use crate::components::cheats::Cheats;
+use crate::components::display::{BitDisplay, Display};
+use crate::components::loader;
+use crate::components::machine::Machine;
+use crate::components::megachip;
+use crate::components::platform::Platform;
+use crate::components::savestate;
+use crate::components::sound::{Beeper, Tone, SAMPLE_RATE};
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+const RETRO_API_VERSION: u32 = 1;
+const RETRO_DEVICE_JOYPAD: u32 = 1;
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: u32 = 10;
+const RETRO_ENVIRONMENT_SET_KEYBOARD_CALLBACK: u32 = 12;
+const RETRO_PIXEL_FORMAT_XRGB8888: u32 = 1;
+const RETRO_REGION_NTSC: u32 = 0;
+const RETRO_MEMORY_SYSTEM_RAM: u32 = 2;
+
+/// Speed of the processor, in instructions per second.
+const HERTZ: u32 = 500;
+/// Size of the biggest display, the one of MegaChip.
+const MAX_WIDTH: u32 = megachip::WIDTH as u32;
+const MAX_HEIGHT: u32 = megachip::HEIGHT as u32;
+/// Deepest stack save states have room for.
+const STACK_ROOM: usize = u8::MAX as usize;
+/// Audio frames sent after every video frame.
+const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / Machine::FRAMES_PER_SECOND) as usize;
+/// Colors of the pixels that are on and off.
+const LIT: u32 = 0x00FF_FFFF;
+const UNLIT: u32 = 0x0000_0000;
+
+/// Keypad key for every RetroPad button, in the order of the RETRO_DEVICE_ID_JOYPAD ids.
+const JOYPAD_KEYS: [u8; 16] = [
+    0x0, // B
+    0x3, // Y
+    0xE, // Select
+    0xF, // Start
+    0x2, // Up
+    0x8, // Down
+    0x4, // Left
+    0x6, // Right
+    0x5, // A
+    0x1, // X
+    0x7, // L
+    0x9, // R
+    0xA, // L2
+    0xB, // R2
+    0xC, // L3
+    0xD, // R3
+];
+
+/// Keypad key for every keyboard key, by RETROK code, which is the lowercase ASCII of the key.
+const KEYBOARD_KEYS: [(u32, u8); 16] = [
+    (b'1' as u32, 0x1),
+    (b'2' as u32, 0x2),
+    (b'3' as u32, 0x3),
+    (b'4' as u32, 0xC),
+    (b'q' as u32, 0x4),
+    (b'w' as u32, 0x5),
+    (b'e' as u32, 0x6),
+    (b'r' as u32, 0xD),
+    (b'a' as u32, 0x7),
+    (b's' as u32, 0x8),
+    (b'd' as u32, 0x9),
+    (b'f' as u32, 0xE),
+    (b'z' as u32, 0xA),
+    (b'x' as u32, 0x0),
+    (b'c' as u32, 0xB),
+    (b'v' as u32, 0xF),
+];
+
+#[repr(C)]
+pub struct RetroSystemInfo {
+    pub library_name: *const c_char,
+    pub library_version: *const c_char,
+    pub valid_extensions: *const c_char,
+    pub need_fullpath: bool,
+    pub block_extract: bool,
+}
+
+#[repr(C)]
+pub struct RetroGameGeometry {
+    pub base_width: u32,
+    pub base_height: u32,
+    pub max_width: u32,
+    pub max_height: u32,
+    pub aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct RetroSystemTiming {
+    pub fps: f64,
+    pub sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct RetroSystemAvInfo {
+    pub geometry: RetroGameGeometry,
+    pub timing: RetroSystemTiming,
+}
+
+#[repr(C)]
+pub struct RetroGameInfo {
+    pub path: *const c_char,
+    pub data: *const c_void,
+    pub size: usize,
+    pub meta: *const c_char,
+}
+
+pub type RetroKeyboardEvent =
+    extern "C" fn(down: bool, keycode: u32, character: u32, modifiers: u16);
+
+#[repr(C)]
+pub struct RetroKeyboardCallback {
+    pub callback: RetroKeyboardEvent,
+}
+
+pub type RetroEnvironment = extern "C" fn(cmd: u32, data: *mut c_void) -> bool;
+pub type RetroVideoRefresh =
+    extern "C" fn(data: *const c_void, width: u32, height: u32, pitch: usize);
+pub type RetroAudioSample = extern "C" fn(left: i16, right: i16);
+pub type RetroAudioSampleBatch = extern "C" fn(data: *const i16, frames: usize) -> usize;
+pub type RetroInputPoll = extern "C" fn();
+pub type RetroInputState = extern "C" fn(port: u32, device: u32, index: u32, id: u32) -> i16;
+
+/// Everything the frontend handed over.
+#[derive(Clone, Copy)]
+struct Callbacks {
+    environment: Option<RetroEnvironment>,
+    video_refresh: Option<RetroVideoRefresh>,
+    audio_sample_batch: Option<RetroAudioSampleBatch>,
+    input_poll: Option<RetroInputPoll>,
+    input_state: Option<RetroInputState>,
+}
+
+/// The loaded game.
+struct Core {
+    machine: Machine,
+    beeper: Beeper,
+}
+
+/// Callbacks can run while the core is busy, so they are kept apart.
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+});
+static CORE: Mutex<Option<Core>> = Mutex::new(None);
+/// Keypad keys held on the keyboard, a bit per key.
+///
+/// Frontends send keyboard events from inside input_poll, while retro_run is going, so this can't wait for a lock.
+static KEYBOARD: AtomicU16 = AtomicU16::new(0);
+
+fn callbacks() -> Callbacks {
+    return *CALLBACKS.lock().unwrap();
+}
+
+/// The loaded game, still there after a crash.
+fn core() -> MutexGuard<'static, Option<Core>> {
+    return CORE.lock().unwrap_or_else(PoisonError::into_inner);
+}
+
+/// Run the body on the machine, halting it instead of letting a panic unwind into the frontend.
+fn guard<T>(machine: &mut Machine, body: impl FnOnce(&mut Machine) -> T) -> Option<T> {
+    return match panic::catch_unwind(AssertUnwindSafe(|| body(&mut *machine))) {
+        Ok(result) => Some(result),
+        Err(_) => {
+            machine.halted = true;
+            None
+        }
+    };
+}
+
+/// Pick the platform of a rom, from its options, its extension or its first bytes.
+fn pick_platform(name: &str, rom: &loader::Rom) -> Platform {
+    let extension = name.rsplit('.').next().unwrap_or("").to_lowercase();
+    let by_extension = match extension.as_str() {
+        "c8x" => Some(Platform::Chip8x),
+        "mc8" => Some(Platform::MegaChip),
+        _ => None,
+    };
+    return rom
+        .options
+        .platform
+        .or(by_extension)
+        .or_else(|| Platform::detect(&rom.program))
+        .unwrap_or_default();
+}
+
+/// The display as XRGB8888 pixels, with its width and height.
+fn picture(machine: &Machine) -> (Vec<u32>, u32, u32) {
+    let xrgb =
+        |colour: [u8; 3]| (colour[0] as u32) << 16 | (colour[1] as u32) << 8 | colour[2] as u32;
+    let mut pixels: Vec<u32> = Vec::with_capacity((MAX_WIDTH * MAX_HEIGHT) as usize);
+    if let Some(megachip) = machine
+        .cpu
+        .megachip
+        .as_ref()
+        .filter(|megachip| megachip.enabled)
+    {
+        for row in 0..megachip::HEIGHT {
+            for col in 0..megachip::WIDTH {
+                pixels.push(xrgb(megachip.shown[col][row]));
+            }
+        }
+        return (pixels, MAX_WIDTH, MAX_HEIGHT);
+    }
+    let (width, height) = (machine.state.width(), machine.state.height());
+    for (idx, pixel) in machine.framebuffer().into_iter().enumerate() {
+        pixels.push(match &machine.cpu.chip8x {
+            Some(chip8x) => xrgb(
+                chip8x
+                    .palette_at(idx % width, idx / width)
+                    .shade(pixel * 0xFF),
+            ),
+            None if pixel > 0 => LIT,
+            None => UNLIT,
+        });
+    }
+    return (pixels, width as u32, height as u32);
+}
+
+extern "C" fn keyboard_event(down: bool, keycode: u32, _character: u32, _modifiers: u16) {
+    if let Some((_, key)) = KEYBOARD_KEYS.iter().find(|(code, _)| *code == keycode) {
+        if down {
+            KEYBOARD.fetch_or(1 << key, Ordering::SeqCst);
+        } else {
+            KEYBOARD.fetch_and(!(1 << key), Ordering::SeqCst);
+        }
+    }
+}
+
+/// Room for a save state with a stack as deep as STACK_ROOM, whatever the depth of the stack in it.
+fn state_size(state: &[u8], machine: &Machine) -> usize {
+    return state.len() - 2 * machine.cpu.stack.len() + 2 * STACK_ROOM;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> u32 {
+    return RETRO_API_VERSION;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(callback: RetroEnvironment) {
+    CALLBACKS.lock().unwrap().environment = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(callback: RetroVideoRefresh) {
+    CALLBACKS.lock().unwrap().video_refresh = Some(callback);
+}
+
+/// Samples always go in batches, single ones are never sent.
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_callback: RetroAudioSample) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(callback: RetroAudioSampleBatch) {
+    CALLBACKS.lock().unwrap().audio_sample_batch = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(callback: RetroInputPoll) {
+    CALLBACKS.lock().unwrap().input_poll = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(callback: RetroInputState) {
+    CALLBACKS.lock().unwrap().input_state = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {
+    KEYBOARD.store(0, Ordering::SeqCst);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    *core() = None;
+}
+
+/// # Safety
+/// `info` must point to a RetroSystemInfo.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut RetroSystemInfo) {
+    if let Some(info) = info.as_mut() {
+        info.library_name = b"chip-aight\0".as_ptr() as *const c_char;
+        info.library_version = concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char;
+        info.valid_extensions =
+            b"ch8|c8|sc8|xo8|c8x|mc8|hex|txt|zip|gif\0".as_ptr() as *const c_char;
+        info.need_fullpath = false;
+        info.block_extract = false;
+    }
+}
+
+/// # Safety
+/// `info` must point to a RetroSystemAvInfo.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo) {
+    if let Some(info) = info.as_mut() {
+        let (width, height) = match core().as_ref() {
+            Some(core) => (core.machine.state.width(), core.machine.state.height()),
+            None => (BitDisplay::WIDTH, BitDisplay::HEIGHT),
+        };
+        info.geometry = RetroGameGeometry {
+            base_width: width as u32,
+            base_height: height as u32,
+            max_width: MAX_WIDTH,
+            max_height: MAX_HEIGHT,
+            aspect_ratio: width as f32 / height as f32,
+        };
+        info.timing = RetroSystemTiming {
+            fps: Machine::FRAMES_PER_SECOND as f64,
+            sample_rate: SAMPLE_RATE as f64,
+        };
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: u32, _device: u32) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    if let Some(core) = core().as_mut() {
+        core.machine.soft_reset();
+        core.beeper.beep_for(0);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let callbacks = callbacks();
+    // No lock is held here, frontends send keyboard events from inside input_poll
+    if let Some(input_poll) = callbacks.input_poll {
+        input_poll();
+    }
+    let mut pressed = KEYBOARD.load(Ordering::SeqCst);
+    if let Some(input_state) = callbacks.input_state {
+        for (id, key) in JOYPAD_KEYS.iter().enumerate() {
+            if input_state(0, RETRO_DEVICE_JOYPAD, 0, id as u32) != 0 {
+                pressed = pressed | 1 << key;
+            }
+        }
+    }
+    let mut samples = [0.0; SAMPLES_PER_FRAME];
+    let (pixels, width, height) = {
+        let mut core = core();
+        let core = match core.as_mut() {
+            Some(core) => core,
+            None => return,
+        };
+        for key in 0..16 {
+            core.machine.set_key(key, pressed & (1 << key) != 0);
+        }
+        guard(&mut core.machine, |machine| {
+            machine.run_frame(HERTZ / Machine::FRAMES_PER_SECOND)
+        });
+        // Breakpoints are for the debugger, the game goes on
+        core.machine.take_breakpoint();
+        if let Some(ticks) = core.machine.take_beep() {
+            core.beeper.beep_for(ticks);
+        }
+        core.beeper.fill(&mut samples, SAMPLE_RATE);
+        picture(&core.machine)
+    };
+    if let Some(video_refresh) = callbacks.video_refresh {
+        video_refresh(
+            pixels.as_ptr() as *const c_void,
+            width,
+            height,
+            width as usize * 4,
+        );
+    }
+    if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+        let mut stereo: Vec<i16> = Vec::with_capacity(SAMPLES_PER_FRAME * 2);
+        for sample in samples.iter() {
+            let value = (sample.max(-1.0).min(1.0) * i16::MAX as f32) as i16;
+            stereo.push(value);
+            stereo.push(value);
+        }
+        let mut sent = 0;
+        while sent < SAMPLES_PER_FRAME {
+            let accepted =
+                audio_sample_batch(stereo[sent * 2..].as_ptr(), SAMPLES_PER_FRAME - sent);
+            if accepted == 0 {
+                break;
+            }
+            sent = sent + accepted;
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    let mut core = core();
+    let core = match core.as_mut() {
+        Some(core) => core,
+        None => return 0,
+    };
+    return guard(&mut core.machine, |machine| {
+        state_size(&savestate::save(machine), machine)
+    })
+    .unwrap_or(0);
+}
+
+/// # Safety
+/// `data` must point to `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    let mut core = core();
+    let core = match core.as_mut() {
+        Some(core) => core,
+        None => return false,
+    };
+    let state = match guard(&mut core.machine, |machine| savestate::save(machine)) {
+        Some(state) => state,
+        None => return false,
+    };
+    if data.is_null() || size < state.len() {
+        return false;
+    }
+    let out = slice::from_raw_parts_mut(data as *mut u8, size);
+    out[..state.len()].copy_from_slice(&state);
+    for byte in out[state.len()..].iter_mut() {
+        *byte = 0;
+    }
+    return true;
+}
+
+/// # Safety
+/// `data` must point to `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    let mut core = core();
+    let core = match core.as_mut() {
+        Some(core) => core,
+        None => return false,
+    };
+    if data.is_null() {
+        return false;
+    }
+    let state = slice::from_raw_parts(data as *const u8, size);
+    match guard(&mut core.machine, |machine| savestate::load(machine, state)) {
+        Some(Ok(())) => {}
+        _ => return false,
+    }
+    core.beeper.beep_for(core.machine.cpu.st);
+    return true;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {
+    if let Some(core) = core().as_mut() {
+        core.machine.cheats.codes.clear();
+    }
+}
+
+/// Add a code in the format of cheat files, frontends join several with `+`.
+///
+/// # Safety
+/// `code` must be a null terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn retro_cheat_set(_index: u32, enabled: bool, code: *const c_char) {
+    if !enabled || code.is_null() {
+        return;
+    }
+    let code = CStr::from_ptr(code).to_string_lossy().replace('+', "\n");
+    if let (Some(core), Ok(cheats)) = (core().as_mut(), Cheats::parse(&code)) {
+        core.machine.cheats.codes.extend(cheats.codes);
+    }
+}
+
+/// # Safety
+/// `game` must point to a RetroGameInfo with the rom in memory.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool {
+    let game = match game.as_ref() {
+        Some(game) if !game.data.is_null() => game,
+        _ => return false,
+    };
+    let data = slice::from_raw_parts(game.data as *const u8, game.size);
+    let name = if game.path.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(game.path).to_string_lossy().into_owned()
+    };
+    let rom = match loader::parse(&name, data, None) {
+        Ok(rom) => rom,
+        Err(_) => return false,
+    };
+    let platform = pick_platform(&name, &rom);
+    let mut machine = match Machine::with_platform(
+        &rom.program,
+        platform,
+        platform.load_address(),
+        platform.entry_point(),
+    ) {
+        Ok(machine) => machine,
+        Err(_) => return false,
+    };
+    if let Some(store_load_quirk) = rom.options.store_load_quirk {
+        machine.cpu.store_load_quirk = store_load_quirk;
+    }
+    if let Some(shift_y) = rom.options.shift_y {
+        machine.cpu.shift_y = shift_y;
+    }
+    if let Some(environment) = callbacks().environment {
+        let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+        if !environment(
+            RETRO_ENVIRONMENT_SET_PIXEL_FORMAT,
+            &mut format as *mut u32 as *mut c_void,
+        ) {
+            return false;
+        }
+        let mut keyboard = RetroKeyboardCallback {
+            callback: keyboard_event,
+        };
+        environment(
+            RETRO_ENVIRONMENT_SET_KEYBOARD_CALLBACK,
+            &mut keyboard as *mut RetroKeyboardCallback as *mut c_void,
+        );
+    }
+    KEYBOARD.store(0, Ordering::SeqCst);
+    *core() = Some(Core {
+        machine: machine,
+        beeper: Beeper::new(Tone::default()),
+    });
+    return true;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(
+    _type: u32,
+    _info: *const RetroGameInfo,
+    _num: usize,
+) -> bool {
+    return false;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    *core() = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> u32 {
+    return RETRO_REGION_NTSC;
+}
+
+/// The memory of the machine, for frontends with cheats and achievements.
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: u32) -> *mut c_void {
+    let mut core = core();
+    return match (id, core.as_mut()) {
+        (RETRO_MEMORY_SYSTEM_RAM, Some(core)) => core.machine.mem.space.as_mut_ptr() as *mut c_void,
+        _ => ptr::null_mut(),
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: u32) -> usize {
+    let core = core();
+    return match (id, core.as_ref()) {
+        (RETRO_MEMORY_SYSTEM_RAM, Some(core)) => core.machine.mem.space.len(),
+        _ => 0,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    //! A tiny libretro frontend, driving the core the way RetroArch would.
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    /// 6005 F018 A20C D111 1208 0000 F0: beep, draw a line of 4 pixels and loop
+    const PROGRAM: [u8; 13] = [
+        0x60, 0x05, 0xF0, 0x18, 0xA2, 0x0C, 0xD1, 0x11, 0x12, 0x08, 0x00, 0x00, 0xF0,
+    ];
+
+    static FRAME: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+    static PITCH: AtomicUsize = AtomicUsize::new(0);
+    static AUDIO_FRAMES: AtomicUsize = AtomicUsize::new(0);
+    static LOUDEST: AtomicUsize = AtomicUsize::new(0);
+    static BUTTONS: AtomicU16 = AtomicU16::new(0);
+    static KEYBOARD_CALLBACK: Mutex<Option<RetroKeyboardEvent>> = Mutex::new(None);
+
+    extern "C" fn environment(cmd: u32, data: *mut c_void) -> bool {
+        unsafe {
+            match cmd {
+                RETRO_ENVIRONMENT_SET_PIXEL_FORMAT => {
+                    return *(data as *const u32) == RETRO_PIXEL_FORMAT_XRGB8888;
+                }
+                RETRO_ENVIRONMENT_SET_KEYBOARD_CALLBACK => {
+                    let keyboard = &*(data as *const RetroKeyboardCallback);
+                    *KEYBOARD_CALLBACK.lock().unwrap() = Some(keyboard.callback);
+                    return true;
+                }
+                _ => return false,
+            }
+        }
+    }
+    extern "C" fn video_refresh(data: *const c_void, width: u32, height: u32, pitch: usize) {
+        let pixels =
+            unsafe { slice::from_raw_parts(data as *const u32, (width * height) as usize) };
+        *FRAME.lock().unwrap() = pixels.to_vec();
+        PITCH.store(pitch, Ordering::SeqCst);
+    }
+    extern "C" fn audio_sample_batch(data: *const i16, frames: usize) -> usize {
+        let samples = unsafe { slice::from_raw_parts(data, frames * 2) };
+        let loudest = samples
+            .iter()
+            .map(|sample| sample.unsigned_abs() as usize)
+            .max();
+        LOUDEST.fetch_max(loudest.unwrap_or(0), Ordering::SeqCst);
+        AUDIO_FRAMES.fetch_add(frames, Ordering::SeqCst);
+        return frames;
+    }
+    extern "C" fn input_poll() {}
+    extern "C" fn input_state(port: u32, device: u32, _index: u32, id: u32) -> i16 {
+        if port == 0 && device == RETRO_DEVICE_JOYPAD {
+            return (BUTTONS.load(Ordering::SeqCst) >> id & 1) as i16;
+        }
+        return 0;
+    }
+
+    #[test]
+    fn host() {
+        assert_eq!(retro_api_version(), 1);
+        retro_set_environment(environment);
+        retro_set_video_refresh(video_refresh);
+        retro_set_audio_sample_batch(audio_sample_batch);
+        retro_set_input_poll(input_poll);
+        retro_set_input_state(input_state);
+        retro_init();
+        let game = RetroGameInfo {
+            path: ptr::null(),
+            data: PROGRAM.as_ptr() as *const c_void,
+            size: PROGRAM.len(),
+            meta: ptr::null(),
+        };
+        assert!(unsafe { retro_load_game(&game) }, "Couldn't load the game");
+        assert_eq!(retro_get_memory_size(RETRO_MEMORY_SYSTEM_RAM), 8192);
+
+        retro_run();
+        let frame = FRAME.lock().unwrap().clone();
+        assert_eq!(frame.len(), 64 * 32);
+        assert_eq!(PITCH.load(Ordering::SeqCst), 64 * 4);
+        assert_eq!(frame[..5], [LIT, LIT, LIT, LIT, UNLIT], "Wrong first row");
+        assert_eq!(AUDIO_FRAMES.load(Ordering::SeqCst), SAMPLES_PER_FRAME);
+        assert!(LOUDEST.load(Ordering::SeqCst) > 1000, "Beeper should ring");
+
+        // A on the RetroPad is key 5, X on the keyboard is key 0
+        BUTTONS.store(1 << 8, Ordering::SeqCst);
+        let keyboard = KEYBOARD_CALLBACK
+            .lock()
+            .unwrap()
+            .expect("No keyboard callback");
+        keyboard(true, b'x' as u32, b'x' as u32, 0);
+        retro_run();
+        {
+            let core = core();
+            let keys = core.as_ref().unwrap().machine.keypad.keys();
+            assert!(keys[5] && keys[0] && !keys[1], "Wrong keys pressed");
+        }
+
+        let size = retro_serialize_size();
+        let mut state = vec![0u8; size];
+        assert!(unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, size) });
+        retro_reset();
+        assert_eq!(
+            CORE.lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .machine
+                .cpu
+                .program_counter,
+            0x200
+        );
+        assert!(unsafe { retro_unserialize(state.as_ptr() as *const c_void, size) });
+        assert_eq!(
+            CORE.lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .machine
+                .cpu
+                .program_counter,
+            0x208,
+            "State wasn't loaded"
+        );
+        unsafe { retro_cheat_set(0, true, "300:AA+301:BB\0".as_ptr() as *const c_char) };
+        retro_run();
+        let space = CORE
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .machine
+            .mem
+            .space
+            .clone();
+        assert_eq!(space[0x300..0x302], [0xAA, 0xBB], "Cheats weren't applied");
+        retro_cheat_reset();
+        // Deeper stacks than the room left for them don't fit
+        core().as_mut().unwrap().machine.cpu.stack = vec![0x200; STACK_ROOM + 1];
+        let size = retro_serialize_size();
+        let mut state = vec![0u8; size];
+        assert!(!unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, size) });
+        retro_unload_game();
+        assert_eq!(retro_serialize_size(), 0);
+
+        // A hex dump of 00EE, returning with nothing on the stack
+        assert!(unsafe { retro_load_game(&game_info(b"00EE", "crash.txt\0")) });
+        retro_run();
+        assert!(core().as_ref().unwrap().machine.halted, "Crash should halt");
+        retro_run();
+
+        // 0011 1202: MegaChip mode on and loop
+        assert!(unsafe { retro_load_game(&game_info(&[0x00, 0x11, 0x12, 0x02], "mega.mc8\0")) });
+        retro_run();
+        assert_eq!(FRAME.lock().unwrap().len(), 256 * 192);
+        assert_eq!(PITCH.load(Ordering::SeqCst), 256 * 4);
+
+        // 1300: loop at the CHIP-8X load address
+        assert!(unsafe { retro_load_game(&game_info(&[0x13, 0x00], "colour.c8x\0")) });
+        retro_run();
+        assert!(core().as_ref().unwrap().machine.cpu.chip8x.is_some());
+        assert_eq!(FRAME.lock().unwrap().len(), 64 * 32);
+        retro_unload_game();
+        retro_deinit();
+    }
+
+    fn game_info(data: &[u8], path: &str) -> RetroGameInfo {
+        return RetroGameInfo {
+            path: path.as_ptr() as *const c_char,
+            data: data.as_ptr() as *const c_void,
+            size: data.len(),
+            meta: ptr::null(),
+        };
+    }
+}
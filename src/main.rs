@@ -1,13 +1,35 @@
-mod components;
-use components::memory::Memory;
-use components::sound::SoundManager;
-use components::{cpu::Cpu, sound};
+use chip_aight::components::analyzer;
+use chip_aight::components::breakpoints::Breakpoints;
+use chip_aight::components::capture::{self, Palette, Recorder};
+use chip_aight::components::cheats::Cheats;
+use chip_aight::components::control::Controls;
+use chip_aight::components::display::Display;
+use chip_aight::components::filter::{DisplayFilter, FilterMode};
+use chip_aight::components::gdbstub::GdbStub;
+use chip_aight::components::keymap::{Keymap, KeymapConfig};
+use chip_aight::components::keypad::KeyWait;
+use chip_aight::components::loader::{self, Rom, RomOptions};
+use chip_aight::components::machine::Machine;
+use chip_aight::components::megachip;
+use chip_aight::components::platform::{self, Platform};
+use chip_aight::components::profiler::Profiler;
+use chip_aight::components::recompiler::Recompiler;
+use chip_aight::components::romdb::{self, RomDatabase};
+use chip_aight::components::rpc::RpcServer;
+use chip_aight::components::savestate;
+use chip_aight::components::screen::Screen;
+use chip_aight::components::script::Script;
+use chip_aight::components::sound;
+use chip_aight::components::sound::{AudioSink, KiraSink, NullSink, Tone, WavSink, Waveform};
+use chip_aight::components::watch::FileWatcher;
 use getopts::Options;
 use pixels::{Error, Pixels, SurfaceTexture};
 use std::env;
 use std::fs;
+use std::path::Path;
+use std::process;
 use std::thread::sleep;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use winit::event::{ElementState, StartCause, VirtualKeyCode};
 use winit::{
     dpi::LogicalSize,
@@ -21,43 +43,6 @@ mod dumb_tests {
         assert_eq!(2 + 2, 4);
     }
 }
-/*static KEY_MAP: [VirtualKeyCode; 16] = [
-    VirtualKeyCode::Key1,
-    VirtualKeyCode::Key2,
-    VirtualKeyCode::Key3,
-    VirtualKeyCode::Key4,
-    VirtualKeyCode::Q,
-    VirtualKeyCode::W,
-    VirtualKeyCode::E,
-    VirtualKeyCode::R,
-    VirtualKeyCode::A,
-    VirtualKeyCode::S,
-    VirtualKeyCode::D,
-    VirtualKeyCode::F,
-    VirtualKeyCode::Z,
-    VirtualKeyCode::X,
-    VirtualKeyCode::C,
-    VirtualKeyCode::V,
-];*/
-static KEY_MAP: [VirtualKeyCode; 16] = [
-    VirtualKeyCode::Key1, // 1
-    VirtualKeyCode::Key2, // 2
-    VirtualKeyCode::Key3, // 3
-    VirtualKeyCode::Q,    // 4
-    VirtualKeyCode::W,    // 5
-    VirtualKeyCode::E,    // 6
-    VirtualKeyCode::A,    // 7
-    VirtualKeyCode::S,    // 8
-    VirtualKeyCode::D,    // 9
-    VirtualKeyCode::Z,    // A
-    VirtualKeyCode::X,    // 0
-    VirtualKeyCode::C,    // B
-    VirtualKeyCode::Key4, // C
-    VirtualKeyCode::R,    // D
-    VirtualKeyCode::F,    // E
-    VirtualKeyCode::V,    // F
-];
-
 fn main() {
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
@@ -69,6 +54,147 @@ fn main() {
         "Used to not change the value of I in Fx55 and Fx65",
     );
     opts.optflag("", "shift-y", "Used to use y as a base in shift functions");
+    opts.optflag(
+        "",
+        "recompile",
+        "Compile blocks of instructions to run them faster",
+    );
+    opts.optopt(
+        "",
+        "key-wait",
+        "When Fx0A stops waiting: release (like the VIP) or press",
+        "MODE",
+    );
+    opts.optopt(
+        "",
+        "filter",
+        "Flicker reduction: raw, phosphor[:decay], or, majority[:frames]",
+        "MODE",
+    );
+    opts.optopt(
+        "",
+        "screenshot",
+        "Save a PNG of the display when the emulator exits",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "record",
+        "Record to a .gif, a .y4m or a folder of PNGs, plus a .wav of the buzzer",
+        "PATH",
+    );
+    opts.optopt("", "scale", "Size of a pixel in captures", "INT");
+    opts.optopt(
+        "",
+        "palette",
+        "Foreground and background colours",
+        "RRGGBB,RRGGBB",
+    );
+    opts.optopt(
+        "",
+        "waveform",
+        "Buzzer wave: square, sine or triangle",
+        "WAVE",
+    );
+    opts.optopt("", "tone", "Buzzer frequency in hertz", "HZ");
+    opts.optopt("", "volume", "Buzzer volume, from 0.0 to 1.0", "FLOAT");
+    opts.optopt(
+        "",
+        "audio",
+        "Where the buzzer goes: auto, none or wav:FILE (headless defaults to none)",
+        "SINK",
+    );
+    opts.optflag(
+        "",
+        "headless",
+        "Run without a window, needs --frames, --script, --rpc or --gdb",
+    );
+    opts.optopt("", "frames", "Frames to run in headless mode", "INT");
+    opts.optopt(
+        "",
+        "config",
+        "Config file with keypad layouts and roms, defaults to chip_aight.toml",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "layout",
+        "Keypad layout: standard, hex, numpad or one from the config",
+        "NAME",
+    );
+    opts.optflag("", "watch", "Reload the rom whenever the file changes");
+    opts.optopt(
+        "",
+        "restore",
+        "Save state loaded on start and after every reload, the rom is put back on top",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "breakpoints",
+        "File with hex addresses to pause at, read again on every reload",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "dot",
+        "With analyze, write the control flow graph in Graphviz DOT",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "profile",
+        "Count every instruction run, writes NAME.lst and NAME.folded on exit",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "gdb",
+        "Listen for gdb on a local port, the machine waits for it once attached",
+        "PORT",
+    );
+    opts.optopt(
+        "",
+        "rpc",
+        "Serve JSON-RPC on a local TCP address or unix:PATH, clients drive the machine",
+        "ADDRESS",
+    );
+    opts.optopt(
+        "",
+        "cheats",
+        "Cheat codes written every frame, defaults to the rom path with a .cht extension",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "script",
+        "Rhai script with hooks on frames, addresses and memory writes",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "zip-entry",
+        "File to run inside a zip archive, defaults to the first rom in it",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "platform",
+        "Where programs are loaded and start, and the display size: chip8, eti660, hires, chip8x or megachip",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "load-addr",
+        "Address the rom is loaded at, overrides the platform",
+        "HEX",
+    );
+    opts.optopt(
+        "",
+        "entry",
+        "Address execution starts at, overrides the platform",
+        "HEX",
+    );
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => {
@@ -81,116 +207,535 @@ fn main() {
         _ => hz,
     };
 
-    let one_cycle_time: u128 = 1000000 / hz;
-    //let one_cycle_time: u128 = 1000000;
+    let filter_mode = match matches.opt_str("filter") {
+        Some(mode) => FilterMode::parse(&mode).expect("filter is not a valid mode"),
+        _ => FilterMode::Raw,
+    };
+    let scale = match matches.opt_str("scale") {
+        Some(scale) => scale.parse::<usize>().expect("scale is not a valid number"),
+        _ => 10,
+    };
+    let mut palette = match matches.opt_str("palette") {
+        Some(palette) => Palette::parse(&palette).expect("palette is not valid"),
+        _ => Palette {
+            ..Default::default()
+        },
+    };
+    let screenshot = matches.opt_str("screenshot");
+    let mut tone = Tone {
+        ..Default::default()
+    };
+    if let Some(waveform) = matches.opt_str("waveform") {
+        tone.waveform = Waveform::parse(&waveform).expect("waveform is not valid");
+    }
+    if let Some(frequency) = matches.opt_str("tone") {
+        tone.frequency = Tone::parse_frequency(&frequency).expect("tone is not valid");
+    }
+    if let Some(volume) = matches.opt_str("volume") {
+        tone.volume = Tone::parse_volume(&volume).expect("volume is not valid");
+    }
+
+    let entry = matches.opt_str("zip-entry");
+    if matches.free.len() == 2 && matches.free[0] == "analyze" {
+        let rom = load_from_file(&matches.free[1], &entry).program;
+        let platform = match matches.opt_str("platform") {
+            Some(name) => Platform::parse(&name).expect("platform is not valid"),
+            _ => Platform::detect(&rom).unwrap_or_default(),
+        };
+        let load_address = match matches.opt_str("load-addr") {
+            Some(address) => platform::parse_address(&address).expect("load-addr is not valid"),
+            _ => platform.load_address(),
+        };
+        // The machine of the platform knows how to decode its programs
+        let (_, decoder) = Machine::with_platform(&rom, platform, load_address, load_address)
+            .expect("Rom doesn't fit in memory")
+            .cpu
+            .decoder();
+        let analysis = analyzer::analyze(&rom, load_address, decoder);
+        print!("{}", analysis.report());
+        if let Some(path) = matches.opt_str("dot") {
+            fs::write(&path, analysis.to_dot()).expect("Couldn't write the DOT file");
+        }
+        return;
+    }
     let filename = if !matches.free.is_empty() {
         matches.free[0].clone()
     } else {
         print_usage(&program, opts);
         return;
     };
-    let file = load_from_file(&filename);
+    let rom = load_from_file(&filename, &entry);
+    let mut file = rom.program.clone();
+    // Settings that came with the rom, unless they were given
+    if let (None, Some(cycles)) = (matches.opt_str("hertz"), rom.options.cycles_per_frame) {
+        hz = cycles as u128 * Machine::FRAMES_PER_SECOND as u128;
+    }
+    if let (None, Some(rom_palette)) = (matches.opt_str("palette"), rom.options.palette) {
+        palette = rom_palette;
+    }
+    let one_cycle_time: u128 = 1000000 / hz;
+    //let one_cycle_time: u128 = 1000000;
+    println!("Rom id: {:08x}", romdb::rom_id(&file));
+    let config = match matches.opt_str("config") {
+        Some(path) => Some(path),
+        _ if Path::new(DEFAULT_CONFIG).exists() => Some(DEFAULT_CONFIG.to_owned()),
+        _ => None,
+    };
+    let layout = matches.opt_str("layout");
+    let mut keymap = load_keymap(&config, &layout, &file).expect("Couldn't load the keypad layout");
+    let platform_option = matches.opt_str("platform");
+    let platform = pick_platform(&platform_option, &rom.options, &config, &file);
+    let load_address = match matches.opt_str("load-addr") {
+        Some(address) => platform::parse_address(&address).expect("load-addr is not valid"),
+        _ => platform.load_address(),
+    };
+    let entry_point = match matches.opt_str("entry") {
+        Some(address) => platform::parse_address(&address).expect("entry is not valid"),
+        _ => platform.entry_point(),
+    };
+    let mut machine = Machine::with_platform(&file, platform, load_address, entry_point)
+        .expect("Couldn't load program to memory");
+    let (width, height) = platform.screen_size();
+    let quirks = (
+        matches.opt_present("store-load-quirks"),
+        matches.opt_present("shift-y"),
+    );
+    apply_quirks(&mut machine, quirks, &rom.options);
+    if matches.opt_present("recompile") {
+        machine.cpu.recompiler = Some(Recompiler::default());
+    }
+    if let Some(mode) = matches.opt_str("key-wait") {
+        machine.cpu.key_wait = KeyWait::parse(&mode).expect("key-wait is not valid");
+    }
+    let profile = matches.opt_str("profile");
+    if profile.is_some() {
+        machine.profiler = Some(Profiler::default());
+    }
+    let restore = matches.opt_str("restore");
+    let breakpoints = matches.opt_str("breakpoints");
+    let cheats = match matches.opt_str("cheats") {
+        Some(path) => Some(path),
+        None => {
+            let path = Path::new(&filename).with_extension("cht");
+            if path.exists() {
+                Some(path.to_string_lossy().into_owned())
+            } else {
+                None
+            }
+        }
+    };
+    start_rom(&mut machine, &file, &restore, &breakpoints, &cheats)
+        .expect("Couldn't start the rom");
+    let mut script = matches.opt_str("script").map(|path| {
+        Script::load(&path, &mut machine)
+            .unwrap_or_else(|err| panic!("Couldn't load the script: {}", err))
+    });
+    let mut watcher = if matches.opt_present("watch") {
+        Some(FileWatcher::new(
+            &filename,
+            &fs::read(&filename).unwrap_or_default(),
+        ))
+    } else {
+        None
+    };
+    let state_path = Path::new(&filename)
+        .with_extension("state")
+        .to_string_lossy()
+        .into_owned();
+    //machine.mem.print_memory();
+    let mut filter = DisplayFilter::new(filter_mode);
+    let mut shades = filter.apply(&machine.state);
+    let mut recorder = matches.opt_str("record").map(|path| {
+        Recorder::new(&path, (width, height), scale, palette, tone)
+            .expect("Couldn't start the recording")
+    });
+    let headless = matches.opt_present("headless");
+    let audio_mode = match matches.opt_str("audio") {
+        Some(mode) => mode,
+        _ if headless => "none".to_owned(),
+        _ => "auto".to_owned(),
+    };
+    let mut audio = open_audio(&audio_mode, tone);
+    let cycles_per_frame = (hz / Machine::FRAMES_PER_SECOND as u128) as u32;
+    let mut rpc = matches.opt_str("rpc").map(|address| {
+        let server = RpcServer::bind(&address, cycles_per_frame)
+            .expect("Couldn't start the json-rpc server");
+        println!("Serving json-rpc on {}", address);
+        server
+    });
+    let mut gdb = matches.opt_str("gdb").map(|port| {
+        let port: u16 = port.parse().expect("gdb is not a valid port");
+        let stub =
+            GdbStub::bind(&format!("127.0.0.1:{}", port)).expect("Couldn't start the gdb stub");
+        println!("Listening for gdb on 127.0.0.1:{}", port);
+        stub
+    });
+
+    if headless {
+        if let Some(server) = rpc.as_mut() {
+            // Clients drive the machine, serve until they are done with it
+            while !server.served() {
+                server.poll(&mut machine);
+                sleep(Duration::from_millis(1));
+            }
+            shades = filter.apply(&machine.state);
+        } else {
+            let frames = match matches.opt_str("frames") {
+                Some(frames) => frames.parse::<u32>().expect("frames is not a valid number"),
+                // Scripts say when they are done with stop(), debuggers by leaving
+                _ if script.is_some() || gdb.is_some() => u32::MAX,
+                _ => panic!("--headless needs --frames, --script or --gdb"),
+            };
+            let until_detached = !matches.opt_present("frames") && script.is_none();
+            let mut debugged = false;
+            for _ in 0..frames {
+                if let Some(gdb) = gdb.as_mut() {
+                    // The debugger decides when the machine runs
+                    gdb.poll(&mut machine);
+                    while !gdb.running() {
+                        sleep(Duration::from_millis(1));
+                        gdb.poll(&mut machine);
+                    }
+                    if until_detached && debugged && !gdb.attached() {
+                        break;
+                    }
+                    debugged = debugged || gdb.attached();
+                }
+                run_frame(&mut machine, &mut script, cycles_per_frame);
+                finish_frame(
+                    &mut machine,
+                    &mut filter,
+                    &mut *audio,
+                    &mut recorder,
+                    &mut shades,
+                );
+                if let Some(err) = script.as_mut().and_then(|script| script.take_error()) {
+                    println!("Script failed: {}", err);
+                    process::exit(1);
+                }
+                if script.as_ref().map_or(false, |script| script.stopped()) {
+                    break;
+                }
+            }
+        }
+        finish_capture(
+            recorder.take(),
+            &mut *audio,
+            &screenshot,
+            &shades,
+            scale,
+            &palette,
+        );
+        write_profile(&machine, &profile, &file);
+        return;
+    }
+
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new().build(&event_loop).unwrap();
-    window.set_inner_size(LogicalSize::new(640, 320));
-    let mut mem = Memory {
-        ..Default::default()
-    };
-    let mut cpu = Cpu {
-        ..Default::default()
+    // MegaChip programs can switch to their own display at any time, the usual one is stretched over it
+    let (buffer_width, buffer_height) = match platform {
+        Platform::MegaChip => (megachip::WIDTH, megachip::HEIGHT),
+        _ => (width, height),
     };
-    cpu.store_load_quirk = matches.opt_present("store-load-quirks");
-    cpu.shift_y = matches.opt_present("shift-y");
-    mem.load(&file).expect("Couldn't load program to memory");
-    Cpu::write_fonts_to_mem(&mut mem);
-    //mem.print_memory();
-    let mut is_key_pressed: [bool; 16] = [false; 16];
+    let zoom = (640 / buffer_width).max(1);
+    window.set_inner_size(LogicalSize::new(
+        (zoom * buffer_width) as u32,
+        (zoom * buffer_height) as u32,
+    ));
     let last_frame = 0;
     let size = window.inner_size();
     let surface_texture = SurfaceTexture::new(size.width, size.height, &window);
-    let mut state: [[bool; 32]; 64] = [[false; 32]; 64];
-    let mut pixels = Pixels::new(64, 32, surface_texture).unwrap();
+    let mut pixels =
+        Pixels::new(buffer_width as u32, buffer_height as u32, surface_texture).unwrap();
     let mut last_draw = Instant::now();
     let mut last_cpu = Instant::now();
-    let mut sound_system = SoundManager::new().unwrap();
-    let mut keep_trying = true;
-    //sound_system.play();
+    let mut controls = Controls {
+        ..Default::default()
+    };
+    let rom = rom_name(&filename);
+    let mut title = controls.title(&rom);
+    window.set_title(&title);
     event_loop.run(move |event, _, control_flow| match event {
         Event::WindowEvent {
             event: WindowEvent::CloseRequested,
             ..
-        } => *control_flow = ControlFlow::Exit,
+        } => {
+            finish_capture(
+                recorder.take(),
+                &mut *audio,
+                &screenshot,
+                &shades,
+                scale,
+                &palette,
+            );
+            write_profile(&machine, &profile, &file);
+            *control_flow = ControlFlow::Exit
+        }
         Event::WindowEvent { event, .. } => match event {
             WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
             WindowEvent::KeyboardInput {
                 input:
                     KeyboardInput {
-                        virtual_keycode: Some(virtual_code),
+                        virtual_keycode: Some(VirtualKeyCode::F12),
                         state: ElementState::Pressed,
                         ..
                     },
                 ..
             } => {
-                let key_pressed = KEY_MAP.iter().position(|&s| s == virtual_code);
-                match key_pressed {
-                    Some(key) => {
-                        is_key_pressed[key] = true;
-                    }
-                    _ => (),
+                let path = screenshot_name(&filename);
+                match capture::save_png(&path, &shades, scale, &palette) {
+                    Ok(_) => println!("Saved {}", path),
+                    Err(err) => println!("{}", err),
                 }
             }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        virtual_keycode: Some(VirtualKeyCode::F5),
+                        state: ElementState::Pressed,
+                        ..
+                    },
+                ..
+            } => match load_keymap(&config, &layout, &file) {
+                Ok(new_keymap) => {
+                    keymap = new_keymap;
+                    machine.set_keys([false; 16]);
+                    println!("Reloaded the keypad layout");
+                }
+                Err(err) => println!("{}, keeping the old layout", err),
+            },
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        virtual_keycode: Some(VirtualKeyCode::F6),
+                        state: ElementState::Pressed,
+                        ..
+                    },
+                ..
+            } => match savestate::save_file(&state_path, &machine) {
+                Ok(_) => println!("Saved {}", state_path),
+                Err(err) => println!("{}", err),
+            },
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        virtual_keycode: Some(VirtualKeyCode::F7),
+                        state: ElementState::Pressed,
+                        ..
+                    },
+                ..
+            } => match savestate::load_file(&state_path, &mut machine) {
+                Ok(_) => {
+                    filter.clear();
+                    println!("Loaded {}", state_path)
+                }
+                Err(err) => println!("{}", err),
+            },
             WindowEvent::KeyboardInput {
                 input:
                     KeyboardInput {
                         virtual_keycode: Some(virtual_code),
-                        state: ElementState::Released,
+                        state: ElementState::Pressed,
                         ..
                     },
                 ..
-            } => {
-                let key_pressed = KEY_MAP.iter().position(|&s| s == virtual_code);
-                match key_pressed {
-                    Some(key) => {
-                        is_key_pressed[key] = false;
-                    }
-                    _ => (),
+            } if CONTROL_KEYS.contains(&virtual_code) => match virtual_code {
+                VirtualKeyCode::F1 => controls.toggle_pause(),
+                VirtualKeyCode::F2 => {
+                    machine.soft_reset();
+                    filter.clear();
                 }
-            }
+                VirtualKeyCode::F3 => {
+                    machine.hard_reset();
+                    filter.clear();
+                }
+                VirtualKeyCode::F4 => controls.advance_frame(),
+                VirtualKeyCode::Tab => controls.turbo = true,
+                VirtualKeyCode::Minus => controls.slower(),
+                VirtualKeyCode::Equals => controls.faster(),
+                _ => (),
+            },
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        virtual_keycode: Some(VirtualKeyCode::Tab),
+                        state: ElementState::Released,
+                        ..
+                    },
+                ..
+            } => controls.turbo = false,
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        virtual_keycode: Some(virtual_code),
+                        state: ElementState::Pressed,
+                        ..
+                    },
+                ..
+            } => set_key(&mut machine, &keymap, virtual_code, true),
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        virtual_keycode: Some(virtual_code),
+                        state: ElementState::Released,
+                        ..
+                    },
+                ..
+            } => set_key(&mut machine, &keymap, virtual_code, false),
             _ => (),
         },
         Event::MainEventsCleared => {
-            if last_cpu.elapsed().as_millis() > 5 {
-                let micro_time = last_cpu.elapsed().as_micros();
-                let mut spent_time: u128 = 0;
-                let mut executions_per_run = 0;
-                while spent_time < micro_time {
-                    executions_per_run = executions_per_run + 1;
-                    if keep_trying {
-                        let result = cpu.run_cycle(&mut mem, &mut state, &is_key_pressed);
-                        match result {
-                            Err(_) => {
-                                keep_trying = false;
-                                println!("{:?}", cpu.v)
+            if let Some(server) = rpc.as_mut() {
+                server.poll(&mut machine);
+            }
+            if rpc.as_ref().map_or(false, |server| server.attached()) {
+                // Clients decide when the machine runs, only show what they did
+                if last_draw.elapsed().as_millis() >= 16 {
+                    if machine.state.take_dirty() || !filter.is_still() {
+                        shades = filter.apply(&machine.state);
+                        window.request_redraw();
+                    }
+                    last_draw = Instant::now();
+                }
+                last_cpu = Instant::now();
+            } else if gdb.as_ref().map_or(false, |gdb| !gdb.running()) {
+                // The debugger decides when the machine runs
+                last_cpu = Instant::now();
+                last_draw = Instant::now();
+            } else if controls.paused {
+                if controls.take_frame() {
+                    run_frame(&mut machine, &mut script, cycles_per_frame);
+                    finish_frame(
+                        &mut machine,
+                        &mut filter,
+                        &mut *audio,
+                        &mut recorder,
+                        &mut shades,
+                    );
+                    window.request_redraw();
+                }
+                // Don't try to catch up with the time spent paused
+                last_cpu = Instant::now();
+                last_draw = Instant::now();
+            } else if controls.turbo {
+                // Run whole frames back to back until the next one has to be drawn
+                let mut changed = false;
+                while last_draw.elapsed().as_millis() < 16 && machine.peek_breakpoint().is_none() {
+                    run_frame(&mut machine, &mut script, cycles_per_frame);
+                    changed = finish_frame(
+                        &mut machine,
+                        &mut filter,
+                        &mut *audio,
+                        &mut recorder,
+                        &mut shades,
+                    ) || changed;
+                }
+                if changed || script.is_some() {
+                    window.request_redraw();
+                }
+                last_cpu = Instant::now();
+                last_draw = Instant::now();
+            } else {
+                if last_cpu.elapsed().as_millis() > 5 {
+                    let micro_time = last_cpu.elapsed().as_micros();
+                    let cycle_time = controls.scale_time(one_cycle_time);
+                    let mut spent_time: u128 = 0;
+                    let mut executions_per_run = 0;
+                    while spent_time < micro_time {
+                        executions_per_run = executions_per_run + 1;
+                        if !machine.halted {
+                            let result = match script.as_mut() {
+                                Some(script) => script.step(&mut machine),
+                                None => machine.step(),
+                            };
+                            match result {
+                                Err("Breakpoint") => break,
+                                Err(_) => println!("{:?}", machine.cpu.v),
+                                _ => (),
                             }
-                            _ => (),
                         }
+                        spent_time = spent_time + cycle_time;
+                    }
+                    last_cpu = Instant::now();
+                }
+                if last_draw.elapsed().as_micros() > controls.scale_time(16000) {
+                    last_draw = Instant::now();
+                    machine.tick_timers();
+                    if let Some(script) = script.as_mut() {
+                        script.end_frame(&mut machine);
+                    }
+                    let changed = finish_frame(
+                        &mut machine,
+                        &mut filter,
+                        &mut *audio,
+                        &mut recorder,
+                        &mut shades,
+                    );
+                    // The script draws its overlay every frame
+                    if changed || script.is_some() {
+                        window.request_redraw();
                     }
-                    spent_time = spent_time + one_cycle_time;
                 }
-                last_cpu = Instant::now();
             }
-            if last_draw.elapsed().as_millis() > 16 {
-                window.request_redraw();
-                last_draw = Instant::now();
-                if cpu.dt > 0 {
-                    cpu.dt = cpu.dt - 1
-                };
-                if cpu.st > 0 {
-                    sound_system.play();
-                    cpu.st = cpu.st - 1
-                } else {
-                    sound_system.pause();
+            // Before anything else takes the breakpoint, so the debugger hears about it
+            if let Some(gdb) = gdb.as_mut() {
+                gdb.poll(&mut machine);
+            }
+            if let Some(address) = machine.take_breakpoint() {
+                controls.paused = true;
+                println!("Breakpoint at {:03x}", address);
+            }
+            let reloaded = watcher
+                .as_mut()
+                .and_then(|watcher| watcher.poll())
+                .map(|contents| loader::parse(&filename, &contents, entry.as_deref()));
+            if let Some(Err(err)) = &reloaded {
+                println!("{}, couldn't reload the rom", err);
+            }
+            if let Some(Ok(Rom {
+                program, options, ..
+            })) = reloaded
+            {
+                let new_platform = pick_platform(&platform_option, &options, &config, &program);
+                if new_platform != platform {
+                    println!(
+                        "The rom is now for {:?}, restart to switch platforms",
+                        new_platform
+                    );
                 }
+                apply_quirks(&mut machine, quirks, &options);
+                match start_rom(&mut machine, &program, &restore, &breakpoints, &cheats) {
+                    Ok(_) => {
+                        filter.clear();
+                        println!("Reloaded {} ({:08x})", filename, romdb::rom_id(&program));
+                    }
+                    Err(err) => println!("{}, couldn't reload the rom", err),
+                }
+                if let Ok(new_keymap) = load_keymap(&config, &layout, &program) {
+                    keymap = new_keymap;
+                }
+                file = program;
+            }
+            if let Some(err) = script.as_mut().and_then(|script| script.take_error()) {
+                println!("Script failed: {}", err);
+            }
+            if script.as_ref().map_or(false, |script| script.stopped()) {
+                finish_capture(
+                    recorder.take(),
+                    &mut *audio,
+                    &screenshot,
+                    &shades,
+                    scale,
+                    &palette,
+                );
+                write_profile(&machine, &profile, &file);
+                *control_flow = ControlFlow::Exit;
+                return;
+            }
+            let new_title = controls.title(&rom);
+            if new_title != title {
+                window.set_title(&new_title);
+                title = new_title;
             }
 
             *control_flow = ControlFlow::Poll
@@ -199,17 +744,23 @@ fn main() {
             // Draw it to the `SurfaceTexture`
             let frame = pixels.get_frame();
             let chunks = frame.chunks_exact_mut(4);
+            let overlay = script
+                .as_ref()
+                .map(|script| script.overlay(shades.width(), shades.height()));
             for (idx, pixel) in chunks.enumerate() {
-                let row = idx / 64;
-                let col = idx % 64;
-                if row < state[0].len() {
-                    for rgba_value in pixel {
-                        if state[col][row] {
-                            *rgba_value = 0xFF
-                        } else {
-                            *rgba_value = 0x00
-                        }
-                    }
+                let row = idx / buffer_width;
+                let col = idx % buffer_width;
+                let x = col * shades.width() / buffer_width;
+                let y = row * shades.height() / buffer_height;
+                if row < buffer_height {
+                    let colour = match (&overlay, &machine.cpu.chip8x, &machine.cpu.megachip) {
+                        (Some(overlay), _, _) if overlay[x][y] => OVERLAY_COLOUR,
+                        (_, _, Some(megachip)) if megachip.enabled => megachip.shown[col][row],
+                        // The colour board paints over the palette
+                        (_, Some(chip8x), _) => chip8x.palette_at(x, y).shade(shades[x][y]),
+                        _ => palette.shade(shades[x][y]),
+                    };
+                    pixel.copy_from_slice(&[colour[0], colour[1], colour[2], 0xFF]);
                 }
             }
             pixels.render().unwrap();
@@ -219,10 +770,228 @@ fn main() {
     });
 }
 
-fn load_from_file(file: &str) -> Vec<u8> {
-    return fs::read(file).expect("Failed to read the input file");
+/// Read a rom in any of the formats of the loader.
+fn load_from_file(file: &str, entry: &Option<String>) -> Rom {
+    return loader::load(file, entry.as_deref()).expect("Failed to read the input file");
+}
+/// Keys that control the emulator instead of going to the keypad.
+const CONTROL_KEYS: [VirtualKeyCode; 7] = [
+    VirtualKeyCode::F1,
+    VirtualKeyCode::F2,
+    VirtualKeyCode::F3,
+    VirtualKeyCode::F4,
+    VirtualKeyCode::Tab,
+    VirtualKeyCode::Minus,
+    VirtualKeyCode::Equals,
+];
+/// Colour of the text scripts draw over the display.
+const OVERLAY_COLOUR: [u8; 3] = [0xFF, 0x40, 0x40];
+/// Config file used when --config isn't given, if it exists.
+const DEFAULT_CONFIG: &str = "chip_aight.toml";
+/// Read the config file, no file is the same as an empty one.
+fn read_config(config: &Option<String>) -> Result<toml::Value, &'static str> {
+    return match config {
+        Some(path) => {
+            let text = fs::read_to_string(path).map_err(|_| "Couldn't read the config file")?;
+            toml::from_str(&text).map_err(|_| "Config file is not valid TOML")
+        }
+        None => Ok(toml::Value::Table(toml::value::Table::new())),
+    };
+}
+/// Pick the platform: --platform, then the options of the rom, the rom database and the first bytes of the rom.
+fn pick_platform(
+    name: &Option<String>,
+    options: &RomOptions,
+    config: &Option<String>,
+    rom: &[u8],
+) -> Platform {
+    if let Some(name) = name {
+        return Platform::parse(name).expect("platform is not valid");
+    }
+    if let Some(platform) = options.platform {
+        return platform;
+    }
+    return match rom_platform(config, rom).expect("Couldn't read the rom database") {
+        Some(platform) => platform,
+        None => Platform::detect(rom).unwrap_or_default(),
+    };
+}
+/// Turn on the quirks asked for on the command line, or else by the rom.
+fn apply_quirks(machine: &mut Machine, (store_load, shift_y): (bool, bool), options: &RomOptions) {
+    machine.cpu.store_load_quirk = store_load || options.store_load_quirk.unwrap_or(false);
+    machine.cpu.shift_y = shift_y || options.shift_y.unwrap_or(false);
+}
+/// Find the platform of the rom in the rom database.
+fn rom_platform(config: &Option<String>, rom: &[u8]) -> Result<Option<Platform>, &'static str> {
+    let database = RomDatabase::from_toml(&read_config(config)?)?;
+    return match database
+        .lookup(rom)
+        .and_then(|entry| entry.platform.as_ref())
+    {
+        Some(name) => Ok(Some(Platform::parse(name)?)),
+        None => Ok(None),
+    };
+}
+/// Read the config file and pick the keypad layout, --layout wins over the rom database.
+fn load_keymap(
+    config: &Option<String>,
+    layout: &Option<String>,
+    rom: &[u8],
+) -> Result<Keymap, &'static str> {
+    let config = read_config(config)?;
+    let keymaps = KeymapConfig::from_toml(&config)?;
+    let database = RomDatabase::from_toml(&config)?;
+    let rom_layout = database.lookup(rom).and_then(|entry| entry.layout.clone());
+    let name = layout.clone().or(rom_layout);
+    if let Some(name) = &name {
+        if !keymaps.layouts.contains_key(name) {
+            return Err("Unknown keypad layout");
+        }
+    }
+    return Ok(keymaps.get(name.as_deref()));
+}
+/// Press or release the key of either keypad a physical key is mapped to.
+fn set_key(machine: &mut Machine, keymap: &Keymap, key: VirtualKeyCode, pressed: bool) {
+    if let Some(key) = keymap.lookup(key) {
+        machine.set_key(key as usize, pressed);
+    } else if let (Some(key), Some(chip8x)) =
+        (keymap.lookup_second(key), machine.cpu.chip8x.as_mut())
+    {
+        chip8x.keys[key as usize] = pressed;
+    }
+}
+/// Pick the audio sink, speakers fall back to silence when there is no audio device.
+fn open_audio(mode: &str, tone: Tone) -> Box<dyn AudioSink> {
+    match mode {
+        "none" => Box::new(NullSink),
+        "auto" => match KiraSink::new(tone) {
+            Ok(sink) => Box::new(sink),
+            Err(err) => {
+                println!("{}, running without sound", err);
+                Box::new(NullSink)
+            }
+        },
+        _ if mode.starts_with("wav:") => {
+            Box::new(WavSink::create(&mode[4..], tone).expect("Couldn't create the WAV file"))
+        }
+        _ => panic!("audio is not a valid sink"),
+    }
+}
+/// Load a rom into a fresh machine, then restore the save state, the breakpoints and the cheats if they were asked for.
+fn start_rom(
+    machine: &mut Machine,
+    program: &[u8],
+    restore: &Option<String>,
+    breakpoints: &Option<String>,
+    cheats: &Option<String>,
+) -> Result<(), &'static str> {
+    machine.load_program(program)?;
+    if let Some(path) = breakpoints {
+        machine.breakpoints = Breakpoints::load(path)?;
+    }
+    if let Some(path) = cheats {
+        machine.cheats.codes = Cheats::load(path)?.codes;
+    }
+    if let Some(path) = restore {
+        savestate::load_file(path, machine)?;
+        // The state has the old rom in memory
+        machine.mem.load(program)?;
+    }
+    return Ok(());
+}
+/// Run a frame through the script hooks, if there is a script.
+fn run_frame(machine: &mut Machine, script: &mut Option<Script>, cycles: u32) -> bool {
+    return match script.as_mut() {
+        Some(script) => script.run_frame(machine, cycles),
+        None => machine.run_frame(cycles),
+    };
+}
+/// Send the sound of the frame to the speakers and the recording, and filter the display.
+///
+/// The shades are only worked out again when they can change, returns if they had to be.
+fn finish_frame(
+    machine: &mut Machine,
+    filter: &mut DisplayFilter,
+    audio: &mut dyn AudioSink,
+    recorder: &mut Option<Recorder>,
+    shades: &mut Screen<u8>,
+) -> bool {
+    if let Some(ticks) = machine.take_beep() {
+        audio.beep_for(ticks);
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.beep_for(ticks);
+        }
+    }
+    if let Some(sample) = machine.take_sample() {
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.play_sample(sample.clone());
+        }
+        audio.play_sample(sample);
+    }
+    audio.end_frame().expect("Couldn't play the frame audio");
+    // The colour hardware changes the picture without touching the pixels
+    let colours = machine.cpu.chip8x.is_some() || machine.cpu.megachip.is_some();
+    let changed = machine.state.take_dirty() || !filter.is_still() || colours;
+    if changed {
+        *shades = filter.apply(&machine.state);
+    }
+    if let Some(recorder) = recorder.as_mut() {
+        recorder
+            .record_frame(shades)
+            .expect("Couldn't record the frame");
+    }
+    return changed;
+}
+/// Close the audio, save the last screenshot and close the recording, if they were asked for.
+fn finish_capture(
+    recorder: Option<Recorder>,
+    audio: &mut dyn AudioSink,
+    screenshot: &Option<String>,
+    shades: &Screen<u8>,
+    scale: usize,
+    palette: &Palette,
+) {
+    audio.finish().expect("Couldn't finish the audio");
+    if let Some(recorder) = recorder {
+        recorder.finish().expect("Couldn't finish the recording");
+    }
+    if let Some(path) = screenshot {
+        capture::save_png(path, shades, scale, palette).expect("Couldn't save the screenshot");
+    }
+}
+/// Write the annotated disassembly and the folded stacks, if profiling.
+fn write_profile(machine: &Machine, name: &Option<String>, program: &[u8]) {
+    if let (Some(profiler), Some(name)) = (&machine.profiler, name) {
+        fs::write(
+            format!("{}.lst", name),
+            profiler.listing(program, machine.mem.start, machine.cpu.decoder().1),
+        )
+        .expect("Couldn't write the profile listing");
+        fs::write(format!("{}.folded", name), profiler.folded())
+            .expect("Couldn't write the folded stacks");
+        println!("Wrote {}.lst and {}.folded", name, name);
+    }
+}
+/// Name of the rom file without the folder or the extension.
+fn rom_name(rom: &str) -> String {
+    return Path::new(rom)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "rom".to_owned());
+}
+/// Screenshots taken with F12 are named after the rom and the time they were taken.
+fn screenshot_name(rom: &str) -> String {
+    let stem = rom_name(rom);
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis())
+        .unwrap_or(0);
+    return format!("{}-{}.png", stem, time);
 }
 fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} FILE [options]", program);
+    let brief = format!(
+        "Usage: {} FILE [options]\n       {} analyze FILE [--dot FILE]",
+        program, program
+    );
     print!("{}", opts.usage(&brief));
 }
@@ -0,0 +1,237 @@
+//! # Python Module
+//! ## Description
+//! Python bindings, built with `maturin` when the `python` feature is on.
+//!
+//! * `Machine`: load a rom, run frames, press keys, read the display and the memory, save and load states
+//! * `Env`: the reset and step loop of the environment module, shaped like a Gymnasium environment
+//!
+//! Displays come as `bytes`, a byte per pixel row by row,
+//! `numpy.frombuffer(machine.framebuffer(), dtype=numpy.uint8).reshape(machine.height, machine.width)`
+//! turns them into an array without copying.
+use crate::components::display::Display;
+use crate::components::environment::{Environment, Score};
+use crate::components::machine::Machine;
+use crate::components::savestate;
+use pyo3::exceptions::{PyIndexError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// Turn the errors of the emulator into Python exceptions.
+fn value_error(err: &'static str) -> PyErr {
+    return PyValueError::new_err(err);
+}
+
+/// The display row by row, a byte per pixel.
+fn framebuffer<'py>(py: Python<'py>, machine: &Machine) -> &'py PyBytes {
+    return PyBytes::new(py, &machine.framebuffer());
+}
+
+/// The whole virtual computer.
+#[pyclass(name = "Machine", unsendable)]
+struct PyMachine {
+    machine: Machine,
+    #[pyo3(get, set)]
+    cycles_per_frame: u32,
+}
+
+#[pymethods]
+impl PyMachine {
+    #[new]
+    #[pyo3(signature = (rom, hertz = 500))]
+    fn new(rom: &[u8], hertz: u32) -> PyResult<PyMachine> {
+        return Ok(PyMachine {
+            machine: Machine::new(rom).map_err(value_error)?,
+            cycles_per_frame: hertz / Machine::FRAMES_PER_SECOND,
+        });
+    }
+    #[getter]
+    fn width(&self) -> usize {
+        return self.machine.state.width();
+    }
+    #[getter]
+    fn height(&self) -> usize {
+        return self.machine.state.height();
+    }
+    #[getter]
+    fn halted(&self) -> bool {
+        return self.machine.halted;
+    }
+    /// Swap the rom and start over.
+    fn load_rom(&mut self, rom: &[u8]) -> PyResult<()> {
+        return self.machine.load_program(rom).map_err(value_error);
+    }
+    /// Run frames, returns if the buzzer rang.
+    #[pyo3(signature = (frames = 1))]
+    fn run_frames(&mut self, frames: u32) -> bool {
+        let mut beep = false;
+        for _ in 0..frames {
+            beep = self.machine.run_frame(self.cycles_per_frame) || beep;
+        }
+        return beep;
+    }
+    /// Hold down exactly these keys.
+    fn set_keys(&mut self, keys: Vec<u8>) -> PyResult<()> {
+        let mut pressed = [false; 16];
+        for key in keys {
+            *pressed
+                .get_mut(key as usize)
+                .ok_or_else(|| PyIndexError::new_err("Keys go from 0 to 15"))? = true;
+        }
+        self.machine.set_keys(pressed);
+        return Ok(());
+    }
+    fn framebuffer<'py>(&self, py: Python<'py>) -> &'py PyBytes {
+        return framebuffer(py, &self.machine);
+    }
+    fn read_memory<'py>(
+        &self,
+        py: Python<'py>,
+        address: usize,
+        length: usize,
+    ) -> PyResult<&'py PyBytes> {
+        let bytes = self
+            .machine
+            .mem
+            .space
+            .get(address..address.saturating_add(length))
+            .ok_or_else(|| PyIndexError::new_err("Range is out of memory"))?;
+        return Ok(PyBytes::new(py, bytes));
+    }
+    fn write_memory(&mut self, address: usize, data: &[u8]) -> PyResult<()> {
+        let bytes = self
+            .machine
+            .mem
+            .space
+            .get_mut(address..address.saturating_add(data.len()))
+            .ok_or_else(|| PyIndexError::new_err("Range is out of memory"))?;
+        bytes.copy_from_slice(data);
+        return Ok(());
+    }
+    fn save_state<'py>(&self, py: Python<'py>) -> &'py PyBytes {
+        return PyBytes::new(py, &savestate::save(&self.machine));
+    }
+    fn load_state(&mut self, state: &[u8]) -> PyResult<()> {
+        return savestate::load(&mut self.machine, state).map_err(value_error);
+    }
+    /// Soft or hard reset, seeding the random numbers makes runs repeatable.
+    #[pyo3(signature = (hard = false, seed = None))]
+    fn reset(&mut self, hard: bool, seed: Option<u64>) {
+        if hard {
+            self.machine.hard_reset();
+        } else {
+            self.machine.soft_reset();
+        }
+        if let Some(seed) = seed {
+            self.machine.cpu.rng = StdRng::seed_from_u64(seed);
+        }
+    }
+}
+
+/// A rom ready to be played by an agent, with the Gymnasium reset and step calls.
+#[pyclass(name = "Env", unsendable)]
+struct PyEnv {
+    env: Environment,
+}
+
+#[pymethods]
+impl PyEnv {
+    #[new]
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (
+        rom,
+        frame_skip = 4,
+        hertz = 500,
+        score = Vec::new(),
+        score_bcd = false,
+        game_over = None,
+        max_frames = None,
+        actions = None,
+    ))]
+    fn new(
+        rom: &[u8],
+        frame_skip: u32,
+        hertz: u32,
+        score: Vec<u16>,
+        score_bcd: bool,
+        game_over: Option<(u16, u8)>,
+        max_frames: Option<u32>,
+        actions: Option<Vec<Vec<u8>>>,
+    ) -> PyResult<PyEnv> {
+        let mut env =
+            Environment::new(rom, hertz / Machine::FRAMES_PER_SECOND).map_err(value_error)?;
+        env.frame_skip = frame_skip;
+        env.score = Score {
+            addresses: score,
+            bcd: score_bcd,
+        };
+        env.game_over = game_over;
+        env.max_frames = max_frames;
+        if let Some(actions) = actions {
+            env.actions = actions;
+        }
+        return Ok(PyEnv { env: env });
+    }
+    /// Number of actions step() takes.
+    #[getter]
+    fn action_count(&self) -> usize {
+        return self.env.actions.len();
+    }
+    #[getter]
+    fn width(&self) -> usize {
+        return self.env.machine.state.width();
+    }
+    #[getter]
+    fn height(&self) -> usize {
+        return self.env.machine.state.height();
+    }
+    /// Start a new episode, returns the observation and an info dict.
+    #[pyo3(signature = (seed = None))]
+    fn reset<'py>(
+        &mut self,
+        py: Python<'py>,
+        seed: Option<u64>,
+    ) -> PyResult<(&'py PyBytes, &'py PyDict)> {
+        self.env.reset(seed);
+        return Ok((framebuffer(py, &self.env.machine), self.info(py)?));
+    }
+    /// Play an action, returns the observation, the reward, terminated, truncated and an info dict.
+    fn step<'py>(
+        &mut self,
+        py: Python<'py>,
+        action: usize,
+    ) -> PyResult<(&'py PyBytes, f64, bool, bool, &'py PyDict)> {
+        let step = self.env.step(action).map_err(value_error)?;
+        return Ok((
+            framebuffer(py, &self.env.machine),
+            step.reward,
+            step.terminated,
+            step.truncated,
+            self.info(py)?,
+        ));
+    }
+    /// Save the machine under the environment, to come back to the same point of an episode.
+    fn save_state<'py>(&self, py: Python<'py>) -> &'py PyBytes {
+        return PyBytes::new(py, &savestate::save(&self.env.machine));
+    }
+    fn load_state(&mut self, state: &[u8]) -> PyResult<()> {
+        return savestate::load(&mut self.env.machine, state).map_err(value_error);
+    }
+}
+
+impl PyEnv {
+    fn info<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let info = PyDict::new(py);
+        info.set_item("frames", self.env.frames())?;
+        info.set_item("halted", self.env.machine.halted)?;
+        return Ok(info);
+    }
+}
+
+#[pymodule]
+fn chip_aight(_py: Python, module: &PyModule) -> PyResult<()> {
+    module.add_class::<PyMachine>()?;
+    module.add_class::<PyEnv>()?;
+    return Ok(());
+}